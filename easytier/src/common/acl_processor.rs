@@ -2,11 +2,16 @@ use std::{
     collections::HashMap,
     net::{IpAddr, SocketAddr},
     str::FromStr as _,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use crate::common::{config::ConfigLoader, global_ctx::ArcGlobalCtx, token_bucket::TokenBucket};
+use crate::common::{
+    config::ConfigLoader, global_ctx::ArcGlobalCtx, token_bucket::TokenBucket, PeerId,
+};
 use crate::proto::acl::*;
 use anyhow::Context as _;
 use dashmap::DashMap;
@@ -66,9 +71,67 @@ pub struct FastLookupRule {
     pub stateful: bool,
     pub rate_limit: u32,
     pub burst_limit: u32,
+    pub time_windows: Vec<TimeWindow>,
+    pub src_peer_ids: Vec<PeerId>,
+    pub src_instance_ids: Vec<String>,
+    pub src_hostnames: Vec<wildmatch::WildMatch>,
+    pub src_tags: Vec<String>,
     pub rule_stats: Arc<RuleStats>,
 }
 
+impl FastLookupRule {
+    /// A rule without time windows is always in schedule
+    pub fn is_in_schedule(&self, unix_secs: u64) -> bool {
+        self.time_windows.is_empty() || self.time_windows.iter().any(|w| w.is_active_at(unix_secs))
+    }
+
+    pub fn has_peer_criteria(&self) -> bool {
+        !self.src_peer_ids.is_empty()
+            || !self.src_instance_ids.is_empty()
+            || !self.src_hostnames.is_empty()
+            || !self.src_tags.is_empty()
+    }
+
+    fn peer_matches(&self, peer: Option<&PeerIdentity>) -> bool {
+        if !self.has_peer_criteria() {
+            return true;
+        }
+        let Some(peer) = peer else {
+            return false;
+        };
+
+        if !self.src_peer_ids.is_empty() && !self.src_peer_ids.contains(&peer.peer_id) {
+            return false;
+        }
+        if !self.src_instance_ids.is_empty()
+            && !self
+                .src_instance_ids
+                .iter()
+                .any(|id| id.eq_ignore_ascii_case(&peer.inst_id))
+        {
+            return false;
+        }
+        if !self.src_hostnames.is_empty()
+            && !self.src_hostnames.iter().any(|p| p.matches(&peer.hostname))
+        {
+            return false;
+        }
+        if !self.src_tags.is_empty() && !self.src_tags.iter().any(|t| peer.tags.contains(t)) {
+            return false;
+        }
+        true
+    }
+}
+
+/// Identity of the peer a packet comes from, used by peer matching rules
+#[derive(Debug, Clone, Default, Hash, PartialEq, Eq)]
+pub struct PeerIdentity {
+    pub peer_id: PeerId,
+    pub inst_id: String,
+    pub hostname: String,
+    pub tags: Vec<String>,
+}
+
 // Cache key combining packet info and chain type
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub struct AclCacheKey {
@@ -78,6 +141,7 @@ pub struct AclCacheKey {
     pub dst_ip: IpAddr,
     pub src_port: u16,
     pub dst_port: u16,
+    pub src_peer_id: Option<PeerId>,
 }
 
 impl AclCacheKey {
@@ -89,6 +153,7 @@ impl AclCacheKey {
             dst_ip: packet_info.dst_ip,
             src_port: packet_info.src_port.unwrap_or(0),
             dst_port: packet_info.dst_port.unwrap_or(0),
+            src_peer_id: packet_info.src_peer.as_ref().map(|p| p.peer_id),
         }
     }
 }
@@ -116,6 +181,7 @@ pub struct PacketInfo {
     pub dst_port: Option<u16>,
     pub protocol: Protocol,
     pub packet_size: usize,
+    pub src_peer: Option<Arc<PeerIdentity>>,
 }
 
 // ACL processing result
@@ -197,6 +263,14 @@ pub struct AclProcessor {
 
     default_rule_stats: Arc<RuleStats>,
 
+    // Unix timestamp of the next time window boundary, the rule cache is
+    // flushed once it passes. u64::MAX if no rule has a schedule.
+    next_schedule_boundary: AtomicU64,
+
+    // Whether any rule matches on peer identity, so callers only resolve
+    // the source peer when needed
+    has_peer_rules: bool,
+
     // Connection tracking table - shared across different processor instances if needed
     conn_track: Arc<DashMap<String, ConnTrackEntry>>,
 
@@ -233,6 +307,22 @@ impl AclProcessor {
         let (default_inbound_action, default_outbound_action, default_forward_action) =
             Self::build_default_actions(&acl_config);
         let tasks = JoinSet::new();
+        let next_schedule_boundary = Self::compute_next_schedule_boundary(
+            inbound_rules
+                .iter()
+                .chain(outbound_rules.iter())
+                .chain(forward_rules.iter()),
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        );
+
+        let has_peer_rules = inbound_rules
+            .iter()
+            .chain(outbound_rules.iter())
+            .chain(forward_rules.iter())
+            .any(|r| r.has_peer_criteria());
 
         let mut processor = Self {
             inbound_rules,
@@ -249,7 +339,10 @@ impl AclProcessor {
                     packet_count: 0,
                     byte_count: 0,
                 }),
+                inactive_by_schedule: false,
             }),
+            next_schedule_boundary: AtomicU64::new(next_schedule_boundary),
+            has_peer_rules,
             conn_track: conn_track.unwrap_or_else(|| Arc::new(DashMap::new())),
             rate_limiters: rate_limiters.unwrap_or_else(|| Arc::new(DashMap::new())),
             rule_cache: Arc::new(DashMap::new()), // Always start with fresh cache
@@ -352,6 +445,52 @@ impl AclProcessor {
         (inbound_rules, outbound_rules, forward_rules)
     }
 
+    fn compute_next_schedule_boundary<'a>(
+        rules: impl Iterator<Item = &'a FastLookupRule>,
+        now: u64,
+    ) -> u64 {
+        rules
+            .flat_map(|r| r.time_windows.iter())
+            .map(|w| w.next_boundary_after(now))
+            .min()
+            .unwrap_or(u64::MAX)
+    }
+
+    /// Flush the rule cache when a time window boundary has passed, so cached
+    /// decisions of scheduled rules do not outlive their window.
+    fn check_schedule_boundary(&self, now: u64) {
+        let boundary = self.next_schedule_boundary.load(Ordering::Relaxed);
+        if now < boundary {
+            return;
+        }
+
+        let next = Self::compute_next_schedule_boundary(
+            self.inbound_rules
+                .iter()
+                .chain(self.outbound_rules.iter())
+                .chain(self.forward_rules.iter()),
+            now,
+        );
+        if self
+            .next_schedule_boundary
+            .compare_exchange(boundary, next, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+            self.rule_cache.clear();
+            self.increment_stat(AclStatKey::ScheduleCacheFlushes);
+            tracing::debug!("ACL time window boundary passed, rule cache flushed");
+        }
+    }
+
+    pub fn has_peer_rules(&self) -> bool {
+        self.has_peer_rules
+    }
+
+    /// Drop all cached decisions, e.g. after the identity of a peer changed
+    pub fn flush_rule_cache(&self) {
+        self.rule_cache.clear();
+    }
+
     /// Start periodic cache cleanup task
     fn start_cache_cleanup_task(&mut self) {
         let rule_cache = self.rule_cache.clone();
@@ -443,31 +582,37 @@ impl AclProcessor {
     }
 
     pub fn get_rules_stats(&self) -> Vec<RuleStats> {
-        let mut stats: Vec<RuleStats> = Vec::new();
-        for rule in self.inbound_rules.iter() {
-            stats.push((*rule.rule_stats).clone());
-        }
-        for rule in self.outbound_rules.iter() {
-            stats.push((*rule.rule_stats).clone());
-        }
-        for rule in self.forward_rules.iter() {
-            stats.push((*rule.rule_stats).clone());
-        }
-        stats
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        self.inbound_rules
+            .iter()
+            .chain(self.outbound_rules.iter())
+            .chain(self.forward_rules.iter())
+            .map(|rule| {
+                let mut stats = (*rule.rule_stats).clone();
+                stats.inactive_by_schedule = !rule.is_in_schedule(now);
+                stats
+            })
+            .collect()
     }
 
     /// Process a packet through ACL rules - Now lock-free!
     pub fn process_packet(&self, packet_info: &PacketInfo, chain_type: ChainType) -> AclResult {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        self.check_schedule_boundary(now);
+
         // Check cache first for performance
         let cache_key = AclCacheKey::from_packet_info(packet_info, chain_type);
 
         // If cache hit and can skip checks, return cached result
         if let Some(mut cached) = self.rule_cache.get_mut(&cache_key) {
             // Update last access time for LRU
-            cached.last_access = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
+            cached.last_access = now;
 
             self.increment_stat(AclStatKey::CacheHits);
             return self.process_packet_with_cache_entry(packet_info, &cached);
@@ -491,10 +636,7 @@ impl AclProcessor {
         let mut cache_entry = AclCacheEntry {
             action: Action::Allow,
             matched_rule: RuleId::Default,
-            last_access: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
+            last_access: now,
             conn_track_key: None,
             rate_limit_keys: vec![],
             chain_type,
@@ -504,7 +646,7 @@ impl AclProcessor {
 
         // Process rules in priority order
         for rule in rules.iter() {
-            if !rule.enabled || !self.rule_matches(rule, packet_info) {
+            if !rule.enabled || !rule.is_in_schedule(now) || !self.rule_matches(rule, packet_info) {
                 continue;
             }
 
@@ -671,6 +813,10 @@ impl AclProcessor {
             }
         }
 
+        if !rule.peer_matches(packet_info.src_peer.as_deref()) {
+            return false;
+        }
+
         // Destination port check
         if let Some(dst_port) = packet_info.dst_port {
             if !rule.dst_port_ranges.is_empty() {
@@ -809,12 +955,22 @@ impl AclProcessor {
             stateful: rule.stateful,
             rate_limit: rule.rate_limit,
             burst_limit: rule.burst_limit,
+            time_windows: rule.time_windows.clone(),
+            src_peer_ids: rule.source_peer_ids.clone(),
+            src_instance_ids: rule.source_instance_ids.clone(),
+            src_hostnames: rule
+                .source_hostnames
+                .iter()
+                .map(|h| wildmatch::WildMatch::new(h))
+                .collect(),
+            src_tags: rule.source_tags.clone(),
             rule_stats: Arc::new(RuleStats {
                 rule: Some(rule.clone()),
                 stat: Some(StatItem {
                     packet_count: 0,
                     byte_count: 0,
                 }),
+                inactive_by_schedule: false,
             }),
         }
     }
@@ -931,6 +1087,7 @@ pub enum AclStatKey {
     RuleMatches,
     DefaultAllows,
     DefaultDrops,
+    ScheduleCacheFlushes,
 
     // Global packet statistics
     PacketsTotal,
@@ -1071,6 +1228,11 @@ impl AclRuleBuilder {
                 rate_limit: 0,
                 burst_limit: 0,
                 stateful: true,
+                time_windows: vec![],
+                source_peer_ids: vec![],
+                source_instance_ids: vec![],
+                source_hostnames: vec![],
+                source_tags: vec![],
             };
             inbound_chain.rules.push(tcp_rule);
             rule_priority -= 1;
@@ -1093,6 +1255,11 @@ impl AclRuleBuilder {
                 rate_limit: 0,
                 burst_limit: 0,
                 stateful: false,
+                time_windows: vec![],
+                source_peer_ids: vec![],
+                source_instance_ids: vec![],
+                source_hostnames: vec![],
+                source_tags: vec![],
             };
             inbound_chain.rules.push(udp_rule);
         }
@@ -1182,6 +1349,7 @@ mod tests {
             dst_port: Some(80),
             protocol: Protocol::Tcp,
             packet_size: 1024,
+            src_peer: None,
         }
     }
 
@@ -1380,6 +1548,7 @@ mod tests {
             dst_port: Some(53),      // DNS
             protocol: Protocol::Udp, // UDP
             packet_size: 512,
+            src_peer: None,
         };
 
         // Test TCP packet (should hit stateful+rate-limited rule)
@@ -1390,6 +1559,7 @@ mod tests {
             dst_port: Some(80),      // HTTP
             protocol: Protocol::Tcp, // TCP
             packet_size: 1024,
+            src_peer: None,
         };
 
         // Process UDP packets multiple times
@@ -1484,4 +1654,171 @@ mod tests {
             Some(AclLogContext::RateLimitDrop)
         ));
     }
+
+    #[test]
+    fn test_time_window_is_active() {
+        // 2024-01-01 00:00:00 UTC, a Monday
+        let monday = 1704067200u64;
+        let hour = 3600u64;
+
+        let office_hours = TimeWindow {
+            days_of_week: vec![1, 2, 3, 4, 5],
+            start_time: 8 * 60,
+            end_time: 19 * 60,
+            timezone_offset: 0,
+        };
+        assert!(office_hours.is_active_at(monday + 9 * hour));
+        assert!(!office_hours.is_active_at(monday + 19 * hour));
+        assert!(!office_hours.is_active_at(monday + 7 * hour));
+        // sunday
+        assert!(!office_hours.is_active_at(monday - 24 * hour + 9 * hour));
+        assert_eq!(
+            office_hours.next_boundary_after(monday + 9 * hour),
+            monday + 19 * hour
+        );
+
+        // UTC+8, monday 08:00 local is monday 00:00 UTC
+        let shanghai = TimeWindow {
+            timezone_offset: 8 * 60,
+            ..office_hours.clone()
+        };
+        assert!(shanghai.is_active_at(monday));
+        assert!(!shanghai.is_active_at(monday + 11 * hour));
+
+        // window across midnight belongs to the day it starts
+        let overnight = TimeWindow {
+            days_of_week: vec![1],
+            start_time: 22 * 60,
+            end_time: 2 * 60,
+            timezone_offset: 0,
+        };
+        assert!(!overnight.is_active_at(monday + hour));
+        assert!(overnight.is_active_at(monday + 23 * hour));
+        assert!(overnight.is_active_at(monday + 25 * hour));
+        assert!(!overnight.is_active_at(monday + 26 * hour));
+    }
+
+    #[tokio::test]
+    async fn test_rule_inactive_by_schedule() {
+        let mut acl_config = create_test_acl_config();
+        if let Some(ref mut acl_v1) = acl_config.acl_v1 {
+            // no day of week matches, so the rule is never in schedule
+            let rule = Rule {
+                name: "scheduled_drop".to_string(),
+                priority: 200,
+                enabled: true,
+                action: Action::Drop as i32,
+                protocol: Protocol::Any as i32,
+                time_windows: vec![TimeWindow {
+                    days_of_week: vec![7],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            };
+            acl_v1.chains[0].rules.push(rule);
+        }
+
+        let processor = AclProcessor::new(acl_config);
+        let packet_info = create_test_packet_info();
+
+        let result = processor.process_packet(&packet_info, ChainType::Inbound);
+        assert_eq!(result.action, Action::Allow);
+        assert_eq!(result.matched_rule, Some(RuleId::Priority(100)));
+
+        let rules_stats = processor.get_rules_stats();
+        let scheduled = rules_stats
+            .iter()
+            .find(|s| s.rule.as_ref().unwrap().name == "scheduled_drop")
+            .unwrap();
+        assert!(scheduled.inactive_by_schedule);
+        let always = rules_stats
+            .iter()
+            .find(|s| s.rule.as_ref().unwrap().name == "allow_all")
+            .unwrap();
+        assert!(!always.inactive_by_schedule);
+    }
+
+    #[tokio::test]
+    async fn test_schedule_boundary_flushes_cache() {
+        let mut acl_config = create_test_acl_config();
+        if let Some(ref mut acl_v1) = acl_config.acl_v1 {
+            acl_v1.chains[0].rules[0].time_windows = vec![TimeWindow::default()];
+        }
+
+        let processor = AclProcessor::new(acl_config);
+        assert_ne!(
+            processor.next_schedule_boundary.load(Ordering::Relaxed),
+            u64::MAX
+        );
+
+        let packet_info = create_test_packet_info();
+        processor.process_packet(&packet_info, ChainType::Inbound);
+        assert_eq!(processor.rule_cache.len(), 1);
+
+        // pretend the boundary has passed
+        processor.next_schedule_boundary.store(0, Ordering::Relaxed);
+        processor.check_schedule_boundary(1);
+        assert_eq!(processor.rule_cache.len(), 0);
+        assert!(processor.next_schedule_boundary.load(Ordering::Relaxed) > 1);
+        assert_eq!(
+            processor
+                .get_stats()
+                .get(&AclStatKey::ScheduleCacheFlushes.as_str()),
+            Some(&1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_peer_identity_matching() {
+        let mut acl_config = create_test_acl_config();
+        if let Some(ref mut acl_v1) = acl_config.acl_v1 {
+            acl_v1.chains[0].default_action = Action::Drop as i32;
+            acl_v1.chains[0].rules[0].source_tags = vec!["lab".to_string()];
+            acl_v1.chains[0].rules.push(Rule {
+                name: "allow_hosts".to_string(),
+                priority: 90,
+                enabled: true,
+                action: Action::Allow as i32,
+                protocol: Protocol::Any as i32,
+                source_hostnames: vec!["office-*".to_string()],
+                ..Default::default()
+            });
+        }
+
+        let processor = AclProcessor::new(acl_config);
+        assert!(processor.has_peer_rules());
+
+        // unknown source peer never matches peer rules
+        let mut packet_info = create_test_packet_info();
+        let result = processor.process_packet(&packet_info, ChainType::Inbound);
+        assert_eq!(result.action, Action::Drop);
+
+        packet_info.src_peer = Some(Arc::new(PeerIdentity {
+            peer_id: 1,
+            hostname: "office-pc".to_string(),
+            ..Default::default()
+        }));
+        let result = processor.process_packet(&packet_info, ChainType::Inbound);
+        assert_eq!(result.action, Action::Allow);
+        assert_eq!(result.matched_rule, Some(RuleId::Priority(90)));
+
+        // same address from another peer must not hit the cached decision
+        packet_info.src_peer = Some(Arc::new(PeerIdentity {
+            peer_id: 2,
+            hostname: "home-pc".to_string(),
+            tags: vec!["lab".to_string()],
+            ..Default::default()
+        }));
+        let result = processor.process_packet(&packet_info, ChainType::Inbound);
+        assert_eq!(result.action, Action::Allow);
+        assert_eq!(result.matched_rule, Some(RuleId::Priority(100)));
+
+        packet_info.src_peer = Some(Arc::new(PeerIdentity {
+            peer_id: 3,
+            hostname: "home-pc".to_string(),
+            ..Default::default()
+        }));
+        let result = processor.process_packet(&packet_info, ChainType::Inbound);
+        assert_eq!(result.action, Action::Drop);
+    }
 }
@@ -1,6 +1,12 @@
 use anyhow::Context;
 use dashmap::DashMap;
-use std::cell::RefCell;
+use once_cell::sync::Lazy;
+use std::{
+    cell::RefCell,
+    hash::{Hash, Hasher},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use zstd::bulk;
 
 use zerocopy::{AsBytes as _, FromBytes as _};
@@ -19,7 +25,10 @@ pub trait Compressor {
     async fn decompress(&self, packet: &mut ZCPacket) -> Result<(), Error>;
 }
 
-pub struct DefaultCompressor {}
+pub struct DefaultCompressor {
+    level: i32,
+    dict_id: u32,
+}
 
 impl Default for DefaultCompressor {
     fn default() -> Self {
@@ -29,7 +38,24 @@ impl Default for DefaultCompressor {
 
 impl DefaultCompressor {
     pub fn new() -> Self {
-        DefaultCompressor {}
+        DefaultCompressor {
+            level: 0,
+            dict_id: 0,
+        }
+    }
+
+    /// Compression level of the zstd based algorithms, 0 is the zstd default.
+    pub fn with_level(mut self, level: i32) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Dictionary used by `CompressorAlgo::ZstdDict`, must be registered with
+    /// `register_zstd_dict` first. Decompression finds the dictionary by the
+    /// id stored in the zstd frame.
+    pub fn with_dict(mut self, dict_id: u32) -> Self {
+        self.dict_id = dict_id;
+        self
     }
 
     pub async fn compress_raw(
@@ -37,19 +63,33 @@ impl DefaultCompressor {
         data: &[u8],
         compress_algo: CompressorAlgo,
     ) -> Result<Vec<u8>, Error> {
-        match compress_algo {
-            CompressorAlgo::ZstdDefault => CTX_MAP.with(|map_cell| {
-                let map = map_cell.borrow();
-                let mut ctx_entry = map.entry(compress_algo).or_default();
-                ctx_entry.compress(data).with_context(|| {
-                    format!(
-                        "Failed to compress data with algorithm: {:?}",
-                        compress_algo
-                    )
-                })
-            }),
-            CompressorAlgo::None => Ok(data.to_vec()),
-        }
+        let dict_id = match compress_algo {
+            CompressorAlgo::ZstdDefault => 0,
+            CompressorAlgo::ZstdDict => self.dict_id,
+            CompressorAlgo::Lz4 => return Ok(lz4_flex::block::compress_prepend_size(data)),
+            CompressorAlgo::None => return Ok(data.to_vec()),
+        };
+
+        CTX_MAP.with(|map_cell| {
+            let map = map_cell.borrow();
+            let key = (compress_algo, self.level, dict_id);
+            if !map.contains_key(&key) {
+                let ctx = if dict_id == 0 {
+                    bulk::Compressor::new(self.level)?
+                } else {
+                    let dict = get_zstd_dict(dict_id)?;
+                    bulk::Compressor::with_dictionary(self.level, &dict)?
+                };
+                map.insert(key, ctx);
+            }
+            let mut ctx_entry = map.get_mut(&key).unwrap();
+            ctx_entry.compress(data).with_context(|| {
+                format!(
+                    "Failed to compress data with algorithm: {:?}",
+                    compress_algo
+                )
+            })
+        })
     }
 
     pub async fn decompress_raw(
@@ -57,30 +97,56 @@ impl DefaultCompressor {
         data: &[u8],
         compress_algo: CompressorAlgo,
     ) -> Result<Vec<u8>, Error> {
-        match compress_algo {
-            CompressorAlgo::ZstdDefault => DCTX_MAP.with(|map_cell| {
-                let map = map_cell.borrow();
-                let mut ctx_entry = map.entry(compress_algo).or_default();
-                for i in 1..=5 {
-                    let mut len = data.len() * 2usize.pow(i);
-                    if i == 5 && len < 64 * 1024 {
-                        len = 64 * 1024; // Ensure a minimum buffer size
-                    }
-                    match ctx_entry.decompress(data, len) {
-                        Ok(buf) => return Ok(buf),
-                        Err(e) if e.to_string().contains("buffer is too small") => {
-                            continue; // Try with a larger buffer
-                        }
-                        Err(e) => return Err(e.into()),
+        let dict_id = match compress_algo {
+            CompressorAlgo::ZstdDefault => 0,
+            CompressorAlgo::ZstdDict => zstd_frame_dict_id(data)
+                .filter(|id| *id != 0)
+                .ok_or(anyhow::anyhow!("zstd frame without dictionary id"))?,
+            CompressorAlgo::Lz4 => {
+                let size = data
+                    .get(..4)
+                    .map(|x| u32::from_le_bytes(x.try_into().unwrap()) as usize)
+                    .ok_or(anyhow::anyhow!("lz4 data too short: {}", data.len()))?;
+                if size > LZ4_MAX_DECOMPRESSED_SIZE {
+                    anyhow::bail!("lz4 decompressed size too large: {}", size);
+                }
+                return lz4_flex::block::decompress_size_prepended(data)
+                    .with_context(|| "Failed to decompress data with algorithm: Lz4");
+            }
+            CompressorAlgo::None => return Ok(data.to_vec()),
+        };
+
+        DCTX_MAP.with(|map_cell| {
+            let map = map_cell.borrow();
+            let key = (compress_algo, dict_id);
+            if !map.contains_key(&key) {
+                let ctx = if dict_id == 0 {
+                    bulk::Decompressor::new()?
+                } else {
+                    let dict = get_zstd_dict(dict_id)?;
+                    bulk::Decompressor::with_dictionary(&dict)?
+                };
+                map.insert(key, ctx);
+            }
+            let mut ctx_entry = map.get_mut(&key).unwrap();
+            for i in 1..=5 {
+                let mut len = data.len() * 2usize.pow(i);
+                if i == 5 && len < 64 * 1024 {
+                    len = 64 * 1024; // Ensure a minimum buffer size
+                }
+                match ctx_entry.decompress(data, len) {
+                    Ok(buf) => return Ok(buf),
+                    Err(e) if e.to_string().contains("buffer is too small") => {
+                        continue; // Try with a larger buffer
                     }
+                    Err(e) => return Err(e.into()),
                 }
-                Err(anyhow::anyhow!(
-                    "Failed to decompress data after multiple attempts with algorithm: {:?}",
-                    compress_algo
-                ))
-            }),
-            CompressorAlgo::None => Ok(data.to_vec()),
-        }
+            }
+            Err(anyhow::anyhow!(
+                "Failed to decompress data after multiple attempts with algorithm: {:?}",
+                compress_algo
+            ))
+        })
     }
 }
 
@@ -169,9 +235,184 @@ impl Compressor for DefaultCompressor {
     }
 }
 
+// a packet never decompresses to more than this, larger sizes in the lz4
+// header are bogus and must not be allocated
+const LZ4_MAX_DECOMPRESSED_SIZE: usize = 4 * 1024 * 1024;
+
+const ZSTD_DICT_MAGIC: u32 = 0xEC30A437;
+const ZSTD_FRAME_MAGIC: u32 = 0xFD2FB528;
+
+static ZSTD_DICTS: Lazy<DashMap<u32, Arc<Vec<u8>>>> = Lazy::new(DashMap::new);
+static ZSTD_DICT_PATHS: Lazy<DashMap<String, u32>> = Lazy::new(DashMap::new);
+
+/// Register a dictionary trained with `zstd --train` and return its id.
+/// Dictionaries without the zstd dictionary header are rejected because the
+/// id is needed to pick the dictionary when decompressing.
+pub fn register_zstd_dict(dict: Vec<u8>) -> Result<u32, Error> {
+    if dict.len() < 8 || u32::from_le_bytes(dict[..4].try_into().unwrap()) != ZSTD_DICT_MAGIC {
+        anyhow::bail!("not a zstd dictionary, train one with `zstd --train`");
+    }
+    let dict_id = u32::from_le_bytes(dict[4..8].try_into().unwrap());
+    if dict_id == 0 {
+        anyhow::bail!("zstd dictionary id must not be 0");
+    }
+    ZSTD_DICTS.insert(dict_id, Arc::new(dict));
+    Ok(dict_id)
+}
+
+/// Load and register the zstd dictionary at `path`, files are read only once.
+pub fn load_zstd_dict(path: &str) -> Result<u32, Error> {
+    if let Some(dict_id) = ZSTD_DICT_PATHS.get(path) {
+        return Ok(*dict_id);
+    }
+    let dict =
+        std::fs::read(path).with_context(|| format!("failed to read zstd dictionary: {}", path))?;
+    let dict_id = register_zstd_dict(dict)?;
+    ZSTD_DICT_PATHS.insert(path.to_string(), dict_id);
+    Ok(dict_id)
+}
+
+fn get_zstd_dict(dict_id: u32) -> Result<Arc<Vec<u8>>, Error> {
+    ZSTD_DICTS
+        .get(&dict_id)
+        .map(|x| x.clone())
+        .ok_or(anyhow::anyhow!("unknown zstd dictionary: {}", dict_id))
+}
+
+// parse the dictionary id from the header of a zstd frame, see RFC 8878
+fn zstd_frame_dict_id(frame: &[u8]) -> Option<u32> {
+    if u32::from_le_bytes(frame.get(..4)?.try_into().unwrap()) != ZSTD_FRAME_MAGIC {
+        return None;
+    }
+    let descriptor = *frame.get(4)?;
+    let single_segment = descriptor & 0x20 != 0;
+    let id_len = [0, 1, 2, 4][(descriptor & 0x03) as usize];
+    let start = if single_segment { 5 } else { 6 };
+    let mut id = [0u8; 4];
+    id[..id_len].copy_from_slice(frame.get(start..start + id_len)?);
+    Some(u32::from_le_bytes(id))
+}
+
+// flows whose packets shrink less than this are not worth the cpu
+const ADAPTIVE_RATIO_THRESHOLD: f32 = 0.95;
+const ADAPTIVE_MIN_SAMPLES: u32 = 4;
+const ADAPTIVE_SKIP_PACKETS: u32 = 64;
+const ADAPTIVE_MAX_SKIP_PACKETS: u32 = 4096;
+const ADAPTIVE_FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const ADAPTIVE_FLOW_GC_THRESHOLD: usize = 4096;
+
+/// Identify the flow of a data packet by the addresses, protocol and ports of
+/// the ip packet in its payload. Returns None for non-ip payloads.
+pub fn flow_key(packet: &ZCPacket) -> Option<u64> {
+    let payload = packet.payload();
+    let (proto, addrs, l4) = match payload.first()? >> 4 {
+        4 => {
+            let ihl = ((payload[0] & 0x0f) as usize) * 4;
+            (*payload.get(9)?, payload.get(12..20)?, payload.get(ihl..))
+        }
+        6 => (*payload.get(6)?, payload.get(8..40)?, payload.get(40..)),
+        _ => return None,
+    };
+    let ports = match proto {
+        // tcp, udp
+        6 | 17 => l4.and_then(|x| x.get(..4)).unwrap_or_default(),
+        _ => &[],
+    };
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    (proto, addrs, ports).hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+#[derive(Debug)]
+struct FlowCompressStats {
+    ratio: f32,
+    samples: u32,
+    skip_remaining: u32,
+    skip_packets: u32,
+    // the next compressed packet is the first one after skipping
+    probing: bool,
+    last_seen: Instant,
+}
+
+impl Default for FlowCompressStats {
+    fn default() -> Self {
+        Self {
+            ratio: 1.0,
+            samples: 0,
+            skip_remaining: 0,
+            skip_packets: ADAPTIVE_SKIP_PACKETS,
+            probing: false,
+            last_seen: Instant::now(),
+        }
+    }
+}
+
+/// Tracks the compression ratio of each flow and bypasses compression for
+/// flows that do not benefit, e.g. tls or video. Skipped flows are probed
+/// again after a number of packets, backing off while they stay
+/// incompressible.
+#[derive(Debug, Default)]
+pub struct AdaptiveCompression {
+    flows: DashMap<u64, FlowCompressStats>,
+}
+
+impl AdaptiveCompression {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn should_compress(&self, flow: u64) -> bool {
+        if self.flows.len() > ADAPTIVE_FLOW_GC_THRESHOLD {
+            self.flows
+                .retain(|_, stats| stats.last_seen.elapsed() < ADAPTIVE_FLOW_IDLE_TIMEOUT);
+        }
+
+        let mut stats = self.flows.entry(flow).or_default();
+        stats.last_seen = Instant::now();
+        if stats.skip_remaining > 0 {
+            stats.skip_remaining -= 1;
+            stats.probing = stats.skip_remaining == 0;
+            return false;
+        }
+        true
+    }
+
+    /// Record the payload length before and after compressing a packet of
+    /// the flow, `after` equals `before` if compression did not help.
+    pub fn record(&self, flow: u64, before: usize, after: usize) {
+        if before == 0 {
+            return;
+        }
+        let mut stats = self.flows.entry(flow).or_default();
+        let ratio = after as f32 / before as f32;
+        stats.samples += 1;
+
+        if stats.probing {
+            stats.probing = false;
+            if ratio >= ADAPTIVE_RATIO_THRESHOLD {
+                // still incompressible, skip for longer
+                stats.skip_packets = (stats.skip_packets * 2).min(ADAPTIVE_MAX_SKIP_PACKETS);
+                stats.skip_remaining = stats.skip_packets;
+            } else {
+                stats.skip_packets = ADAPTIVE_SKIP_PACKETS;
+                stats.ratio = ratio;
+            }
+            return;
+        }
+
+        stats.ratio = stats.ratio * 0.75 + ratio * 0.25;
+        if stats.samples >= ADAPTIVE_MIN_SAMPLES && stats.ratio >= ADAPTIVE_RATIO_THRESHOLD {
+            stats.skip_remaining = stats.skip_packets;
+        }
+    }
+}
+
+type ZstdCtxKey = (CompressorAlgo, i32, u32);
+
 thread_local! {
-    static CTX_MAP: RefCell<DashMap<CompressorAlgo, bulk::Compressor<'static>>> = RefCell::new(DashMap::new());
-    static DCTX_MAP: RefCell<DashMap<CompressorAlgo, bulk::Decompressor<'static>>> = RefCell::new(DashMap::new());
+    static CTX_MAP: RefCell<DashMap<ZstdCtxKey, bulk::Compressor<'static>>> = RefCell::new(DashMap::new());
+    static DCTX_MAP: RefCell<DashMap<(CompressorAlgo, u32), bulk::Decompressor<'static>>> = RefCell::new(DashMap::new());
 }
 
 #[cfg(test)]
@@ -184,7 +425,7 @@ pub mod tests {
         let mut packet = ZCPacket::new_with_payload(text);
         packet.fill_peer_manager_hdr(0, 0, 0);
 
-        let compressor = DefaultCompressor {};
+        let compressor = DefaultCompressor::new();
 
         println!(
             "Uncompressed packet: {:?}, len: {}",
@@ -214,7 +455,7 @@ pub mod tests {
         let mut packet = ZCPacket::new_with_payload(text);
         packet.fill_peer_manager_hdr(0, 0, 0);
 
-        let compressor = DefaultCompressor {};
+        let compressor = DefaultCompressor::new();
 
         // short text can't be compressed
         compressor
@@ -227,4 +468,132 @@ pub mod tests {
         assert_eq!(packet.payload(), text);
         assert!(!packet.peer_manager_header().unwrap().is_compressed());
     }
+
+    async fn roundtrip(compressor: &DefaultCompressor, algo: CompressorAlgo, text: &[u8]) {
+        let mut packet = ZCPacket::new_with_payload(text);
+        packet.fill_peer_manager_hdr(0, 0, 0);
+
+        compressor.compress(&mut packet, algo).await.unwrap();
+        assert!(packet.peer_manager_header().unwrap().is_compressed());
+        assert!(packet.payload().len() < text.len());
+
+        DefaultCompressor::new()
+            .decompress(&mut packet)
+            .await
+            .unwrap();
+        assert_eq!(packet.payload(), text);
+        assert!(!packet.peer_manager_header().unwrap().is_compressed());
+    }
+
+    #[tokio::test]
+    async fn test_lz4_compress() {
+        let text = [b'a'; 1000];
+        roundtrip(&DefaultCompressor::new(), CompressorAlgo::Lz4, &text).await;
+
+        // bogus size header must not allocate
+        let mut data = (u32::MAX).to_le_bytes().to_vec();
+        data.extend_from_slice(&[0u8; 16]);
+        assert!(DefaultCompressor::new()
+            .decompress_raw(&data, CompressorAlgo::Lz4)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_zstd_level_compress() {
+        let text = b"12345670000000000000000000000000000000000000000000";
+        for level in [-5, 1, 19] {
+            let compressor = DefaultCompressor::new().with_level(level);
+            roundtrip(&compressor, CompressorAlgo::ZstdDefault, text).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_zstd_dict_compress() {
+        let samples: Vec<Vec<u8>> = (0..1000)
+            .map(|i| {
+                format!(
+                    "{{\"type\":\"player_state\",\"id\":{},\"x\":{},\"y\":{},\"hp\":{}}}",
+                    i,
+                    i * 7 % 1024,
+                    i * 13 % 768,
+                    i % 100
+                )
+                .into_bytes()
+            })
+            .collect();
+        let dict = zstd::dict::from_samples(&samples, 4096).unwrap();
+        let dict_id = register_zstd_dict(dict).unwrap();
+
+        let text = br#"{"type":"player_state","id":4242,"x":17,"y":99,"hp":3}"#;
+        let compressor = DefaultCompressor::new().with_dict(dict_id);
+        roundtrip(&compressor, CompressorAlgo::ZstdDict, text).await;
+
+        let compressed = compressor
+            .compress_raw(text, CompressorAlgo::ZstdDict)
+            .await
+            .unwrap();
+        assert_eq!(zstd_frame_dict_id(&compressed), Some(dict_id));
+
+        assert!(register_zstd_dict(b"not a dictionary".to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_adaptive_compression() {
+        let adaptive = AdaptiveCompression::new();
+
+        // a compressible flow is never skipped
+        for _ in 0..100 {
+            assert!(adaptive.should_compress(1));
+            adaptive.record(1, 1000, 300);
+        }
+
+        // an incompressible flow is skipped after a few samples
+        for _ in 0..ADAPTIVE_MIN_SAMPLES {
+            assert!(adaptive.should_compress(2));
+            adaptive.record(2, 1000, 1000);
+        }
+        for _ in 0..ADAPTIVE_SKIP_PACKETS {
+            assert!(!adaptive.should_compress(2));
+        }
+
+        // the probe fails, the next skip period is longer
+        assert!(adaptive.should_compress(2));
+        adaptive.record(2, 1000, 1000);
+        for _ in 0..ADAPTIVE_SKIP_PACKETS * 2 {
+            assert!(!adaptive.should_compress(2));
+        }
+
+        // the flow becomes compressible again
+        assert!(adaptive.should_compress(2));
+        adaptive.record(2, 1000, 200);
+        for _ in 0..10 {
+            assert!(adaptive.should_compress(2));
+            adaptive.record(2, 1000, 200);
+        }
+    }
+
+    #[test]
+    fn test_flow_key() {
+        let mut ipv4_udp = vec![0u8; 28];
+        ipv4_udp[0] = 0x45;
+        ipv4_udp[9] = 17;
+        ipv4_udp[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        ipv4_udp[16..20].copy_from_slice(&[10, 0, 0, 2]);
+        ipv4_udp[20..24].copy_from_slice(&[0x30, 0x39, 0x01, 0xbb]);
+        let key = flow_key(&ZCPacket::new_with_payload(&ipv4_udp)).unwrap();
+
+        let mut other_port = ipv4_udp.clone();
+        other_port[21] = 0x3a;
+        assert_ne!(
+            flow_key(&ZCPacket::new_with_payload(&other_port)),
+            Some(key)
+        );
+
+        // payload after the ports does not matter
+        ipv4_udp[27] = 1;
+        assert_eq!(flow_key(&ZCPacket::new_with_payload(&ipv4_udp)), Some(key));
+
+        assert_eq!(flow_key(&ZCPacket::new_with_payload(b"not ip")), None);
+    }
 }
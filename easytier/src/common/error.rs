@@ -48,6 +48,9 @@ pub enum Error {
 
     #[error("secret key error: {0}")]
     SecretKeyError(String),
+
+    #[error("node auth error: {0}")]
+    NodeAuthError(String),
 }
 
 pub type Result<T> = result::Result<T, Error>;
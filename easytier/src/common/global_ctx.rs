@@ -1,6 +1,7 @@
 use std::collections::hash_map::DefaultHasher;
 use std::{
     hash::Hasher,
+    net::IpAddr,
     sync::{Arc, Mutex},
 };
 
@@ -48,6 +49,11 @@ pub enum GlobalCtxEvent {
     DhcpIpv4Conflicted(Option<cidr::Ipv4Inet>),
 
     PortForwardAdded(PortForwardConfigPb),
+
+    ForeignNetworkRejected(String, String), // (network name, reason)
+
+    ExitNodeHealthChanged(IpAddr, bool, String), // (exit node, healthy, reason)
+    ExitNodeSwitched(Option<IpAddr>, Option<IpAddr>), // (old, new)
 }
 
 pub type EventBus = tokio::sync::broadcast::Sender<GlobalCtxEvent>;
@@ -96,6 +96,25 @@ pub fn set_default_machine_id(mid: Option<String>) {
     set_global_var!(MACHINE_UID, mid);
 }
 
+/// Default path of a state file kept across restarts, in the directory
+/// systemd gives the service by `StateDirectory=` or the local state
+/// directory of the user. `None` if that directory can not be created, the
+/// state is only kept in memory then.
+pub fn default_state_file(name: &str) -> Option<std::path::PathBuf> {
+    let dir = std::env::var_os("STATE_DIRECTORY")
+        .and_then(|paths| std::env::split_paths(&paths).next())
+        .or_else(|| {
+            dirs::state_dir()
+                .or_else(dirs::data_local_dir)
+                .map(|dir| dir.join("easytier"))
+        })?;
+    if let Err(e) = std::fs::create_dir_all(&dir) {
+        tracing::warn!(?e, ?dir, "failed to create state directory");
+        return None;
+    }
+    Some(dir.join(name))
+}
+
 pub fn get_machine_id() -> uuid::Uuid {
     if let Some(default_mid) = use_global_var!(MACHINE_UID) {
         let mut b = [0u8; 16];
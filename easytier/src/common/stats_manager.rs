@@ -57,6 +57,8 @@ pub enum MetricName {
     TrafficPacketsForeignForwardTx,
     /// Traffic packets forwarded for foreign network, forward
     TrafficPacketsForeignForwardForwarded,
+    /// Encrypted packets dropped by the anti-replay window
+    TrafficPacketsReplayDropped,
 
     /// Compression bytes before compression
     CompressionBytesRxBefore,
@@ -66,6 +68,33 @@ pub enum MetricName {
     CompressionBytesTxBefore,
     /// Compression bytes after compression
     CompressionBytesTxAfter,
+    /// Bytes saved by compressing outgoing packets
+    CompressionBytesSaved,
+    /// Outgoing packets not compressed because their flow does not compress
+    CompressionPacketsSkipped,
+
+    /// Lost udp packets rebuilt from fec parity
+    FecPacketsRecovered,
+    /// Lost udp packets fec could not rebuild
+    FecPacketsUnrecoverable,
+
+    /// Bytes a peer sent out through this exit node
+    ExitClientBytesTx,
+    /// Bytes returned to a peer through this exit node
+    ExitClientBytesRx,
+    /// Packets a peer sent out through this exit node
+    ExitClientPacketsTx,
+    /// Packets returned to a peer through this exit node
+    ExitClientPacketsRx,
+    /// Exit traffic of a peer dropped because it is denied or over its limit
+    ExitClientPacketsDropped,
+
+    /// Bytes sent by a qos class
+    QosBytesTx,
+    /// Packets sent by a qos class
+    QosPacketsTx,
+    /// Packets dropped because the queue of a qos class is full
+    QosPacketsDropped,
 }
 
 impl fmt::Display for MetricName {
@@ -107,11 +136,29 @@ impl fmt::Display for MetricName {
             MetricName::TrafficPacketsForeignForwardForwarded => {
                 write!(f, "traffic_packets_foreign_forward_forwarded")
             }
+            MetricName::TrafficPacketsReplayDropped => {
+                write!(f, "traffic_packets_replay_dropped")
+            }
 
             MetricName::CompressionBytesRxBefore => write!(f, "compression_bytes_rx_before"),
             MetricName::CompressionBytesRxAfter => write!(f, "compression_bytes_rx_after"),
             MetricName::CompressionBytesTxBefore => write!(f, "compression_bytes_tx_before"),
             MetricName::CompressionBytesTxAfter => write!(f, "compression_bytes_tx_after"),
+            MetricName::CompressionBytesSaved => write!(f, "compression_bytes_saved"),
+            MetricName::CompressionPacketsSkipped => write!(f, "compression_packets_skipped"),
+
+            MetricName::FecPacketsRecovered => write!(f, "fec_packets_recovered"),
+            MetricName::FecPacketsUnrecoverable => write!(f, "fec_packets_unrecoverable"),
+
+            MetricName::ExitClientBytesTx => write!(f, "exit_client_bytes_tx"),
+            MetricName::ExitClientBytesRx => write!(f, "exit_client_bytes_rx"),
+            MetricName::ExitClientPacketsTx => write!(f, "exit_client_packets_tx"),
+            MetricName::ExitClientPacketsRx => write!(f, "exit_client_packets_rx"),
+            MetricName::ExitClientPacketsDropped => write!(f, "exit_client_packets_dropped"),
+
+            MetricName::QosBytesTx => write!(f, "qos_bytes_tx"),
+            MetricName::QosPacketsTx => write!(f, "qos_packets_tx"),
+            MetricName::QosPacketsDropped => write!(f, "qos_packets_dropped"),
         }
     }
 }
@@ -139,6 +186,8 @@ pub enum LabelType {
     ErrorType(String),
     /// Status
     Status(String),
+    /// Qos class
+    QosClass(String),
 }
 
 impl fmt::Display for LabelType {
@@ -154,6 +203,7 @@ impl fmt::Display for LabelType {
             LabelType::CompressionAlgo(algo) => write!(f, "compression_algo={}", algo),
             LabelType::ErrorType(err) => write!(f, "error_type={}", err),
             LabelType::Status(status) => write!(f, "status={}", status),
+            LabelType::QosClass(class) => write!(f, "qos_class={}", class),
         }
     }
 }
@@ -171,6 +221,7 @@ impl LabelType {
             LabelType::CompressionAlgo(_) => "compression_algo",
             LabelType::ErrorType(_) => "error_type",
             LabelType::Status(_) => "status",
+            LabelType::QosClass(_) => "qos_class",
         }
     }
 
@@ -186,6 +237,7 @@ impl LabelType {
             LabelType::CompressionAlgo(algo) => algo.clone(),
             LabelType::ErrorType(err) => err.clone(),
             LabelType::Status(status) => status.clone(),
+            LabelType::QosClass(class) => class.clone(),
         }
     }
 }
@@ -9,6 +9,7 @@ use anyhow::Context;
 use chrono::Local;
 use crossbeam::atomic::AtomicCell;
 use rand::seq::IteratorRandom;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{lookup_host, UdpSocket};
 use tokio::sync::{broadcast, Mutex};
 use tokio::task::JoinSet;
@@ -19,6 +20,7 @@ use stun_codec::rfc5389::methods::BINDING;
 use stun_codec::{Message, MessageClass, MessageDecoder, MessageEncoder};
 
 use crate::common::error::Error;
+use crate::tunnel::common::bind_reuse_port_tcp_socket;
 
 use super::dns::resolve_txt_record;
 use super::stun_codec_ext::*;
@@ -323,6 +325,45 @@ impl StunClient {
     }
 }
 
+const TCP_STUN_TIMEOUT: Duration = Duration::from_secs(3);
+
+// binding request over tcp from `local_port`, the port may be shared with other
+// sockets of the caller so it is bound with reuse port
+async fn tcp_bind_request(local_port: u16, stun_server: SocketAddr) -> Result<SocketAddr, Error> {
+    let socket = bind_reuse_port_tcp_socket(local_port)?;
+
+    let mut stream = tokio::time::timeout(TCP_STUN_TIMEOUT, socket.connect(stun_server)).await??;
+
+    let tid = rand::random::<u32>();
+    let message = Message::<Attribute>::new(MessageClass::Request, BINDING, u32_to_tid(tid));
+    let msg = MessageEncoder::new()
+        .encode_into_bytes(message)
+        .with_context(|| "encode stun message")?;
+    stream.write_all(&msg).await?;
+
+    // messages over tcp are delimited by the length in their 20 bytes header
+    let mut buf = vec![0u8; 20];
+    tokio::time::timeout(TCP_STUN_TIMEOUT, async {
+        stream.read_exact(&mut buf).await?;
+        let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        buf.resize(20 + len, 0);
+        stream.read_exact(&mut buf[20..]).await
+    })
+    .await??;
+
+    let msg = MessageDecoder::<Attribute>::new()
+        .decode_from_bytes(&buf)
+        .with_context(|| "decode stun msg")?
+        .map_err(|e| anyhow::anyhow!("broken stun msg: {:?}", e))?;
+    if msg.class() != MessageClass::SuccessResponse
+        || msg.method() != BINDING
+        || tid_to_u32(&msg.transaction_id()) != tid
+    {
+        return Err(anyhow::anyhow!("unexpected stun response: {:?}", msg).into());
+    }
+    StunClient::extrace_mapped_addr(&msg).ok_or(Error::NotFound)
+}
+
 struct StunClientBuilder {
     udp: Arc<UdpSocket>,
     task_set: JoinSet<()>,
@@ -632,6 +673,9 @@ impl UdpNatTypeDetector {
 pub trait StunInfoCollectorTrait: Send + Sync {
     fn get_stun_info(&self) -> StunInfo;
     async fn get_udp_port_mapping(&self, local_port: u16) -> Result<SocketAddr, Error>;
+    /// Mapped address of tcp connections from `local_port`, the port must be
+    /// bound with reuse port by the caller.
+    async fn get_tcp_port_mapping(&self, local_port: u16) -> Result<SocketAddr, Error>;
 }
 
 pub struct StunInfoCollector {
@@ -715,6 +759,20 @@ impl StunInfoCollectorTrait for StunInfoCollector {
 
         Err(Error::NotFound)
     }
+
+    async fn get_tcp_port_mapping(&self, local_port: u16) -> Result<SocketAddr, Error> {
+        let mut host_resolver =
+            HostResolverIter::new(self.stun_servers.read().unwrap().clone(), 2, false);
+        // not every stun server accepts tcp
+        while let Some(server) = host_resolver.next().await {
+            match tcp_bind_request(local_port, server).await {
+                Ok(mapped_addr) => return Ok(mapped_addr),
+                Err(e) => tracing::warn!(?server, ?e, "tcp stun bind request failed"),
+            }
+        }
+
+        Err(Error::NotFound)
+    }
 }
 
 impl StunInfoCollector {
@@ -895,6 +953,10 @@ impl StunInfoCollectorTrait for MockStunInfoCollector {
         }
         Ok(format!("127.0.0.1:{}", port).parse().unwrap())
     }
+
+    async fn get_tcp_port_mapping(&self, local_port: u16) -> Result<std::net::SocketAddr, Error> {
+        Ok(format!("127.0.0.1:{}", local_port).parse().unwrap())
+    }
 }
 
 #[cfg(test)]
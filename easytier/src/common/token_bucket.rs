@@ -154,6 +154,27 @@ impl TokenBucket {
             }
         }
     }
+
+    /// Time until the refill which makes `tokens` available, zero if they
+    /// already are. Other consumers may take them first, so callers retry
+    /// `try_consume` after waiting.
+    pub fn wait_time(&self, tokens: u64) -> Duration {
+        let available = self.available_tokens.load(Ordering::Relaxed);
+        if available >= tokens || self.config.fill_rate == 0 {
+            return Duration::ZERO;
+        }
+
+        let missing = tokens.min(self.config.capacity) - available;
+        let now = self.elapsed_micros();
+        let ready_at =
+            now + ((missing as u128 * 1_000_000 / self.config.fill_rate as u128) as u64).max(1);
+        // tokens are only added by the refill task, at its interval
+        let last_refill = self.last_refill_time.load(Ordering::Relaxed);
+        let interval = (self.config.refill_interval.as_micros() as u64).max(1);
+        let refill_at =
+            last_refill + ready_at.saturating_sub(last_refill).div_ceil(interval) * interval;
+        Duration::from_micros(refill_at.saturating_sub(now))
+    }
 }
 
 pub struct TokenBucketManager {
@@ -255,6 +276,22 @@ mod tests {
         assert!(!bucket.try_consume(10)); // But not full capacity
     }
 
+    /// Test waiting for tokens instead of polling
+    #[tokio::test]
+    async fn test_wait_time() {
+        let bucket = TokenBucket::new(1000, 1000, Duration::from_millis(10));
+        assert_eq!(Duration::ZERO, bucket.wait_time(1000));
+
+        assert!(bucket.try_consume(1000));
+        let wait = bucket.wait_time(100);
+        assert!(wait >= Duration::from_millis(90), "{:?}", wait);
+        assert!(wait <= Duration::from_millis(110), "{:?}", wait);
+
+        // give the refill task due at the same time a moment to run
+        sleep(wait + Duration::from_millis(5)).await;
+        assert!(bucket.try_consume(100));
+    }
+
     /// Test capacity enforcement
     #[tokio::test]
     async fn test_capacity_limit() {
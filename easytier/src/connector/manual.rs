@@ -40,7 +40,7 @@ use crate::{
     use_global_var,
 };
 
-use super::create_connector_by_url;
+use super::{create_manual_connector_by_url, outbound_proxy_for};
 
 type ConnectorMap = Arc<DashSet<String>>;
 
@@ -313,7 +313,7 @@ impl ManualConnectorManager {
         ip_version: IpVersion,
     ) -> Result<ReconnResult, Error> {
         let connector =
-            create_connector_by_url(&dead_url, &data.global_ctx.clone(), ip_version).await?;
+            create_manual_connector_by_url(&dead_url, &data.global_ctx.clone(), ip_version).await?;
 
         data.global_ctx
             .issue_event(GlobalCtxEvent::Connecting(connector.remote_url().clone()));
@@ -342,8 +342,12 @@ impl ManualConnectorManager {
         let mut ip_versions = vec![];
         let u = url::Url::parse(&dead_url)
             .with_context(|| format!("failed to parse connector url {:?}", dead_url))?;
+        let proxied = outbound_proxy_for(&u, &data.global_ctx).is_some();
         if u.scheme() == "ring" || u.scheme() == "txt" || u.scheme() == "srv" {
             ip_versions.push(IpVersion::Both);
+        } else if proxied {
+            // the host is resolved by the proxy, it may not resolve locally
+            ip_versions.push(IpVersion::Both);
         } else {
             let addrs = match socket_addrs(&u, || Some(1000)).await {
                 Ok(addrs) => addrs,
@@ -383,9 +387,10 @@ impl ManualConnectorManager {
         for ip_version in ip_versions {
             let use_long_timeout = dead_url.starts_with("http")
                 || dead_url.starts_with("srv")
-                || dead_url.starts_with("txt");
+                || dead_url.starts_with("txt")
+                || proxied;
             let ret = timeout(
-                // allow http connector to wait longer
+                // allow http connector and proxied connections to wait longer
                 std::time::Duration::from_secs(if use_long_timeout { 20 } else { 2 }),
                 Self::conn_reconnect_with_ip_version(data.clone(), dead_url.clone(), ip_version),
             )
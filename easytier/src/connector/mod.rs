@@ -7,23 +7,77 @@ use http_connector::HttpTunnelConnector;
 
 #[cfg(feature = "quic")]
 use crate::tunnel::quic::QUICTunnelConnector;
+#[cfg(any(feature = "quic", feature = "websocket"))]
+use crate::tunnel::tls::ServerVerification;
 #[cfg(feature = "wireguard")]
 use crate::tunnel::wireguard::{WgConfig, WgTunnelConnector};
 use crate::{
-    common::{error::Error, global_ctx::ArcGlobalCtx, network::IPCollector},
+    common::{
+        error::Error,
+        global_ctx::ArcGlobalCtx,
+        network::IPCollector,
+        stats_manager::{LabelSet, LabelType},
+    },
     tunnel::{
-        check_scheme_and_get_socket_addr, ring::RingTunnelConnector, tcp::TcpTunnelConnector,
-        udp::UdpTunnelConnector, IpVersion, TunnelConnector,
+        check_scheme_and_get_socket_addr, fec::FecStats, proxy::proxy_from_env,
+        ring::RingTunnelConnector, tcp::TcpTunnelConnector, udp::UdpTunnelConnector, IpVersion,
+        TunnelConnector,
     },
 };
 
 pub mod direct;
 pub mod manual;
+pub mod tcp_hole_punch;
 pub mod udp_hole_punch;
 
 pub mod dns_connector;
 pub mod http_connector;
 
+pub(crate) fn fec_stats(global_ctx: &ArcGlobalCtx) -> FecStats {
+    FecStats::new(
+        global_ctx.stats_manager(),
+        LabelSet::new().with_label_type(LabelType::NetworkName(global_ctx.get_network_name())),
+    )
+}
+
+/// The http or socks5 proxy to dial the manually configured `url` through,
+/// only tcp based transports can be proxied. The proxy of the matching peer
+/// config is preferred over the outbound proxy of the instance, the environment
+/// is only used if enabled.
+pub(crate) fn outbound_proxy_for(url: &url::Url, global_ctx: &ArcGlobalCtx) -> Option<url::Url> {
+    if !matches!(url.scheme(), "tcp" | "ws" | "wss") {
+        return None;
+    }
+    global_ctx
+        .config
+        .get_peers()
+        .into_iter()
+        .find(|p| &p.uri == url)
+        .and_then(|p| p.proxy)
+        .or_else(|| global_ctx.config.get_outbound_proxy())
+        .or_else(|| {
+            global_ctx
+                .get_flags()
+                .outbound_proxy_from_env
+                .then(|| proxy_from_env(url))
+                .flatten()
+        })
+}
+
+/// How the quic and wss connectors of manually configured peers verify the
+/// certificate of the server, any certificate is accepted unless verification
+/// is configured.
+#[cfg(any(feature = "quic", feature = "websocket"))]
+fn server_verification(global_ctx: &ArcGlobalCtx) -> ServerVerification {
+    match global_ctx.config.get_tls_config() {
+        Some(tls) if tls.verify_server => match tls.ca_file {
+            Some(ca_file) => ServerVerification::Ca(ca_file),
+            None => ServerVerification::SystemRoots,
+        },
+        _ => ServerVerification::Insecure,
+    }
+}
+
 async fn set_bind_addr_for_peer_connector(
     connector: &mut (impl TunnelConnector + ?Sized),
     is_ipv4: bool,
@@ -52,12 +106,62 @@ async fn set_bind_addr_for_peer_connector(
     let _ = connector;
 }
 
+/// Connector for a manually configured peer url, dialed through the outbound
+/// proxy if one applies to it, verifying the server certificate if configured
+/// and applying the ws options of the url. Urls found by other means, like
+/// those advertised for direct connections, are dialed with
+/// [`create_connector_by_url`].
+pub async fn create_manual_connector_by_url(
+    url: &str,
+    global_ctx: &ArcGlobalCtx,
+    ip_version: IpVersion,
+) -> Result<Box<dyn TunnelConnector + 'static>, Error> {
+    let parsed = url::Url::parse(url).map_err(|_| Error::InvalidUrl(url.to_owned()))?;
+    let Some(proxy) = outbound_proxy_for(&parsed, global_ctx) else {
+        return new_connector_by_url(parsed, global_ctx, ip_version, true).await;
+    };
+
+    // the url is not resolved locally, the proxy connects to its host
+    let mut connector: Box<dyn TunnelConnector + 'static> = match parsed.scheme() {
+        "tcp" => {
+            let mut connector = TcpTunnelConnector::new(parsed);
+            connector.set_proxy(Some(proxy));
+            Box::new(connector)
+        }
+        #[cfg(feature = "websocket")]
+        "ws" | "wss" => {
+            let mut connector = crate::tunnel::websocket::WSTunnelConnector::new(parsed);
+            connector.set_server_verification(server_verification(global_ctx));
+            connector.set_url_options(true);
+            connector.set_proxy(Some(proxy));
+            Box::new(connector)
+        }
+        _ => return new_connector_by_url(parsed, global_ctx, ip_version, true).await,
+    };
+    connector.set_ip_version(ip_version);
+
+    Ok(connector)
+}
+
 pub async fn create_connector_by_url(
     url: &str,
     global_ctx: &ArcGlobalCtx,
     ip_version: IpVersion,
 ) -> Result<Box<dyn TunnelConnector + 'static>, Error> {
     let url = url::Url::parse(url).map_err(|_| Error::InvalidUrl(url.to_owned()))?;
+    new_connector_by_url(url, global_ctx, ip_version, false).await
+}
+
+async fn new_connector_by_url(
+    url: url::Url,
+    global_ctx: &ArcGlobalCtx,
+    ip_version: IpVersion,
+    #[cfg_attr(
+        not(any(feature = "quic", feature = "websocket")),
+        allow(unused_variables)
+    )]
+    manual: bool,
+) -> Result<Box<dyn TunnelConnector + 'static>, Error> {
     let mut connector: Box<dyn TunnelConnector + 'static> = match url.scheme() {
         "tcp" => {
             let dst_addr =
@@ -77,6 +181,7 @@ pub async fn create_connector_by_url(
             let dst_addr =
                 check_scheme_and_get_socket_addr::<SocketAddr>(&url, "udp", ip_version).await?;
             let mut connector = UdpTunnelConnector::new(url);
+            connector.set_fec_stats(fec_stats(global_ctx));
             if global_ctx.config.get_flags().bind_device {
                 set_bind_addr_for_peer_connector(
                     &mut connector,
@@ -101,6 +206,9 @@ pub async fn create_connector_by_url(
             let dst_addr =
                 check_scheme_and_get_socket_addr::<SocketAddr>(&url, "quic", ip_version).await?;
             let mut connector = QUICTunnelConnector::new(url);
+            if manual {
+                connector.set_server_verification(server_verification(global_ctx));
+            }
             if global_ctx.config.get_flags().bind_device {
                 set_bind_addr_for_peer_connector(
                     &mut connector,
@@ -136,6 +244,10 @@ pub async fn create_connector_by_url(
             use crate::tunnel::FromUrl;
             let dst_addr = SocketAddr::from_url(url.clone(), ip_version).await?;
             let mut connector = crate::tunnel::websocket::WSTunnelConnector::new(url);
+            if manual {
+                connector.set_server_verification(server_verification(global_ctx));
+                connector.set_url_options(true);
+            }
             if global_ctx.config.get_flags().bind_device {
                 set_bind_addr_for_peer_connector(
                     &mut connector,
@@ -0,0 +1,642 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{Context, Error};
+use dashmap::DashMap;
+use tokio::{
+    net::{TcpListener, TcpSocket, TcpStream},
+    sync::mpsc,
+    task::{JoinHandle, JoinSet},
+    time::timeout,
+};
+
+use crate::{
+    common::{global_ctx::ArcGlobalCtx, stun::StunInfoCollectorTrait, PeerId},
+    connector::udp_hole_punch::{common::UdpNatType, handle_rpc_result, BackOff},
+    peers::{
+        peer_manager::PeerManager,
+        peer_task::{PeerTaskLauncher, PeerTaskManager},
+    },
+    proto::{
+        common::NatType,
+        peer_rpc::{
+            ExchangeTcpPunchAddrRequest, ExchangeTcpPunchAddrResponse, TcpHolePunchRpc,
+            TcpHolePunchRpcClientFactory, TcpHolePunchRpcServer,
+        },
+        rpc_types::{self, controller::BaseController},
+    },
+    tunnel::{
+        build_url_from_socket_addr, common::bind_reuse_port_tcp_socket,
+        tcp::get_tunnel_with_tcp_stream, Tunnel,
+    },
+};
+
+// how long both sides keep opening the connection
+const PUNCH_DURATION: Duration = Duration::from_secs(10);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(200);
+// the callee does a tcp stun request before answering
+const EXCHANGE_ADDR_TIMEOUT_MS: i32 = 10000;
+// every punch holds a listener and keeps connecting for the punch duration
+const MAX_PUNCHES_PER_PEER: u32 = 2;
+
+// the listener, the stun request and the connect attempts of a punch all use
+// the same local port, so every socket is bound with reuse port
+fn new_punch_socket(global_ctx: &ArcGlobalCtx, port: u16) -> Result<TcpSocket, Error> {
+    let _g = global_ctx.net_ns.guard();
+    Ok(bind_reuse_port_tcp_socket(port)?)
+}
+
+fn stream_to_tunnel(stream: TcpStream) -> Result<Box<dyn Tunnel>, Error> {
+    let remote_url = build_url_from_socket_addr(&stream.peer_addr()?.to_string(), "tcp");
+    Ok(get_tunnel_with_tcp_stream(stream, remote_url)?)
+}
+
+struct PunchPort {
+    listener: TcpListener,
+    local_port: u16,
+    mapped_addr: SocketAddr,
+}
+
+// streams established by a punch, the punch stops when this is dropped
+struct PunchStreams {
+    streams: mpsc::Receiver<TcpStream>,
+    _tasks: JoinSet<()>,
+}
+
+impl PunchPort {
+    async fn new(global_ctx: &ArcGlobalCtx) -> Result<Self, Error> {
+        let socket = new_punch_socket(global_ctx, 0)?;
+        let local_port = socket.local_addr()?.port();
+        let listener = socket.listen(16)?;
+        let mapped_addr = global_ctx
+            .get_stun_info_collector()
+            .get_tcp_port_mapping(local_port)
+            .await
+            .with_context(|| "failed to get tcp port mapping")?;
+        Ok(Self {
+            listener,
+            local_port,
+            mapped_addr,
+        })
+    }
+
+    // keeps connecting to `remote` while accepting on the same port. with both
+    // sides doing so, the syns either cross and the connection opens
+    // simultaneously, or one of them reaches the other side's listener.
+    fn punch(self, global_ctx: ArcGlobalCtx, remote: SocketAddr) -> PunchStreams {
+        let (sender, streams) = mpsc::channel(4);
+        let mut tasks = JoinSet::new();
+
+        let listener = self.listener;
+        let accept_sender = sender.clone();
+        tasks.spawn(async move {
+            while let Ok((stream, addr)) = listener.accept().await {
+                if addr.ip() != remote.ip() {
+                    tracing::debug!(?addr, ?remote, "ignore tcp punch stream from other addr");
+                    continue;
+                }
+                if accept_sender.send(stream).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let local_port = self.local_port;
+        tasks.spawn(async move {
+            loop {
+                let socket = match new_punch_socket(&global_ctx, local_port) {
+                    Ok(socket) => socket,
+                    Err(e) => {
+                        tracing::warn!(?e, local_port, "failed to create tcp punch socket");
+                        break;
+                    }
+                };
+                match timeout(CONNECT_TIMEOUT, socket.connect(remote)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = sender.send(stream).await;
+                        break;
+                    }
+                    Ok(Err(e)) => {
+                        tracing::trace!(?e, ?remote, "tcp punch connect failed");
+                        tokio::time::sleep(CONNECT_RETRY_INTERVAL).await;
+                    }
+                    Err(_) => {}
+                }
+            }
+        });
+
+        PunchStreams {
+            streams,
+            _tasks: tasks,
+        }
+    }
+}
+
+// a punch the server runs for a peer, counted until dropped
+struct PunchSlot {
+    punching: Arc<DashMap<PeerId, u32>>,
+    peer_id: PeerId,
+}
+
+impl PunchSlot {
+    fn acquire(punching: &Arc<DashMap<PeerId, u32>>, peer_id: PeerId) -> Option<Self> {
+        let mut count = punching.entry(peer_id).or_default();
+        if *count >= MAX_PUNCHES_PER_PEER {
+            return None;
+        }
+        *count += 1;
+        Some(Self {
+            punching: punching.clone(),
+            peer_id,
+        })
+    }
+}
+
+impl Drop for PunchSlot {
+    fn drop(&mut self) {
+        if let Some(mut count) = self.punching.get_mut(&self.peer_id) {
+            *count -= 1;
+        }
+        self.punching
+            .remove_if(&self.peer_id, |_, count| *count == 0);
+    }
+}
+
+// both sides keep connecting to the addr the other peer gives and handshake
+// whatever answers, so the addr must not reach services of this host or its
+// lan. a lan addr is only punched if the peer is connected from it already.
+fn is_punchable_addr(addr: &SocketAddr, peer_ips: &[IpAddr], allow_loopback: bool) -> bool {
+    let ip = addr.ip().to_canonical();
+    if ip.is_unspecified() || ip.is_multicast() {
+        return false;
+    }
+    if ip.is_loopback() {
+        return allow_loopback;
+    }
+    let is_private = match ip {
+        IpAddr::V4(ip) => {
+            if ip.is_link_local() || ip.is_broadcast() {
+                return false;
+            }
+            ip.is_private()
+        }
+        IpAddr::V6(ip) => {
+            if ip.is_unicast_link_local() {
+                return false;
+            }
+            ip.is_unique_local()
+        }
+    };
+    !is_private || peer_ips.contains(&ip)
+}
+
+// remote ips of the tunnels the peer is connected to this node with
+async fn peer_ips(peer_mgr: &PeerManager, peer_id: PeerId) -> Vec<IpAddr> {
+    let conns = peer_mgr
+        .get_peer_map()
+        .list_peer_conns(peer_id)
+        .await
+        .unwrap_or_default();
+    conns
+        .into_iter()
+        .filter_map(|conn| {
+            let url = url::Url::from(conn.tunnel?.remote_addr?);
+            match url.host()? {
+                url::Host::Ipv4(ip) => Some(IpAddr::V4(ip)),
+                url::Host::Ipv6(ip) => Some(IpAddr::V6(ip).to_canonical()),
+                url::Host::Domain(_) => None,
+            }
+        })
+        .collect()
+}
+
+struct TcpHolePunchServer {
+    peer_mgr: Arc<PeerManager>,
+    punching: Arc<DashMap<PeerId, u32>>,
+}
+
+#[async_trait::async_trait]
+impl TcpHolePunchRpc for TcpHolePunchServer {
+    type Controller = BaseController;
+
+    #[tracing::instrument(skip(self), ret, err)]
+    async fn exchange_tcp_punch_addr(
+        &self,
+        ctrl: Self::Controller,
+        input: ExchangeTcpPunchAddrRequest,
+    ) -> rpc_types::error::Result<ExchangeTcpPunchAddrResponse> {
+        let connector_addr: SocketAddr = input
+            .connector_mapped_addr
+            .ok_or(anyhow::anyhow!(
+                "exchange_tcp_punch_addr request missing connector_mapped_addr"
+            ))?
+            .into();
+        let from_peer_id = ctrl.get_from_peer_id().unwrap_or_default();
+        if !is_punchable_addr(
+            &connector_addr,
+            &peer_ips(&self.peer_mgr, from_peer_id).await,
+            self.peer_mgr.allow_loopback_tunnel(),
+        ) {
+            return Err(anyhow::anyhow!(
+                "exchange_tcp_punch_addr connector addr is not punchable, {:?}",
+                connector_addr
+            )
+            .into());
+        }
+
+        let Some(slot) = PunchSlot::acquire(&self.punching, from_peer_id) else {
+            return Err(anyhow::anyhow!(
+                "too many tcp punches in progress for peer {}",
+                from_peer_id
+            )
+            .into());
+        };
+
+        let global_ctx = self.peer_mgr.get_global_ctx();
+        let port = PunchPort::new(&global_ctx).await?;
+        let listener_mapped_addr = port.mapped_addr;
+
+        // the initiator picks one of the streams and drops the others, so every
+        // stream is handshaked and only the one it picked survives
+        let mut punch = port.punch(global_ctx, connector_addr);
+        let peer_mgr = self.peer_mgr.clone();
+        tokio::spawn(async move {
+            let _slot = slot;
+            let _ = timeout(PUNCH_DURATION, async {
+                while let Some(stream) = punch.streams.recv().await {
+                    let tunnel = match stream_to_tunnel(stream) {
+                        Ok(tunnel) => tunnel,
+                        Err(e) => {
+                            tracing::warn!(?e, "failed to create tunnel from tcp punch stream");
+                            continue;
+                        }
+                    };
+                    tracing::info!(?tunnel, "tcp hole punched");
+                    let peer_mgr = peer_mgr.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = peer_mgr.add_tunnel_as_server(tunnel, false).await {
+                            tracing::info!(?e, "add tcp punched tunnel as server failed");
+                        }
+                    });
+                }
+            })
+            .await;
+        });
+
+        Ok(ExchangeTcpPunchAddrResponse {
+            listener_mapped_addr: Some(listener_mapped_addr.into()),
+        })
+    }
+}
+
+struct TcpHolePunchConnectorData {
+    peer_mgr: Arc<PeerManager>,
+    blacklist: Arc<timedmap::TimedMap<PeerId, ()>>,
+}
+
+impl TcpHolePunchConnectorData {
+    fn new(peer_mgr: Arc<PeerManager>) -> Arc<Self> {
+        Arc::new(Self {
+            peer_mgr,
+            blacklist: Arc::new(timedmap::TimedMap::new()),
+        })
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn do_hole_punching(
+        &self,
+        dst_peer_id: PeerId,
+    ) -> Result<Option<Box<dyn Tunnel>>, Error> {
+        if self.blacklist.contains(&dst_peer_id) {
+            tracing::debug!(?dst_peer_id, "peer is blacklisted, skipping hole punching");
+            return Ok(None);
+        }
+
+        tracing::info!(?dst_peer_id, "start tcp hole punching");
+        let global_ctx = self.peer_mgr.get_global_ctx();
+        let port = PunchPort::new(&global_ctx).await?;
+
+        let rpc_stub = self
+            .peer_mgr
+            .get_peer_rpc_mgr()
+            .rpc_client()
+            .scoped_client::<TcpHolePunchRpcClientFactory<BaseController>>(
+                self.peer_mgr.my_peer_id(),
+                dst_peer_id,
+                global_ctx.get_network_name(),
+            );
+        let resp = rpc_stub
+            .exchange_tcp_punch_addr(
+                BaseController {
+                    timeout_ms: EXCHANGE_ADDR_TIMEOUT_MS,
+                    ..Default::default()
+                },
+                ExchangeTcpPunchAddrRequest {
+                    connector_mapped_addr: Some(port.mapped_addr.into()),
+                },
+            )
+            .await;
+        let resp = handle_rpc_result(resp, dst_peer_id, &self.blacklist)?;
+        let remote_mapped_addr: SocketAddr = resp
+            .listener_mapped_addr
+            .ok_or(anyhow::anyhow!(
+                "exchange_tcp_punch_addr response missing listener_mapped_addr"
+            ))?
+            .into();
+        if !is_punchable_addr(
+            &remote_mapped_addr,
+            &peer_ips(&self.peer_mgr, dst_peer_id).await,
+            self.peer_mgr.allow_loopback_tunnel(),
+        ) {
+            return Err(anyhow::anyhow!(
+                "exchange_tcp_punch_addr listener addr is not punchable, {:?}",
+                remote_mapped_addr
+            ));
+        }
+
+        tracing::debug!(
+            local_mapped_addr = ?port.mapped_addr,
+            ?remote_mapped_addr,
+            "tcp hole punch got remote addr"
+        );
+
+        let mut punch = port.punch(global_ctx, remote_mapped_addr);
+        match timeout(PUNCH_DURATION, punch.streams.recv()).await {
+            Ok(Some(stream)) => Ok(Some(stream_to_tunnel(stream)?)),
+            _ => Ok(None),
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn punch_peer(self: Arc<Self>, dst_peer_id: PeerId) -> Result<(), Error> {
+        let mut backoff = BackOff::new(vec![1000, 2000, 4000, 8000, 16000, 32000, 64000]);
+
+        loop {
+            backoff.sleep_for_next_backoff().await;
+
+            match self.do_hole_punching(dst_peer_id).await {
+                Ok(Some(tunnel)) => {
+                    tracing::info!(?tunnel, "tcp hole punching get tunnel success");
+                    match self.peer_mgr.add_client_tunnel(tunnel, false).await {
+                        Ok(_) => break,
+                        Err(e) => {
+                            tracing::warn!(?e, "add client tunnel failed");
+                            backoff.rollback();
+                        }
+                    }
+                }
+                Ok(None) => {
+                    tracing::info!("tcp hole punching failed, no punched stream");
+                }
+                Err(e) => {
+                    tracing::info!(?e, "tcp hole punching failed");
+                    backoff.rollback();
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct TcpHolePunchPeerTaskLauncher {}
+
+#[async_trait::async_trait]
+impl PeerTaskLauncher for TcpHolePunchPeerTaskLauncher {
+    type Data = Arc<TcpHolePunchConnectorData>;
+    type CollectPeerItem = PeerId;
+    type TaskRet = ();
+
+    fn new_data(&self, peer_mgr: Arc<PeerManager>) -> Self::Data {
+        TcpHolePunchConnectorData::new(peer_mgr)
+    }
+
+    async fn collect_peers_need_task(&self, data: &Self::Data) -> Vec<Self::CollectPeerItem> {
+        let nat_type =
+            |x: i32| -> UdpNatType { NatType::try_from(x).unwrap_or(NatType::Unknown).into() };
+        let my_nat_type = nat_type(
+            data.peer_mgr
+                .get_global_ctx()
+                .get_stun_info_collector()
+                .get_stun_info()
+                .udp_nat_type,
+        );
+        // peers can connect us directly
+        if my_nat_type.is_open() {
+            return vec![];
+        }
+
+        let my_peer_id = data.peer_mgr.my_peer_id();
+        data.blacklist.cleanup();
+
+        let mut peers_to_connect = Vec::new();
+        for route in data.peer_mgr.list_routes().await.iter() {
+            if route
+                .feature_flag
+                .map(|x| x.is_public_server)
+                .unwrap_or(false)
+            {
+                continue;
+            }
+
+            // only one side initiates
+            let peer_id: PeerId = route.peer_id;
+            if my_peer_id > peer_id || data.blacklist.contains(&peer_id) {
+                continue;
+            }
+
+            // udp hole punching handles peers whose nat type both sides know,
+            // tcp is for those where udp is blocked or stun failed
+            let peer_nat_type = nat_type(
+                route
+                    .stun_info
+                    .as_ref()
+                    .map(|x| x.udp_nat_type)
+                    .unwrap_or(0),
+            );
+            if peer_nat_type.is_open()
+                || !(my_nat_type == UdpNatType::Unknown || peer_nat_type == UdpNatType::Unknown)
+            {
+                continue;
+            }
+
+            let conns = data.peer_mgr.list_peer_conns(peer_id).await;
+            if conns.is_some_and(|x| !x.is_empty()) {
+                continue;
+            }
+
+            tracing::info!(
+                ?peer_id,
+                ?peer_nat_type,
+                ?my_nat_type,
+                "found peer to do tcp hole punching"
+            );
+            peers_to_connect.push(peer_id);
+        }
+
+        peers_to_connect
+    }
+
+    async fn launch_task(
+        &self,
+        data: &Self::Data,
+        item: Self::CollectPeerItem,
+    ) -> JoinHandle<Result<Self::TaskRet, Error>> {
+        tokio::spawn(data.clone().punch_peer(item))
+    }
+
+    fn loop_interval_ms(&self) -> u64 {
+        5000
+    }
+}
+
+/// Connects peers behind nat with tcp simultaneous open, for sites where
+/// outbound udp is blocked and udp hole punching can not work. Both sides
+/// learn the mapped address of a punch port with tcp stun, exchange it over
+/// peer rpc and then connect to each other from that port. The node with the
+/// smaller peer id initiates and becomes the client of the peer conn.
+pub struct TcpHolePunchConnector {
+    server: Arc<TcpHolePunchServer>,
+    client: PeerTaskManager<TcpHolePunchPeerTaskLauncher>,
+    peer_mgr: Arc<PeerManager>,
+}
+
+impl TcpHolePunchConnector {
+    pub fn new(peer_mgr: Arc<PeerManager>) -> Self {
+        Self {
+            server: Arc::new(TcpHolePunchServer {
+                peer_mgr: peer_mgr.clone(),
+                punching: Arc::new(DashMap::new()),
+            }),
+            client: PeerTaskManager::new(TcpHolePunchPeerTaskLauncher {}, peer_mgr.clone()),
+            peer_mgr,
+        }
+    }
+
+    pub async fn run_as_client(&mut self) -> Result<(), Error> {
+        self.client.start();
+        Ok(())
+    }
+
+    pub async fn run_as_server(&mut self) -> Result<(), Error> {
+        self.peer_mgr
+            .get_peer_rpc_mgr()
+            .rpc_server()
+            .registry()
+            .register(
+                TcpHolePunchRpcServer::new(self.server.clone()),
+                &self.peer_mgr.get_global_ctx().get_network_name(),
+            );
+
+        Ok(())
+    }
+
+    pub async fn run(&mut self) -> Result<(), Error> {
+        let global_ctx = self.peer_mgr.get_global_ctx();
+
+        if global_ctx.get_flags().disable_p2p {
+            return Ok(());
+        }
+        if global_ctx.get_flags().disable_tcp_hole_punching {
+            return Ok(());
+        }
+
+        self.run_as_client().await?;
+        self.run_as_server().await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        connector::udp_hole_punch::tests::create_mock_peer_manager_with_mock_stun,
+        peers::tests::{connect_peer_manager, wait_route_appear, wait_route_appear_with_cost},
+        proto::common::NatType,
+    };
+
+    use std::sync::Arc;
+
+    use dashmap::DashMap;
+
+    use super::{is_punchable_addr, PunchSlot, TcpHolePunchConnector, MAX_PUNCHES_PER_PEER};
+
+    #[test]
+    fn tcp_punch_slot_per_peer() {
+        let punching = Arc::new(DashMap::new());
+        let slots: Vec<_> = (0..MAX_PUNCHES_PER_PEER)
+            .map(|_| PunchSlot::acquire(&punching, 1).unwrap())
+            .collect();
+        assert!(PunchSlot::acquire(&punching, 1).is_none());
+        // other peers are not affected
+        assert!(PunchSlot::acquire(&punching, 2).is_some());
+
+        drop(slots);
+        assert!(punching.is_empty());
+        assert!(PunchSlot::acquire(&punching, 1).is_some());
+    }
+
+    #[test]
+    fn tcp_punch_addr_check() {
+        let lan_peer = ["192.168.1.5".parse().unwrap()];
+        for (addr, allow_loopback, punchable) in [
+            ("1.2.3.4:1000", false, true),
+            ("[2001:db8::1]:1000", false, true),
+            ("127.0.0.1:22", false, false),
+            ("127.0.0.1:22", true, true),
+            ("[::1]:22", false, false),
+            ("[::ffff:127.0.0.1]:22", false, false),
+            ("0.0.0.0:22", false, false),
+            ("169.254.169.254:80", false, false),
+            ("[fe80::1]:22", false, false),
+            ("224.0.0.1:22", false, false),
+            ("10.0.0.1:22", false, false),
+            ("[fd00::1]:22", false, false),
+            ("192.168.1.5:1000", false, true),
+            ("[::ffff:192.168.1.5]:1000", false, true),
+        ] {
+            assert_eq!(
+                punchable,
+                is_punchable_addr(&addr.parse().unwrap(), &lan_peer, allow_loopback),
+                "{}",
+                addr
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn hole_punching_tcp() {
+        let p_a = create_mock_peer_manager_with_mock_stun(NatType::Unknown).await;
+        let p_b = create_mock_peer_manager_with_mock_stun(NatType::PortRestricted).await;
+        let p_c = create_mock_peer_manager_with_mock_stun(NatType::PortRestricted).await;
+        connect_peer_manager(p_a.clone(), p_b.clone()).await;
+        connect_peer_manager(p_b.clone(), p_c.clone()).await;
+
+        wait_route_appear(p_a.clone(), p_c.clone()).await.unwrap();
+
+        let mut hole_punching_a = TcpHolePunchConnector::new(p_a.clone());
+        let mut hole_punching_c = TcpHolePunchConnector::new(p_c.clone());
+        hole_punching_a.run().await.unwrap();
+        hole_punching_c.run().await.unwrap();
+
+        hole_punching_a.client.run_immediately().await;
+        hole_punching_c.client.run_immediately().await;
+
+        wait_route_appear_with_cost(p_a.clone(), p_c.my_peer_id(), Some(1))
+            .await
+            .unwrap();
+        let conns = p_a.list_peer_conns(p_c.my_peer_id()).await.unwrap();
+        assert_eq!(
+            "tcp",
+            conns[0].tunnel.as_ref().unwrap().tunnel_type,
+            "{:?}",
+            conns
+        );
+    }
+}
@@ -21,10 +21,11 @@ use tokio::time::timeout;
 
 use crate::{
     common::{
-        config::PortForwardConfig,
+        config::{NetworkIdentity, PortForwardConfig},
         constants::EASYTIER_VERSION,
         stun::{StunInfoCollector, StunInfoCollectorTrait},
     },
+    peers::{node_auth, relay_admission},
     proto::{
         cli::{
             list_peer_route_pair, AclManageRpc, AclManageRpcClientFactory, AddPortForwardRequest,
@@ -32,8 +33,8 @@ use crate::{
             GetAclStatsRequest, GetPrometheusStatsRequest, GetStatsRequest,
             GetVpnPortalInfoRequest, GetWhitelistRequest, ListConnectorRequest,
             ListForeignNetworkRequest, ListGlobalForeignNetworkRequest, ListMappedListenerRequest,
-            ListPeerRequest, ListPeerResponse, ListPortForwardRequest, ListRouteRequest,
-            ListRouteResponse, ManageMappedListenerRequest, MappedListenerManageAction,
+            ListPeerRequest, ListPeerResponse, ListPolicyRouteRequest, ListPortForwardRequest,
+            ListRouteRequest, ListRouteResponse, LookupRouteRequest, ManageMappedListenerRequest, MappedListenerManageAction,
             MappedListenerManageRpc, MappedListenerManageRpcClientFactory, NodeInfo, PeerManageRpc,
             PeerManageRpcClientFactory, PortForwardManageRpc, PortForwardManageRpcClientFactory,
             RemovePortForwardRequest, SetWhitelistRequest, ShowNodeInfoRequest, StatsRpc,
@@ -105,6 +106,8 @@ enum SubCommand {
     Whitelist(WhitelistArgs),
     #[command(about = "show statistics information")]
     Stats(StatsArgs),
+    #[command(about = "generate node keys and certificates for node authentication")]
+    NodeAuth(NodeAuthArgs),
     #[command(about = t!("core_clap.generate_completions").to_string())]
     GenAutocomplete { shell: Shell },
 }
@@ -140,6 +143,13 @@ struct RouteArgs {
 enum RouteSubCommand {
     List,
     Dump,
+    /// Show policy routes and how often they matched
+    Policy,
+    /// Show the policy route and next hop used for a destination
+    Lookup {
+        #[arg(help = "Destination ip (e.g., 10.20.1.1)")]
+        destination: String,
+    },
 }
 
 #[derive(Args, Debug)]
@@ -270,6 +280,52 @@ enum StatsSubCommand {
     Show,
     /// Show statistics in Prometheus format
     Prometheus,
+    /// Show traffic of the peers using this node as exit node
+    ExitClients,
+}
+
+#[derive(Args, Debug)]
+struct NodeAuthArgs {
+    #[command(subcommand)]
+    sub_command: NodeAuthSubCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum NodeAuthSubCommand {
+    /// Generate a new ed25519 private key and print it with its public key
+    GenKey,
+    /// Print the public key of a private key
+    PublicKey {
+        #[arg(help = "base64 encoded private key")]
+        private_key: String,
+    },
+    /// Sign a node certificate with a ca private key
+    IssueCert {
+        #[arg(long, help = "base64 encoded private key of the ca")]
+        ca_private_key: String,
+        #[arg(long, help = "base64 encoded public key of the node")]
+        node_public_key: String,
+        #[arg(long, help = "network name the certificate is valid for")]
+        network_name: String,
+        #[arg(long, default_value = "", help = "name of the node, for display only")]
+        node_name: String,
+        #[arg(long, help = "validity in days, never expire if not set")]
+        valid_days: Option<u64>,
+    },
+    /// Sign a token admitting a foreign network to relays trusting the key
+    IssueRelayToken {
+        #[arg(long, help = "base64 encoded private key of the relay operator")]
+        signer_private_key: String,
+        #[arg(long, help = "network name the token is valid for, globs allowed")]
+        network_name: String,
+        #[arg(
+            long,
+            help = "only admit the network with this secret, needs an exact network name"
+        )]
+        network_secret: Option<String>,
+        #[arg(long, help = "validity in days, never expire if not set")]
+        valid_days: Option<u64>,
+    },
 }
 
 #[derive(Args, Debug)]
@@ -601,6 +657,180 @@ impl CommandHandler<'_> {
         Ok(())
     }
 
+    async fn handle_route_policy(&self) -> Result<(), Error> {
+        #[derive(tabled::Tabled, serde::Serialize)]
+        struct PolicyRouteTableItem {
+            index: u32,
+            name: String,
+            destinations: String,
+            via: String,
+            avoid: String,
+            strict: bool,
+            hits: u64,
+        }
+
+        let client = self.get_peer_manager_client().await?;
+        let response = client
+            .list_policy_route(BaseController::default(), ListPolicyRouteRequest::default())
+            .await?;
+        if self.verbose {
+            println!("{}", serde_json::to_string_pretty(&response.policy_routes)?);
+            return Ok(());
+        }
+
+        let items = response
+            .policy_routes
+            .into_iter()
+            .map(|r| PolicyRouteTableItem {
+                index: r.index,
+                name: r.name,
+                destinations: r
+                    .destinations
+                    .into_iter()
+                    .chain(r.domains)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                via: match (r.via.is_empty(), r.via_peer_id) {
+                    (true, _) => "-".to_string(),
+                    (false, Some(peer_id)) => format!("{} ({})", r.via, peer_id),
+                    (false, None) => format!("{} (unreachable)", r.via),
+                },
+                avoid: r.avoid.join(", "),
+                strict: r.strict,
+                hits: r.hits,
+            })
+            .collect::<Vec<_>>();
+        print_output(&items, self.output_format)?;
+
+        Ok(())
+    }
+
+    async fn handle_stats_exit_clients(&self) -> Result<(), Error> {
+        #[derive(tabled::Tabled, serde::Serialize, Default)]
+        struct ExitClientTableItem {
+            #[tabled(skip)]
+            peer_id: u32,
+            client: String,
+            tx_bytes: String,
+            rx_bytes: String,
+            tx_packets: u64,
+            rx_packets: u64,
+            denied: u64,
+            rate_limited: u64,
+        }
+
+        let client = self.get_stats_client().await?;
+        let response = client
+            .get_stats(BaseController::default(), GetStatsRequest {})
+            .await?;
+
+        let mut clients = std::collections::BTreeMap::<u32, (ExitClientTableItem, u64, u64)>::new();
+        for metric in response.metrics {
+            if !metric.name.starts_with("exit_client_") {
+                continue;
+            }
+            let Some(peer_id) = metric
+                .labels
+                .get("src_peer_id")
+                .or(metric.labels.get("dst_peer_id"))
+                .and_then(|x| x.parse::<u32>().ok())
+            else {
+                continue;
+            };
+            let (item, tx_bytes, rx_bytes) = clients.entry(peer_id).or_default();
+            item.peer_id = peer_id;
+            match metric.name.as_str() {
+                "exit_client_bytes_tx" => *tx_bytes = metric.value,
+                "exit_client_bytes_rx" => *rx_bytes = metric.value,
+                "exit_client_packets_tx" => item.tx_packets = metric.value,
+                "exit_client_packets_rx" => item.rx_packets = metric.value,
+                "exit_client_packets_dropped" => {
+                    match metric.labels.get("status").map(String::as_str) {
+                        Some("denied") => item.denied += metric.value,
+                        _ => item.rate_limited += metric.value,
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let peer_routes = self.list_peer_route_pair().await?;
+        let items = clients
+            .into_values()
+            .map(|(mut item, tx_bytes, rx_bytes)| {
+                let route = peer_routes
+                    .iter()
+                    .filter_map(|p| p.route.as_ref())
+                    .find(|r| r.peer_id == item.peer_id);
+                item.client = match route {
+                    Some(r) => format!("{} ({})", r.hostname, item.peer_id),
+                    None => item.peer_id.to_string(),
+                };
+                item.tx_bytes = format_size(tx_bytes, humansize::BINARY);
+                item.rx_bytes = format_size(rx_bytes, humansize::BINARY);
+                item
+            })
+            .collect::<Vec<_>>();
+        print_output(&items, self.output_format)?;
+
+        Ok(())
+    }
+
+    async fn handle_route_lookup(&self, destination: String) -> Result<(), Error> {
+        let client = self.get_peer_manager_client().await?;
+        let response = client
+            .lookup_route(
+                BaseController::default(),
+                LookupRouteRequest { destination },
+            )
+            .await?;
+        if self.verbose || *self.output_format == OutputFormat::Json {
+            println!("{}", serde_json::to_string_pretty(&response)?);
+            return Ok(());
+        }
+
+        let peer_routes = self.list_peer_route_pair().await?;
+        let peer_name = |peer_id: u32| {
+            peer_routes
+                .iter()
+                .filter_map(|p| p.route.as_ref())
+                .find(|r| r.peer_id == peer_id)
+                .map(|r| format!("{} ({})", r.hostname, peer_id))
+                .unwrap_or(peer_id.to_string())
+        };
+
+        match &response.policy_route {
+            Some(r) if r.name.is_empty() => println!("policy route: #{}", r.index),
+            Some(r) => println!("policy route: #{} {}", r.index, r.name),
+            None => println!("policy route: none, default routing"),
+        }
+        if response.dst_peer_ids.is_empty() {
+            println!("no destination peer, packets are dropped");
+        }
+        for (peer_id, next_hop) in response
+            .dst_peer_ids
+            .iter()
+            .zip(response.next_hop_peer_ids.iter())
+        {
+            println!(
+                "to: {}{}, next hop: {}",
+                peer_name(*peer_id),
+                if response.is_exit_node {
+                    " as exit node"
+                } else {
+                    ""
+                },
+                if *next_hop == 0 {
+                    "unreachable".to_string()
+                } else {
+                    peer_name(*next_hop)
+                }
+            );
+        }
+
+        Ok(())
+    }
+
     async fn handle_foreign_network_list(&self) -> Result<(), Error> {
         let client = self.get_peer_manager_client().await?;
         let request = ListForeignNetworkRequest::default();
@@ -616,6 +846,34 @@ impl CommandHandler<'_> {
 
         for (idx, (k, v)) in network_map.foreign_networks.iter().enumerate() {
             println!("{} Network Name: {}", idx + 1, k);
+            if let Some(usage) = &v.usage {
+                println!(
+                    "  policy: {}, month_bytes: {}, quota: {}, bps_limit: {}, max_peers: {}{}",
+                    if usage.policy.is_empty() {
+                        "-"
+                    } else {
+                        &usage.policy
+                    },
+                    format_size(usage.month_bytes, humansize::BINARY),
+                    usage
+                        .monthly_quota_bytes
+                        .map(|q| format_size(q, humansize::BINARY))
+                        .unwrap_or("-".to_string()),
+                    usage
+                        .bps_limit
+                        .map(|b| format!("{}/s", format_size(b, humansize::BINARY)))
+                        .unwrap_or("-".to_string()),
+                    usage
+                        .max_peers
+                        .map(|m| m.to_string())
+                        .unwrap_or("-".to_string()),
+                    if usage.quota_exceeded {
+                        " (quota exceeded)"
+                    } else {
+                        ""
+                    },
+                );
+            }
             for peer in v.peers.iter() {
                 println!(
                     "  peer_id: {}, peer_conn_count: {}, conns: [ {} ]",
@@ -681,6 +939,7 @@ impl CommandHandler<'_> {
             next_hop_ipv4: String,
             next_hop_hostname: String,
             next_hop_lat: f64,
+            next_hop_link: String,
             path_len: i32,
             path_latency: i32,
 
@@ -725,6 +984,7 @@ impl CommandHandler<'_> {
             next_hop_ipv4: "-".to_string(),
             next_hop_hostname: "Local".to_string(),
             next_hop_lat: 0.0,
+            next_hop_link: "-".to_string(),
             path_len: 0,
             path_latency: 0,
 
@@ -779,6 +1039,19 @@ impl CommandHandler<'_> {
                         .clone()
                 },
                 next_hop_lat: next_hop_pair.get_latency_ms().unwrap_or(0.0),
+                next_hop_link: route
+                    .next_hop_link_metric
+                    .as_ref()
+                    .map(|m| {
+                        format!(
+                            "{} ({:.1}ms, ±{:.1}ms, {:.1}% loss)",
+                            m.cost,
+                            m.latency_us as f64 / 1000.0,
+                            m.jitter_us as f64 / 1000.0,
+                            m.loss_rate * 100.0
+                        )
+                    })
+                    .unwrap_or("-".to_string()),
                 path_len: route.cost,
                 path_latency: route.path_latency,
 
@@ -1516,6 +1789,10 @@ async fn main() -> Result<(), Error> {
         SubCommand::Route(route_args) => match route_args.sub_command {
             Some(RouteSubCommand::List) | None => handler.handle_route_list().await?,
             Some(RouteSubCommand::Dump) => handler.handle_route_dump().await?,
+            Some(RouteSubCommand::Policy) => handler.handle_route_policy().await?,
+            Some(RouteSubCommand::Lookup { destination }) => {
+                handler.handle_route_lookup(destination).await?
+            }
         },
         SubCommand::Stun => {
             timeout(Duration::from_secs(25), async move {
@@ -1954,6 +2231,9 @@ async fn main() -> Result<(), Error> {
                     print_output(&table_rows, &cli.output_format)?
                 }
             }
+            Some(StatsSubCommand::ExitClients) => {
+                handler.handle_stats_exit_clients().await?;
+            }
             Some(StatsSubCommand::Prometheus) => {
                 let client = handler.get_stats_client().await?;
                 let request = GetPrometheusStatsRequest {};
@@ -1964,6 +2244,48 @@ async fn main() -> Result<(), Error> {
                 println!("{}", response.prometheus_text);
             }
         },
+        SubCommand::NodeAuth(node_auth_args) => match node_auth_args.sub_command {
+            NodeAuthSubCommand::GenKey => {
+                let private_key = node_auth::generate_private_key()?;
+                println!("private key: {}", private_key);
+                println!("public key: {}", node_auth::public_key_of(&private_key)?);
+            }
+            NodeAuthSubCommand::PublicKey { private_key } => {
+                println!("{}", node_auth::public_key_of(&private_key)?);
+            }
+            NodeAuthSubCommand::IssueCert {
+                ca_private_key,
+                node_public_key,
+                network_name,
+                node_name,
+                valid_days,
+            } => {
+                let cert = node_auth::issue_certificate(
+                    &ca_private_key,
+                    &node_public_key,
+                    &network_name,
+                    &node_name,
+                    valid_days.map(|d| Duration::from_secs(d * 24 * 3600)),
+                )?;
+                println!("{}", cert);
+            }
+            NodeAuthSubCommand::IssueRelayToken {
+                signer_private_key,
+                network_name,
+                network_secret,
+                valid_days,
+            } => {
+                let network = network_secret
+                    .map(|secret| NetworkIdentity::new(network_name.clone(), secret));
+                let token = relay_admission::issue_relay_token(
+                    &signer_private_key,
+                    &network_name,
+                    network.as_ref(),
+                    valid_days.map(|d| Duration::from_secs(d * 24 * 3600)),
+                )?;
+                println!("{}", token);
+            }
+        },
         SubCommand::GenAutocomplete { shell } => {
             let mut cmd = Cli::command();
             easytier::print_completions(shell, &mut cmd, "easytier-cli");
@@ -30,7 +30,7 @@ use easytier::{
     connector::create_connector_by_url,
     instance_manager::NetworkInstanceManager,
     launcher::{add_proxy_network_to_config, ConfigSource},
-    proto::common::{CompressionAlgoPb, NatType},
+    proto::common::{CompressionAlgoPb, MultipathModePb, NatType},
     tunnel::{IpVersion, PROTO_PORT_OFFSET},
     utils::{init_logger, setup_panic_handler},
     web_client,
@@ -150,6 +150,38 @@ struct NetworkOptions {
     )]
     network_secret: Option<String>,
 
+    #[arg(
+        long,
+        env = "ET_NODE_PRIVATE_KEY",
+        help = t!("core_clap.node_private_key").to_string(),
+    )]
+    node_private_key: Option<String>,
+
+    #[arg(
+        long,
+        env = "ET_NODE_CERTIFICATE",
+        help = t!("core_clap.node_certificate").to_string(),
+    )]
+    node_certificate: Option<String>,
+
+    #[arg(
+        long,
+        env = "ET_NODE_CA_PUBLIC_KEYS",
+        value_delimiter = ',',
+        help = t!("core_clap.node_ca_public_keys").to_string(),
+        num_args = 0..
+    )]
+    node_ca_public_keys: Vec<String>,
+
+    #[arg(
+        long,
+        env = "ET_NODE_REVOKED_PUBLIC_KEYS",
+        value_delimiter = ',',
+        help = t!("core_clap.node_revoked_public_keys").to_string(),
+        num_args = 0..
+    )]
+    node_revoked_public_keys: Vec<String>,
+
     #[arg(
         short,
         long,
@@ -252,6 +284,15 @@ struct NetworkOptions {
     )]
     hostname: Option<String>,
 
+    #[arg(
+        long,
+        env = "ET_TAGS",
+        value_delimiter = ',',
+        help = t!("core_clap.tags").to_string(),
+        num_args = 0..
+    )]
+    tags: Vec<String>,
+
     #[arg(
         short = 'm',
         long,
@@ -350,6 +391,21 @@ struct NetworkOptions {
     )]
     exit_nodes: Vec<IpAddr>,
 
+    #[arg(
+        long,
+        env = "ET_EXIT_NODE_PROBE",
+        help = t!("core_clap.exit_node_probe").to_string()
+    )]
+    exit_node_probe: Option<String>,
+
+    #[arg(
+        long,
+        env = "ET_EXIT_NODE_HEALTH_CHECK",
+        help = t!("core_clap.exit_node_health_check").to_string(),
+        default_value = "false",
+    )]
+    exit_node_health_check: bool,
+
     #[arg(
         long,
         env = "ET_ENABLE_EXIT_NODE",
@@ -407,6 +463,29 @@ struct NetworkOptions {
     )]
     relay_network_whitelist: Option<Vec<String>>,
 
+    #[arg(
+        long,
+        env = "ET_RELAY_ADMISSION_TOKEN",
+        help = t!("core_clap.relay_admission_token").to_string(),
+    )]
+    relay_admission_token: Option<String>,
+
+    #[arg(
+        long,
+        env = "ET_WS_LISTENER_TOKEN",
+        help = t!("core_clap.ws_listener_token").to_string(),
+    )]
+    ws_listener_token: Option<String>,
+
+    #[arg(
+        long,
+        env = "ET_RELAY_TOKEN_PUBLIC_KEYS",
+        value_delimiter = ',',
+        help = t!("core_clap.relay_token_public_keys").to_string(),
+        num_args = 0..
+    )]
+    relay_token_public_keys: Vec<String>,
+
     #[arg(
         long,
         env = "ET_DISABLE_P2P",
@@ -425,6 +504,15 @@ struct NetworkOptions {
     )]
     disable_udp_hole_punching: Option<bool>,
 
+    #[arg(
+        long,
+        env = "ET_DISABLE_TCP_HOLE_PUNCHING",
+        help = t!("core_clap.disable_tcp_hole_punching").to_string(),
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    disable_tcp_hole_punching: Option<bool>,
+
     #[arg(
         long,
         env = "ET_RELAY_ALL_PEER_RPC",
@@ -442,6 +530,59 @@ struct NetworkOptions {
     )]
     socks5: Option<u16>,
 
+    #[arg(
+        long,
+        env = "ET_OUTBOUND_PROXY",
+        help = t!("core_clap.outbound_proxy").to_string()
+    )]
+    outbound_proxy: Option<url::Url>,
+
+    #[arg(
+        long,
+        env = "ET_OUTBOUND_PROXY_FROM_ENV",
+        help = t!("core_clap.outbound_proxy_from_env").to_string(),
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    outbound_proxy_from_env: Option<bool>,
+
+    #[arg(
+        long,
+        env = "ET_TLS_CERT_FILE",
+        help = t!("core_clap.tls_cert_file").to_string()
+    )]
+    tls_cert_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        env = "ET_TLS_KEY_FILE",
+        help = t!("core_clap.tls_key_file").to_string()
+    )]
+    tls_key_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        env = "ET_TLS_VERIFY_SERVER",
+        help = t!("core_clap.tls_verify_server").to_string(),
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    tls_verify_server: Option<bool>,
+
+    #[arg(
+        long,
+        env = "ET_TLS_CA_FILE",
+        help = t!("core_clap.tls_ca_file").to_string()
+    )]
+    tls_ca_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        env = "ET_TLS_CLIENT_CA_FILE",
+        help = t!("core_clap.tls_client_ca_file").to_string()
+    )]
+    tls_client_ca_file: Option<PathBuf>,
+
     #[arg(
         long,
         env = "ET_COMPRESSION",
@@ -449,6 +590,64 @@ struct NetworkOptions {
     )]
     compression: Option<String>,
 
+    #[arg(
+        long,
+        env = "ET_COMPRESSION_LEVEL",
+        help = t!("core_clap.compression_level").to_string(),
+        allow_negative_numbers = true
+    )]
+    compression_level: Option<i32>,
+
+    #[arg(
+        long,
+        env = "ET_COMPRESSION_DICT",
+        help = t!("core_clap.compression_dict").to_string()
+    )]
+    compression_dict: Option<String>,
+
+    #[arg(
+        long,
+        env = "ET_COMPRESSION_ADAPTIVE",
+        help = t!("core_clap.compression_adaptive").to_string(),
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    compression_adaptive: Option<bool>,
+
+    #[arg(
+        long,
+        env = "ET_MULTIPATH_MODE",
+        help = t!("core_clap.multipath_mode").to_string(),
+    )]
+    multipath_mode: Option<String>,
+
+    #[arg(
+        long,
+        env = "ET_DISABLE_PMTU_DISCOVERY",
+        help = t!("core_clap.disable_pmtu_discovery").to_string(),
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    disable_pmtu_discovery: Option<bool>,
+
+    #[arg(
+        long,
+        env = "ET_ENABLE_PORT_MAPPING",
+        help = t!("core_clap.enable_port_mapping").to_string(),
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    enable_port_mapping: Option<bool>,
+
+    #[arg(
+        long,
+        env = "ET_ENABLE_LINK_METRIC_COST",
+        help = t!("core_clap.enable_link_metric_cost").to_string(),
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    enable_link_metric_cost: Option<bool>,
+
     #[arg(
         long,
         env = "ET_BIND_DEVICE",
@@ -657,13 +856,65 @@ impl NetworkOptions {
             cfg.set_hostname(self.hostname.clone());
         }
 
+        if !self.tags.is_empty() {
+            cfg.set_tags(self.tags.clone());
+        }
+
         let old_ns = cfg.get_network_identity();
         let network_name = self.network_name.clone().unwrap_or(old_ns.network_name);
         let network_secret = self
             .network_secret
             .clone()
             .unwrap_or(old_ns.network_secret.unwrap_or_default());
-        cfg.set_network_identity(NetworkIdentity::new(network_name, network_secret));
+        cfg.set_network_identity(NetworkIdentity::new(network_name.clone(), network_secret));
+
+        if self.node_private_key.is_some()
+            || self.node_certificate.is_some()
+            || !self.node_ca_public_keys.is_empty()
+            || !self.node_revoked_public_keys.is_empty()
+        {
+            let mut node_auth = cfg.get_node_auth().unwrap_or_default();
+            if let Some(private_key) = &self.node_private_key {
+                node_auth.private_key = private_key.clone();
+            }
+            if let Some(certificate) = &self.node_certificate {
+                node_auth.certificate = Some(certificate.clone());
+            }
+            if !self.node_ca_public_keys.is_empty() {
+                node_auth.ca_public_keys = self.node_ca_public_keys.clone();
+            }
+            if !self.node_revoked_public_keys.is_empty() {
+                node_auth.revoked_public_keys = self.node_revoked_public_keys.clone();
+            }
+            cfg.set_node_auth(Some(node_auth));
+        }
+
+        if let Some(node_auth) = cfg.get_node_auth() {
+            easytier::peers::node_auth::NodeAuthenticator::new(&network_name, &node_auth)
+                .with_context(|| "invalid node auth config")?;
+        }
+
+        if let Some(token) = &self.relay_admission_token {
+            cfg.set_relay_admission_token(Some(token.clone()));
+        }
+        if let Some(token) = cfg.get_relay_admission_token() {
+            easytier::peers::relay_admission::decode_relay_token(&token)
+                .with_context(|| "invalid relay admission token")?;
+        }
+
+        if let Some(token) = &self.ws_listener_token {
+            cfg.set_ws_listener_token(Some(token.clone()));
+        }
+
+        if !self.relay_token_public_keys.is_empty() {
+            let mut admission = cfg.get_relay_admission().unwrap_or_default();
+            admission.token_public_keys = self.relay_token_public_keys.clone();
+            cfg.set_relay_admission(Some(admission));
+        }
+        if let Some(admission) = cfg.get_relay_admission() {
+            easytier::peers::relay_admission::RelayAdmission::new(&admission)
+                .with_context(|| "invalid relay admission config")?;
+        }
 
         if let Some(dhcp) = self.dhcp {
             cfg.set_dhcp(dhcp);
@@ -689,6 +940,7 @@ impl NetworkOptions {
                     uri: p
                         .parse()
                         .with_context(|| format!("failed to parse peer uri: {}", p))?,
+                    proxy: None,
                 });
             }
             cfg.set_peers(peers);
@@ -767,6 +1019,7 @@ impl NetworkOptions {
                 uri: external_nodes.parse().with_context(|| {
                     format!("failed to parse external node uri: {}", external_nodes)
                 })?,
+                proxy: None,
             });
             cfg.set_peers(old_peers);
         }
@@ -796,7 +1049,7 @@ impl NetworkOptions {
         }
 
         if let Some(manual_routes) = self.manual_routes.as_ref() {
-            let mut routes = Vec::<cidr::Ipv4Cidr>::with_capacity(manual_routes.len());
+            let mut routes = Vec::<cidr::IpCidr>::with_capacity(manual_routes.len());
             for r in manual_routes {
                 routes.push(
                     r.parse()
@@ -815,6 +1068,64 @@ impl NetworkOptions {
             ));
         }
 
+        if let Some(proxy) = &self.outbound_proxy {
+            cfg.set_outbound_proxy(Some(proxy.clone()));
+        }
+        if let Some(proxy) = cfg.get_outbound_proxy() {
+            easytier::tunnel::proxy::check_proxy_url(&proxy)
+                .with_context(|| format!("invalid outbound proxy: {}", proxy))?;
+        }
+
+        if self.tls_cert_file.is_some()
+            || self.tls_key_file.is_some()
+            || self.tls_verify_server.is_some()
+            || self.tls_ca_file.is_some()
+            || self.tls_client_ca_file.is_some()
+        {
+            let mut tls = cfg.get_tls_config().unwrap_or_default();
+            if let Some(cert_file) = &self.tls_cert_file {
+                tls.cert_file = Some(cert_file.clone());
+            }
+            if let Some(key_file) = &self.tls_key_file {
+                tls.key_file = Some(key_file.clone());
+            }
+            if let Some(verify_server) = self.tls_verify_server {
+                tls.verify_server = verify_server;
+            }
+            if let Some(ca_file) = &self.tls_ca_file {
+                tls.ca_file = Some(ca_file.clone());
+            }
+            if let Some(client_ca_file) = &self.tls_client_ca_file {
+                tls.client_ca_file = Some(client_ca_file.clone());
+            }
+            cfg.set_tls_config(Some(tls));
+        }
+        if let Some(tls) = cfg.get_tls_config() {
+            if tls.cert_file.is_some() != tls.key_file.is_some() {
+                anyhow::bail!("tls cert file and key file must be given together");
+            }
+            #[cfg(any(feature = "quic", feature = "websocket"))]
+            {
+                use easytier::tunnel::tls::{load_certs, load_private_key, load_root_store};
+                if let (Some(cert_file), Some(key_file)) = (&tls.cert_file, &tls.key_file) {
+                    load_certs(cert_file).with_context(|| {
+                        format!("invalid tls cert file: {}", cert_file.display())
+                    })?;
+                    load_private_key(key_file)
+                        .with_context(|| format!("invalid tls key file: {}", key_file.display()))?;
+                }
+                if let Some(ca_file) = &tls.ca_file {
+                    load_root_store(ca_file)
+                        .with_context(|| format!("invalid tls ca file: {}", ca_file.display()))?;
+                }
+                if let Some(client_ca_file) = &tls.client_ca_file {
+                    load_root_store(client_ca_file).with_context(|| {
+                        format!("invalid tls client ca file: {}", client_ca_file.display())
+                    })?;
+                }
+            }
+        }
+
         #[cfg(feature = "socks5")]
         for port_forward in self.port_forward.iter() {
             let example_str = ", example: udp://0.0.0.0:12345/10.126.126.1:12345";
@@ -882,19 +1193,59 @@ impl NetworkOptions {
         f.disable_udp_hole_punching = self
             .disable_udp_hole_punching
             .unwrap_or(f.disable_udp_hole_punching);
+        f.disable_tcp_hole_punching = self
+            .disable_tcp_hole_punching
+            .unwrap_or(f.disable_tcp_hole_punching);
         f.relay_all_peer_rpc = self.relay_all_peer_rpc.unwrap_or(f.relay_all_peer_rpc);
         f.multi_thread = self.multi_thread.unwrap_or(f.multi_thread);
         if let Some(compression) = &self.compression {
             f.data_compress_algo = match compression.as_str() {
                 "none" => CompressionAlgoPb::None,
                 "zstd" => CompressionAlgoPb::Zstd,
+                "lz4" => CompressionAlgoPb::Lz4,
+                "zstd-dict" => CompressionAlgoPb::ZstdDict,
                 _ => panic!(
-                    "unknown compression algorithm: {}, supported: none, zstd",
+                    "unknown compression algorithm: {}, supported: none, zstd, lz4, zstd-dict",
                     compression
                 ),
             }
             .into();
         }
+        f.data_compress_level = self.compression_level.unwrap_or(f.data_compress_level);
+        f.data_compress_adaptive = self
+            .compression_adaptive
+            .unwrap_or(f.data_compress_adaptive);
+        if let Some(dict) = &self.compression_dict {
+            easytier::common::compressor::load_zstd_dict(dict)
+                .with_context(|| format!("invalid compression dictionary: {}", dict))?;
+            f.data_compress_dict = dict.clone();
+        }
+        if f.data_compress_algo() == CompressionAlgoPb::ZstdDict && f.data_compress_dict.is_empty()
+        {
+            anyhow::bail!("zstd-dict compression requires --compression-dict");
+        }
+        if let Some(mode) = &self.multipath_mode {
+            f.multipath_mode = match mode.as_str() {
+                "disabled" => MultipathModePb::Disabled,
+                "round-robin" => MultipathModePb::RoundRobin,
+                "flow-hash" => MultipathModePb::FlowHash,
+                _ => anyhow::bail!(
+                    "unknown multipath mode: {}, supported: disabled, round-robin, flow-hash",
+                    mode
+                ),
+            }
+            .into();
+        }
+        f.disable_pmtu_discovery = self
+            .disable_pmtu_discovery
+            .unwrap_or(f.disable_pmtu_discovery);
+        f.enable_port_mapping = self.enable_port_mapping.unwrap_or(f.enable_port_mapping);
+        f.enable_link_metric_cost = self
+            .enable_link_metric_cost
+            .unwrap_or(f.enable_link_metric_cost);
+        f.outbound_proxy_from_env = self
+            .outbound_proxy_from_env
+            .unwrap_or(f.outbound_proxy_from_env);
         f.bind_device = self.bind_device.unwrap_or(f.bind_device);
         f.enable_kcp_proxy = self.enable_kcp_proxy.unwrap_or(f.enable_kcp_proxy);
         f.disable_kcp_input = self.disable_kcp_input.unwrap_or(f.disable_kcp_input);
@@ -916,6 +1267,17 @@ impl NetworkOptions {
             cfg.set_exit_nodes(self.exit_nodes.clone());
         }
 
+        if let Some(target) = &self.exit_node_probe {
+            let mut egress = cfg.get_exit_node_egress().unwrap_or_default();
+            egress.probe_targets = vec![target.clone()];
+            cfg.set_exit_node_egress(Some(egress));
+        }
+
+        if self.exit_node_health_check {
+            let health = cfg.get_exit_node_health().unwrap_or_default();
+            cfg.set_exit_node_health(Some(health));
+        }
+
         let mut old_tcp_whitelist = cfg.get_tcp_whitelist();
         old_tcp_whitelist.extend(self.tcp_whitelist.clone());
         cfg.set_tcp_whitelist(old_tcp_whitelist);
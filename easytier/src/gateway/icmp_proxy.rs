@@ -1,6 +1,6 @@
 use std::{
     mem::MaybeUninit,
-    net::{IpAddr, Ipv4Addr, SocketAddrV4},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6},
     sync::{Arc, Weak},
     thread,
     time::Duration,
@@ -9,8 +9,10 @@ use std::{
 use anyhow::Context;
 use pnet::packet::{
     icmp::{self, echo_reply::MutableEchoReplyPacket, IcmpCode, IcmpTypes, MutableIcmpPacket},
+    icmpv6::{self, Icmpv6Code, Icmpv6Packet, Icmpv6Types, MutableIcmpv6Packet},
     ip::IpNextHeaderProtocols,
     ipv4::Ipv4Packet,
+    ipv6::Ipv6Packet,
     Packet,
 };
 use socket2::Socket;
@@ -23,7 +25,10 @@ use tracing::Instrument;
 
 use crate::{
     common::{error::Error, global_ctx::ArcGlobalCtx, PeerId},
-    gateway::ip_reassembler::ComposeIpv4PacketArgs,
+    gateway::ip_reassembler::{
+        compose_ipv6_packet, ComposeIpv4PacketArgs, ComposeIpv6PacketArgs,
+        IPV6_PACKET_HEADER_RESERVE,
+    },
     peers::{peer_manager::PeerManager, PeerPacketFilter},
     tunnel::packet_def::{PacketType, ZCPacket},
 };
@@ -46,7 +51,7 @@ struct IcmpNatEntry {
     my_peer_id: PeerId,
     src_ip: IpAddr,
     start_time: std::time::Instant,
-    mapped_dst_ip: std::net::Ipv4Addr,
+    mapped_dst_ip: IpAddr,
 }
 
 impl IcmpNatEntry {
@@ -54,7 +59,7 @@ impl IcmpNatEntry {
         src_peer_id: PeerId,
         my_peer_id: PeerId,
         src_ip: IpAddr,
-        mapped_dst_ip: Ipv4Addr,
+        mapped_dst_ip: IpAddr,
     ) -> Result<Self, Error> {
         Ok(Self {
             src_peer_id,
@@ -77,6 +82,7 @@ pub struct IcmpProxy {
 
     cidr_set: CidrSet,
     socket: std::sync::Mutex<Option<Arc<socket2::Socket>>>,
+    socket_v6: std::sync::Mutex<Option<Arc<socket2::Socket>>>,
 
     nat_table: IcmpNatTable,
 
@@ -152,7 +158,7 @@ fn socket_recv_loop(
         };
 
         // send packet back to the peer where this request origin.
-        let IpAddr::V4(dest_ip) = v.src_ip else {
+        let (IpAddr::V4(dest_ip), IpAddr::V4(mapped_dst_ip)) = (v.src_ip, v.mapped_dst_ip) else {
             continue;
         };
 
@@ -161,7 +167,7 @@ fn socket_recv_loop(
         let _ = compose_ipv4_packet(
             ComposeIpv4PacketArgs {
                 buf: &mut buf[..],
-                src_v4: &v.mapped_dst_ip,
+                src_v4: &mapped_dst_ip,
                 dst_v4: &dest_ip,
                 next_protocol: IpNextHeaderProtocols::Icmp,
                 payload_len,
@@ -182,6 +188,106 @@ fn socket_recv_loop(
     }
 }
 
+// the icmpv6 raw socket delivers the icmp message without ip header
+fn socket_recv_loop_v6(
+    socket: Arc<Socket>,
+    nat_table: IcmpNatTable,
+    sender: UnboundedSender<ZCPacket>,
+) {
+    let mut buf = [0u8; 8192];
+
+    loop {
+        let payload_buf = &mut buf[IPV6_PACKET_HEADER_RESERVE..];
+        let data: &mut [MaybeUninit<u8>] = unsafe { std::mem::transmute(&mut payload_buf[..]) };
+        let (len, peer_ip) = match socket_recv(&socket, data) {
+            Ok((len, peer_ip)) => (len, peer_ip),
+            Err(e) => {
+                tracing::error!("recv icmpv6 packet failed: {:?}", e);
+                if sender.is_closed() {
+                    break;
+                } else {
+                    continue;
+                }
+            }
+        };
+
+        if len == 0 {
+            tracing::error!("recv empty icmpv6 packet, len: {}", len);
+            return;
+        }
+
+        let Some((id, seq)) = parse_icmpv6_echo(
+            &buf[IPV6_PACKET_HEADER_RESERVE..IPV6_PACKET_HEADER_RESERVE + len],
+            Icmpv6Types::EchoReply,
+        ) else {
+            continue;
+        };
+
+        let key = IcmpNatKey {
+            real_dst_ip: peer_ip,
+            icmp_id: id,
+            icmp_seq: seq,
+        };
+
+        let Some((_, v)) = nat_table.remove(&key) else {
+            continue;
+        };
+
+        let (IpAddr::V6(dest_ip), IpAddr::V6(mapped_dst_ip)) = (v.src_ip, v.mapped_dst_ip) else {
+            continue;
+        };
+
+        // the pseudo header changed, so the checksum must be recomputed
+        let mut icmp_packet = MutableIcmpv6Packet::new(
+            &mut buf[IPV6_PACKET_HEADER_RESERVE..IPV6_PACKET_HEADER_RESERVE + len],
+        )
+        .unwrap();
+        icmp_packet.set_checksum(icmpv6::checksum(
+            &icmp_packet.to_immutable(),
+            &mapped_dst_ip,
+            &dest_ip,
+        ));
+
+        let _ = compose_ipv6_packet(
+            ComposeIpv6PacketArgs {
+                buf: &mut buf[..],
+                src_v6: &mapped_dst_ip,
+                dst_v6: &dest_ip,
+                next_protocol: IpNextHeaderProtocols::Icmpv6,
+                payload_len: len,
+                payload_mtu: 1200,
+                ip_id: rand::random(),
+            },
+            |buf| {
+                let mut p = ZCPacket::new_with_payload(buf);
+                p.fill_peer_manager_hdr(v.my_peer_id, v.src_peer_id, PacketType::Data as u8);
+                p.mut_peer_manager_header().unwrap().set_no_proxy(true);
+
+                if let Err(e) = sender.send(p) {
+                    tracing::error!("send icmpv6 packet to peer failed: {:?}, may exiting..", e);
+                }
+                Ok(())
+            },
+        );
+    }
+}
+
+// returns the identifier and sequence number of an icmpv6 echo message
+fn parse_icmpv6_echo(buf: &[u8], expected_type: icmpv6::Icmpv6Type) -> Option<(u16, u16)> {
+    let icmp_packet = Icmpv6Packet::new(buf)?;
+    if icmp_packet.get_icmpv6_type() != expected_type {
+        return None;
+    }
+    let payload = icmp_packet.payload();
+    if payload.len() < 4 {
+        return None;
+    }
+    Some((
+        u16::from_be_bytes([payload[0], payload[1]]),
+        u16::from_be_bytes([payload[2], payload[3]]),
+    ))
+}
+
 #[async_trait::async_trait]
 impl PeerPacketFilter for IcmpProxy {
     async fn try_process_packet_from_peer(&self, packet: ZCPacket) -> Option<ZCPacket> {
@@ -204,6 +310,7 @@ impl IcmpProxy {
             peer_manager: Arc::downgrade(&peer_manager),
             cidr_set,
             socket: std::sync::Mutex::new(None),
+            socket_v6: std::sync::Mutex::new(None),
 
             nat_table: Arc::new(dashmap::DashMap::new()),
             tasks: Mutex::new(JoinSet::new()),
@@ -229,6 +336,22 @@ impl IcmpProxy {
         Ok(socket)
     }
 
+    fn create_raw_socket_v6(self: &Arc<Self>) -> Result<Socket, Error> {
+        let _g = self.global_ctx.net_ns.guard();
+        let socket = socket2::Socket::new(
+            socket2::Domain::IPV6,
+            socket2::Type::RAW,
+            Some(socket2::Protocol::ICMPV6),
+        )?;
+        socket.bind(&socket2::SockAddr::from(SocketAddrV6::new(
+            Ipv6Addr::UNSPECIFIED,
+            0,
+            0,
+            0,
+        )))?;
+        Ok(socket)
+    }
+
     pub async fn start(self: &Arc<Self>) -> Result<(), Error> {
         let socket = self.create_raw_socket();
         match socket {
@@ -243,6 +366,18 @@ impl IcmpProxy {
             }
         }
 
+        match self.create_raw_socket_v6() {
+            Ok(socket) => {
+                self.socket_v6.lock().unwrap().replace(Arc::new(socket));
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "create icmpv6 socket failed, ipv6 icmp proxy disabled: {:?}",
+                    e
+                );
+            }
+        }
+
         self.start_icmp_proxy().await?;
         self.start_nat_table_cleaner().await?;
         Ok(())
@@ -268,10 +403,18 @@ impl IcmpProxy {
         if let Some(socket) = self.socket.lock().unwrap().as_ref() {
             let socket = socket.clone();
             let nat_table = self.nat_table.clone();
+            let sender = sender.clone();
             thread::spawn(|| {
                 socket_recv_loop(socket, nat_table, sender);
             });
         }
+        if let Some(socket) = self.socket_v6.lock().unwrap().as_ref() {
+            let socket = socket.clone();
+            let nat_table = self.nat_table.clone();
+            thread::spawn(|| {
+                socket_recv_loop_v6(socket, nat_table, sender);
+            });
+        }
 
         let peer_manager = self.peer_manager.clone();
         self.tasks.lock().await.spawn(
@@ -377,7 +520,6 @@ impl IcmpProxy {
             return None;
         }
 
-        let _ = self.global_ctx.get_ipv4()?;
         let hdr = packet.peer_manager_header().unwrap();
         let is_exit_node = hdr.is_exit_node();
 
@@ -385,6 +527,12 @@ impl IcmpProxy {
             return None;
         };
 
+        if packet.payload().first().map(|b| b >> 4) == Some(6) {
+            return self.try_handle_ipv6_peer_packet(packet).await;
+        }
+
+        let _ = self.global_ctx.get_ipv4()?;
+
         let ipv4 = Ipv4Packet::new(packet.payload())?;
 
         if ipv4.get_version() != 4 || ipv4.get_next_level_protocol() != IpNextHeaderProtocols::Icmp
@@ -458,7 +606,7 @@ impl IcmpProxy {
             hdr.from_peer_id.into(),
             hdr.to_peer_id.into(),
             ipv4.get_source().into(),
-            ipv4.get_destination(),
+            ipv4.get_destination().into(),
         )
         .ok()?;
 
@@ -474,6 +622,133 @@ impl IcmpProxy {
     }
 }
 
+impl IcmpProxy {
+    async fn send_icmpv6_reply_to_peer(
+        &self,
+        src_ip: &Ipv6Addr,
+        dst_ip: &Ipv6Addr,
+        src_peer_id: PeerId,
+        dst_peer_id: PeerId,
+        request: &[u8],
+    ) {
+        let mut buf = vec![0u8; IPV6_PACKET_HEADER_RESERVE + request.len()];
+        buf[IPV6_PACKET_HEADER_RESERVE..].copy_from_slice(request);
+        let mut reply_packet =
+            MutableIcmpv6Packet::new(&mut buf[IPV6_PACKET_HEADER_RESERVE..]).unwrap();
+        reply_packet.set_icmpv6_type(Icmpv6Types::EchoReply);
+        reply_packet.set_icmpv6_code(Icmpv6Code::new(0));
+        reply_packet.set_checksum(icmpv6::checksum(
+            &reply_packet.to_immutable(),
+            src_ip,
+            dst_ip,
+        ));
+
+        let _ = compose_ipv6_packet(
+            ComposeIpv6PacketArgs {
+                buf: &mut buf[..],
+                src_v6: src_ip,
+                dst_v6: dst_ip,
+                next_protocol: IpNextHeaderProtocols::Icmpv6,
+                payload_len: request.len(),
+                payload_mtu: 1200,
+                ip_id: rand::random(),
+            },
+            |buf| {
+                let mut packet = ZCPacket::new_with_payload(buf);
+                packet.fill_peer_manager_hdr(src_peer_id, dst_peer_id, PacketType::Data as u8);
+                let _ = self
+                    .icmp_sender
+                    .lock()
+                    .unwrap()
+                    .as_ref()
+                    .unwrap()
+                    .send(packet);
+                Ok(())
+            },
+        );
+    }
+
+    async fn try_handle_ipv6_peer_packet(&self, packet: &ZCPacket) -> Option<()> {
+        let hdr = packet.peer_manager_header().unwrap();
+        let is_exit_node = hdr.is_exit_node();
+
+        // fragmented echo requests carry a fragment header and are not proxied.
+        let ipv6 = Ipv6Packet::new(packet.payload())?;
+        if ipv6.get_next_header() != IpNextHeaderProtocols::Icmpv6 {
+            return None;
+        }
+
+        let my_ipv6 = self.global_ctx.get_ipv6().map(|x| x.address());
+        let is_to_self_no_tun = self.global_ctx.no_tun() && Some(ipv6.get_destination()) == my_ipv6;
+
+        let mut real_dst_ip = ipv6.get_destination();
+        if !(self
+            .cidr_set
+            .contains_v6(ipv6.get_destination(), &mut real_dst_ip)
+            || is_exit_node
+            || is_to_self_no_tun)
+        {
+            return None;
+        }
+
+        let Some((icmp_id, icmp_seq)) = parse_icmpv6_echo(ipv6.payload(), Icmpv6Types::EchoRequest)
+        else {
+            // other icmpv6 types (ndp, errors...) are not proxied.
+            return None;
+        };
+
+        if is_to_self_no_tun {
+            self.send_icmpv6_reply_to_peer(
+                &ipv6.get_destination(),
+                &ipv6.get_source(),
+                hdr.to_peer_id.get(),
+                hdr.from_peer_id.get(),
+                ipv6.payload(),
+            )
+            .await;
+            return Some(());
+        }
+
+        let key = IcmpNatKey {
+            real_dst_ip: real_dst_ip.into(),
+            icmp_id,
+            icmp_seq,
+        };
+
+        let value = IcmpNatEntry::new(
+            hdr.from_peer_id.into(),
+            hdr.to_peer_id.into(),
+            ipv6.get_source().into(),
+            ipv6.get_destination().into(),
+        )
+        .ok()?;
+
+        if let Some(old) = self.nat_table.insert(key, value) {
+            tracing::info!("icmp nat table entry replaced: {:?}", old);
+        }
+
+        // the kernel fills the icmpv6 checksum for raw sockets
+        let ret = self
+            .socket_v6
+            .lock()
+            .unwrap()
+            .as_ref()
+            .with_context(|| "icmpv6 socket not created")
+            .and_then(|s| {
+                s.send_to(
+                    ipv6.payload(),
+                    &SocketAddrV6::new(real_dst_ip, 0, 0, 0).into(),
+                )
+                .map_err(Into::into)
+            });
+        if let Err(e) = ret {
+            tracing::error!("send icmpv6 packet failed: {:?}", e);
+        }
+
+        Some(())
+    }
+}
+
 impl Drop for IcmpProxy {
     fn drop(&mut self) {
         tracing::info!(
@@ -484,5 +759,9 @@ impl Drop for IcmpProxy {
             tracing::info!("shutting down icmp socket");
             let _ = s.shutdown(std::net::Shutdown::Both);
         }
+        if let Some(s) = self.socket_v6.lock().unwrap().as_ref() {
+            tracing::info!("shutting down icmpv6 socket");
+            let _ = s.shutdown(std::net::Shutdown::Both);
+        }
     }
 }
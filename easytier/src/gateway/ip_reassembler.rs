@@ -1,8 +1,9 @@
 use dashmap::DashMap;
-use pnet::packet::ip::IpNextHeaderProtocol;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
 use pnet::packet::ipv4::{self, Ipv4Flags, Ipv4Packet, MutableIpv4Packet};
+use pnet::packet::ipv6::MutableIpv6Packet;
 use pnet::packet::Packet;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::time::{Duration, Instant};
 
 use crate::common::error::Error;
@@ -251,6 +252,95 @@ where
     Ok(())
 }
 
+pub struct ComposeIpv6PacketArgs<'a> {
+    pub buf: &'a mut [u8],
+    pub src_v6: &'a Ipv6Addr,
+    pub dst_v6: &'a Ipv6Addr,
+    pub next_protocol: IpNextHeaderProtocol,
+    pub payload_len: usize,
+    pub payload_mtu: usize,
+    pub ip_id: u32,
+}
+
+const IPV6_HEADER_LEN: usize = 40;
+const IPV6_FRAGMENT_HEADER_LEN: usize = 8;
+// room reserved before the ip payload, enough for ipv6 header and fragment header
+pub const IPV6_PACKET_HEADER_RESERVE: usize = IPV6_HEADER_LEN + IPV6_FRAGMENT_HEADER_LEN;
+
+// ip payload should be in buf[48..], the fragment header is only added when the
+// payload does not fit in payload_mtu.
+pub fn compose_ipv6_packet<F>(args: ComposeIpv6PacketArgs, cb: F) -> Result<(), Error>
+where
+    F: Fn(&[u8]) -> Result<(), Error>,
+{
+    let (src_v6, dst_v6) = (args.src_v6, args.dst_v6);
+    if args.payload_len <= args.payload_mtu {
+        let start = IPV6_FRAGMENT_HEADER_LEN;
+        let end = IPV6_PACKET_HEADER_RESERVE + args.payload_len;
+        let mut ipv6_packet = MutableIpv6Packet::new(&mut args.buf[start..end]).unwrap();
+        fill_ipv6_header(
+            &mut ipv6_packet,
+            src_v6,
+            dst_v6,
+            args.payload_len,
+            args.next_protocol,
+        );
+        tracing::trace!(?ipv6_packet, "ipv6 packet composed");
+        return cb(ipv6_packet.packet());
+    }
+
+    assert_eq!(0, args.payload_mtu % 8);
+    let mut fragment_offset = 0;
+    while fragment_offset < args.payload_len {
+        let next_fragment_offset =
+            std::cmp::min(fragment_offset + args.payload_mtu, args.payload_len);
+        let fragment_len = next_fragment_offset - fragment_offset;
+        let more_fragments = next_fragment_offset < args.payload_len;
+
+        let buf = &mut args.buf
+            [fragment_offset..fragment_offset + IPV6_PACKET_HEADER_RESERVE + fragment_len];
+
+        let frag_hdr = &mut buf[IPV6_HEADER_LEN..IPV6_PACKET_HEADER_RESERVE];
+        frag_hdr[0] = args.next_protocol.0;
+        frag_hdr[1] = 0;
+        let offset_and_flags = (fragment_offset as u16) | more_fragments as u16;
+        frag_hdr[2..4].copy_from_slice(&offset_and_flags.to_be_bytes());
+        frag_hdr[4..8].copy_from_slice(&args.ip_id.to_be_bytes());
+
+        let mut ipv6_packet = MutableIpv6Packet::new(buf).unwrap();
+        fill_ipv6_header(
+            &mut ipv6_packet,
+            src_v6,
+            dst_v6,
+            fragment_len + IPV6_FRAGMENT_HEADER_LEN,
+            IpNextHeaderProtocols::Ipv6Frag,
+        );
+        tracing::trace!(?ipv6_packet, "ipv6 fragment composed");
+
+        cb(ipv6_packet.packet())?;
+
+        fragment_offset = next_fragment_offset;
+    }
+    Ok(())
+}
+
+fn fill_ipv6_header(
+    ipv6_packet: &mut MutableIpv6Packet,
+    src_v6: &Ipv6Addr,
+    dst_v6: &Ipv6Addr,
+    payload_len: usize,
+    next_header: IpNextHeaderProtocol,
+) {
+    ipv6_packet.set_version(6);
+    ipv6_packet.set_traffic_class(0);
+    ipv6_packet.set_flow_label(0);
+    ipv6_packet.set_payload_length(payload_len as u16);
+    ipv6_packet.set_next_header(next_header);
+    ipv6_packet.set_hop_limit(32);
+    ipv6_packet.set_source(*src_v6);
+    ipv6_packet.set_destination(*dst_v6);
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -308,4 +398,50 @@ mod tests {
         resembler.remove_expired_packets();
         assert_eq!(0, resembler.packets.len());
     }
+
+    #[test]
+    fn compose_ipv6_fragments() {
+        let src: Ipv6Addr = "fd00::1".parse().unwrap();
+        let dst: Ipv6Addr = "fd00::2".parse().unwrap();
+        let payload_len = 100;
+        let mut buf = vec![0u8; IPV6_PACKET_HEADER_RESERVE + payload_len];
+        for (i, b) in buf[IPV6_PACKET_HEADER_RESERVE..].iter_mut().enumerate() {
+            *b = i as u8;
+        }
+
+        let pieces = std::sync::Mutex::new(vec![]);
+        compose_ipv6_packet(
+            ComposeIpv6PacketArgs {
+                buf: &mut buf[..],
+                src_v6: &src,
+                dst_v6: &dst,
+                next_protocol: IpNextHeaderProtocols::Udp,
+                payload_len,
+                payload_mtu: 48,
+                ip_id: 7,
+            },
+            |p| {
+                pieces.lock().unwrap().push(p.to_vec());
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        let pieces = pieces.into_inner().unwrap();
+        assert_eq!(3, pieces.len());
+        let mut data = vec![];
+        for (idx, p) in pieces.iter().enumerate() {
+            let packet = pnet::packet::ipv6::Ipv6Packet::new(p).unwrap();
+            assert_eq!(IpNextHeaderProtocols::Ipv6Frag, packet.get_next_header());
+            assert_eq!(dst, packet.get_destination());
+            let frag = &p[IPV6_HEADER_LEN..IPV6_PACKET_HEADER_RESERVE];
+            assert_eq!(IpNextHeaderProtocols::Udp.0, frag[0]);
+            let offset_and_flags = u16::from_be_bytes([frag[2], frag[3]]);
+            assert_eq!(idx * 48, (offset_and_flags & !0x7) as usize);
+            assert_eq!(idx != 2, offset_and_flags & 1 == 1);
+            assert_eq!(7, u32::from_be_bytes([frag[4], frag[5], frag[6], frag[7]]));
+            data.extend_from_slice(&p[IPV6_PACKET_HEADER_RESERVE..]);
+        }
+        assert_eq!((0..payload_len).map(|i| i as u8).collect::<Vec<_>>(), data);
+    }
 }
@@ -23,10 +23,10 @@ pub mod quic_proxy;
 #[derive(Debug)]
 pub(crate) struct CidrSet {
     global_ctx: ArcGlobalCtx,
-    cidr_set: Arc<Mutex<Vec<cidr::Ipv4Cidr>>>,
+    cidr_set: Arc<Mutex<Vec<cidr::IpCidr>>>,
     tasks: JoinSet<()>,
 
-    mapped_to_real: Arc<DashMap<cidr::Ipv4Cidr, cidr::Ipv4Cidr>>,
+    mapped_to_real: Arc<DashMap<cidr::IpCidr, cidr::IpCidr>>,
 }
 
 impl CidrSet {
@@ -73,8 +73,13 @@ impl CidrSet {
         let ip = ipv4;
         let s = self.cidr_set.lock().unwrap();
         for cidr in s.iter() {
+            let cidr::IpCidr::V4(cidr) = cidr else {
+                continue;
+            };
             if cidr.contains(&ip) {
-                if let Some(real_cidr) = self.mapped_to_real.get(cidr).map(|v| *v.value()) {
+                if let Some(cidr::IpCidr::V4(real_cidr)) =
+                    self.mapped_to_real.get(&(*cidr).into()).map(|v| *v.value())
+                {
                     let origin_network_bits = real_cidr.first().address().to_bits();
                     let network_mask = cidr.mask().to_bits();
 
@@ -92,7 +97,104 @@ impl CidrSet {
         false
     }
 
+    pub fn contains_v6(&self, ipv6: std::net::Ipv6Addr, real_ip: &mut std::net::Ipv6Addr) -> bool {
+        let s = self.cidr_set.lock().unwrap();
+        for cidr in s.iter() {
+            let cidr::IpCidr::V6(cidr) = cidr else {
+                continue;
+            };
+            if cidr.contains(&ipv6) {
+                // NPTv6 style prefix translation, the interface id is kept as is
+                if let Some(cidr::IpCidr::V6(real_cidr)) =
+                    self.mapped_to_real.get(&(*cidr).into()).map(|v| *v.value())
+                {
+                    let origin_network_bits = u128::from(real_cidr.first_address());
+                    let network_mask = u128::from(cidr.mask());
+
+                    let mut converted_ip = u128::from(ipv6);
+                    converted_ip &= !network_mask;
+                    converted_ip |= origin_network_bits;
+
+                    *real_ip = std::net::Ipv6Addr::from(converted_ip);
+                } else {
+                    *real_ip = ipv6;
+                }
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn contains_ip(&self, ip: std::net::IpAddr, real_ip: &mut std::net::IpAddr) -> bool {
+        match ip {
+            std::net::IpAddr::V4(ipv4) => {
+                let mut real_ipv4 = ipv4;
+                let ret = self.contains_v4(ipv4, &mut real_ipv4);
+                *real_ip = real_ipv4.into();
+                ret
+            }
+            std::net::IpAddr::V6(ipv6) => {
+                let mut real_ipv6 = ipv6;
+                let ret = self.contains_v6(ipv6, &mut real_ipv6);
+                *real_ip = real_ipv6.into();
+                ret
+            }
+        }
+    }
+
     pub fn is_empty(&self) -> bool {
         self.cidr_set.lock().unwrap().is_empty()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        common::global_ctx::tests::get_mock_global_ctx, tunnel::common::tests::wait_for_condition,
+    };
+
+    use super::CidrSet;
+
+    #[tokio::test]
+    async fn cidr_set_maps_ipv4_and_ipv6() {
+        let global_ctx = get_mock_global_ctx();
+        global_ctx
+            .config
+            .add_proxy_cidr(
+                "10.1.2.0/24".parse().unwrap(),
+                Some("10.1.3.0/24".parse().unwrap()),
+            )
+            .unwrap();
+        global_ctx
+            .config
+            .add_proxy_cidr(
+                "fd00:1:2::/64".parse().unwrap(),
+                Some("fd00:1:3::/64".parse().unwrap()),
+            )
+            .unwrap();
+        global_ctx
+            .config
+            .add_proxy_cidr("fd00:5::/64".parse().unwrap(), None)
+            .unwrap();
+
+        let cidr_set = CidrSet::new(global_ctx);
+        wait_for_condition(|| async { !cidr_set.is_empty() }, Duration::from_secs(5)).await;
+
+        let mut real_v4 = std::net::Ipv4Addr::UNSPECIFIED;
+        assert!(cidr_set.contains_v4("10.1.3.4".parse().unwrap(), &mut real_v4));
+        assert_eq!("10.1.2.4".parse::<std::net::Ipv4Addr>().unwrap(), real_v4);
+        assert!(!cidr_set.contains_v4("10.1.2.4".parse().unwrap(), &mut real_v4));
+
+        let mut real_v6 = std::net::Ipv6Addr::UNSPECIFIED;
+        assert!(cidr_set.contains_v6("fd00:1:3::1234".parse().unwrap(), &mut real_v6));
+        assert_eq!(
+            "fd00:1:2::1234".parse::<std::net::Ipv6Addr>().unwrap(),
+            real_v6
+        );
+        assert!(cidr_set.contains_v6("fd00:5::1".parse().unwrap(), &mut real_v6));
+        assert_eq!("fd00:5::1".parse::<std::net::Ipv6Addr>().unwrap(), real_v6);
+        assert!(!cidr_set.contains_v6("fd00:1:2::1".parse().unwrap(), &mut real_v6));
+    }
+}
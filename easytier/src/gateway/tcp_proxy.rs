@@ -1,15 +1,16 @@
 use anyhow::Context;
-use cidr::Ipv4Inet;
+use cidr::{Ipv4Inet, Ipv6Inet};
 use core::panic;
 use crossbeam::atomic::AtomicCell;
 use dashmap::DashMap;
 use pnet::packet::ip::IpNextHeaderProtocols;
 use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet};
-use pnet::packet::tcp::{ipv4_checksum, MutableTcpPacket, TcpPacket};
+use pnet::packet::ipv6::{Ipv6Packet, MutableIpv6Packet};
+use pnet::packet::tcp::{ipv4_checksum, ipv6_checksum, MutableTcpPacket, TcpPacket};
 use pnet::packet::MutablePacket;
 use pnet::packet::Packet;
 use socket2::{SockRef, TcpKeepalive};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
 use std::sync::atomic::{AtomicBool, AtomicU16};
 use std::sync::{Arc, Weak};
 use std::time::{Duration, Instant};
@@ -50,12 +51,42 @@ pub(crate) trait NatDstConnector: Send + Sync + Clone + 'static {
         cidr_set: &CidrSet,
         global_ctx: &GlobalCtx,
         hdr: &PeerManagerHeader,
-        ipv4: &Ipv4Packet,
-        real_dst_ip: &mut Ipv4Addr,
+        dst_ip: IpAddr,
+        real_dst_ip: &mut IpAddr,
     ) -> bool;
     fn transport_type(&self) -> TcpProxyEntryTransportType;
 }
 
+/// Returns the source, destination and the offset of the tcp header if the
+/// ip packet carries tcp directly (no ipv6 extension headers).
+pub(crate) fn parse_tcp_ip_packet(data: &[u8]) -> Option<(IpAddr, IpAddr, usize)> {
+    match data.first().map(|b| b >> 4) {
+        Some(4) => {
+            let ipv4 = Ipv4Packet::new(data)?;
+            if ipv4.get_next_level_protocol() != IpNextHeaderProtocols::Tcp {
+                return None;
+            }
+            Some((
+                ipv4.get_source().into(),
+                ipv4.get_destination().into(),
+                ipv4.get_header_length() as usize * 4,
+            ))
+        }
+        Some(6) => {
+            let ipv6 = Ipv6Packet::new(data)?;
+            if ipv6.get_next_header() != IpNextHeaderProtocols::Tcp {
+                return None;
+            }
+            Some((
+                ipv6.get_source().into(),
+                ipv6.get_destination().into(),
+                Ipv6Packet::minimum_packet_size(),
+            ))
+        }
+        _ => None,
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct NatDstTcpConnector;
 
@@ -63,10 +94,14 @@ pub struct NatDstTcpConnector;
 impl NatDstConnector for NatDstTcpConnector {
     type DstStream = TcpStream;
     async fn connect(&self, _src: SocketAddr, nat_dst: SocketAddr) -> Result<Self::DstStream> {
-        let socket = match TcpSocket::new_v4() {
+        let socket = match if nat_dst.is_ipv4() {
+            TcpSocket::new_v4()
+        } else {
+            TcpSocket::new_v6()
+        } {
             Ok(s) => s,
             Err(e) => {
-                eprintln!("create v4 socket failed: {:?}", e);
+                eprintln!("create socket for {:?} failed: {:?}", nat_dst, e);
                 return Err(e.into());
             }
         };
@@ -104,16 +139,18 @@ impl NatDstConnector for NatDstTcpConnector {
         cidr_set: &CidrSet,
         global_ctx: &GlobalCtx,
         hdr: &PeerManagerHeader,
-        ipv4: &Ipv4Packet,
-        real_dst_ip: &mut Ipv4Addr,
+        dst_ip: IpAddr,
+        real_dst_ip: &mut IpAddr,
     ) -> bool {
         let is_exit_node = hdr.is_exit_node();
+        let is_to_self = match dst_ip {
+            IpAddr::V4(ip) => Some(ip) == global_ctx.get_ipv4().as_ref().map(Ipv4Inet::address),
+            IpAddr::V6(ip) => Some(ip) == global_ctx.get_ipv6().as_ref().map(Ipv6Inet::address),
+        };
 
-        if !(cidr_set.contains_v4(ipv4.get_destination(), real_dst_ip)
+        if !(cidr_set.contains_ip(dst_ip, real_dst_ip)
             || is_exit_node
-            || global_ctx.no_tun()
-                && Some(ipv4.get_destination())
-                    == global_ctx.get_ipv4().as_ref().map(Ipv4Inet::address))
+            || global_ctx.no_tun() && is_to_self)
         {
             return false;
         }
@@ -284,6 +321,8 @@ impl ProxyTcpListener {
         match self {
             Self::KernelTcpListener(listener) => {
                 let (stream, addr) = listener.accept().await?;
+                // the dual stack listener reports ipv4 peers as ipv4-mapped ipv6 addresses
+                let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
                 Ok((ProxyTcpStream::KernelTcpStream(stream), addr))
             }
             #[cfg(feature = "smoltcp")]
@@ -351,6 +390,10 @@ impl<C: NatDstConnector> PeerPacketFilter for TcpProxy<C> {
 #[async_trait::async_trait]
 impl<C: NatDstConnector> NicPacketFilter for TcpProxy<C> {
     async fn try_process_packet_from_nic(&self, zc_packet: &mut ZCPacket) -> bool {
+        if zc_packet.payload().first().map(|b| b >> 4) == Some(6) {
+            return self.try_process_ipv6_packet_from_nic(zc_packet);
+        }
+
         let Some(my_ipv4_inet) = self.get_local_inet() else {
             return false;
         };
@@ -475,6 +518,91 @@ impl<C: NatDstConnector> TcpProxy<C> {
         ip_packet.set_checksum(pnet::packet::ipv4::checksum(&ip_packet.to_immutable()));
     }
 
+    fn update_tcp_packet_checksum_v6(
+        tcp_packet: &mut MutableTcpPacket,
+        ipv6_src: &Ipv6Addr,
+        ipv6_dst: &Ipv6Addr,
+    ) {
+        tcp_packet.set_checksum(ipv6_checksum(
+            &tcp_packet.to_immutable(),
+            ipv6_src,
+            ipv6_dst,
+        ));
+    }
+
+    fn try_process_ipv6_packet_from_nic(&self, zc_packet: &mut ZCPacket) -> bool {
+        let Some(my_ipv6_inet) = self.get_local_inet_v6() else {
+            return false;
+        };
+        let my_ipv6 = my_ipv6_inet.address();
+
+        let Some(ip_packet) = Ipv6Packet::new(zc_packet.payload()) else {
+            return false;
+        };
+        if ip_packet.get_source() != my_ipv6
+            || ip_packet.get_next_header() != IpNextHeaderProtocols::Tcp
+        {
+            return false;
+        }
+
+        let Some(tcp_packet) = TcpPacket::new(ip_packet.payload()) else {
+            return false;
+        };
+        if tcp_packet.get_source() != self.get_local_port() {
+            return false;
+        }
+
+        let mut dst_addr = SocketAddr::new(
+            ip_packet.get_destination().into(),
+            tcp_packet.get_destination(),
+        );
+        let mut need_transform_dst = false;
+        if dst_addr.ip() == Self::get_fake_local_ipv6(&my_ipv6_inet) {
+            dst_addr.set_ip(IpAddr::V6(my_ipv6));
+            need_transform_dst = true;
+        }
+
+        tracing::trace!(dst_addr = ?dst_addr, "ipv6 tcp packet try find entry");
+        let entry = if let Some(entry) = self.addr_conn_map.get(&dst_addr) {
+            entry
+        } else {
+            let Some(syn_entry) = self.syn_map.get(&dst_addr) else {
+                return false;
+            };
+            syn_entry
+        };
+        let nat_entry = entry.clone();
+        drop(entry);
+
+        let IpAddr::V6(ip) = nat_entry.mapped_dst.ip() else {
+            tracing::error!(?nat_entry, "v6 nat entry mapped dst is not v6");
+            return false;
+        };
+
+        zc_packet
+            .mut_peer_manager_header()
+            .unwrap()
+            .set_no_proxy(true);
+        if need_transform_dst {
+            zc_packet.mut_peer_manager_header().unwrap().to_peer_id = self.get_my_peer_id().into();
+        }
+
+        let mut ip_packet = MutableIpv6Packet::new(zc_packet.mut_payload()).unwrap();
+        ip_packet.set_source(ip);
+        if need_transform_dst {
+            ip_packet.set_destination(my_ipv6);
+        }
+        let dst = ip_packet.get_destination();
+
+        let mut tcp_packet = MutableTcpPacket::new(ip_packet.payload_mut()).unwrap();
+        tcp_packet.set_source(nat_entry.real_dst.port());
+        Self::update_tcp_packet_checksum_v6(&mut tcp_packet, &ip, &dst);
+
+        tracing::trace!(dst_addr = ?dst_addr, nat_entry = ?nat_entry, "ipv6 tcp packet after modified");
+
+        true
+    }
+
     pub async fn start(self: &Arc<Self>, add_pipeline: bool) -> Result<()> {
         self.run_syn_map_cleaner().await?;
         self.run_listener().await?;
@@ -598,7 +726,18 @@ impl<C: NatDstConnector> TcpProxy<C> {
             let listen_addr = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0);
             let net_ns = self.global_ctx.net_ns.clone();
             let tcp_listener = net_ns
-                .run_async(|| async { TcpListener::bind(&listen_addr).await })
+                .run_async(|| async {
+                    match Self::bind_dual_stack_listener() {
+                        Ok(listener) => Ok(listener),
+                        Err(e) => {
+                            tracing::warn!(
+                                ?e,
+                                "bind dual stack tcp proxy listener failed, fallback to ipv4 only"
+                            );
+                            TcpListener::bind(&listen_addr).await
+                        }
+                    }
+                })
                 .await?;
             self.local_port.store(
                 tcp_listener.local_addr()?.port(),
@@ -612,6 +751,19 @@ impl<C: NatDstConnector> TcpProxy<C> {
         }
     }
 
+    fn bind_dual_stack_listener() -> std::io::Result<TcpListener> {
+        let socket = socket2::Socket::new(
+            socket2::Domain::IPV6,
+            socket2::Type::STREAM,
+            Some(socket2::Protocol::TCP),
+        )?;
+        socket.set_only_v6(false)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0).into())?;
+        socket.listen(1024)?;
+        TcpListener::from_std(socket.into())
+    }
+
     async fn run_listener(&self) -> Result<()> {
         // bind on both v4 & v6
         let mut tcp_listener = self.get_proxy_listener().await?;
@@ -643,6 +795,12 @@ impl<C: NatDstConnector> TcpProxy<C> {
                     socket_addr.set_ip(IpAddr::V4(my_ip));
                 }
 
+                if let Some(my_ipv6_inet) = global_ctx.get_ipv6() {
+                    if socket_addr.ip() == Self::get_fake_local_ipv6(&my_ipv6_inet) {
+                        socket_addr.set_ip(IpAddr::V6(my_ipv6_inet.address()));
+                    }
+                }
+
                 let Some(entry) = syn_map.get(&socket_addr) else {
                     tracing::error!(
                         ?my_ip,
@@ -718,6 +876,10 @@ impl<C: NatDstConnector> TcpProxy<C> {
             format!("127.0.0.1:{}", nat_entry.real_dst.port())
                 .parse()
                 .unwrap()
+        } else if Some(nat_entry.real_dst.ip())
+            == global_ctx.get_ipv6().map(|ip| IpAddr::V6(ip.address()))
+        {
+            SocketAddr::new(Ipv6Addr::LOCALHOST.into(), nat_entry.real_dst.port())
         } else {
             nat_entry.real_dst
         };
@@ -797,6 +959,15 @@ impl<C: NatDstConnector> TcpProxy<C> {
         }
     }
 
+    pub fn get_local_inet_v6(&self) -> Option<Ipv6Inet> {
+        // smoltcp stack is ipv4 only, ipv6 proxy always goes through the kernel stack
+        if self.is_smoltcp_enabled() {
+            None
+        } else {
+            self.global_ctx.get_ipv6()
+        }
+    }
+
     pub fn get_global_ctx(&self) -> &ArcGlobalCtx {
         &self.global_ctx
     }
@@ -810,6 +981,10 @@ impl<C: NatDstConnector> TcpProxy<C> {
         local_ip.first_address()
     }
 
+    pub fn get_fake_local_ipv6(local_ip: &Ipv6Inet) -> Ipv6Addr {
+        local_ip.first_address()
+    }
+
     async fn try_handle_peer_packet(&self, packet: &mut ZCPacket) -> Option<()> {
         if !self
             .connector
@@ -818,14 +993,18 @@ impl<C: NatDstConnector> TcpProxy<C> {
             return None;
         }
 
-        let ipv4_inet = self.get_local_inet()?;
-        let ipv4_addr = ipv4_inet.address();
         let hdr = packet.peer_manager_header().unwrap().clone();
 
         if hdr.packet_type != PacketType::Data as u8 || hdr.is_no_proxy() {
             return None;
         };
 
+        if packet.payload().first().map(|b| b >> 4) == Some(6) {
+            return self.try_handle_ipv6_peer_packet(packet, &hdr).await;
+        }
+
+        let ipv4_inet = self.get_local_inet()?;
+        let ipv4_addr = ipv4_inet.address();
         let payload_bytes = packet.mut_payload();
 
         let ipv4 = Ipv4Packet::new(payload_bytes)?;
@@ -833,13 +1012,13 @@ impl<C: NatDstConnector> TcpProxy<C> {
             return None;
         }
 
-        let mut real_dst_ip = ipv4.get_destination();
+        let mut real_dst_ip = IpAddr::V4(ipv4.get_destination());
 
         if !self.connector.check_packet_from_peer(
             &self.cidr_set,
             &self.global_ctx,
             &hdr,
-            &ipv4,
+            ipv4.get_destination().into(),
             &mut real_dst_ip,
         ) {
             return None;
@@ -860,7 +1039,7 @@ impl<C: NatDstConnector> TcpProxy<C> {
             let dest_ip = ip_packet.get_destination();
             let dest_port = tcp_packet.get_destination();
             let mapped_dst = SocketAddr::V4(SocketAddrV4::new(dest_ip, dest_port));
-            let real_dst = SocketAddr::V4(SocketAddrV4::new(real_dst_ip, dest_port));
+            let real_dst = SocketAddr::new(real_dst_ip, dest_port);
 
             let old_val = self
                 .syn_map
@@ -903,6 +1082,69 @@ impl<C: NatDstConnector> TcpProxy<C> {
         Some(())
     }
 
+    async fn try_handle_ipv6_peer_packet(
+        &self,
+        packet: &mut ZCPacket,
+        hdr: &PeerManagerHeader,
+    ) -> Option<()> {
+        let ipv6_inet = self.get_local_inet_v6()?;
+        let ipv6_addr = ipv6_inet.address();
+
+        let payload_bytes = packet.mut_payload();
+        let ipv6 = Ipv6Packet::new(payload_bytes)?;
+        if ipv6.get_next_header() != IpNextHeaderProtocols::Tcp {
+            return None;
+        }
+
+        let mut real_dst_ip = IpAddr::V6(ipv6.get_destination());
+        if !self.connector.check_packet_from_peer(
+            &self.cidr_set,
+            &self.global_ctx,
+            hdr,
+            ipv6.get_destination().into(),
+            &mut real_dst_ip,
+        ) {
+            return None;
+        }
+
+        tracing::trace!(ipv6 = ?ipv6, cidr_set = ?self.cidr_set, "proxy ipv6 tcp packet received");
+
+        let tcp_packet = TcpPacket::new(ipv6.payload())?;
+        let source_ip = ipv6.get_source();
+        let src = SocketAddr::new(source_ip.into(), tcp_packet.get_source());
+
+        let is_tcp_syn = tcp_packet.get_flags() & pnet::packet::tcp::TcpFlags::SYN != 0;
+        let is_tcp_ack = tcp_packet.get_flags() & pnet::packet::tcp::TcpFlags::ACK != 0;
+        if is_tcp_syn && !is_tcp_ack {
+            let dest_port = tcp_packet.get_destination();
+            let mapped_dst = SocketAddr::new(ipv6.get_destination().into(), dest_port);
+            let real_dst = SocketAddr::new(real_dst_ip, dest_port);
+
+            let old_val = self
+                .syn_map
+                .insert(src, Arc::new(NatDstEntry::new(src, real_dst, mapped_dst)));
+            tracing::info!(src = ?src, ?real_dst, ?mapped_dst, old_entry = ?old_val, "ipv6 tcp syn received");
+        } else if !self.addr_conn_map.contains_key(&src) && !self.syn_map.contains_key(&src) {
+            return None;
+        }
+
+        let mut ip_packet = MutableIpv6Packet::new(payload_bytes).unwrap();
+        if source_ip == ipv6_addr {
+            // modify the source so the response packet can be handled by tun device
+            ip_packet.set_source(Self::get_fake_local_ipv6(&ipv6_inet));
+        }
+        ip_packet.set_destination(ipv6_addr);
+        let source = ip_packet.get_source();
+
+        let mut tcp_packet = MutableTcpPacket::new(ip_packet.payload_mut()).unwrap();
+        tcp_packet.set_destination(self.get_local_port());
+        Self::update_tcp_packet_checksum_v6(&mut tcp_packet, &source, &ipv6_addr);
+
+        tracing::trace!(?source, ?ipv6_addr, "ipv6 tcp packet after modified");
+
+        Some(())
+    }
+
     pub fn get_peer_manager(&self) -> &Arc<PeerManager> {
         &self.peer_manager
     }
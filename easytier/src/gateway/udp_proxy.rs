@@ -1,16 +1,17 @@
 use std::{
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
     sync::{atomic::AtomicBool, Arc},
     time::Duration,
 };
 
 use bytes::{BufMut, BytesMut};
-use cidr::Ipv4Inet;
+use cidr::{Ipv4Inet, Ipv6Inet};
 use crossbeam::atomic::AtomicCell;
 use dashmap::DashMap;
 use pnet::packet::{
     ip::IpNextHeaderProtocols,
     ipv4::Ipv4Packet,
+    ipv6::Ipv6Packet,
     udp::{self, MutableUdpPacket},
     Packet,
 };
@@ -26,7 +27,10 @@ use tracing::Level;
 
 use crate::{
     common::{error::Error, global_ctx::ArcGlobalCtx, scoped_task::ScopedTask, PeerId},
-    gateway::ip_reassembler::{compose_ipv4_packet, ComposeIpv4PacketArgs},
+    gateway::ip_reassembler::{
+        compose_ipv4_packet, compose_ipv6_packet, ComposeIpv4PacketArgs, ComposeIpv6PacketArgs,
+        IPV6_PACKET_HEADER_RESERVE,
+    },
     peers::{peer_manager::PeerManager, PeerPacketFilter},
     tunnel::{
         common::{reserve_buf, setup_sokcet2},
@@ -57,12 +61,13 @@ impl UdpNatEntry {
     #[tracing::instrument(err(level = Level::WARN))]
     fn new(src_peer_id: PeerId, my_peer_id: PeerId, src_socket: SocketAddr) -> Result<Self, Error> {
         // TODO: try use src port, so we will be ip restricted nat type
-        let socket2_socket = socket2::Socket::new(
-            socket2::Domain::IPV4,
-            socket2::Type::DGRAM,
-            Some(socket2::Protocol::UDP),
-        )?;
-        let dst_socket_addr = "0.0.0.0:0".parse().unwrap();
+        let (domain, dst_socket_addr) = if src_socket.is_ipv4() {
+            (socket2::Domain::IPV4, "0.0.0.0:0".parse().unwrap())
+        } else {
+            (socket2::Domain::IPV6, "[::]:0".parse().unwrap())
+        };
+        let socket2_socket =
+            socket2::Socket::new(domain, socket2::Type::DGRAM, Some(socket2::Protocol::UDP))?;
         setup_sokcet2(&socket2_socket, &dst_socket_addr)?;
         let socket = UdpSocket::from_std(socket2_socket.into())?;
 
@@ -119,36 +124,94 @@ impl UdpNatEntry {
                 payload_mtu,
                 ip_id,
             },
-            |buf| {
-                let mut p = ZCPacket::new_with_payload(buf);
-                p.fill_peer_manager_hdr(self.my_peer_id, self.src_peer_id, PacketType::Data as u8);
-                p.mut_peer_manager_header().unwrap().set_no_proxy(true);
-
-                match packet_sender.try_send(p) {
-                    Err(TrySendError::Closed(e)) => {
-                        tracing::error!("send icmp packet to peer failed: {:?}, may exiting..", e);
-                        Err(Error::Unknown)
-                    }
-                    _ => Ok(()),
-                }
+            |buf| self.send_composed_packet(packet_sender, buf),
+        )?;
+
+        Ok(())
+    }
+
+    async fn compose_ipv6_packet(
+        self: &Arc<Self>,
+        packet_sender: &mut Sender<ZCPacket>,
+        buf: &mut [u8],
+        src_v6: &SocketAddrV6,
+        payload_len: usize,
+        payload_mtu: usize,
+        ip_id: u32,
+    ) -> Result<(), Error> {
+        let SocketAddr::V6(nat_src_v6) = self.src_socket else {
+            return Err(Error::Unknown);
+        };
+
+        // udp payload is in buf[IPV6_PACKET_HEADER_RESERVE + 8..]
+        let udp_start = IPV6_PACKET_HEADER_RESERVE;
+        let mut udp_packet =
+            MutableUdpPacket::new(&mut buf[udp_start..udp_start + 8 + payload_len]).unwrap();
+        udp_packet.set_source(src_v6.port());
+        udp_packet.set_destination(self.src_socket.port());
+        udp_packet.set_length(payload_len as u16 + 8);
+        udp_packet.set_checksum(udp::ipv6_checksum(
+            &udp_packet.to_immutable(),
+            src_v6.ip(),
+            nat_src_v6.ip(),
+        ));
+
+        compose_ipv6_packet(
+            ComposeIpv6PacketArgs {
+                buf: &mut buf[..],
+                src_v6: src_v6.ip(),
+                dst_v6: nat_src_v6.ip(),
+                next_protocol: IpNextHeaderProtocols::Udp,
+                payload_len: payload_len + 8, // include udp header
+                payload_mtu,
+                ip_id,
             },
+            |buf| self.send_composed_packet(packet_sender, buf),
         )?;
 
         Ok(())
     }
 
+    fn send_composed_packet(
+        &self,
+        packet_sender: &Sender<ZCPacket>,
+        buf: &[u8],
+    ) -> Result<(), Error> {
+        let mut p = ZCPacket::new_with_payload(buf);
+        p.fill_peer_manager_hdr(self.my_peer_id, self.src_peer_id, PacketType::Data as u8);
+        p.mut_peer_manager_header().unwrap().set_no_proxy(true);
+
+        match packet_sender.try_send(p) {
+            Err(TrySendError::Closed(e)) => {
+                tracing::error!("send udp packet to peer failed: {:?}, may exiting..", e);
+                Err(Error::Unknown)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // room reserved before the udp payload for the ip and udp headers
+    fn header_room(&self) -> usize {
+        if self.src_socket.is_ipv4() {
+            28
+        } else {
+            IPV6_PACKET_HEADER_RESERVE + 8
+        }
+    }
+
     async fn forward_task(
         self: Arc<Self>,
         mut packet_sender: Sender<ZCPacket>,
-        virtual_ipv4: Ipv4Addr,
-        real_ipv4: Ipv4Addr,
-        mapped_ipv4: Ipv4Addr,
+        virtual_ip: Option<IpAddr>,
+        real_ip: IpAddr,
+        mapped_ip: IpAddr,
     ) {
         let (s, mut r) = tachyonix::channel(128);
 
         let self_clone = self.clone();
         let recv_task = ScopedTask::from(tokio::spawn(async move {
             let mut cur_buf = BytesMut::new();
+            let header_room = self_clone.header_room();
             loop {
                 if self_clone
                     .stopped
@@ -157,10 +220,14 @@ impl UdpNatEntry {
                     break;
                 }
 
-                reserve_buf(&mut cur_buf, 64 * 1024 + 28, 128 * 1024 + 28);
+                reserve_buf(
+                    &mut cur_buf,
+                    64 * 1024 + header_room,
+                    128 * 1024 + header_room,
+                );
                 assert_eq!(cur_buf.len(), 0);
                 unsafe {
-                    cur_buf.advance_mut(28);
+                    cur_buf.advance_mut(header_room);
                 }
 
                 let (len, src_socket) = match timeout(
@@ -189,33 +256,50 @@ impl UdpNatEntry {
 
         let self_clone = self.clone();
         let send_task = ScopedTask::from(tokio::spawn(async move {
-            let mut ip_id = 1;
+            let mut ip_id: u32 = 1;
             while let Ok((mut packet, len, src_socket)) = r.recv().await {
-                let SocketAddr::V4(mut src_v4) = src_socket else {
-                    continue;
-                };
+                let mut src_socket =
+                    SocketAddr::new(src_socket.ip().to_canonical(), src_socket.port());
 
                 self_clone.mark_active();
 
-                if src_v4.ip().is_loopback() {
-                    src_v4.set_ip(virtual_ipv4);
+                if src_socket.ip().is_loopback() {
+                    if let Some(virtual_ip) = virtual_ip {
+                        src_socket.set_ip(virtual_ip);
+                    }
                 }
 
-                if *src_v4.ip() == real_ipv4 {
-                    src_v4.set_ip(mapped_ipv4);
+                if src_socket.ip() == real_ip {
+                    src_socket.set_ip(mapped_ip);
                 }
 
-                let Ok(_) = Self::compose_ipv4_packet(
-                    &self_clone,
-                    &mut packet_sender,
-                    &mut packet,
-                    &src_v4,
-                    len,
-                    1280,
-                    ip_id,
-                )
-                .await
-                else {
+                let ret = match src_socket {
+                    SocketAddr::V4(src_v4) => {
+                        Self::compose_ipv4_packet(
+                            &self_clone,
+                            &mut packet_sender,
+                            &mut packet,
+                            &src_v4,
+                            len,
+                            1280,
+                            ip_id as u16,
+                        )
+                        .await
+                    }
+                    SocketAddr::V6(src_v6) => {
+                        Self::compose_ipv6_packet(
+                            &self_clone,
+                            &mut packet_sender,
+                            &mut packet,
+                            &src_v6,
+                            len,
+                            1280,
+                            ip_id,
+                        )
+                        .await
+                    }
+                };
+                let Ok(_) = ret else {
                     break;
                 };
                 ip_id = ip_id.wrapping_add(1);
@@ -262,13 +346,18 @@ impl UdpProxy {
             return None;
         }
 
-        let _ = self.global_ctx.get_ipv4()?;
         let hdr = packet.peer_manager_header().unwrap();
-        let is_exit_node = hdr.is_exit_node();
         if hdr.packet_type != PacketType::Data as u8 || hdr.is_no_proxy() {
             return None;
         };
 
+        if packet.payload().first().map(|b| b >> 4) == Some(6) {
+            return self.try_handle_ipv6_packet(packet).await;
+        }
+
+        let _ = self.global_ctx.get_ipv4()?;
+        let is_exit_node = hdr.is_exit_node();
+
         let ipv4 = Ipv4Packet::new(packet.payload())?;
         if ipv4.get_version() != 4 || ipv4.get_next_level_protocol() != IpNextHeaderProtocols::Udp {
             return None;
@@ -305,14 +394,80 @@ impl UdpProxy {
             "udp nat packet request received"
         );
 
+        self.forward_udp_packet(
+            packet,
+            ipv4.get_source().into(),
+            ipv4.get_destination().into(),
+            real_dst_ip.into(),
+            &udp_packet,
+        )
+        .await
+    }
+
+    async fn try_handle_ipv6_packet(&self, packet: &ZCPacket) -> Option<()> {
+        let hdr = packet.peer_manager_header().unwrap();
+        let is_exit_node = hdr.is_exit_node();
+
+        // fragmented ipv6 packets carry a fragment header and are not proxied.
+        let ipv6 = Ipv6Packet::new(packet.payload())?;
+        if ipv6.get_next_header() != IpNextHeaderProtocols::Udp {
+            return None;
+        }
+
+        let mut real_dst_ip = ipv6.get_destination();
+
+        if !(self
+            .cidr_set
+            .contains_v6(ipv6.get_destination(), &mut real_dst_ip)
+            || is_exit_node
+            || self.global_ctx.no_tun()
+                && Some(ipv6.get_destination())
+                    == self.global_ctx.get_ipv6().as_ref().map(Ipv6Inet::address))
+        {
+            return None;
+        }
+
+        let udp_packet = udp::UdpPacket::new(ipv6.payload())?;
+
+        tracing::trace!(
+            ?packet,
+            ?ipv6,
+            ?udp_packet,
+            "ipv6 udp nat packet request received"
+        );
+
+        self.forward_udp_packet(
+            packet,
+            ipv6.get_source().into(),
+            ipv6.get_destination().into(),
+            real_dst_ip.into(),
+            &udp_packet,
+        )
+        .await
+    }
+
+    async fn forward_udp_packet(
+        &self,
+        packet: &ZCPacket,
+        src_ip: IpAddr,
+        dst_ip: IpAddr,
+        real_dst_ip: IpAddr,
+        udp_packet: &udp::UdpPacket<'_>,
+    ) -> Option<()> {
+        let hdr = packet.peer_manager_header().unwrap();
+        let virtual_ip = match dst_ip {
+            IpAddr::V4(_) => self.global_ctx.get_ipv4().map(|x| x.address().into()),
+            IpAddr::V6(_) => self.global_ctx.get_ipv6().map(|x| x.address().into()),
+        };
+
         let nat_key = UdpNatKey {
-            src_socket: SocketAddr::new(ipv4.get_source().into(), udp_packet.get_source()),
+            src_socket: SocketAddr::new(src_ip, udp_packet.get_source()),
         };
         let nat_entry = self
             .nat_table
             .entry(nat_key)
             .or_try_insert_with::<Error>(|| {
-                tracing::info!(?packet, ?ipv4, ?udp_packet, "udp nat table entry created");
+                tracing::info!(?packet, ?udp_packet, "udp nat table entry created");
                 let _g = self.global_ctx.net_ns.guard();
                 Ok(Arc::new(UdpNatEntry::new(
                     hdr.from_peer_id.get(),
@@ -331,23 +486,23 @@ impl UdpProxy {
                 .replace(tokio::spawn(UdpNatEntry::forward_task(
                     nat_entry.clone(),
                     self.sender.clone(),
-                    self.global_ctx.get_ipv4().map(|x| x.address())?,
+                    virtual_ip,
                     real_dst_ip,
-                    ipv4.get_destination(),
+                    dst_ip,
                 )));
         }
 
         nat_entry.mark_active();
 
         // TODO: should it be async.
-        let dst_socket = if Some(ipv4.get_destination())
-            == self.global_ctx.get_ipv4().as_ref().map(Ipv4Inet::address)
-        {
-            format!("127.0.0.1:{}", udp_packet.get_destination())
-                .parse()
-                .unwrap()
+        let dst_socket = if Some(dst_ip) == virtual_ip {
+            let loopback: IpAddr = match dst_ip {
+                IpAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                IpAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            };
+            SocketAddr::new(loopback, udp_packet.get_destination())
         } else {
-            SocketAddr::new(real_dst_ip.into(), udp_packet.get_destination())
+            SocketAddr::new(real_dst_ip, udp_packet.get_destination())
         };
 
         let send_ret = {
@@ -0,0 +1,215 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::Ipv4Addr,
+    path::PathBuf,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use cidr::Ipv4Inet;
+use serde::{Deserialize, Serialize};
+
+use crate::common::config::DhcpReservation;
+
+// serializes read-modify-write of the lease file between instances
+static LEASE_FILE_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+struct DhcpLease {
+    ipv4: Ipv4Inet,
+    updated_at: u64,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct DhcpLeases {
+    leases: BTreeMap<uuid::Uuid, DhcpLease>,
+}
+
+/// Last dhcp address of each instance, kept on disk so a node gets the same
+/// address back after a restart.
+#[derive(Debug, Clone)]
+pub struct DhcpLeaseStore {
+    path: Option<PathBuf>,
+}
+
+impl DhcpLeaseStore {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        let path = path.or_else(|| crate::common::default_state_file("dhcp_leases.json"));
+        Self { path }
+    }
+
+    fn read(&self) -> DhcpLeases {
+        self.path
+            .as_ref()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .and_then(|x| serde_json::from_str(&x).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn load(&self, instance_id: &uuid::Uuid) -> Option<Ipv4Inet> {
+        let _guard = LEASE_FILE_LOCK.lock().unwrap();
+        self.read().leases.get(instance_id).map(|x| x.ipv4)
+    }
+
+    pub fn save(&self, instance_id: uuid::Uuid, ipv4: Ipv4Inet) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let _guard = LEASE_FILE_LOCK.lock().unwrap();
+        let mut leases = self.read();
+        if leases.leases.get(&instance_id).map(|x| x.ipv4) == Some(ipv4) {
+            return Ok(());
+        }
+        leases.leases.insert(
+            instance_id,
+            DhcpLease {
+                ipv4,
+                updated_at: SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+            },
+        );
+
+        // write to a temp file first so a crash never leaves a broken file
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, serde_json::to_string_pretty(&leases)?)
+            .with_context(|| format!("failed to write dhcp lease file: {:?}", tmp_path))?;
+        std::fs::rename(&tmp_path, path)
+            .with_context(|| format!("failed to write dhcp lease file: {:?}", path))?;
+        Ok(())
+    }
+}
+
+/// Split the reservations into the address reserved for this node and the
+/// addresses reserved for others.
+pub fn split_reservations(
+    reservations: &[DhcpReservation],
+    hostname: &str,
+    instance_id: &uuid::Uuid,
+) -> (Option<Ipv4Addr>, HashSet<Ipv4Addr>) {
+    let mut mine = None;
+    let mut others = HashSet::new();
+    for r in reservations {
+        let is_mine =
+            r.instance_id.as_ref() == Some(instance_id) || r.hostname.as_deref() == Some(hostname);
+        if is_mine && mine.is_none() {
+            mine = Some(r.ipv4);
+        } else if !is_mine {
+            others.insert(r.ipv4);
+        }
+    }
+    (mine, others)
+}
+
+/// Pick an address in the subnet of `dhcp_inet`. The first usable address of
+/// `preferred` wins, otherwise the lowest free address not reserved for
+/// another node is used.
+pub fn select_dhcp_ipv4(
+    dhcp_inet: &Ipv4Inet,
+    used: &HashSet<Ipv4Inet>,
+    preferred: &[Ipv4Addr],
+    reserved: &HashSet<Ipv4Addr>,
+) -> Option<Ipv4Inet> {
+    let network = dhcp_inet.network();
+    let usable = |ip: &Ipv4Inet| {
+        ip.address() != dhcp_inet.first_address()
+            && ip.address() != dhcp_inet.last_address()
+            && !used.contains(ip)
+    };
+
+    preferred
+        .iter()
+        .filter(|addr| network.contains(addr))
+        .filter_map(|addr| Ipv4Inet::new(*addr, network.network_length()).ok())
+        .find(|ip| usable(ip))
+        .or_else(|| {
+            network
+                .iter()
+                .find(|ip| usable(ip) && !reserved.contains(&ip.address()))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inet(s: &str) -> Ipv4Inet {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn select_dhcp_ipv4_prefers_lease() {
+        let dhcp_inet = inet("10.126.126.1/24");
+        let used = HashSet::from([inet("10.126.126.1/24"), inet("10.126.126.2/24")]);
+        let reserved = HashSet::from(["10.126.126.3".parse().unwrap()]);
+
+        // lowest free address skipping the reserved one
+        assert_eq!(
+            Some(inet("10.126.126.4/24")),
+            select_dhcp_ipv4(&dhcp_inet, &used, &[], &reserved)
+        );
+
+        // the previous lease is used if free
+        let lease = "10.126.126.20".parse().unwrap();
+        assert_eq!(
+            Some(inet("10.126.126.20/24")),
+            select_dhcp_ipv4(&dhcp_inet, &used, &[lease], &reserved)
+        );
+
+        // a taken or out of subnet lease is ignored
+        let taken = "10.126.126.2".parse().unwrap();
+        let other_net = "10.1.1.20".parse().unwrap();
+        assert_eq!(
+            Some(inet("10.126.126.4/24")),
+            select_dhcp_ipv4(&dhcp_inet, &used, &[taken, other_net], &reserved)
+        );
+    }
+
+    #[test]
+    fn dhcp_reservations() {
+        let id = uuid::Uuid::new_v4();
+        let reservations = vec![
+            DhcpReservation {
+                ipv4: "10.126.126.10".parse().unwrap(),
+                hostname: Some("nas".to_string()),
+                instance_id: None,
+            },
+            DhcpReservation {
+                ipv4: "10.126.126.11".parse().unwrap(),
+                hostname: None,
+                instance_id: Some(id),
+            },
+        ];
+
+        let (mine, others) = split_reservations(&reservations, "laptop", &id);
+        assert_eq!(Some("10.126.126.11".parse().unwrap()), mine);
+        assert_eq!(HashSet::from(["10.126.126.10".parse().unwrap()]), others);
+
+        let (mine, others) = split_reservations(&reservations, "nas", &uuid::Uuid::new_v4());
+        assert_eq!(Some("10.126.126.10".parse().unwrap()), mine);
+        assert_eq!(HashSet::from(["10.126.126.11".parse().unwrap()]), others);
+    }
+
+    #[test]
+    fn dhcp_lease_store() {
+        let path =
+            std::env::temp_dir().join(format!("et_dhcp_leases_{}.json", uuid::Uuid::new_v4()));
+        let store = DhcpLeaseStore::new(Some(path.clone()));
+        let id = uuid::Uuid::new_v4();
+
+        assert_eq!(None, store.load(&id));
+        store.save(id, inet("10.126.126.5/24")).unwrap();
+        store
+            .save(uuid::Uuid::new_v4(), inet("10.126.126.6/24"))
+            .unwrap();
+        assert_eq!(Some(inet("10.126.126.5/24")), store.load(&id));
+
+        store.save(id, inet("10.126.126.7/24")).unwrap();
+        let store = DhcpLeaseStore::new(Some(path.clone()));
+        assert_eq!(Some(inet("10.126.126.7/24")), store.load(&id));
+
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -18,6 +18,7 @@ use crate::common::scoped_task::ScopedTask;
 use crate::common::PeerId;
 use crate::connector::direct::DirectConnectorManager;
 use crate::connector::manual::{ConnectorManagerRpcService, ManualConnectorManager};
+use crate::connector::tcp_hole_punch::TcpHolePunchConnector;
 use crate::connector::udp_hole_punch::UdpHolePunchConnector;
 use crate::gateway::icmp_proxy::IcmpProxy;
 use crate::gateway::kcp_proxy::{KcpProxyDst, KcpProxyDstRpcService, KcpProxySrc};
@@ -47,9 +48,11 @@ use crate::proto::rpc_types::controller::BaseController;
 use crate::tunnel::tcp::TcpTunnelListener;
 use crate::vpn_portal::{self, VpnPortal};
 
+use super::dhcp::{select_dhcp_ipv4, split_reservations, DhcpLeaseStore};
 use super::dns_server::runner::DnsRunner;
 use super::dns_server::MAGIC_DNS_FAKE_IP;
 use super::listeners::ListenerManager;
+use super::port_mapping::PortMappingManager;
 
 #[cfg(feature = "socks5")]
 use crate::gateway::socks5::Socks5Server;
@@ -236,6 +239,8 @@ pub struct Instance {
     conn_manager: Arc<ManualConnectorManager>,
     direct_conn_manager: Arc<DirectConnectorManager>,
     udp_hole_puncher: Arc<Mutex<UdpHolePunchConnector>>,
+    tcp_hole_puncher: Arc<Mutex<TcpHolePunchConnector>>,
+    port_mapping: Arc<PortMappingManager>,
 
     ip_proxy: Option<IpProxy>,
 
@@ -293,6 +298,8 @@ impl Instance {
         direct_conn_manager.run();
 
         let udp_hole_puncher = UdpHolePunchConnector::new(peer_manager.clone());
+        let tcp_hole_puncher = TcpHolePunchConnector::new(peer_manager.clone());
+        let port_mapping = PortMappingManager::new(global_ctx.clone());
 
         let peer_center = Arc::new(PeerCenterInstance::new(peer_manager.clone()));
 
@@ -322,6 +329,8 @@ impl Instance {
             conn_manager,
             direct_conn_manager: Arc::new(direct_conn_manager),
             udp_hole_puncher: Arc::new(Mutex::new(udp_hole_puncher)),
+            tcp_hole_puncher: Arc::new(Mutex::new(tcp_hole_puncher)),
+            port_mapping,
 
             ip_proxy: None,
             kcp_proxy_src: None,
@@ -424,6 +433,20 @@ impl Instance {
         tokio::spawn(async move {
             let default_ipv4_addr = Ipv4Inet::new(Ipv4Addr::new(10, 126, 126, 0), 24).unwrap();
             let mut current_dhcp_ip: Option<Ipv4Inet> = None;
+
+            let instance_id = global_ctx_c.get_id();
+            let lease_store = DhcpLeaseStore::new(global_ctx_c.config.get_dhcp_lease_file());
+            let last_lease = lease_store.load(&instance_id);
+            let (reserved_ip, reserved_by_others) = split_reservations(
+                &global_ctx_c.config.get_dhcp_reservations(),
+                &global_ctx_c.get_hostname(),
+                &instance_id,
+            );
+            let save_lease = |ip: Ipv4Inet| {
+                if let Err(e) = lease_store.save(instance_id, ip) {
+                    tracing::warn!(?e, ?ip, "save dhcp lease failed");
+                }
+            };
             let mut next_sleep_time = 0;
             loop {
                 tokio::time::sleep(std::time::Duration::from_secs(next_sleep_time)).await;
@@ -452,19 +475,15 @@ impl Instance {
                 }
 
                 let dhcp_inet = used_ipv4.iter().next().unwrap_or(&default_ipv4_addr);
-                // if old ip is already in this subnet and not conflicted, use it
-                if let Some(ip) = current_dhcp_ip {
-                    if ip.network() == dhcp_inet.network() && !used_ipv4.contains(&ip) {
-                        continue;
-                    }
-                }
-
-                // find an available ip in the subnet
-                let candidate_ipv4_addr = dhcp_inet.network().iter().find(|ip| {
-                    ip.address() != dhcp_inet.first_address()
-                        && ip.address() != dhcp_inet.last_address()
-                        && !used_ipv4.contains(ip)
-                });
+                // the reserved address comes first, then keep the old ip if it is
+                // not conflicted, then try the lease from the last run
+                let preferred = reserved_ip
+                    .into_iter()
+                    .chain(current_dhcp_ip.map(|x| x.address()))
+                    .chain(last_lease.map(|x| x.address()))
+                    .collect::<Vec<_>>();
+                let candidate_ipv4_addr =
+                    select_dhcp_ipv4(dhcp_inet, &used_ipv4, &preferred, &reserved_by_others);
 
                 if current_dhcp_ip == candidate_ipv4_addr {
                     continue;
@@ -483,6 +502,7 @@ impl Instance {
                     if global_ctx_c.no_tun() {
                         current_dhcp_ip = Some(ip);
                         global_ctx_c.set_ipv4(Some(ip));
+                        save_lease(ip);
                         global_ctx_c
                             .issue_event(GlobalCtxEvent::DhcpIpv4Changed(last_ip, Some(ip)));
                         continue;
@@ -516,6 +536,7 @@ impl Instance {
 
                     current_dhcp_ip = Some(ip);
                     global_ctx_c.set_ipv4(Some(ip));
+                    save_lease(ip);
                     global_ctx_c.issue_event(GlobalCtxEvent::DhcpIpv4Changed(last_ip, Some(ip)));
                 } else {
                     current_dhcp_ip = None;
@@ -621,6 +642,11 @@ impl Instance {
         self.run_ip_proxy().await?;
 
         self.udp_hole_puncher.lock().await.run().await?;
+        self.tcp_hole_puncher.lock().await.run().await?;
+
+        if self.global_ctx.get_flags().enable_port_mapping {
+            self.port_mapping.start();
+        }
 
         self.peer_center.init().await;
         let route_calc = self.peer_center.get_cost_calculator();
@@ -1058,6 +1084,7 @@ impl Instance {
     }
 
     pub async fn clear_resources(&mut self) {
+        self.port_mapping.stop().await;
         self.peer_manager.clear_resources().await;
         let _ = self.nic_ctx.lock().await.take();
         if let Some(rpc_server) = self.rpc_server.take() {
@@ -1071,10 +1098,12 @@ impl Drop for Instance {
         let my_peer_id = self.peer_manager.my_peer_id();
         let pm = Arc::downgrade(&self.peer_manager);
         let nic_ctx = self.nic_ctx.clone();
+        let port_mapping = self.port_mapping.clone();
         if let Some(rpc_server) = self.rpc_server.take() {
             rpc_server.registry().unregister_all();
         };
         tokio::spawn(async move {
+            port_mapping.stop().await;
             nic_ctx.lock().await.take();
             if let Some(pm) = pm.upgrade() {
                 pm.clear_resources().await;
@@ -19,6 +19,7 @@ use crate::{
         global_ctx::{ArcGlobalCtx, GlobalCtxEvent},
         netns::NetNS,
     },
+    connector::fec_stats,
     peers::peer_manager::PeerManager,
     tunnel::{
         ring::RingTunnelListener, tcp::TcpTunnelListener, udp::UdpTunnelListener, Tunnel,
@@ -32,7 +33,11 @@ pub fn get_listener_by_url(
 ) -> Result<Box<dyn TunnelListener>, Error> {
     Ok(match l.scheme() {
         "tcp" => Box::new(TcpTunnelListener::new(l.clone())),
-        "udp" => Box::new(UdpTunnelListener::new(l.clone())),
+        "udp" => {
+            let mut listener = UdpTunnelListener::new(l.clone());
+            listener.set_fec_stats(fec_stats(&_ctx));
+            Box::new(listener)
+        }
         #[cfg(feature = "wireguard")]
         "wg" => {
             let nid = _ctx.get_network_identity();
@@ -43,11 +48,33 @@ pub fn get_listener_by_url(
             Box::new(WgTunnelListener::new(l.clone(), wg_config))
         }
         #[cfg(feature = "quic")]
-        "quic" => Box::new(QUICTunnelListener::new(l.clone())),
+        "quic" => {
+            let mut listener = QUICTunnelListener::new(l.clone());
+            if let Some((cert_file, key_file)) = tls_cert_files(&_ctx) {
+                listener.set_cert_files(cert_file, key_file);
+            }
+            Box::new(listener)
+        }
         #[cfg(feature = "websocket")]
         "ws" | "wss" => {
             use crate::tunnel::websocket::WSTunnelListener;
-            Box::new(WSTunnelListener::new(l.clone()))
+            let mut listener = WSTunnelListener::new(l.clone());
+            if let Some((cert_file, key_file)) = tls_cert_files(&_ctx) {
+                listener.set_cert_files(cert_file, key_file);
+            }
+            if let Some(token) = _ctx.config.get_ws_listener_token() {
+                listener.set_token(token);
+            }
+            if l.scheme() == "wss" {
+                if let Some(client_ca_file) = _ctx
+                    .config
+                    .get_tls_config()
+                    .and_then(|tls| tls.client_ca_file)
+                {
+                    listener.set_client_ca_file(client_ca_file);
+                }
+            }
+            Box::new(listener)
         }
         _ => {
             return Err(Error::InvalidUrl(l.to_string()));
@@ -55,6 +82,14 @@ pub fn get_listener_by_url(
     })
 }
 
+/// Certificate and key configured for tls listeners, they fall back to a self
+/// signed certificate without them.
+#[cfg(any(feature = "quic", feature = "websocket"))]
+fn tls_cert_files(ctx: &ArcGlobalCtx) -> Option<(std::path::PathBuf, std::path::PathBuf)> {
+    let tls = ctx.config.get_tls_config()?;
+    Some((tls.cert_file?, tls.key_file?))
+}
+
 pub fn is_url_host_ipv6(l: &url::Url) -> bool {
     l.host_str().is_some_and(|h| h.contains(':'))
 }
@@ -1,8 +1,11 @@
+pub mod dhcp;
 pub mod dns_server;
 #[allow(clippy::module_inception)]
 pub mod instance;
 
 pub mod listeners;
+pub mod port_mapping;
+pub mod qos;
 
 #[cfg(feature = "tun")]
 pub mod virtual_nic;
@@ -0,0 +1,404 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
+
+use anyhow::Error;
+use tokio::{net::UdpSocket, sync::Mutex};
+
+use crate::common::{global_ctx::ArcGlobalCtx, netns::NetNS, scoped_task::ScopedTask};
+
+use natpmp::NatPmpMapper;
+use pcp::PcpMapper;
+use upnp::UpnpMapper;
+
+pub(crate) mod natpmp;
+pub(crate) mod pcp;
+pub(crate) mod upnp;
+
+// nat-pmp and pcp servers listen on the same port of the gateway
+const GATEWAY_PORT: u16 = 5351;
+const LEASE_DURATION: Duration = Duration::from_secs(3600);
+// renewing a lease earlier than this is pointless
+const MIN_RENEW_INTERVAL: Duration = Duration::from_secs(60);
+// listeners added later and failed mappings are retried this often
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+const SSDP_WAIT: Duration = Duration::from_secs(2);
+// nat-pmp retransmits after 250ms and doubles the timeout for each try
+const UDP_REQUEST_TRIES: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransportProtocol {
+    Tcp,
+    Udp,
+}
+
+impl TransportProtocol {
+    fn from_scheme(scheme: &str) -> Option<Self> {
+        match scheme {
+            "tcp" | "ws" | "wss" => Some(TransportProtocol::Tcp),
+            "udp" | "quic" | "wg" => Some(TransportProtocol::Udp),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedPort {
+    pub external: SocketAddrV4,
+    pub lifetime: Duration,
+}
+
+/// A protocol asking the gateway to forward an external port to a local one.
+#[async_trait::async_trait]
+pub trait PortMapper: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn gateway(&self) -> Ipv4Addr;
+    /// Creates or renews the mapping of `local`, an external port equal to
+    /// the local one is requested.
+    async fn add(
+        &self,
+        protocol: TransportProtocol,
+        local: SocketAddrV4,
+        lifetime: Duration,
+    ) -> Result<MappedPort, Error>;
+    async fn remove(
+        &self,
+        protocol: TransportProtocol,
+        local: SocketAddrV4,
+        mapped: &MappedPort,
+    ) -> Result<(), Error>;
+}
+
+// sends `req` to the gateway until a datagram accepted by `is_resp` comes back
+async fn udp_request(
+    net_ns: &NetNS,
+    gateway: SocketAddr,
+    req: &[u8],
+    is_resp: impl Fn(&[u8]) -> bool,
+) -> Result<Vec<u8>, Error> {
+    let socket = {
+        let _g = net_ns.guard();
+        UdpSocket::bind("0.0.0.0:0").await?
+    };
+    socket.connect(gateway).await?;
+
+    let mut buf = vec![0u8; 1100];
+    let mut wait = Duration::from_millis(250);
+    for _ in 0..UDP_REQUEST_TRIES {
+        socket.send(req).await?;
+        let deadline = tokio::time::Instant::now() + wait;
+        while let Ok(len) = tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+            let len = len?;
+            if is_resp(&buf[..len]) {
+                buf.truncate(len);
+                return Ok(buf);
+            }
+        }
+        wait *= 2;
+    }
+    Err(anyhow::anyhow!("no response from gateway {}", gateway))
+}
+
+// address of the local interface packets to `gateway` leave from
+async fn local_ip_to(net_ns: &NetNS, gateway: Ipv4Addr) -> Result<Ipv4Addr, Error> {
+    let socket = {
+        let _g = net_ns.guard();
+        UdpSocket::bind("0.0.0.0:0").await?
+    };
+    socket.connect((gateway, GATEWAY_PORT)).await?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(ip) => Ok(ip),
+        IpAddr::V6(ip) => Err(anyhow::anyhow!("unexpected local ip {} to gateway", ip)),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn default_gateway() -> Option<Ipv4Addr> {
+    // Iface Destination Gateway Flags ..., addresses in network order hex
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let dst = u32::from_str_radix(fields.get(1)?, 16).ok()?;
+        let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+        // default route through a gateway, routes of tun devices have none
+        (dst == 0 && gateway != 0).then(|| Ipv4Addr::from(gateway.to_le_bytes()))
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn default_gateway() -> Option<Ipv4Addr> {
+    None
+}
+
+struct ListenerMapping {
+    listener: url::Url,
+    protocol: TransportProtocol,
+    local: SocketAddrV4,
+    mapped: MappedPort,
+    mapped_listener: url::Url,
+    renew_at: Instant,
+}
+
+/// Maps the ports of the running listeners on the gateway with PCP, NAT-PMP
+/// or UPnP IGD, whichever the gateway answers first, and registers the
+/// public addresses as mapped listeners so peers can connect directly.
+/// Mappings are renewed at half their lifetime and removed on stop.
+pub struct PortMappingManager {
+    global_ctx: ArcGlobalCtx,
+    mapper: Mutex<Option<Arc<dyn PortMapper>>>,
+    mappings: Mutex<Vec<ListenerMapping>>,
+    task: std::sync::Mutex<Option<ScopedTask<()>>>,
+}
+
+impl PortMappingManager {
+    pub fn new(global_ctx: ArcGlobalCtx) -> Arc<Self> {
+        Arc::new(Self {
+            global_ctx,
+            mapper: Mutex::new(None),
+            mappings: Mutex::new(Vec::new()),
+            task: std::sync::Mutex::new(None),
+        })
+    }
+
+    pub fn start(self: &Arc<Self>) {
+        let weak: Weak<Self> = Arc::downgrade(self);
+        let task = tokio::spawn(async move {
+            loop {
+                let Some(this) = weak.upgrade() else {
+                    break;
+                };
+                let wait = this.refresh().await;
+                drop(this);
+                tokio::time::sleep(wait).await;
+            }
+        });
+        *self.task.lock().unwrap() = Some(task.into());
+    }
+
+    async fn discover_mappers(&self) -> Vec<Arc<dyn PortMapper>> {
+        let net_ns = self.global_ctx.net_ns.clone();
+        let upnp = match upnp::discover(&net_ns, SSDP_WAIT).await {
+            Ok(location) => UpnpMapper::from_location(net_ns.clone(), location)
+                .await
+                .inspect_err(|e| tracing::debug!(?e, "failed to load upnp gateway description"))
+                .ok(),
+            Err(e) => {
+                tracing::debug!(?e, "no upnp gateway found");
+                None
+            }
+        };
+
+        let mut mappers: Vec<Arc<dyn PortMapper>> = vec![];
+        if let Some(gateway) = default_gateway().or(upnp.as_ref().map(|x| x.gateway())) {
+            let gateway_addr = SocketAddr::from((gateway, GATEWAY_PORT));
+            mappers.push(Arc::new(PcpMapper::new(net_ns.clone(), gateway_addr)));
+            mappers.push(Arc::new(NatPmpMapper::new(net_ns.clone(), gateway_addr)));
+        }
+        if let Some(upnp) = upnp {
+            mappers.push(Arc::new(upnp));
+        }
+        mappers
+    }
+
+    fn listener_local_addr(
+        listener: &url::Url,
+    ) -> Option<(TransportProtocol, Option<Ipv4Addr>, u16)> {
+        let protocol = TransportProtocol::from_scheme(listener.scheme())?;
+        let ip = match listener.host()? {
+            url::Host::Ipv4(ip) => (!ip.is_unspecified()).then_some(ip),
+            url::Host::Domain(_) => None,
+            url::Host::Ipv6(_) => return None,
+        };
+        Some((protocol, ip, listener.port()?))
+    }
+
+    async fn add_mapping(
+        &self,
+        protocol: TransportProtocol,
+        local_ip: Option<Ipv4Addr>,
+        port: u16,
+    ) -> Result<(SocketAddrV4, MappedPort), Error> {
+        let mut mapper = self.mapper.lock().await;
+        let candidates = match mapper.as_ref() {
+            Some(mapper) => vec![mapper.clone()],
+            None => self.discover_mappers().await,
+        };
+
+        let mut last_err = anyhow::anyhow!("no port mapping capable gateway found");
+        for candidate in candidates {
+            let local_ip = match local_ip {
+                Some(ip) => ip,
+                None => match local_ip_to(&self.global_ctx.net_ns, candidate.gateway()).await {
+                    Ok(ip) => ip,
+                    Err(e) => {
+                        last_err = e;
+                        continue;
+                    }
+                },
+            };
+            let local = SocketAddrV4::new(local_ip, port);
+            match candidate.add(protocol, local, LEASE_DURATION).await {
+                Ok(mapped) => {
+                    if mapper.is_none() {
+                        tracing::info!(mapper = candidate.name(), gateway = ?candidate.gateway(), "use port mapping protocol");
+                        *mapper = Some(candidate);
+                    }
+                    return Ok((local, mapped));
+                }
+                Err(e) => {
+                    tracing::debug!(mapper = candidate.name(), ?e, "port mapping failed");
+                    last_err = e;
+                }
+            }
+        }
+        // the gateway may have changed, look for it again next time
+        *mapper = None;
+        Err(last_err)
+    }
+
+    fn register_mapped_listener(&self, url: &url::Url) {
+        let mut urls = self.global_ctx.config.get_mapped_listeners();
+        if !urls.contains(url) {
+            urls.push(url.clone());
+            self.global_ctx.config.set_mapped_listeners(Some(urls));
+        }
+    }
+
+    fn unregister_mapped_listener(&self, url: &url::Url) {
+        let mut urls = self.global_ctx.config.get_mapped_listeners();
+        urls.retain(|x| x != url);
+        self.global_ctx.config.set_mapped_listeners(Some(urls));
+    }
+
+    async fn remove_mapping(&self, mapping: &ListenerMapping) {
+        self.unregister_mapped_listener(&mapping.mapped_listener);
+        let Some(mapper) = self.mapper.lock().await.clone() else {
+            return;
+        };
+        if let Err(e) = mapper
+            .remove(mapping.protocol, mapping.local, &mapping.mapped)
+            .await
+        {
+            tracing::warn!(?e, listener = %mapping.listener, "failed to remove port mapping");
+        }
+    }
+
+    // maps new listeners and renews due mappings, returns when to run again
+    async fn refresh(&self) -> Duration {
+        let listeners = self.global_ctx.get_running_listeners();
+        let mut mappings = self.mappings.lock().await;
+
+        let (stale, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut *mappings)
+            .into_iter()
+            .partition(|x| !listeners.contains(&x.listener));
+        *mappings = kept;
+        for mapping in stale.iter() {
+            self.remove_mapping(mapping).await;
+        }
+
+        let now = Instant::now();
+        for listener in listeners.iter() {
+            let Some((protocol, local_ip, port)) = Self::listener_local_addr(listener) else {
+                continue;
+            };
+            let idx = mappings.iter().position(|x| &x.listener == listener);
+            if idx.is_some_and(|i| mappings[i].renew_at > now) {
+                continue;
+            }
+
+            let (local, mapped) = match self.add_mapping(protocol, local_ip, port).await {
+                Ok(ret) => ret,
+                Err(e) => {
+                    tracing::info!(?e, %listener, "failed to map listener port");
+                    continue;
+                }
+            };
+            let mut mapped_listener = listener.clone();
+            if mapped_listener
+                .set_ip_host(IpAddr::V4(*mapped.external.ip()))
+                .is_err()
+                || mapped_listener
+                    .set_port(Some(mapped.external.port()))
+                    .is_err()
+            {
+                continue;
+            }
+
+            let mapping = ListenerMapping {
+                listener: listener.clone(),
+                protocol,
+                local,
+                mapped,
+                mapped_listener: mapped_listener.clone(),
+                renew_at: now + (mapped.lifetime / 2).max(MIN_RENEW_INTERVAL),
+            };
+            match idx {
+                Some(i) => {
+                    if mappings[i].mapped_listener != mapped_listener {
+                        self.unregister_mapped_listener(&mappings[i].mapped_listener);
+                    }
+                    mappings[i] = mapping;
+                }
+                None => {
+                    tracing::info!(%listener, %mapped_listener, "listener port mapped");
+                    mappings.push(mapping);
+                }
+            }
+            self.register_mapped_listener(&mapped_listener);
+        }
+
+        mappings
+            .iter()
+            .map(|x| x.renew_at.saturating_duration_since(now))
+            .min()
+            .unwrap_or(RETRY_INTERVAL)
+            .min(RETRY_INTERVAL)
+    }
+
+    /// Removes all mappings from the gateway and the mapped listeners.
+    pub async fn stop(&self) {
+        self.task.lock().unwrap().take();
+        let mappings = std::mem::take(&mut *self.mappings.lock().await);
+        for mapping in mappings.iter() {
+            self.remove_mapping(mapping).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::global_ctx::tests::get_mock_global_ctx;
+
+    #[tokio::test]
+    async fn port_mapping_register_mapped_listener() {
+        let igd = upnp::tests::MockIgd::start().await;
+        let global_ctx = get_mock_global_ctx();
+        global_ctx.add_running_listener("tcp://0.0.0.0:11010".parse().unwrap());
+        global_ctx.add_running_listener("udp://[::]:11010".parse().unwrap());
+        global_ctx.add_running_listener("ring://test".parse().unwrap());
+
+        let mgr = PortMappingManager::new(global_ctx.clone());
+        let mapper = UpnpMapper::from_location(global_ctx.net_ns.clone(), igd.location())
+            .await
+            .unwrap();
+        *mgr.mapper.lock().await = Some(Arc::new(mapper));
+
+        assert_eq!(RETRY_INTERVAL, mgr.refresh().await);
+        assert_eq!(
+            vec!["tcp://203.0.113.7:11010".parse::<url::Url>().unwrap()],
+            global_ctx.config.get_mapped_listeners()
+        );
+        assert_eq!(Some(11010), igd.mapped_port(TransportProtocol::Tcp, 11010));
+
+        // mapped listeners are only renewed when due
+        mgr.refresh().await;
+        assert_eq!(1, igd.add_count());
+
+        mgr.stop().await;
+        assert!(global_ctx.config.get_mapped_listeners().is_empty());
+        assert_eq!(None, igd.mapped_port(TransportProtocol::Tcp, 11010));
+    }
+}
@@ -0,0 +1,196 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use anyhow::Error;
+
+use crate::common::netns::NetNS;
+
+use super::{udp_request, MappedPort, PortMapper, TransportProtocol};
+
+const VERSION: u8 = 0;
+const OP_EXTERNAL_ADDR: u8 = 0;
+const OP_MAP_UDP: u8 = 1;
+const OP_MAP_TCP: u8 = 2;
+// responses carry the request opcode plus 128
+const OP_RESPONSE: u8 = 128;
+
+fn map_opcode(protocol: TransportProtocol) -> u8 {
+    match protocol {
+        TransportProtocol::Udp => OP_MAP_UDP,
+        TransportProtocol::Tcp => OP_MAP_TCP,
+    }
+}
+
+fn check_result(resp: &[u8]) -> Result<(), Error> {
+    match u16::from_be_bytes([resp[2], resp[3]]) {
+        0 => Ok(()),
+        1 => Err(anyhow::anyhow!("nat-pmp unsupported version")),
+        2 => Err(anyhow::anyhow!("nat-pmp not authorized")),
+        3 => Err(anyhow::anyhow!("nat-pmp network failure")),
+        4 => Err(anyhow::anyhow!("nat-pmp out of resources")),
+        code => Err(anyhow::anyhow!("nat-pmp error {}", code)),
+    }
+}
+
+/// NAT-PMP (RFC 6886) client.
+pub(crate) struct NatPmpMapper {
+    net_ns: NetNS,
+    gateway: SocketAddr,
+}
+
+impl NatPmpMapper {
+    pub(crate) fn new(net_ns: NetNS, gateway: SocketAddr) -> Self {
+        Self { net_ns, gateway }
+    }
+
+    async fn request(&self, req: &[u8], resp_len: usize) -> Result<Vec<u8>, Error> {
+        let op = req[1] | OP_RESPONSE;
+        let resp = udp_request(&self.net_ns, self.gateway, req, |resp| {
+            resp.len() >= 4 && resp[0] == VERSION && resp[1] == op
+        })
+        .await?;
+        check_result(&resp)?;
+        if resp.len() < resp_len {
+            return Err(anyhow::anyhow!("nat-pmp response too short"));
+        }
+        Ok(resp)
+    }
+
+    async fn external_ip(&self) -> Result<Ipv4Addr, Error> {
+        let resp = self.request(&[VERSION, OP_EXTERNAL_ADDR], 12).await?;
+        Ok(Ipv4Addr::new(resp[8], resp[9], resp[10], resp[11]))
+    }
+
+    // returns the external port and lifetime granted by the gateway
+    async fn map(
+        &self,
+        protocol: TransportProtocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: u32,
+    ) -> Result<(u16, u32), Error> {
+        let mut req = vec![VERSION, map_opcode(protocol), 0, 0];
+        req.extend_from_slice(&internal_port.to_be_bytes());
+        req.extend_from_slice(&external_port.to_be_bytes());
+        req.extend_from_slice(&lifetime.to_be_bytes());
+
+        let resp = self.request(&req, 16).await?;
+        Ok((
+            u16::from_be_bytes([resp[10], resp[11]]),
+            u32::from_be_bytes([resp[12], resp[13], resp[14], resp[15]]),
+        ))
+    }
+}
+
+#[async_trait::async_trait]
+impl PortMapper for NatPmpMapper {
+    fn name(&self) -> &'static str {
+        "nat-pmp"
+    }
+
+    fn gateway(&self) -> Ipv4Addr {
+        match self.gateway {
+            SocketAddr::V4(addr) => *addr.ip(),
+            SocketAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+        }
+    }
+
+    async fn add(
+        &self,
+        protocol: TransportProtocol,
+        local: SocketAddrV4,
+        lifetime: Duration,
+    ) -> Result<MappedPort, Error> {
+        // the gateway maps the source address of the request
+        let (port, lifetime) = self
+            .map(
+                protocol,
+                local.port(),
+                local.port(),
+                lifetime.as_secs() as u32,
+            )
+            .await?;
+        Ok(MappedPort {
+            external: SocketAddrV4::new(self.external_ip().await?, port),
+            lifetime: Duration::from_secs(lifetime as u64),
+        })
+    }
+
+    async fn remove(
+        &self,
+        protocol: TransportProtocol,
+        local: SocketAddrV4,
+        _mapped: &MappedPort,
+    ) -> Result<(), Error> {
+        self.map(protocol, local.port(), 0, 0).await.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use tokio::net::UdpSocket;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn natpmp_map_and_remove() {
+        let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let gateway_addr = gateway.local_addr().unwrap();
+        let mappings = Arc::new(std::sync::Mutex::new(HashMap::new()));
+        let mappings_clone = mappings.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            loop {
+                let (len, from) = gateway.recv_from(&mut buf).await.unwrap();
+                let req = &buf[..len];
+                let mut resp = vec![VERSION, req[1] | OP_RESPONSE, 0, 0, 0, 0, 0, 1];
+                if req[1] == OP_EXTERNAL_ADDR {
+                    resp.extend_from_slice(&[203, 0, 113, 7]);
+                } else {
+                    let internal = u16::from_be_bytes([req[4], req[5]]);
+                    let lifetime = u32::from_be_bytes([req[8], req[9], req[10], req[11]]);
+                    // the suggested port is taken, hand out another one
+                    let external = if lifetime == 0 { 0 } else { internal + 1 };
+                    if lifetime == 0 {
+                        mappings_clone.lock().unwrap().remove(&(req[1], internal));
+                    } else {
+                        mappings_clone
+                            .lock()
+                            .unwrap()
+                            .insert((req[1], internal), external);
+                    }
+                    resp.extend_from_slice(&req[4..6]);
+                    resp.extend_from_slice(&external.to_be_bytes());
+                    resp.extend_from_slice(&lifetime.min(1800).to_be_bytes());
+                }
+                gateway.send_to(&resp, from).await.unwrap();
+            }
+        });
+
+        let mapper = NatPmpMapper::new(NetNS::new(None), gateway_addr);
+        let local = "127.0.0.1:11010".parse().unwrap();
+        let mapped = mapper
+            .add(TransportProtocol::Udp, local, Duration::from_secs(3600))
+            .await
+            .unwrap();
+        assert_eq!(
+            "203.0.113.7:11011".parse::<SocketAddrV4>().unwrap(),
+            mapped.external
+        );
+        assert_eq!(Duration::from_secs(1800), mapped.lifetime);
+        assert_eq!(
+            Some(&11011),
+            mappings.lock().unwrap().get(&(OP_MAP_UDP, 11010))
+        );
+
+        mapper
+            .remove(TransportProtocol::Udp, local, &mapped)
+            .await
+            .unwrap();
+        assert!(mappings.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use anyhow::Error;
+use once_cell::sync::Lazy;
+
+use crate::common::netns::NetNS;
+
+use super::{udp_request, MappedPort, PortMapper, TransportProtocol};
+
+const VERSION: u8 = 2;
+const OP_MAP: u8 = 1;
+const RESPONSE_BIT: u8 = 0x80;
+const HEADER_LEN: usize = 24;
+const MAP_LEN: usize = 36;
+
+// identifies this client to the gateway, a mapping can only be renewed or
+// deleted with the nonce that created it, so it lives as long as the process
+// and survives rediscovering the gateway
+static NONCE: Lazy<[u8; 12]> = Lazy::new(rand::random);
+
+fn ip_protocol(protocol: TransportProtocol) -> u8 {
+    match protocol {
+        TransportProtocol::Tcp => 6,
+        TransportProtocol::Udp => 17,
+    }
+}
+
+fn result_error(code: u8) -> Error {
+    let reason = match code {
+        1 => "unsupported version",
+        2 => "not authorized",
+        3 => "malformed request",
+        4 => "unsupported opcode",
+        5 => "unsupported option",
+        6 => "malformed option",
+        7 => "network failure",
+        8 => "no resources",
+        9 => "unsupported protocol",
+        10 => "user exceeded quota",
+        11 => "cannot provide external",
+        12 => "address mismatch",
+        13 => "excessive remote peers",
+        _ => "unknown error",
+    };
+    anyhow::anyhow!("pcp error {}: {}", code, reason)
+}
+
+/// PCP (RFC 6887) client, ipv4 mappings only.
+pub(crate) struct PcpMapper {
+    net_ns: NetNS,
+    gateway: SocketAddr,
+}
+
+impl PcpMapper {
+    pub(crate) fn new(net_ns: NetNS, gateway: SocketAddr) -> Self {
+        Self { net_ns, gateway }
+    }
+
+    fn map_request(
+        &self,
+        protocol: TransportProtocol,
+        local: SocketAddrV4,
+        external_port: u16,
+        lifetime: u32,
+    ) -> Vec<u8> {
+        let mut req = vec![VERSION, OP_MAP, 0, 0];
+        req.extend_from_slice(&lifetime.to_be_bytes());
+        req.extend_from_slice(&local.ip().to_ipv6_mapped().octets());
+        req.extend_from_slice(&*NONCE);
+        req.extend_from_slice(&[ip_protocol(protocol), 0, 0, 0]);
+        req.extend_from_slice(&local.port().to_be_bytes());
+        req.extend_from_slice(&external_port.to_be_bytes());
+        // any external ipv4 address
+        req.extend_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+        req
+    }
+
+    async fn map(
+        &self,
+        protocol: TransportProtocol,
+        local: SocketAddrV4,
+        external_port: u16,
+        lifetime: u32,
+    ) -> Result<MappedPort, Error> {
+        let req = self.map_request(protocol, local, external_port, lifetime);
+        let nonce = *NONCE;
+        let resp = udp_request(&self.net_ns, self.gateway, &req, |resp| {
+            // errors of gateways only speaking nat-pmp are answered as version 0
+            resp.len() >= 4
+                && (resp[0] != VERSION
+                    || (resp[1] == OP_MAP | RESPONSE_BIT
+                        && (resp[3] != 0 || resp.get(24..36) == Some(&nonce[..]))))
+        })
+        .await?;
+
+        if resp[0] != VERSION {
+            return Err(anyhow::anyhow!("gateway does not support pcp"));
+        }
+        if resp[3] != 0 {
+            return Err(result_error(resp[3]));
+        }
+        if resp.len() < HEADER_LEN + MAP_LEN {
+            return Err(anyhow::anyhow!("pcp response too short"));
+        }
+
+        let lifetime = u32::from_be_bytes([resp[4], resp[5], resp[6], resp[7]]);
+        let port = u16::from_be_bytes([resp[42], resp[43]]);
+        let ip: [u8; 16] = resp[44..60].try_into().unwrap();
+        let ip = std::net::Ipv6Addr::from(ip)
+            .to_ipv4_mapped()
+            .ok_or(anyhow::anyhow!("pcp assigned a non ipv4 external address"))?;
+        Ok(MappedPort {
+            external: SocketAddrV4::new(ip, port),
+            lifetime: Duration::from_secs(lifetime as u64),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl PortMapper for PcpMapper {
+    fn name(&self) -> &'static str {
+        "pcp"
+    }
+
+    fn gateway(&self) -> Ipv4Addr {
+        match self.gateway {
+            SocketAddr::V4(addr) => *addr.ip(),
+            SocketAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+        }
+    }
+
+    async fn add(
+        &self,
+        protocol: TransportProtocol,
+        local: SocketAddrV4,
+        lifetime: Duration,
+    ) -> Result<MappedPort, Error> {
+        self.map(protocol, local, local.port(), lifetime.as_secs() as u32)
+            .await
+    }
+
+    async fn remove(
+        &self,
+        protocol: TransportProtocol,
+        local: SocketAddrV4,
+        _mapped: &MappedPort,
+    ) -> Result<(), Error> {
+        self.map(protocol, local, 0, 0).await.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::UdpSocket;
+
+    use super::*;
+
+    async fn mock_gateway(version: u8) -> SocketAddr {
+        let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = gateway.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1100];
+            loop {
+                let (len, from) = gateway.recv_from(&mut buf).await.unwrap();
+                let req = &buf[..len];
+                if version != VERSION {
+                    // nat-pmp only gateway, unsupported version
+                    gateway.send_to(&[0, 128, 0, 1], from).await.unwrap();
+                    continue;
+                }
+                let mut resp = vec![0u8; HEADER_LEN + MAP_LEN];
+                resp[0] = VERSION;
+                resp[1] = req[1] | RESPONSE_BIT;
+                resp[4..8].copy_from_slice(&req[4..8]);
+                resp[24..40].copy_from_slice(&req[24..40]);
+                resp[40..42].copy_from_slice(&req[40..42]);
+                resp[42..44].copy_from_slice(&req[42..44]);
+                resp[44..60]
+                    .copy_from_slice(&Ipv4Addr::new(203, 0, 113, 7).to_ipv6_mapped().octets());
+                gateway.send_to(&resp, from).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn pcp_map() {
+        let mapper = PcpMapper::new(NetNS::new(None), mock_gateway(VERSION).await);
+        let local = "127.0.0.1:11010".parse().unwrap();
+        let mapped = mapper
+            .add(TransportProtocol::Tcp, local, Duration::from_secs(3600))
+            .await
+            .unwrap();
+        assert_eq!(
+            "203.0.113.7:11010".parse::<SocketAddrV4>().unwrap(),
+            mapped.external
+        );
+        assert_eq!(Duration::from_secs(3600), mapped.lifetime);
+        mapper
+            .remove(TransportProtocol::Tcp, local, &mapped)
+            .await
+            .unwrap();
+
+        let mapper = PcpMapper::new(NetNS::new(None), mock_gateway(0).await);
+        assert!(mapper
+            .add(TransportProtocol::Tcp, local, Duration::from_secs(3600))
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,501 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddrV4},
+    time::Duration,
+};
+
+use anyhow::{Context, Error};
+use http_req::{
+    request::{Method, RedirectPolicy, Request},
+    response::StatusCode,
+};
+use tokio::net::UdpSocket;
+
+use crate::common::netns::NetNS;
+
+use super::{MappedPort, PortMapper, TransportProtocol};
+
+const SSDP_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+const IGD_DEVICE: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+// descriptions and soap responses are a few kilobytes
+const MAX_RESPONSE_SIZE: usize = 64 * 1024;
+const MAPPING_DESCRIPTION: &str = "easytier";
+// the igd only supports leases without expiry
+const ONLY_PERMANENT_LEASES: &str = "725";
+
+fn protocol_name(protocol: TransportProtocol) -> &'static str {
+    match protocol {
+        TransportProtocol::Tcp => "TCP",
+        TransportProtocol::Udp => "UDP",
+    }
+}
+
+// text of the first `<tag>` element, namespace prefixes are not supported
+fn xml_text<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = xml[start..].find(&format!("</{}>", tag))? + start;
+    Some(xml[start..end].trim())
+}
+
+// collects a response body, failing once it exceeds the limit
+struct LimitedBody {
+    body: Vec<u8>,
+    limit: usize,
+}
+
+impl std::io::Write for LimitedBody {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.body.len() + buf.len() > self.limit {
+            return Err(std::io::Error::other("http response too large"));
+        }
+        self.body.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+async fn http_request(
+    net_ns: &NetNS,
+    url: &url::Url,
+    method: Method,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> Result<(StatusCode, String), Error> {
+    let net_ns = net_ns.clone();
+    let url = url.to_string();
+    let headers: Vec<(String, String)> = headers
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    let body = body.as_bytes().to_vec();
+    tokio::task::spawn_blocking(move || {
+        let uri = http_req::uri::Uri::try_from(url.as_str())
+            .with_context(|| format!("parsing url failed. url: {}", url))?;
+        let mut req = Request::new(&uri);
+        req.method(method)
+            .redirect_policy(RedirectPolicy::Limit(0))
+            .timeout(HTTP_TIMEOUT)
+            .header("Connection", "close");
+        for (name, value) in headers.iter() {
+            req.header(name, value);
+        }
+        if !body.is_empty() {
+            req.header("Content-Length", &body.len()).body(&body);
+        }
+
+        let mut resp_body = LimitedBody {
+            body: vec![],
+            limit: MAX_RESPONSE_SIZE,
+        };
+        let _g = net_ns.guard();
+        let resp = req
+            .send(&mut resp_body)
+            .with_context(|| format!("http request to {} failed", url))?;
+        Ok::<_, Error>((
+            resp.status_code(),
+            String::from_utf8_lossy(&resp_body.body).into_owned(),
+        ))
+    })
+    .await?
+}
+
+/// Finds an internet gateway device with ssdp, returns the location of its
+/// description.
+pub(crate) async fn discover(net_ns: &NetNS, wait: Duration) -> Result<url::Url, Error> {
+    let socket = {
+        let _g = net_ns.guard();
+        UdpSocket::bind("0.0.0.0:0").await?
+    };
+    let req = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: {}\r\n\r\n",
+        SSDP_ADDR,
+        IGD_DEVICE,
+        wait.as_secs().max(1)
+    );
+    socket.send_to(req.as_bytes(), SSDP_ADDR).await?;
+
+    let mut buf = [0u8; 2048];
+    let deadline = tokio::time::Instant::now() + wait;
+    while let Ok(ret) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, from) = ret?;
+        let resp = String::from_utf8_lossy(&buf[..len]);
+        let location = resp.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim()
+                .eq_ignore_ascii_case("location")
+                .then(|| value.trim().parse::<url::Url>().ok())?
+        });
+        match location {
+            Some(location) => return Ok(location),
+            None => tracing::debug!(?from, "ssdp response without location"),
+        }
+    }
+    Err(anyhow::anyhow!("no internet gateway device answered"))
+}
+
+/// UPnP IGD client using the WANIPConnection or WANPPPConnection service.
+pub(crate) struct UpnpMapper {
+    net_ns: NetNS,
+    control_url: url::Url,
+    service_type: String,
+    gateway: Ipv4Addr,
+}
+
+impl UpnpMapper {
+    pub(crate) async fn from_location(net_ns: NetNS, location: url::Url) -> Result<Self, Error> {
+        let (status, desc) = http_request(&net_ns, &location, Method::GET, &[], "").await?;
+        if !status.is_success() {
+            return Err(anyhow::anyhow!(
+                "get igd description failed, status {}",
+                status
+            ));
+        }
+
+        let (service_type, control_url) = desc
+            .split("<service>")
+            .skip(1)
+            .find_map(|service| {
+                let service_type = xml_text(service, "serviceType")?;
+                (service_type.contains(":WANIPConnection:")
+                    || service_type.contains(":WANPPPConnection:"))
+                .then(|| Some((service_type, xml_text(service, "controlURL")?)))?
+            })
+            .ok_or(anyhow::anyhow!("igd has no wan connection service"))?;
+        let control_url = location.join(control_url)?;
+        let gateway = match control_url.host() {
+            Some(url::Host::Ipv4(ip)) => ip,
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "igd control url {} is not ipv4",
+                    control_url
+                ))
+            }
+        };
+
+        Ok(Self {
+            net_ns,
+            control_url,
+            service_type: service_type.to_string(),
+            gateway,
+        })
+    }
+
+    async fn soap(&self, action: &str, args: &[(&str, String)]) -> Result<String, Error> {
+        let args: String = args
+            .iter()
+            .map(|(name, value)| format!("<{0}>{1}</{0}>", name, value))
+            .collect();
+        let body = format!(
+            concat!(
+                r#"<?xml version="1.0"?>"#,
+                r#"<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" "#,
+                r#"s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">"#,
+                r#"<s:Body><u:{0} xmlns:u="{1}">{2}</u:{0}></s:Body></s:Envelope>"#
+            ),
+            action, self.service_type, args
+        );
+        let soap_action = format!("\"{}#{}\"", self.service_type, action);
+        let (status, resp) = http_request(
+            &self.net_ns,
+            &self.control_url,
+            Method::POST,
+            &[
+                ("Content-Type", "text/xml; charset=\"utf-8\""),
+                ("SOAPAction", &soap_action),
+            ],
+            &body,
+        )
+        .await?;
+        if !status.is_success() {
+            return Err(anyhow::anyhow!(
+                "upnp {} failed, error {} {}",
+                action,
+                xml_text(&resp, "errorCode").unwrap_or_default(),
+                xml_text(&resp, "errorDescription").unwrap_or_default()
+            ));
+        }
+        Ok(resp)
+    }
+
+    async fn add_port_mapping(
+        &self,
+        protocol: TransportProtocol,
+        local: SocketAddrV4,
+        lease: u64,
+    ) -> Result<(), Error> {
+        self.soap(
+            "AddPortMapping",
+            &[
+                ("NewRemoteHost", "".to_string()),
+                ("NewExternalPort", local.port().to_string()),
+                ("NewProtocol", protocol_name(protocol).to_string()),
+                ("NewInternalPort", local.port().to_string()),
+                ("NewInternalClient", local.ip().to_string()),
+                ("NewEnabled", "1".to_string()),
+                ("NewPortMappingDescription", MAPPING_DESCRIPTION.to_string()),
+                ("NewLeaseDuration", lease.to_string()),
+            ],
+        )
+        .await
+        .map(|_| ())
+    }
+}
+
+#[async_trait::async_trait]
+impl PortMapper for UpnpMapper {
+    fn name(&self) -> &'static str {
+        "upnp"
+    }
+
+    fn gateway(&self) -> Ipv4Addr {
+        self.gateway
+    }
+
+    async fn add(
+        &self,
+        protocol: TransportProtocol,
+        local: SocketAddrV4,
+        lifetime: Duration,
+    ) -> Result<MappedPort, Error> {
+        let lifetime = match self
+            .add_port_mapping(protocol, local, lifetime.as_secs())
+            .await
+        {
+            Ok(_) => lifetime,
+            Err(e) if e.to_string().contains(ONLY_PERMANENT_LEASES) => {
+                self.add_port_mapping(protocol, local, 0).await?;
+                lifetime
+            }
+            Err(e) => return Err(e),
+        };
+
+        let resp = self.soap("GetExternalIPAddress", &[]).await?;
+        let ip = xml_text(&resp, "NewExternalIPAddress")
+            .and_then(|x| x.parse::<IpAddr>().ok())
+            .ok_or(anyhow::anyhow!("igd returned no external ip"))?;
+        let IpAddr::V4(ip) = ip else {
+            return Err(anyhow::anyhow!(
+                "igd returned a non ipv4 external ip {}",
+                ip
+            ));
+        };
+        Ok(MappedPort {
+            external: SocketAddrV4::new(ip, local.port()),
+            lifetime,
+        })
+    }
+
+    async fn remove(
+        &self,
+        protocol: TransportProtocol,
+        _local: SocketAddrV4,
+        mapped: &MappedPort,
+    ) -> Result<(), Error> {
+        self.soap(
+            "DeletePortMapping",
+            &[
+                ("NewRemoteHost", "".to_string()),
+                ("NewExternalPort", mapped.external.port().to_string()),
+                ("NewProtocol", protocol_name(protocol).to_string()),
+            ],
+        )
+        .await
+        .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        collections::HashMap,
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use crate::common::scoped_task::ScopedTask;
+
+    use super::*;
+
+    const SERVICE_TYPE: &str = "urn:schemas-upnp-org:service:WANIPConnection:1";
+
+    fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+        haystack.windows(needle.len()).position(|x| x == needle)
+    }
+
+    /// Internet gateway device serving a description and the soap actions
+    /// used by the mapper, over plain http on localhost.
+    pub(crate) struct MockIgd {
+        addr: SocketAddr,
+        // (protocol, external port) -> internal port
+        mappings: Arc<Mutex<HashMap<(String, u16), u16>>>,
+        add_count: Arc<AtomicU32>,
+        _task: ScopedTask<()>,
+    }
+
+    fn http_response(status: &str, body: &str) -> String {
+        // chunked, as some routers answer
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/xml\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n{}\r\n0\r\n\r\n",
+            status,
+            body.len(),
+            body
+        )
+    }
+
+    impl MockIgd {
+        pub(crate) async fn start() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let mappings = Arc::new(Mutex::new(HashMap::new()));
+            let add_count = Arc::new(AtomicU32::new(0));
+
+            let (mappings_clone, add_count_clone) = (mappings.clone(), add_count.clone());
+            let task = tokio::spawn(async move {
+                loop {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let mut buf = vec![0u8; 4096];
+                    let mut len = 0;
+                    // read the headers and the content length of the body
+                    loop {
+                        let n = stream.read(&mut buf[len..]).await.unwrap();
+                        if n == 0 {
+                            break;
+                        }
+                        len += n;
+                        let Some(end) = find(&buf[..len], b"\r\n\r\n") else {
+                            continue;
+                        };
+                        let header = String::from_utf8_lossy(&buf[..end]).to_lowercase();
+                        let body_len: usize = header
+                            .lines()
+                            .find_map(|x| x.strip_prefix("content-length:"))
+                            .map(|x| x.trim().parse().unwrap())
+                            .unwrap_or(0);
+                        if len >= end + 4 + body_len {
+                            break;
+                        }
+                    }
+                    let req = String::from_utf8_lossy(&buf[..len]).into_owned();
+
+                    let resp = if req.starts_with("GET /desc.xml") {
+                        http_response(
+                            "200 OK",
+                            &format!(
+                                "<root><device><serviceList><service><serviceType>{}</serviceType><controlURL>/ctl</controlURL></service></serviceList></device></root>",
+                                SERVICE_TYPE
+                            ),
+                        )
+                    } else if req.contains("#AddPortMapping\"") {
+                        add_count_clone.fetch_add(1, Ordering::Relaxed);
+                        let protocol = xml_text(&req, "NewProtocol").unwrap().to_string();
+                        let external = xml_text(&req, "NewExternalPort").unwrap().parse().unwrap();
+                        let internal = xml_text(&req, "NewInternalPort").unwrap().parse().unwrap();
+                        mappings_clone
+                            .lock()
+                            .unwrap()
+                            .insert((protocol, external), internal);
+                        http_response("200 OK", "<s:Envelope></s:Envelope>")
+                    } else if req.contains("#DeletePortMapping\"") {
+                        let protocol = xml_text(&req, "NewProtocol").unwrap().to_string();
+                        let external = xml_text(&req, "NewExternalPort").unwrap().parse().unwrap();
+                        mappings_clone.lock().unwrap().remove(&(protocol, external));
+                        http_response("200 OK", "<s:Envelope></s:Envelope>")
+                    } else if req.contains("#GetExternalIPAddress\"") {
+                        http_response(
+                            "200 OK",
+                            "<s:Envelope><NewExternalIPAddress>203.0.113.7</NewExternalIPAddress></s:Envelope>",
+                        )
+                    } else {
+                        http_response(
+                            "500 Internal Server Error",
+                            "<errorCode>401</errorCode><errorDescription>Invalid Action</errorDescription>",
+                        )
+                    };
+                    stream.write_all(resp.as_bytes()).await.unwrap();
+                }
+            });
+
+            Self {
+                addr,
+                mappings,
+                add_count,
+                _task: task.into(),
+            }
+        }
+
+        pub(crate) fn location(&self) -> url::Url {
+            format!("http://{}/desc.xml", self.addr).parse().unwrap()
+        }
+
+        pub(crate) fn mapped_port(
+            &self,
+            protocol: TransportProtocol,
+            external: u16,
+        ) -> Option<u16> {
+            self.mappings
+                .lock()
+                .unwrap()
+                .get(&(protocol_name(protocol).to_string(), external))
+                .copied()
+        }
+
+        pub(crate) fn add_count(&self) -> u32 {
+            self.add_count.load(Ordering::Relaxed)
+        }
+    }
+
+    #[test]
+    fn upnp_response_size_limit() {
+        use std::io::Write as _;
+
+        let mut body = LimitedBody {
+            body: vec![],
+            limit: 8,
+        };
+        body.write_all(b"12345").unwrap();
+        assert!(body.write_all(b"6789").is_err());
+        assert_eq!(b"12345", &body.body[..]);
+    }
+
+    #[tokio::test]
+    async fn upnp_map_with_mock_igd() {
+        let igd = MockIgd::start().await;
+        let mapper = UpnpMapper::from_location(NetNS::new(None), igd.location())
+            .await
+            .unwrap();
+        assert_eq!(SERVICE_TYPE, mapper.service_type);
+        assert_eq!(Ipv4Addr::LOCALHOST, mapper.gateway());
+
+        let local = "127.0.0.1:11010".parse().unwrap();
+        let mapped = mapper
+            .add(TransportProtocol::Udp, local, Duration::from_secs(3600))
+            .await
+            .unwrap();
+        assert_eq!(
+            "203.0.113.7:11010".parse::<SocketAddrV4>().unwrap(),
+            mapped.external
+        );
+        assert_eq!(Some(11010), igd.mapped_port(TransportProtocol::Udp, 11010));
+
+        mapper
+            .remove(TransportProtocol::Udp, local, &mapped)
+            .await
+            .unwrap();
+        assert_eq!(None, igd.mapped_port(TransportProtocol::Udp, 11010));
+
+        // unknown actions are reported with the igd error
+        let err = mapper.soap("GetStatusInfo", &[]).await.unwrap_err();
+        assert!(err.to_string().contains("Invalid Action"), "{}", err);
+    }
+}
@@ -0,0 +1,398 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    ops::RangeInclusive,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::sync::Notify;
+
+use crate::{
+    common::{
+        config::{QosClassConfig, QosConfig},
+        error::Error,
+        global_ctx::ArcGlobalCtx,
+        stats_manager::{CounterHandle, LabelSet, LabelType, MetricName},
+        token_bucket::TokenBucket,
+    },
+    tunnel::packet_def::ZCPacket,
+};
+
+const DEFAULT_CLASS: &str = "default";
+const DEFAULT_PRIORITY: u8 = 4;
+const DEFAULT_QUEUE_LEN: usize = 1024;
+// bytes a class of weight 1 may send in each round of its priority
+const QUANTUM: u64 = 1500;
+// the shaper lets through at most this much traffic at once, more would only
+// queue up in the network where the classes are not known
+const SHAPER_BURST: Duration = Duration::from_millis(20);
+const MAX_PACKET_SIZE: u64 = 65535;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PacketInfo {
+    protocol: u8,
+    dscp: u8,
+    ports: Option<(u16, u16)>,
+}
+
+fn parse_packet(payload: &[u8]) -> Option<PacketInfo> {
+    let (protocol, tclass, l4) = match payload.first()? >> 4 {
+        4 => {
+            let ihl = ((payload[0] & 0x0f) as usize) * 4;
+            (*payload.get(9)?, *payload.get(1)?, payload.get(ihl..))
+        }
+        6 => {
+            let tclass = (payload[0] << 4) | (payload.get(1)? >> 4);
+            (*payload.get(6)?, tclass, payload.get(40..))
+        }
+        _ => return None,
+    };
+    let ports = match protocol {
+        // tcp, udp
+        6 | 17 => l4.and_then(|x| x.get(..4)).map(|x| {
+            (
+                u16::from_be_bytes([x[0], x[1]]),
+                u16::from_be_bytes([x[2], x[3]]),
+            )
+        }),
+        _ => None,
+    };
+    Some(PacketInfo {
+        protocol,
+        dscp: tclass >> 2,
+        ports,
+    })
+}
+
+fn parse_protocol(protocol: &str) -> Result<&'static [u8], Error> {
+    match protocol.to_lowercase().as_str() {
+        "tcp" => Ok(&[6]),
+        "udp" => Ok(&[17]),
+        "icmp" => Ok(&[1, 58]),
+        _ => Err(anyhow::anyhow!("unknown qos protocol: {}", protocol).into()),
+    }
+}
+
+fn parse_port_range(ports: &str) -> Result<RangeInclusive<u16>, Error> {
+    let parse = |x: &str| {
+        x.trim()
+            .parse::<u16>()
+            .map_err(|_| anyhow::anyhow!("invalid qos port: {}", ports))
+    };
+    let range = match ports.split_once('-') {
+        Some((start, end)) => parse(start)?..=parse(end)?,
+        None => parse(ports)?..=parse(ports)?,
+    };
+    if range.is_empty() {
+        return Err(anyhow::anyhow!("invalid qos port range: {}", ports).into());
+    }
+    Ok(range)
+}
+
+#[derive(Debug, Default)]
+struct QosClassMatcher {
+    protocols: Vec<u8>,
+    ports: Vec<RangeInclusive<u16>>,
+    dscp: Vec<u8>,
+}
+
+impl QosClassMatcher {
+    fn new(config: &QosClassConfig) -> Result<Self, Error> {
+        let mut protocols = vec![];
+        for protocol in config.protocols.iter() {
+            protocols.extend_from_slice(parse_protocol(protocol)?);
+        }
+        Ok(QosClassMatcher {
+            protocols,
+            ports: config
+                .ports
+                .iter()
+                .map(|x| parse_port_range(x))
+                .collect::<Result<_, _>>()?,
+            dscp: config.dscp.clone(),
+        })
+    }
+
+    fn matches(&self, info: &PacketInfo) -> bool {
+        (self.protocols.is_empty() || self.protocols.contains(&info.protocol))
+            && (self.ports.is_empty()
+                || info.ports.is_some_and(|(src, dst)| {
+                    self.ports
+                        .iter()
+                        .any(|r| r.contains(&src) || r.contains(&dst))
+                }))
+            && (self.dscp.is_empty() || self.dscp.contains(&info.dscp))
+    }
+}
+
+struct QosClass {
+    matcher: QosClassMatcher,
+    quantum: u64,
+    queue: VecDeque<ZCPacket>,
+    deficit: u64,
+    bytes_tx: CounterHandle,
+    packets_tx: CounterHandle,
+    packets_dropped: CounterHandle,
+}
+
+impl QosClass {
+    fn new(global_ctx: &ArcGlobalCtx, name: &str, matcher: QosClassMatcher, weight: u32) -> Self {
+        let stats_mgr = global_ctx.stats_manager();
+        let labels = LabelSet::new()
+            .with_label_type(LabelType::NetworkName(global_ctx.get_network_name()))
+            .with_label_type(LabelType::QosClass(name.to_string()));
+        QosClass {
+            matcher,
+            quantum: QUANTUM * weight.max(1) as u64,
+            queue: VecDeque::new(),
+            deficit: 0,
+            bytes_tx: stats_mgr.get_counter(MetricName::QosBytesTx, labels.clone()),
+            packets_tx: stats_mgr.get_counter(MetricName::QosPacketsTx, labels.clone()),
+            packets_dropped: stats_mgr.get_counter(MetricName::QosPacketsDropped, labels),
+        }
+    }
+}
+
+// classes of the same priority, served by deficit round robin
+#[derive(Debug, Default)]
+struct QosLevel {
+    classes: Vec<usize>,
+    cursor: usize,
+}
+
+struct QosState {
+    classes: Vec<QosClass>,
+    levels: Vec<QosLevel>,
+}
+
+/// Queues the packets read from the virtual nic by class and hands them to the
+/// peer manager by strict priority between priorities and weighted fair
+/// queuing between the classes of a priority.
+pub struct QosScheduler {
+    queue_len: usize,
+    default_class: usize,
+    state: Mutex<QosState>,
+    notify: Notify,
+    shaper: Option<Arc<TokenBucket>>,
+}
+
+impl QosScheduler {
+    pub fn new(global_ctx: &ArcGlobalCtx, config: QosConfig) -> Result<Self, Error> {
+        let mut classes = vec![];
+        let mut priorities = BTreeMap::<u8, Vec<usize>>::new();
+        for class in config.class.iter() {
+            let matcher = QosClassMatcher::new(class)?;
+            let weight = class.weight.unwrap_or(1);
+            priorities
+                .entry(class.priority.unwrap_or(DEFAULT_PRIORITY))
+                .or_default()
+                .push(classes.len());
+            classes.push(QosClass::new(global_ctx, &class.name, matcher, weight));
+        }
+
+        let default_class = match config.class.iter().position(|x| x.name == DEFAULT_CLASS) {
+            Some(index) => index,
+            None => {
+                priorities
+                    .entry(DEFAULT_PRIORITY)
+                    .or_default()
+                    .push(classes.len());
+                classes.push(QosClass::new(
+                    global_ctx,
+                    DEFAULT_CLASS,
+                    QosClassMatcher::default(),
+                    1,
+                ));
+                classes.len() - 1
+            }
+        };
+
+        let shaper = config.bps_limit.map(|bps| {
+            let burst = (bps as u128 * SHAPER_BURST.as_millis() / 1000) as u64;
+            TokenBucket::new(burst.max(MAX_PACKET_SIZE), bps, Duration::from_millis(10))
+        });
+
+        Ok(QosScheduler {
+            queue_len: config.queue_len.unwrap_or(DEFAULT_QUEUE_LEN).max(1),
+            default_class,
+            state: Mutex::new(QosState {
+                classes,
+                levels: priorities
+                    .into_values()
+                    .map(|classes| QosLevel { classes, cursor: 0 })
+                    .collect(),
+            }),
+            notify: Notify::new(),
+            shaper,
+        })
+    }
+
+    fn classify(&self, classes: &[QosClass], packet: &ZCPacket) -> usize {
+        parse_packet(packet.payload())
+            .and_then(|info| classes.iter().position(|c| c.matcher.matches(&info)))
+            .unwrap_or(self.default_class)
+    }
+
+    /// Queue a packet, false if its class is full and it is dropped.
+    pub fn enqueue(&self, packet: ZCPacket) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let index = self.classify(&state.classes, &packet);
+        let class = &mut state.classes[index];
+        if class.queue.len() >= self.queue_len {
+            class.packets_dropped.inc();
+            return false;
+        }
+        class.queue.push_back(packet);
+        drop(state);
+        self.notify.notify_one();
+        true
+    }
+
+    fn try_dequeue(&self) -> Option<ZCPacket> {
+        let mut state = self.state.lock().unwrap();
+        let QosState { classes, levels } = &mut *state;
+        for level in levels.iter_mut() {
+            if level.classes.iter().all(|x| classes[*x].queue.is_empty()) {
+                continue;
+            }
+            loop {
+                let class = &mut classes[level.classes[level.cursor]];
+                let Some(len) = class.queue.front().map(|x| x.payload().len() as u64) else {
+                    class.deficit = 0;
+                    level.cursor = (level.cursor + 1) % level.classes.len();
+                    continue;
+                };
+                if len > class.deficit {
+                    class.deficit += class.quantum;
+                    level.cursor = (level.cursor + 1) % level.classes.len();
+                    continue;
+                }
+                class.deficit -= len;
+                class.bytes_tx.add(len);
+                class.packets_tx.inc();
+                return class.queue.pop_front();
+            }
+        }
+        None
+    }
+
+    async fn shape(&self, len: u64) {
+        let Some(shaper) = &self.shaper else {
+            return;
+        };
+        while !shaper.try_consume(len) {
+            tokio::time::sleep(shaper.wait_time(len)).await;
+        }
+    }
+
+    /// Next packet to send, waits for one to be queued and for the rate limit.
+    pub async fn dequeue(&self) -> ZCPacket {
+        loop {
+            if let Some(packet) = self.try_dequeue() {
+                self.shape(packet.payload().len() as u64).await;
+                return packet;
+            }
+            self.notify.notified().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::global_ctx::tests::get_mock_global_ctx;
+
+    fn udp_packet(dst_port: u16, dscp: u8, len: usize) -> ZCPacket {
+        let mut payload = vec![0u8; len];
+        payload[0] = 0x45;
+        payload[1] = dscp << 2;
+        payload[9] = 17;
+        payload[22..24].copy_from_slice(&dst_port.to_be_bytes());
+        ZCPacket::new_with_payload(&payload)
+    }
+
+    fn class(name: &str, ports: &[&str], priority: u8, weight: u32) -> QosClassConfig {
+        QosClassConfig {
+            name: name.to_string(),
+            ports: ports.iter().map(|x| x.to_string()).collect(),
+            priority: Some(priority),
+            weight: Some(weight),
+            ..Default::default()
+        }
+    }
+
+    fn dst_port(packet: &ZCPacket) -> u16 {
+        parse_packet(packet.payload()).unwrap().ports.unwrap().1
+    }
+
+    #[test]
+    fn qos_classify() {
+        assert_eq!(1000..=2000, parse_port_range("1000-2000").unwrap());
+        assert!(parse_port_range("2000-1000").is_err());
+        assert!(parse_protocol("sctp").is_err());
+
+        let voip = QosClassConfig {
+            name: "voip".to_string(),
+            protocols: vec!["udp".to_string()],
+            ports: vec!["5060".to_string(), "10000-20000".to_string()],
+            ..Default::default()
+        };
+        let ef = QosClassConfig {
+            name: "ef".to_string(),
+            dscp: vec![46],
+            ..Default::default()
+        };
+        let scheduler = QosScheduler::new(
+            &get_mock_global_ctx(),
+            QosConfig {
+                class: vec![voip, ef],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let state = scheduler.state.lock().unwrap();
+        let classify = |packet| scheduler.classify(&state.classes, &packet);
+        assert_eq!(0, classify(udp_packet(5060, 0, 100)));
+        assert_eq!(0, classify(udp_packet(15000, 46, 100)));
+        assert_eq!(1, classify(udp_packet(443, 46, 100)));
+        assert_eq!(2, classify(udp_packet(443, 0, 100)));
+        assert_eq!(2, classify(ZCPacket::new_with_payload(&[0u8; 10])));
+    }
+
+    #[tokio::test]
+    async fn qos_strict_priority_and_weights() {
+        let scheduler = QosScheduler::new(
+            &get_mock_global_ctx(),
+            QosConfig {
+                queue_len: Some(40),
+                class: vec![
+                    class("voip", &["5060"], 0, 1),
+                    class("web", &["443"], 2, 3),
+                    class("bulk", &["445"], 2, 1),
+                ],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        for _ in 0..40 {
+            assert!(scheduler.enqueue(udp_packet(445, 0, 1000)));
+            assert!(scheduler.enqueue(udp_packet(443, 0, 1000)));
+        }
+        assert!(!scheduler.enqueue(udp_packet(445, 0, 1000)));
+        assert!(scheduler.enqueue(udp_packet(5060, 0, 200)));
+
+        assert_eq!(5060, dst_port(&scheduler.dequeue().await));
+        let mut web = 0;
+        for _ in 0..40 {
+            if dst_port(&scheduler.dequeue().await) == 443 {
+                web += 1;
+            }
+        }
+        assert!((28..=32).contains(&web), "web: {}", web);
+
+        let state = scheduler.state.lock().unwrap();
+        assert_eq!(1, state.classes[0].packets_tx.get());
+        assert_eq!(1, state.classes[2].packets_dropped.get());
+    }
+}
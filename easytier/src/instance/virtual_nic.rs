@@ -9,11 +9,16 @@ use std::{
 
 use crate::{
     common::{
+        config::QosConfig,
         error::Error,
         global_ctx::{ArcGlobalCtx, GlobalCtxEvent},
         ifcfg::{IfConfiger, IfConfiguerTrait},
     },
-    peers::{peer_manager::PeerManager, recv_packet_from_chan, PacketRecvChanReceiver},
+    instance::qos::QosScheduler,
+    peers::{
+        encrypt::session_key::SESSION_ENCRYPTION_RESERVED, peer_manager::PeerManager,
+        recv_packet_from_chan, PacketRecvChanReceiver,
+    },
     tunnel::{
         common::{reserve_buf, FramedWriter, TunnelWrapper, ZCPacketToBytes},
         packet_def::{ZCPacket, ZCPacketType, TAIL_RESERVED_SIZE},
@@ -508,6 +513,12 @@ impl VirtualNic {
         let mut mtu_in_config = flags.mtu;
         if flags.enable_encryption {
             mtu_in_config -= 20;
+            // packets of session keyed connections are encrypted twice. the
+            // path mtu probed per connection covers that, without probing the
+            // tun mtu has to leave room for it
+            if flags.disable_pmtu_discovery {
+                mtu_in_config -= SESSION_ENCRYPTION_RESERVED as u32;
+            }
         }
         {
             // set mtu by ourselves, rust-tun does not handle it correctly on windows
@@ -753,6 +764,9 @@ impl NicCtx {
         let Some(mgr) = self.peer_mgr.upgrade() else {
             return Err(anyhow::anyhow!("peer manager not available").into());
         };
+        if let Some(qos) = self.global_ctx.config.get_qos() {
+            return self.do_forward_nic_to_peers_with_qos(stream, mgr, qos);
+        }
         self.tasks.spawn(async move {
             while let Some(ret) = stream.next().await {
                 if ret.is_err() {
@@ -767,6 +781,42 @@ impl NicCtx {
         Ok(())
     }
 
+    // packets wait in the queue of their class while the peer manager is busy,
+    // so urgent classes overtake a backlog of bulk traffic
+    fn do_forward_nic_to_peers_with_qos(
+        &mut self,
+        mut stream: Pin<Box<dyn ZCPacketStream>>,
+        mgr: Arc<PeerManager>,
+        qos: QosConfig,
+    ) -> Result<(), Error> {
+        let scheduler = Arc::new(QosScheduler::new(&self.global_ctx, qos)?);
+        let enqueue_scheduler = scheduler.clone();
+        self.tasks.spawn(async move {
+            while let Some(ret) = stream.next().await {
+                match ret {
+                    Ok(packet) => {
+                        if !enqueue_scheduler.enqueue(packet) {
+                            tracing::trace!("[USER_PACKET] qos queue full, drop packet");
+                        }
+                    }
+                    Err(e) => {
+                        tracing::error!("read from nic failed: {:?}", e);
+                        break;
+                    }
+                }
+            }
+            panic!("nic stream closed");
+        });
+        self.tasks.spawn(async move {
+            loop {
+                let packet = scheduler.dequeue().await;
+                Self::do_forward_nic_to_peers(packet, mgr.as_ref()).await;
+            }
+        });
+
+        Ok(())
+    }
+
     fn do_forward_peers_to_nic(&mut self, mut sink: Pin<Box<dyn ZCPacketSink>>) {
         let channel = self.peer_packet_receiver.clone();
         self.tasks.spawn(async move {
@@ -803,7 +853,7 @@ impl NicCtx {
                 let routes = peer_mgr.list_routes().await;
                 for r in routes {
                     for cidr in r.proxy_cidrs {
-                        let Ok(cidr) = cidr.parse::<cidr::Ipv4Cidr>() else {
+                        let Ok(cidr) = cidr.parse::<cidr::IpCidr>() else {
                             continue;
                         };
                         proxy_cidrs.insert(cidr);
@@ -811,12 +861,18 @@ impl NicCtx {
                 }
                 // add vpn portal cidr to proxy_cidrs
                 if let Some(vpn_cfg) = global_ctx.config.get_vpn_portal_config() {
-                    proxy_cidrs.insert(vpn_cfg.client_cidr);
+                    proxy_cidrs.insert(vpn_cfg.client_cidr.into());
                 }
 
                 if let Some(routes) = global_ctx.config.get_routes() {
-                    // if has manual routes, just override entire proxy_cidrs
-                    proxy_cidrs = routes.into_iter().collect();
+                    // manual routes override the proxy_cidrs of the same address family,
+                    // an empty list disables all of them.
+                    let override_v4 = routes.is_empty() || routes.iter().any(|r| r.is_ipv4());
+                    let override_v6 = routes.is_empty() || routes.iter().any(|r| r.is_ipv6());
+                    proxy_cidrs.retain(|c: &cidr::IpCidr| {
+                        !(c.is_ipv4() && override_v4 || c.is_ipv6() && override_v6)
+                    });
+                    proxy_cidrs.extend(routes);
                 }
 
                 // if route is in cur_proxy_cidrs but not in proxy_cidrs, delete it.
@@ -826,13 +882,26 @@ impl NicCtx {
                     }
 
                     let _g = net_ns.guard();
-                    let ret = ifcfg
-                        .remove_ipv4_route(
-                            ifname.as_str(),
-                            cidr.first_address(),
-                            cidr.network_length(),
-                        )
-                        .await;
+                    let ret = match cidr {
+                        cidr::IpCidr::V4(cidr) => {
+                            ifcfg
+                                .remove_ipv4_route(
+                                    ifname.as_str(),
+                                    cidr.first_address(),
+                                    cidr.network_length(),
+                                )
+                                .await
+                        }
+                        cidr::IpCidr::V6(cidr) => {
+                            ifcfg
+                                .remove_ipv6_route(
+                                    ifname.as_str(),
+                                    cidr.first_address(),
+                                    cidr.network_length(),
+                                )
+                                .await
+                        }
+                    };
 
                     if ret.is_err() {
                         tracing::trace!(
@@ -848,14 +917,28 @@ impl NicCtx {
                         continue;
                     }
                     let _g = net_ns.guard();
-                    let ret = ifcfg
-                        .add_ipv4_route(
-                            ifname.as_str(),
-                            cidr.first_address(),
-                            cidr.network_length(),
-                            None,
-                        )
-                        .await;
+                    let ret = match cidr {
+                        cidr::IpCidr::V4(cidr) => {
+                            ifcfg
+                                .add_ipv4_route(
+                                    ifname.as_str(),
+                                    cidr.first_address(),
+                                    cidr.network_length(),
+                                    None,
+                                )
+                                .await
+                        }
+                        cidr::IpCidr::V6(cidr) => {
+                            ifcfg
+                                .add_ipv6_route(
+                                    ifname.as_str(),
+                                    cidr.first_address(),
+                                    cidr.network_length(),
+                                    None,
+                                )
+                                .await
+                        }
+                    };
 
                     if ret.is_err() {
                         tracing::trace!(
@@ -306,6 +306,35 @@ fn handle_event(
                             ),
                         );
                     }
+
+                    GlobalCtxEvent::ForeignNetworkRejected(network_name, reason) => {
+                        print_event(
+                            instance_id,
+                            format!(
+                                "foreign network rejected. network: {}, reason: {}",
+                                network_name, reason
+                            ),
+                        );
+                    }
+
+                    GlobalCtxEvent::ExitNodeHealthChanged(exit_node, healthy, reason) => {
+                        print_event(
+                            instance_id,
+                            format!(
+                                "exit node {} is {}. reason: {}",
+                                exit_node,
+                                if healthy { "healthy" } else { "unhealthy" },
+                                reason
+                            ),
+                        );
+                    }
+
+                    GlobalCtxEvent::ExitNodeSwitched(old, new) => {
+                        print_event(
+                            instance_id,
+                            format!("exit node switched. old: {:?}, new: {:?}", old, new),
+                        );
+                    }
                 }
             } else {
                 events = events.resubscribe();
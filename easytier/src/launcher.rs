@@ -561,6 +561,7 @@ impl NetworkConfig {
                     uri: public_server_url.parse().with_context(|| {
                         format!("failed to parse public server uri: {}", public_server_url)
                     })?,
+                    proxy: None,
                 }]);
             }
             NetworkingMethod::Manual => {
@@ -573,6 +574,7 @@ impl NetworkConfig {
                         uri: peer_url
                             .parse()
                             .with_context(|| format!("failed to parse peer uri: {}", peer_url))?,
+                        proxy: None,
                     });
                 }
 
@@ -669,7 +671,7 @@ impl NetworkConfig {
         }
 
         if self.enable_manual_routes.unwrap_or_default() {
-            let mut routes = Vec::<cidr::Ipv4Cidr>::with_capacity(self.routes.len());
+            let mut routes = Vec::<cidr::IpCidr>::with_capacity(self.routes.len());
             for route in self.routes.iter() {
                 routes.push(
                     route
@@ -1034,7 +1036,7 @@ mod tests {
                 let uri = format!("{}://127.0.0.1:{}", protocol, port)
                     .parse()
                     .unwrap();
-                peers.push(crate::common::config::PeerConfig { uri });
+                peers.push(crate::common::config::PeerConfig { uri, proxy: None });
             }
             config.set_peers(peers);
 
@@ -1077,7 +1079,9 @@ mod tests {
                     } else {
                         None
                     };
-                    config.add_proxy_cidr(network, mapped_network).unwrap();
+                    config
+                        .add_proxy_cidr(network.into(), mapped_network.map(Into::into))
+                        .unwrap();
                 }
             }
 
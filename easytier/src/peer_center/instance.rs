@@ -395,6 +395,12 @@ impl PeerCenterInstance {
             fn need_update(&self) -> bool {
                 self.last_update_time.load() < self.global_peer_map_update_time.load()
             }
+
+            // the center collects the latency of each link, so it fills in
+            // links without a live metric in the same unit.
+            fn cost_in_ms(&self) -> bool {
+                true
+            }
         }
 
         Box::new(RouteCostCalculatorImpl {
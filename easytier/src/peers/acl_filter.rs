@@ -1,30 +1,41 @@
 use std::net::{Ipv4Addr, Ipv6Addr};
 use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
 use std::{
     net::IpAddr,
     sync::{atomic::AtomicBool, Arc},
 };
 
 use arc_swap::ArcSwap;
+use dashmap::DashMap;
 use pnet::packet::ipv6::Ipv6Packet;
 use pnet::packet::{
     ip::IpNextHeaderProtocols, ipv4::Ipv4Packet, tcp::TcpPacket, udp::UdpPacket, Packet as _,
 };
 
+use crate::common::PeerId;
 use crate::proto::acl::{AclStats, Protocol};
+use crate::proto::peer_rpc::RoutePeerInfo;
 use crate::tunnel::packet_def::PacketType;
 use crate::{
-    common::acl_processor::{AclProcessor, AclResult, AclStatKey, AclStatType, PacketInfo},
+    common::acl_processor::{
+        AclProcessor, AclResult, AclStatKey, AclStatType, PacketInfo, PeerIdentity,
+    },
     proto::acl::{Acl, Action, ChainType},
     tunnel::packet_def::ZCPacket,
 };
 
+// how long a resolved peer identity is trusted before asking the route again
+const PEER_IDENTITY_TTL: Duration = Duration::from_secs(10);
+
 /// ACL filter that can be inserted into the packet processing pipeline
 /// Optimized with lock-free hot reloading via atomic processor replacement
 pub struct AclFilter {
     // Use ArcSwap for lock-free atomic replacement during hot reload
     acl_processor: ArcSwap<AclProcessor>,
     acl_enabled: Arc<AtomicBool>,
+    // identities of source peers, only filled when some rule matches on them
+    peer_identities: DashMap<PeerId, (Instant, Arc<PeerIdentity>)>,
 }
 
 impl Default for AclFilter {
@@ -38,6 +49,7 @@ impl AclFilter {
         Self {
             acl_processor: ArcSwap::from(Arc::new(AclProcessor::new(Acl::default()))),
             acl_enabled: Arc::new(AtomicBool::new(false)),
+            peer_identities: DashMap::new(),
         }
     }
 
@@ -74,6 +86,60 @@ impl AclFilter {
         self.acl_processor.load_full()
     }
 
+    /// Whether the caller should resolve the identity of this peer (with
+    /// `update_peer_identity`) before passing its packets to the filter
+    pub fn need_peer_identity(&self, peer_id: PeerId) -> bool {
+        if !self.acl_enabled.load(Ordering::Relaxed) || !self.get_processor().has_peer_rules() {
+            return false;
+        }
+        self.peer_identities
+            .get(&peer_id)
+            .map(|x| x.0.elapsed() > PEER_IDENTITY_TTL)
+            .unwrap_or(true)
+    }
+
+    pub fn update_peer_identity(&self, peer_id: PeerId, info: Option<&RoutePeerInfo>) {
+        let identity = PeerIdentity {
+            peer_id,
+            inst_id: info
+                .and_then(|x| x.inst_id)
+                .map(|x| x.to_string())
+                .unwrap_or_default(),
+            hostname: info.and_then(|x| x.hostname.clone()).unwrap_or_default(),
+            tags: info.map(|x| x.tags.clone()).unwrap_or_default(),
+        };
+
+        let changed = self
+            .peer_identities
+            .get(&peer_id)
+            .map(|x| *x.1 != identity)
+            .unwrap_or(false);
+        self.peer_identities
+            .insert(peer_id, (Instant::now(), Arc::new(identity)));
+
+        // cached decisions were made with the old identity
+        if changed {
+            self.get_processor().flush_rule_cache();
+        }
+    }
+
+    /// Forget the identities of peers that left the network
+    pub fn retain_peer_identities(&self, is_alive: impl Fn(PeerId) -> bool) {
+        self.peer_identities.retain(|peer_id, _| is_alive(*peer_id));
+    }
+
+    fn get_peer_identity(&self, peer_id: PeerId) -> Arc<PeerIdentity> {
+        self.peer_identities
+            .get(&peer_id)
+            .map(|x| x.1.clone())
+            .unwrap_or_else(|| {
+                Arc::new(PeerIdentity {
+                    peer_id,
+                    ..Default::default()
+                })
+            })
+    }
+
     pub fn get_stats(&self) -> AclStats {
         let processor = self.get_processor();
         let global_stats = processor.get_stats();
@@ -162,6 +228,7 @@ impl AclFilter {
             dst_port,
             protocol: acl_protocol,
             packet_size: payload.len(),
+            src_peer: None,
         })
     }
 
@@ -243,7 +310,7 @@ impl AclFilter {
         }
 
         // Extract packet information
-        let packet_info = match self.extract_packet_info(packet) {
+        let mut packet_info = match self.extract_packet_info(packet) {
             Some(info) => info,
             None => {
                 tracing::warn!(
@@ -271,6 +338,11 @@ impl AclFilter {
         // Get current processor atomically
         let processor = self.get_processor();
 
+        if is_in && processor.has_peer_rules() {
+            let from_peer_id = packet.peer_manager_header().unwrap().from_peer_id.get();
+            packet_info.src_peer = Some(self.get_peer_identity(from_peer_id));
+        }
+
         // Process through ACL rules
         let acl_result = processor.process_packet(&packet_info, chain_type);
 
@@ -0,0 +1,257 @@
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+
+use crate::{
+    common::{
+        compressor::{
+            flow_key, load_zstd_dict, AdaptiveCompression, Compressor as _, DefaultCompressor,
+        },
+        global_ctx::ArcGlobalCtx,
+        stats_manager::{CounterHandle, LabelSet, LabelType, MetricName},
+        PeerId,
+    },
+    proto::{common::CompressionAlgoPb, peer_rpc::RoutePeerInfo},
+    tunnel::packet_def::{CompressorAlgo, ZCPacket},
+};
+
+use super::peer_map::PeerMap;
+
+const NEGOTIATED_ALGO_TTL: Duration = Duration::from_secs(10);
+const NEGOTIATED_ALGO_GC_THRESHOLD: usize = 1024;
+
+/// Load the zstd dictionary configured in the flags, returns 0 if there is
+/// none or it cannot be loaded.
+pub fn local_zstd_dict_id(global_ctx: &ArcGlobalCtx) -> u32 {
+    let path = global_ctx.get_flags().data_compress_dict;
+    if path.is_empty() {
+        return 0;
+    }
+    match load_zstd_dict(&path) {
+        Ok(dict_id) => dict_id,
+        Err(e) => {
+            tracing::error!(?e, ?path, "load zstd dictionary failed");
+            0
+        }
+    }
+}
+
+/// Algorithms this node can decompress, advertised in `RoutePeerInfo`.
+pub fn supported_compress_algos(dict_id: u32) -> Vec<i32> {
+    let mut algos = vec![
+        CompressionAlgoPb::None as i32,
+        CompressionAlgoPb::Zstd as i32,
+        CompressionAlgoPb::Lz4 as i32,
+    ];
+    if dict_id != 0 {
+        algos.push(CompressionAlgoPb::ZstdDict as i32);
+    }
+    algos
+}
+
+/// Picks the data compression algorithm per destination peer. The configured
+/// algorithm is only used if the peer advertises it (and the same dictionary
+/// for zstd-dict), otherwise zstd is used, which every version can decompress.
+/// In adaptive mode flows that do not shrink are sent uncompressed.
+pub struct DataCompressNegotiator {
+    algo: CompressorAlgo,
+    level: i32,
+    dict_id: u32,
+    negotiated: DashMap<PeerId, (CompressorAlgo, Instant)>,
+
+    adaptive: Option<AdaptiveCompression>,
+    saved_bytes: CounterHandle,
+    skipped_packets: CounterHandle,
+}
+
+impl DataCompressNegotiator {
+    pub fn new(global_ctx: &ArcGlobalCtx) -> Self {
+        let flags = global_ctx.get_flags();
+        let mut algo: CompressorAlgo = flags
+            .data_compress_algo()
+            .try_into()
+            .expect("invalid data compress algo, maybe some features not enabled");
+        let dict_id = local_zstd_dict_id(global_ctx);
+        if algo == CompressorAlgo::ZstdDict && dict_id == 0 {
+            tracing::warn!("zstd-dict compression without a dictionary, use zstd instead");
+            algo = CompressorAlgo::ZstdDefault;
+        }
+
+        let stats_mgr = global_ctx.stats_manager();
+        let label_set =
+            LabelSet::new().with_label_type(LabelType::NetworkName(global_ctx.get_network_name()));
+
+        Self {
+            algo,
+            level: flags.data_compress_level,
+            dict_id,
+            negotiated: DashMap::new(),
+
+            adaptive: flags.data_compress_adaptive.then(AdaptiveCompression::new),
+            saved_bytes: stats_mgr
+                .get_counter(MetricName::CompressionBytesSaved, label_set.clone()),
+            skipped_packets: stats_mgr
+                .get_counter(MetricName::CompressionPacketsSkipped, label_set),
+        }
+    }
+
+    pub async fn compress(
+        &self,
+        msg: &mut ZCPacket,
+        compress_algo: CompressorAlgo,
+    ) -> Result<(), anyhow::Error> {
+        if compress_algo == CompressorAlgo::None {
+            return Ok(());
+        }
+
+        let flow = self.adaptive.as_ref().and_then(|_| flow_key(msg));
+        if let (Some(adaptive), Some(flow)) = (&self.adaptive, flow) {
+            if !adaptive.should_compress(flow) {
+                self.skipped_packets.inc();
+                return Ok(());
+            }
+        }
+
+        let before = msg.payload_len();
+        self.compressor().compress(msg, compress_algo).await?;
+        let after = msg.payload_len();
+
+        if after < before {
+            self.saved_bytes.add((before - after) as u64);
+        }
+        if let (Some(adaptive), Some(flow)) = (&self.adaptive, flow) {
+            adaptive.record(flow, before, after.min(before));
+        }
+        Ok(())
+    }
+
+    pub fn compressor(&self) -> DefaultCompressor {
+        DefaultCompressor::new()
+            .with_level(self.level)
+            .with_dict(self.dict_id)
+    }
+
+    fn select(&self, info: Option<&RoutePeerInfo>) -> CompressorAlgo {
+        let Some(info) = info else {
+            return CompressorAlgo::ZstdDefault;
+        };
+        let Ok(algo) = CompressionAlgoPb::try_from(self.algo) else {
+            return CompressorAlgo::ZstdDefault;
+        };
+        let supported = info.supported_compress_algos.contains(&(algo as i32));
+        if supported
+            && (self.algo != CompressorAlgo::ZstdDict || info.compress_dict_id == self.dict_id)
+        {
+            self.algo
+        } else {
+            CompressorAlgo::ZstdDefault
+        }
+    }
+
+    pub async fn negotiate(&self, peers: &PeerMap, dst_peer_id: PeerId) -> CompressorAlgo {
+        // every version supports these, no need to look at the peer
+        if matches!(
+            self.algo,
+            CompressorAlgo::None | CompressorAlgo::ZstdDefault
+        ) {
+            return self.algo;
+        }
+
+        if let Some(entry) = self.negotiated.get(&dst_peer_id) {
+            if entry.1.elapsed() < NEGOTIATED_ALGO_TTL {
+                return entry.0;
+            }
+        }
+
+        let info = peers.get_route_peer_info(dst_peer_id).await;
+        let algo = self.select(info.as_ref());
+        if self.negotiated.len() > NEGOTIATED_ALGO_GC_THRESHOLD {
+            self.negotiated
+                .retain(|_, (_, updated)| updated.elapsed() < NEGOTIATED_ALGO_TTL);
+        }
+        self.negotiated.insert(dst_peer_id, (algo, Instant::now()));
+        algo
+    }
+
+    /// Negotiate for a packet sent to multiple peers, falls back to zstd if
+    /// they do not agree.
+    pub async fn negotiate_many(&self, peers: &PeerMap, dst_peer_ids: &[PeerId]) -> CompressorAlgo {
+        let mut ret = None;
+        for dst_peer_id in dst_peer_ids {
+            let algo = self.negotiate(peers, *dst_peer_id).await;
+            match ret {
+                None => ret = Some(algo),
+                Some(prev) if prev != algo => return CompressorAlgo::ZstdDefault,
+                _ => {}
+            }
+        }
+        ret.unwrap_or(self.algo)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::global_ctx::tests::get_mock_global_ctx;
+
+    use super::*;
+
+    fn negotiator(algo: CompressorAlgo, dict_id: u32) -> DataCompressNegotiator {
+        let mut negotiator = DataCompressNegotiator::new(&get_mock_global_ctx());
+        negotiator.algo = algo;
+        negotiator.dict_id = dict_id;
+        negotiator
+    }
+
+    #[test]
+    fn select_compress_algo() {
+        let old_peer = RoutePeerInfo::default();
+        let new_peer = RoutePeerInfo {
+            supported_compress_algos: supported_compress_algos(0),
+            ..Default::default()
+        };
+        let dict_peer = RoutePeerInfo {
+            supported_compress_algos: supported_compress_algos(42),
+            compress_dict_id: 42,
+            ..Default::default()
+        };
+
+        let lz4 = negotiator(CompressorAlgo::Lz4, 0);
+        assert_eq!(lz4.select(None), CompressorAlgo::ZstdDefault);
+        assert_eq!(lz4.select(Some(&old_peer)), CompressorAlgo::ZstdDefault);
+        assert_eq!(lz4.select(Some(&new_peer)), CompressorAlgo::Lz4);
+
+        let dict = negotiator(CompressorAlgo::ZstdDict, 42);
+        assert_eq!(dict.select(Some(&new_peer)), CompressorAlgo::ZstdDefault);
+        assert_eq!(dict.select(Some(&dict_peer)), CompressorAlgo::ZstdDict);
+
+        let other_dict = negotiator(CompressorAlgo::ZstdDict, 7);
+        assert_eq!(
+            other_dict.select(Some(&dict_peer)),
+            CompressorAlgo::ZstdDefault
+        );
+    }
+
+    #[tokio::test]
+    async fn adaptive_compress_skips_incompressible_flow() {
+        let mut negotiator = negotiator(CompressorAlgo::ZstdDefault, 0);
+        negotiator.adaptive = Some(AdaptiveCompression::new());
+
+        let mut payload = vec![0u8; 1024];
+        payload[0] = 0x45;
+        payload[9] = 17;
+        // random bytes after the headers never compress
+        rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut payload[28..]);
+
+        for _ in 0..32 {
+            let mut packet = ZCPacket::new_with_payload(&payload);
+            packet.fill_peer_manager_hdr(0, 0, 0);
+            negotiator
+                .compress(&mut packet, CompressorAlgo::ZstdDefault)
+                .await
+                .unwrap();
+            assert!(!packet.peer_manager_header().unwrap().is_compressed());
+        }
+        assert!(negotiator.skipped_packets.get() > 0);
+        assert_eq!(negotiator.saved_bytes.get(), 0);
+    }
+}
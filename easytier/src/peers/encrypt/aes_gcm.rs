@@ -1,16 +1,18 @@
+use std::sync::Arc;
+
 use aes_gcm::aead::consts::{U12, U16};
 use aes_gcm::aead::generic_array::GenericArray;
-use aes_gcm::{AeadCore, AeadInPlace, Aes128Gcm, Aes256Gcm, Key, KeyInit, Nonce, Tag};
-use rand::rngs::OsRng;
+use aes_gcm::{AeadInPlace, Aes128Gcm, Aes256Gcm, Key, KeyInit, Nonce, Tag};
 use zerocopy::{AsBytes, FromBytes};
 
 use crate::tunnel::packet_def::{AesGcmTail, ZCPacket, AES_GCM_ENCRYPTION_RESERVED};
 
-use super::{Encryptor, Error};
+use super::{Encryptor, Error, NonceCounter};
 
 #[derive(Clone)]
 pub struct AesGcmCipher {
     pub(crate) cipher: AesGcmEnum,
+    nonce_counter: Arc<NonceCounter>,
 }
 
 #[derive(Clone)]
@@ -24,12 +26,14 @@ impl AesGcmCipher {
         let key: &Key<Aes128Gcm> = &key.into();
         Self {
             cipher: AesGcmEnum::AES128GCM(Box::new(Aes128Gcm::new(key))),
+            nonce_counter: Default::default(),
         }
     }
     pub fn new_256(key: [u8; 32]) -> Self {
         let key: &Key<Aes256Gcm> = &key.into();
         Self {
             cipher: AesGcmEnum::AES256GCM(Box::new(Aes256Gcm::new(key))),
+            nonce_counter: Default::default(),
         }
     }
 }
@@ -91,16 +95,16 @@ impl Encryptor for AesGcmCipher {
         }
 
         let mut tail = AesGcmTail::default();
+        self.nonce_counter
+            .fill_sequence_nonce(zc_packet, &mut tail.nonce);
+        let nonce_bytes = tail.nonce;
+        let nonce: &GenericArray<u8, U12> = Nonce::from_slice(&nonce_bytes);
         let rs = match &self.cipher {
             AesGcmEnum::AES128GCM(aes_gcm) => {
-                let nonce = Aes128Gcm::generate_nonce(&mut OsRng);
-                tail.nonce.copy_from_slice(nonce.as_slice());
-                aes_gcm.encrypt_in_place_detached(&nonce, &[], zc_packet.mut_payload())
+                aes_gcm.encrypt_in_place_detached(nonce, &[], zc_packet.mut_payload())
             }
             AesGcmEnum::AES256GCM(aes_gcm) => {
-                let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-                tail.nonce.copy_from_slice(nonce.as_slice());
-                aes_gcm.encrypt_in_place_detached(&nonce, &[], zc_packet.mut_payload())
+                aes_gcm.encrypt_in_place_detached(nonce, &[], zc_packet.mut_payload())
             }
         };
 
@@ -116,6 +120,14 @@ impl Encryptor for AesGcmCipher {
             Err(_) => Err(Error::EncryptionFailed),
         }
     }
+
+    fn nonce(&self, zc_packet: &ZCPacket) -> Option<[u8; 12]> {
+        let pm_header = zc_packet.peer_manager_header()?;
+        if !pm_header.is_encrypted() || zc_packet.payload().len() < AES_GCM_ENCRYPTION_RESERVED {
+            return None;
+        }
+        AesGcmTail::ref_from_suffix(zc_packet.payload()).map(|tail| tail.nonce)
+    }
 }
 
 #[cfg(test)]
@@ -1,6 +1,9 @@
 use std::sync::Arc;
 
-use crate::{common::config::EncryptionAlgorithm, tunnel::packet_def::ZCPacket};
+use crate::{
+    common::{config::EncryptionAlgorithm, PeerId},
+    tunnel::packet_def::ZCPacket,
+};
 
 #[cfg(feature = "wireguard")]
 pub mod ring_aes_gcm;
@@ -14,6 +17,8 @@ pub mod aes_gcm;
 #[cfg(feature = "openssl-crypto")]
 pub mod openssl_cipher;
 
+pub mod replay_window;
+pub mod session_key;
 pub mod xor_cipher;
 
 #[derive(thiserror::Error, Debug)]
@@ -26,11 +31,57 @@ pub enum Error {
     EncryptionFailed,
     #[error("invalid tag. tag: {0:?}")]
     InvalidTag(Vec<u8>),
+    #[error("session key exchange failed")]
+    KeyExchangeFailed,
+    #[error("replayed packet")]
+    ReplayDetected,
 }
 
 pub trait Encryptor: Send + Sync + 'static {
     fn encrypt(&self, zc_packet: &mut ZCPacket) -> Result<(), Error>;
     fn decrypt(&self, zc_packet: &mut ZCPacket) -> Result<(), Error>;
+
+    /// Nonce of an encrypted packet, read before decryption for replay
+    /// detection. None if the cipher has no nonce.
+    fn nonce(&self, _zc_packet: &ZCPacket) -> Option<[u8; 12]> {
+        None
+    }
+}
+
+/// Nonce counter of a cipher keyed by the network key, shared by its clones.
+#[derive(Debug)]
+pub struct NonceCounter(atomic_shim::AtomicU64);
+
+impl Default for NonceCounter {
+    // starts from the current time so nonces keep increasing across restarts
+    fn default() -> Self {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        Self(atomic_shim::AtomicU64::new(now.as_nanos() as u64))
+    }
+}
+
+impl NonceCounter {
+    /// Fill the nonce of a packet encrypted with the network key: the sender
+    /// peer id followed by an increasing counter. The nonce is unique as long
+    /// as peer ids are, and lets the receiver run a replay window per sender.
+    pub fn fill_sequence_nonce(&self, zc_packet: &ZCPacket, nonce: &mut [u8]) {
+        let from_peer_id = zc_packet.peer_manager_header().unwrap().from_peer_id.get();
+        let counter = self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        nonce[..4].copy_from_slice(&from_peer_id.to_be_bytes());
+        nonce[4..12].copy_from_slice(&counter.to_be_bytes());
+    }
+}
+
+pub fn parse_sequence_nonce(nonce: &[u8]) -> Option<(PeerId, u64)> {
+    if nonce.len() < 12 {
+        return None;
+    }
+    Some((
+        PeerId::from_be_bytes(nonce[..4].try_into().unwrap()),
+        u64::from_be_bytes(nonce[4..12].try_into().unwrap()),
+    ))
 }
 
 pub struct NullCipher;
@@ -1,10 +1,11 @@
+use std::sync::Arc;
+
 use openssl::symm::{Cipher, Crypter, Mode};
-use rand::RngCore;
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
 use crate::tunnel::packet_def::ZCPacket;
 
-use crate::peers::encrypt::{Encryptor, Error};
+use crate::peers::encrypt::{Encryptor, Error, NonceCounter};
 
 // OpenSSL 加密尾部结构
 #[repr(C, packed)]
@@ -18,6 +19,7 @@ pub const OPENSSL_ENCRYPTION_RESERVED: usize = std::mem::size_of::<OpenSslTail>(
 #[derive(Clone)]
 pub struct OpenSslCipher {
     pub(crate) cipher: OpenSslEnum,
+    nonce_counter: Arc<NonceCounter>,
 }
 
 pub enum OpenSslEnum {
@@ -40,18 +42,21 @@ impl OpenSslCipher {
     pub fn new_aes128_gcm(key: [u8; 16]) -> Self {
         Self {
             cipher: OpenSslEnum::Aes128Gcm(key),
+            nonce_counter: Default::default(),
         }
     }
 
     pub fn new_aes256_gcm(key: [u8; 32]) -> Self {
         Self {
             cipher: OpenSslEnum::Aes256Gcm(key),
+            nonce_counter: Default::default(),
         }
     }
 
     pub fn new_chacha20(key: [u8; 32]) -> Self {
         Self {
             cipher: OpenSslEnum::Chacha20(key),
+            nonce_counter: Default::default(),
         }
     }
 
@@ -153,7 +158,8 @@ impl Encryptor for OpenSslCipher {
         let nonce_size = self.get_nonce_size();
 
         let mut tail = OpenSslTail::default();
-        rand::thread_rng().fill_bytes(&mut tail.nonce[..nonce_size]);
+        self.nonce_counter
+            .fill_sequence_nonce(zc_packet, &mut tail.nonce[..nonce_size]);
 
         let mut encrypter =
             Crypter::new(cipher, Mode::Encrypt, key, Some(&tail.nonce[..nonce_size]))
@@ -190,6 +196,15 @@ impl Encryptor for OpenSslCipher {
 
         Ok(())
     }
+
+    fn nonce(&self, zc_packet: &ZCPacket) -> Option<[u8; 12]> {
+        let pm_header = zc_packet.peer_manager_header()?;
+        if !pm_header.is_encrypted() || zc_packet.payload().len() < OPENSSL_ENCRYPTION_RESERVED {
+            return None;
+        }
+        let tail = OpenSslTail::ref_from_suffix(zc_packet.payload())?;
+        tail.nonce[..12].try_into().ok()
+    }
 }
 
 #[cfg(test)]
@@ -1,15 +1,17 @@
-use rand::RngCore;
+use std::sync::Arc;
+
 use ring::aead::{self};
 use ring::aead::{LessSafeKey, UnboundKey};
 use zerocopy::{AsBytes, FromBytes};
 
 use crate::tunnel::packet_def::{AesGcmTail, ZCPacket, AES_GCM_ENCRYPTION_RESERVED};
 
-use super::{Encryptor, Error};
+use super::{Encryptor, Error, NonceCounter};
 
 #[derive(Clone)]
 pub struct AesGcmCipher {
     pub(crate) cipher: AesGcmEnum,
+    nonce_counter: Arc<NonceCounter>,
 }
 
 pub enum AesGcmEnum {
@@ -39,6 +41,7 @@ impl AesGcmCipher {
         let cipher = LessSafeKey::new(UnboundKey::new(&aead::AES_128_GCM, &key).unwrap());
         Self {
             cipher: AesGcmEnum::AesGCM128(cipher, key),
+            nonce_counter: Default::default(),
         }
     }
 
@@ -46,6 +49,7 @@ impl AesGcmCipher {
         let cipher = LessSafeKey::new(UnboundKey::new(&aead::AES_256_GCM, &key).unwrap());
         Self {
             cipher: AesGcmEnum::AesGCM256(cipher, key),
+            nonce_counter: Default::default(),
         }
     }
 }
@@ -100,7 +104,8 @@ impl Encryptor for AesGcmCipher {
         }
 
         let mut tail = AesGcmTail::default();
-        rand::thread_rng().fill_bytes(&mut tail.nonce);
+        self.nonce_counter
+            .fill_sequence_nonce(zc_packet, &mut tail.nonce);
         let nonce = aead::Nonce::assume_unique_for_key(tail.nonce);
 
         let rs = match &self.cipher {
@@ -131,6 +136,14 @@ impl Encryptor for AesGcmCipher {
             Err(_) => Err(Error::EncryptionFailed),
         }
     }
+
+    fn nonce(&self, zc_packet: &ZCPacket) -> Option<[u8; 12]> {
+        let pm_header = zc_packet.peer_manager_header()?;
+        if !pm_header.is_encrypted() || zc_packet.payload().len() < AES_GCM_ENCRYPTION_RESERVED {
+            return None;
+        }
+        AesGcmTail::ref_from_suffix(zc_packet.payload()).map(|tail| tail.nonce)
+    }
 }
 
 #[cfg(test)]
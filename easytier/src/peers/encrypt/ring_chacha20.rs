@@ -1,8 +1,9 @@
-use rand::RngCore;
+use std::sync::Arc;
+
 use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
-use super::{Encryptor, Error};
+use super::{Encryptor, Error, NonceCounter};
 use crate::tunnel::packet_def::ZCPacket;
 
 #[repr(C, packed)]
@@ -19,13 +20,18 @@ pub const CHACHA20_POLY1305_ENCRYPTION_RESERVED: usize =
 pub struct RingChaCha20Cipher {
     cipher: LessSafeKey,
     key: [u8; 32],
+    nonce_counter: Arc<NonceCounter>,
 }
 
 impl RingChaCha20Cipher {
     pub fn new(key: [u8; 32]) -> Self {
         let unbound_key = UnboundKey::new(&aead::CHACHA20_POLY1305, &key).unwrap();
         let cipher = LessSafeKey::new(unbound_key);
-        Self { cipher, key }
+        Self {
+            cipher,
+            key,
+            nonce_counter: Default::default(),
+        }
     }
 }
 
@@ -74,7 +80,8 @@ impl Encryptor for RingChaCha20Cipher {
         }
 
         let mut tail = ChaCha20Poly1305Tail::default();
-        rand::thread_rng().fill_bytes(&mut tail.nonce);
+        self.nonce_counter
+            .fill_sequence_nonce(zc_packet, &mut tail.nonce);
         let nonce = Nonce::assume_unique_for_key(tail.nonce);
 
         let rs =
@@ -92,6 +99,16 @@ impl Encryptor for RingChaCha20Cipher {
             Err(_) => Err(Error::EncryptionFailed),
         }
     }
+
+    fn nonce(&self, zc_packet: &ZCPacket) -> Option<[u8; 12]> {
+        let pm_header = zc_packet.peer_manager_header()?;
+        if !pm_header.is_encrypted()
+            || zc_packet.payload().len() < CHACHA20_POLY1305_ENCRYPTION_RESERVED
+        {
+            return None;
+        }
+        ChaCha20Poly1305Tail::ref_from_suffix(zc_packet.payload()).map(|tail| tail.nonce)
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,384 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use ring::{
+    aead::{self, LessSafeKey, UnboundKey},
+    agreement::{self, EphemeralPrivateKey, X25519},
+    hkdf,
+    rand::SystemRandom,
+};
+use zerocopy::{AsBytes, FromBytes};
+
+use crate::{
+    common::PeerId,
+    tunnel::packet_def::{AesGcmTail, ZCPacket, AES_GCM_ENCRYPTION_RESERVED},
+};
+
+use super::{replay_window::ReplayWindow, Error};
+
+/// Advertised in `HandshakeRequest.features` by nodes supporting per
+/// connection session keys.
+pub const SESSION_KEY_FEATURE: &str = "session-key-v1";
+
+/// Tail appended by the session cipher on top of the network encryption.
+pub const SESSION_ENCRYPTION_RESERVED: usize = AES_GCM_ENCRYPTION_RESERVED;
+
+const REKEY_INTERVAL: Duration = Duration::from_secs(120);
+const REKEY_AFTER_PACKETS: u64 = 1 << 32;
+// how many epochs the receiver may ratchet forward at once
+const MAX_EPOCH_SKIP: u32 = 8;
+
+struct OkmLen(usize);
+
+impl hkdf::KeyType for OkmLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+fn hkdf_expand(prk: &hkdf::Prk, info: &[u8]) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    prk.expand(&[info], OkmLen(out.len()))
+        .and_then(|okm| okm.fill(&mut out))
+        .expect("hkdf output length is valid");
+    out
+}
+
+/// The ephemeral half of the key exchange, created for one handshake and
+/// consumed when the peer's public key arrives.
+pub struct SessionKeyExchange {
+    private_key: EphemeralPrivateKey,
+    public_key: Vec<u8>,
+}
+
+impl SessionKeyExchange {
+    pub fn new() -> Result<Self, Error> {
+        let private_key = EphemeralPrivateKey::generate(&X25519, &SystemRandom::new())
+            .map_err(|_| Error::KeyExchangeFailed)?;
+        let public_key = private_key
+            .compute_public_key()
+            .map_err(|_| Error::KeyExchangeFailed)?
+            .as_ref()
+            .to_vec();
+        Ok(Self {
+            private_key,
+            public_key,
+        })
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// Derive the session cipher of a connection. Client and server get the
+    /// same keys with tx and rx swapped.
+    pub fn derive(
+        self,
+        peer_public_key: &[u8],
+        is_client: bool,
+        client_peer_id: PeerId,
+        server_peer_id: PeerId,
+    ) -> Result<SessionCipher, Error> {
+        let (client_key, server_key) = if is_client {
+            (self.public_key.clone(), peer_public_key.to_vec())
+        } else {
+            (peer_public_key.to_vec(), self.public_key.clone())
+        };
+
+        let mut salt = Vec::with_capacity(72);
+        salt.extend_from_slice(&client_peer_id.to_be_bytes());
+        salt.extend_from_slice(&server_peer_id.to_be_bytes());
+        salt.extend_from_slice(&client_key);
+        salt.extend_from_slice(&server_key);
+
+        let prk = agreement::agree_ephemeral(
+            self.private_key,
+            &agreement::UnparsedPublicKey::new(&X25519, peer_public_key),
+            |shared| hkdf::Salt::new(hkdf::HKDF_SHA256, &salt).extract(shared),
+        )
+        .map_err(|_| Error::KeyExchangeFailed)?;
+
+        let c2s = DirectionKey::new(hkdf_expand(&prk, b"easytier session c2s"), 0);
+        let s2c = DirectionKey::new(hkdf_expand(&prk, b"easytier session s2c"), 0);
+        let (tx, rx) = if is_client { (c2s, s2c) } else { (s2c, c2s) };
+
+        Ok(SessionCipher {
+            tx: Mutex::new(TxState {
+                key: tx,
+                counter: 0,
+                epoch_packets: 0,
+                started_at: Instant::now(),
+            }),
+            rx: Mutex::new(RxState {
+                key: rx,
+                previous: None,
+                replay: ReplayWindow::new(),
+            }),
+        })
+    }
+}
+
+// Keys of one direction form a hash chain. Every epoch derives its packet key
+// and the next chain key from the current chain key, old chain keys are
+// dropped so a leaked state cannot decrypt earlier epochs.
+struct DirectionKey {
+    chain_key: [u8; 32],
+    epoch: u32,
+    key: LessSafeKey,
+}
+
+impl DirectionKey {
+    fn new(chain_key: [u8; 32], epoch: u32) -> Self {
+        let prk = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, &chain_key);
+        let key = hkdf_expand(&prk, b"easytier session key");
+        Self {
+            chain_key,
+            epoch,
+            key: LessSafeKey::new(UnboundKey::new(&aead::AES_256_GCM, &key).unwrap()),
+        }
+    }
+
+    fn next(&self) -> Self {
+        let prk = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, &self.chain_key);
+        Self::new(
+            hkdf_expand(&prk, b"easytier session ratchet"),
+            self.epoch.wrapping_add(1),
+        )
+    }
+}
+
+struct TxState {
+    key: DirectionKey,
+    // keeps counting across epochs so one replay window covers all of them
+    counter: u64,
+    epoch_packets: u64,
+    started_at: Instant,
+}
+
+struct RxState {
+    key: DirectionKey,
+    // key of the last epoch, for packets still in flight during a rekey
+    previous: Option<LessSafeKey>,
+    replay: ReplayWindow,
+}
+
+/// Per connection AEAD keyed by an ephemeral x25519 exchange, rekeyed
+/// periodically by ratcheting both directions forward.
+pub struct SessionCipher {
+    tx: Mutex<TxState>,
+    rx: Mutex<RxState>,
+}
+
+impl std::fmt::Debug for SessionCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionCipher")
+            .field("tx_epoch", &self.tx.lock().unwrap().key.epoch)
+            .field("rx_epoch", &self.rx.lock().unwrap().key.epoch)
+            .finish()
+    }
+}
+
+impl SessionCipher {
+    pub fn encrypt(&self, zc_packet: &mut ZCPacket) -> Result<(), Error> {
+        let pm_header = zc_packet.peer_manager_header().unwrap();
+        if pm_header.is_session_encrypted() {
+            return Ok(());
+        }
+
+        let mut tail = AesGcmTail::default();
+        let mut tx = self.tx.lock().unwrap();
+        if tx.epoch_packets >= REKEY_AFTER_PACKETS || tx.started_at.elapsed() >= REKEY_INTERVAL {
+            tx.key = tx.key.next();
+            tx.epoch_packets = 0;
+            tx.started_at = Instant::now();
+        }
+        tail.nonce[..4].copy_from_slice(&tx.key.epoch.to_be_bytes());
+        tail.nonce[4..].copy_from_slice(&tx.counter.to_be_bytes());
+        tx.counter += 1;
+        tx.epoch_packets += 1;
+
+        let tag = tx
+            .key
+            .key
+            .seal_in_place_separate_tag(
+                aead::Nonce::assume_unique_for_key(tail.nonce),
+                aead::Aad::empty(),
+                zc_packet.mut_payload(),
+            )
+            .map_err(|_| Error::EncryptionFailed)?;
+        drop(tx);
+
+        tail.tag.copy_from_slice(tag.as_ref());
+        zc_packet
+            .mut_peer_manager_header()
+            .unwrap()
+            .set_session_encrypted(true);
+        zc_packet.mut_inner().extend_from_slice(tail.as_bytes());
+        Ok(())
+    }
+
+    pub fn decrypt(&self, zc_packet: &mut ZCPacket) -> Result<(), Error> {
+        let payload_len = zc_packet.payload().len();
+        if payload_len < AES_GCM_ENCRYPTION_RESERVED {
+            return Err(Error::PacketTooShort(payload_len));
+        }
+        let text_and_tag_len = payload_len - AES_GCM_ENCRYPTION_RESERVED + 16;
+        let nonce = AesGcmTail::ref_from_suffix(zc_packet.payload())
+            .unwrap()
+            .nonce;
+        let epoch = u32::from_be_bytes(nonce[..4].try_into().unwrap());
+        let counter = u64::from_be_bytes(nonce[4..].try_into().unwrap());
+
+        let mut rx = self.rx.lock().unwrap();
+        let open = |key: &LessSafeKey, buf: &mut [u8]| {
+            key.open_in_place(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::empty(),
+                buf,
+            )
+            .map(|_| ())
+            .map_err(|_| Error::DecryptionFailed)
+        };
+        let buf = &mut zc_packet.mut_payload()[..text_and_tag_len];
+
+        let cur_epoch = rx.key.epoch;
+        if epoch == cur_epoch {
+            open(&rx.key.key, buf)?;
+        } else if epoch.wrapping_add(1) == cur_epoch {
+            let Some(previous) = &rx.previous else {
+                return Err(Error::DecryptionFailed);
+            };
+            open(previous, buf)?;
+        } else {
+            let skip = epoch.wrapping_sub(cur_epoch);
+            if skip > MAX_EPOCH_SKIP {
+                return Err(Error::DecryptionFailed);
+            }
+            // only move the ratchet forward after the packet is authenticated
+            let mut previous = None;
+            let mut next = rx.key.next();
+            while next.epoch != epoch {
+                let following = next.next();
+                previous = Some(next);
+                next = following;
+            }
+            open(&next.key, buf)?;
+            let old = std::mem::replace(&mut rx.key, next);
+            rx.previous = Some(previous.unwrap_or(old).key);
+        }
+        if !rx.replay.check_and_update(counter) {
+            return Err(Error::ReplayDetected);
+        }
+        drop(rx);
+
+        zc_packet
+            .mut_peer_manager_header()
+            .unwrap()
+            .set_session_encrypted(false);
+        let old_len = zc_packet.buf_len();
+        zc_packet
+            .mut_inner()
+            .truncate(old_len - AES_GCM_ENCRYPTION_RESERVED);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cipher_pair() -> (SessionCipher, SessionCipher) {
+        let client = SessionKeyExchange::new().unwrap();
+        let server = SessionKeyExchange::new().unwrap();
+        let (client_pub, server_pub) = (client.public_key().to_vec(), server.public_key().to_vec());
+        (
+            client.derive(&server_pub, true, 1, 2).unwrap(),
+            server.derive(&client_pub, false, 1, 2).unwrap(),
+        )
+    }
+
+    fn new_packet(text: &[u8]) -> ZCPacket {
+        let mut packet = ZCPacket::new_with_payload(text);
+        packet.fill_peer_manager_hdr(0, 0, 0);
+        packet
+    }
+
+    #[test]
+    fn session_cipher_roundtrip() {
+        let (client, server) = cipher_pair();
+        let text = b"1234567";
+
+        let mut packet = new_packet(text);
+        client.encrypt(&mut packet).unwrap();
+        assert!(packet.peer_manager_header().unwrap().is_session_encrypted());
+        assert_eq!(
+            packet.payload().len(),
+            text.len() + AES_GCM_ENCRYPTION_RESERVED
+        );
+        server.decrypt(&mut packet).unwrap();
+        assert_eq!(packet.payload(), text);
+        assert!(!packet.peer_manager_header().unwrap().is_session_encrypted());
+
+        // directions use different keys
+        let mut packet = new_packet(text);
+        client.encrypt(&mut packet).unwrap();
+        assert!(client.decrypt(&mut packet).is_err());
+    }
+
+    #[test]
+    fn session_cipher_rekey() {
+        let (client, server) = cipher_pair();
+        let text = b"rekey";
+
+        let mut in_flight = new_packet(text);
+        client.encrypt(&mut in_flight).unwrap();
+
+        for _ in 0..3 {
+            client.tx.lock().unwrap().epoch_packets = REKEY_AFTER_PACKETS;
+            let mut packet = new_packet(text);
+            client.encrypt(&mut packet).unwrap();
+        }
+        let mut packet = new_packet(text);
+        client.encrypt(&mut packet).unwrap();
+        assert_eq!(client.tx.lock().unwrap().key.epoch, 3);
+
+        // receiver ratchets forward multiple epochs at once
+        server.decrypt(&mut packet).unwrap();
+        assert_eq!(packet.payload(), text);
+        assert_eq!(server.rx.lock().unwrap().key.epoch, 3);
+
+        // packets older than the previous epoch are rejected
+        assert!(server.decrypt(&mut in_flight).is_err());
+    }
+
+    #[test]
+    fn session_cipher_replay() {
+        let (client, server) = cipher_pair();
+        let text = b"replay";
+
+        let mut first = new_packet(text);
+        client.encrypt(&mut first).unwrap();
+        let mut second = new_packet(text);
+        client.encrypt(&mut second).unwrap();
+        let mut replayed = first.clone();
+
+        // reordering is fine, duplicates are not
+        server.decrypt(&mut second).unwrap();
+        server.decrypt(&mut first).unwrap();
+        assert!(matches!(
+            server.decrypt(&mut replayed),
+            Err(Error::ReplayDetected)
+        ));
+    }
+
+    #[test]
+    fn session_cipher_tampered() {
+        let (client, server) = cipher_pair();
+        let mut packet = new_packet(b"tampered");
+        client.encrypt(&mut packet).unwrap();
+        packet.mut_payload()[0] ^= 1;
+        assert!(server.decrypt(&mut packet).is_err());
+    }
+}
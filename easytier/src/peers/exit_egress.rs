@@ -0,0 +1,430 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use arc_swap::ArcSwap;
+use crossbeam::atomic::AtomicCell;
+use dashmap::DashMap;
+
+use crate::{
+    common::{
+        config::ExitNodeEgressConfig,
+        global_ctx::ArcGlobalCtx,
+        stats_manager::{CounterHandle, LabelSet, LabelType, MetricName},
+        token_bucket::TokenBucket,
+        PeerId,
+    },
+    proto::{cli::Route, common::LimiterConfig},
+    tunnel::packet_def::ZCPacket,
+};
+
+use super::{peer_map::PeerMap, policy_route::resolve_peer};
+
+// clients, which are referenced by hostname, and the proxy cidrs are
+// refreshed this often
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+// clients idle for this long are dropped with their token buckets
+const CLIENT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Default)]
+struct ResolvedClients {
+    deny: BTreeSet<PeerId>,
+    limits: BTreeMap<PeerId, Option<u64>>,
+}
+
+struct ExitClientLimiter {
+    bps: u64,
+    tx: Arc<TokenBucket>,
+    rx: Arc<TokenBucket>,
+}
+
+struct ExitClient {
+    tx_bytes: CounterHandle,
+    tx_packets: CounterHandle,
+    rx_bytes: CounterHandle,
+    rx_packets: CounterHandle,
+    tx_denied: CounterHandle,
+    tx_limited: CounterHandle,
+    rx_limited: CounterHandle,
+    limiter: Mutex<Option<ExitClientLimiter>>,
+    last_active: AtomicCell<Instant>,
+}
+
+impl ExitClient {
+    fn new(global_ctx: &ArcGlobalCtx, peer_id: PeerId) -> Self {
+        let stats_mgr = global_ctx.stats_manager();
+        let network =
+            LabelSet::new().with_label_type(LabelType::NetworkName(global_ctx.get_network_name()));
+        let src = network
+            .clone()
+            .with_label_type(LabelType::SrcPeerId(peer_id));
+        let dst = network.with_label_type(LabelType::DstPeerId(peer_id));
+        let dropped = |labels: &LabelSet, status: &str| {
+            stats_mgr.get_counter(
+                MetricName::ExitClientPacketsDropped,
+                labels
+                    .clone()
+                    .with_label_type(LabelType::Status(status.to_string())),
+            )
+        };
+        ExitClient {
+            tx_bytes: stats_mgr.get_counter(MetricName::ExitClientBytesTx, src.clone()),
+            tx_packets: stats_mgr.get_counter(MetricName::ExitClientPacketsTx, src.clone()),
+            rx_bytes: stats_mgr.get_counter(MetricName::ExitClientBytesRx, dst.clone()),
+            rx_packets: stats_mgr.get_counter(MetricName::ExitClientPacketsRx, dst.clone()),
+            tx_denied: dropped(&src, "denied"),
+            tx_limited: dropped(&src, "rate_limited"),
+            rx_limited: dropped(&dst, "rate_limited"),
+            limiter: Mutex::new(None),
+            last_active: AtomicCell::new(Instant::now()),
+        }
+    }
+
+    fn set_limit(&self, bps: Option<u64>) {
+        let mut limiter = self.limiter.lock().unwrap();
+        if limiter.as_ref().map(|x| x.bps) == bps {
+            return;
+        }
+        *limiter = bps.map(|bps| {
+            let bucket = || {
+                TokenBucket::new_from_cfg(
+                    LimiterConfig {
+                        burst_rate: None,
+                        bps: Some(bps),
+                        fill_duration_ms: None,
+                    }
+                    .into(),
+                )
+            };
+            ExitClientLimiter {
+                bps,
+                tx: bucket(),
+                rx: bucket(),
+            }
+        });
+    }
+
+    fn try_tx(&self, len: u64) -> bool {
+        self.last_active.store(Instant::now());
+        let allowed = match self.limiter.lock().unwrap().as_ref() {
+            Some(limiter) => limiter.tx.try_consume(len),
+            None => true,
+        };
+        if allowed {
+            self.tx_bytes.add(len);
+            self.tx_packets.inc();
+        } else {
+            self.tx_limited.inc();
+        }
+        allowed
+    }
+
+    fn try_rx(&self, len: u64) -> bool {
+        self.last_active.store(Instant::now());
+        let allowed = match self.limiter.lock().unwrap().as_ref() {
+            Some(limiter) => limiter.rx.try_consume(len),
+            None => true,
+        };
+        if allowed {
+            self.rx_bytes.add(len);
+            self.rx_packets.inc();
+        } else {
+            self.rx_limited.inc();
+        }
+        allowed
+    }
+}
+
+// (src, dst) of an ip packet
+fn packet_addrs(payload: &[u8]) -> Option<(IpAddr, IpAddr)> {
+    match payload.first()? >> 4 {
+        4 => {
+            let src: [u8; 4] = payload.get(12..16)?.try_into().ok()?;
+            let dst: [u8; 4] = payload.get(16..20)?.try_into().ok()?;
+            Some((Ipv4Addr::from(src).into(), Ipv4Addr::from(dst).into()))
+        }
+        6 => {
+            let src: [u8; 16] = payload.get(8..24)?.try_into().ok()?;
+            let dst: [u8; 16] = payload.get(24..40)?.try_into().ok()?;
+            Some((Ipv6Addr::from(src).into(), Ipv6Addr::from(dst).into()))
+        }
+        _ => None,
+    }
+}
+
+/// Accounting and limits for the peers using this node as exit node. Traffic
+/// of a client is counted when it enters the exit node and when the replies
+/// from outside the virtual network are sent back to it, each direction with
+/// its own token bucket.
+///
+/// The clients and proxy cidrs are refreshed by [`run_exit_egress_refresh`],
+/// the checks of a packet only load them.
+pub struct ExitEgressManager {
+    global_ctx: ArcGlobalCtx,
+    config: ExitNodeEgressConfig,
+    clients: DashMap<PeerId, Arc<ExitClient>>,
+    resolved: ArcSwap<ResolvedClients>,
+    proxy_cidrs: ArcSwap<Vec<cidr::IpCidr>>,
+}
+
+impl std::fmt::Debug for ExitEgressManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExitEgressManager")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl ExitEgressManager {
+    pub fn new(global_ctx: ArcGlobalCtx) -> Self {
+        let config = global_ctx.config.get_exit_node_egress().unwrap_or_default();
+        let mgr = ExitEgressManager {
+            global_ctx,
+            config,
+            clients: DashMap::new(),
+            resolved: ArcSwap::from_pointee(ResolvedClients::default()),
+            proxy_cidrs: ArcSwap::from_pointee(vec![]),
+        };
+        mgr.update_proxy_cidrs();
+        mgr
+    }
+
+    fn resolve(&self, routes: &[Route]) -> ResolvedClients {
+        ResolvedClients {
+            deny: self
+                .config
+                .deny
+                .iter()
+                .filter_map(|x| resolve_peer(x, routes))
+                .collect(),
+            limits: self
+                .config
+                .client_limits
+                .iter()
+                .filter_map(|x| Some((resolve_peer(&x.peer, routes)?, x.bps_limit)))
+                .collect(),
+        }
+    }
+
+    /// Re-resolves the configured clients, called when the routes changed.
+    pub fn update_clients(&self, routes: &[Route]) {
+        self.resolved.store(Arc::new(self.resolve(routes)));
+    }
+
+    pub fn update_proxy_cidrs(&self) {
+        let proxy_cidrs = self
+            .global_ctx
+            .config
+            .get_proxy_cidrs()
+            .into_iter()
+            .map(|x| x.cidr)
+            .collect();
+        self.proxy_cidrs.store(Arc::new(proxy_cidrs));
+    }
+
+    fn evict_idle_clients(&self) {
+        self.clients
+            .retain(|_, client| client.last_active.load().elapsed() < CLIENT_IDLE_TIMEOUT);
+    }
+
+    fn get_client(&self, peer_id: PeerId) -> Arc<ExitClient> {
+        self.clients
+            .entry(peer_id)
+            .or_insert_with(|| Arc::new(ExitClient::new(&self.global_ctx, peer_id)))
+            .clone()
+    }
+
+    fn check_client_tx(&self, from_peer_id: PeerId, len: u64, resolved: &ResolvedClients) -> bool {
+        let client = self.get_client(from_peer_id);
+        if resolved.deny.contains(&from_peer_id) {
+            client.tx_denied.inc();
+            return false;
+        }
+        let bps = resolved
+            .limits
+            .get(&from_peer_id)
+            .copied()
+            .unwrap_or(self.config.bps_limit);
+        client.set_limit(bps);
+        client.try_tx(len)
+    }
+
+    /// Whether a data packet a peer sent to this node may be forwarded. Only
+    /// packets leaving the virtual network through this node are checked, the
+    /// destination decides that and not the flags set by the sender.
+    pub async fn check_tx(&self, packet: &ZCPacket, from_peer_id: PeerId, peers: &PeerMap) -> bool {
+        let payload = packet.payload();
+        let Some((_, dst)) = packet_addrs(payload) else {
+            return true;
+        };
+        if !self.is_outside(&dst) {
+            return true;
+        }
+        // destinations proxied by other peers stay in the network
+        let routed = match dst {
+            IpAddr::V4(ip) => peers.get_peer_id_by_ipv4(&ip).await,
+            IpAddr::V6(ip) => peers.get_peer_id_by_ipv6(&ip).await,
+        };
+        if routed.is_some() {
+            return true;
+        }
+        self.check_client_tx(from_peer_id, payload.len() as u64, &self.resolved.load())
+    }
+
+    // addresses only reachable through the upstream of this node, so not in
+    // the virtual network, the subnets proxied by this node, or link scope
+    fn is_outside(&self, addr: &IpAddr) -> bool {
+        let local = match addr {
+            IpAddr::V4(ip) => {
+                ip.is_multicast()
+                    || ip.is_broadcast()
+                    || ip.is_link_local()
+                    || self
+                        .global_ctx
+                        .get_ipv4()
+                        .is_some_and(|x| x.network().contains(ip))
+            }
+            IpAddr::V6(ip) => {
+                ip.is_multicast()
+                    || ip.is_unicast_link_local()
+                    || self
+                        .global_ctx
+                        .get_ipv6()
+                        .is_some_and(|x| x.network().contains(ip))
+            }
+        };
+        !local && !self.proxy_cidrs.load().iter().any(|x| x.contains(addr))
+    }
+
+    /// Whether a data packet from this node to `dst_peer_id` may be sent.
+    /// Only replies to peers which used this node as exit node are checked.
+    pub fn check_rx(&self, packet: &ZCPacket, dst_peer_id: PeerId) -> bool {
+        let Some(client) = self.clients.get(&dst_peer_id).map(|x| x.clone()) else {
+            return true;
+        };
+        let payload = packet.payload();
+        match packet_addrs(payload) {
+            Some((src, _)) if self.is_outside(&src) => client.try_rx(payload.len() as u64),
+            _ => true,
+        }
+    }
+}
+
+/// Keeps the clients of an exit node resolved against the current routes and
+/// its proxy cidrs up to date, and drops idle clients.
+pub async fn run_exit_egress_refresh(mgr: Arc<ExitEgressManager>, peers: Weak<PeerMap>) {
+    if !mgr.global_ctx.enable_exit_node() {
+        return;
+    }
+
+    let mut routes_updated_at = None;
+    loop {
+        let Some(peers) = peers.upgrade() else {
+            return;
+        };
+
+        let updated_at = peers.get_route_peer_info_last_update_time().await;
+        if routes_updated_at != Some(updated_at) {
+            mgr.update_clients(&peers.list_route_infos().await);
+            routes_updated_at = Some(updated_at);
+        }
+        drop(peers);
+
+        mgr.update_proxy_cidrs();
+        mgr.evict_idle_clients();
+
+        tokio::time::sleep(REFRESH_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::{config::ExitClientLimitConfig, global_ctx::tests::get_mock_global_ctx},
+        proto::common::Ipv4Inet,
+    };
+
+    fn route(peer_id: PeerId, hostname: &str, ipv4: &str) -> Route {
+        Route {
+            peer_id,
+            hostname: hostname.to_string(),
+            ipv4_addr: Some(Ipv4Inet {
+                address: Some(ipv4.parse::<Ipv4Addr>().unwrap().into()),
+                network_length: 24,
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn ipv4_packet(src: &str) -> ZCPacket {
+        let mut payload = vec![0u8; 1000];
+        payload[0] = 0x45;
+        payload[12..16].copy_from_slice(&src.parse::<Ipv4Addr>().unwrap().octets());
+        ZCPacket::new_with_payload(&payload)
+    }
+
+    #[tokio::test]
+    async fn exit_egress_deny_and_limit() {
+        let global_ctx = get_mock_global_ctx();
+        global_ctx.set_ipv4(Some("10.144.144.1/24".parse().unwrap()));
+        global_ctx
+            .config
+            .set_exit_node_egress(Some(ExitNodeEgressConfig {
+                bps_limit: Some(10000),
+                client_limits: vec![ExitClientLimitConfig {
+                    peer: "trusted".to_string(),
+                    bps_limit: None,
+                }],
+                deny: vec!["10.144.144.3".to_string()],
+                ..Default::default()
+            }));
+        let mgr = ExitEgressManager::new(global_ctx);
+        let resolved = mgr.resolve(&[
+            route(2, "trusted", "10.144.144.2"),
+            route(3, "guest", "10.144.144.3"),
+            route(4, "other", "10.144.144.4"),
+        ]);
+
+        assert!(!mgr.check_client_tx(3, 1000, &resolved));
+        assert_eq!(1, mgr.get_client(3).tx_denied.get());
+
+        for _ in 0..20 {
+            assert!(mgr.check_client_tx(2, 1000, &resolved));
+        }
+        for _ in 0..10 {
+            assert!(mgr.check_client_tx(4, 1000, &resolved));
+        }
+        assert!(!mgr.check_client_tx(4, 1000, &resolved));
+
+        let other = mgr.get_client(4);
+        assert_eq!(10000, other.tx_bytes.get());
+        assert_eq!(10, other.tx_packets.get());
+        assert_eq!(1, other.tx_limited.get());
+        assert_eq!(20000, mgr.get_client(2).tx_bytes.get());
+
+        // replies from outside are counted, traffic inside the network is not
+        assert!(mgr.check_rx(&ipv4_packet("8.8.8.8"), 2));
+        assert!(mgr.check_rx(&ipv4_packet("10.144.144.1"), 2));
+        assert!(mgr.check_rx(&ipv4_packet("8.8.8.8"), 5));
+        assert_eq!(1, mgr.get_client(2).rx_packets.get());
+        assert_eq!(1000, mgr.get_client(2).rx_bytes.get());
+        assert!(!mgr.clients.contains_key(&5));
+
+        assert!(mgr.is_outside(&"8.8.8.8".parse().unwrap()));
+        assert!(!mgr.is_outside(&"10.144.144.5".parse().unwrap()));
+        assert!(!mgr.is_outside(&"224.0.0.1".parse().unwrap()));
+        assert!(!mgr.is_outside(&"255.255.255.255".parse().unwrap()));
+
+        // idle clients are dropped with their buckets
+        mgr.get_client(4)
+            .last_active
+            .store(Instant::now() - CLIENT_IDLE_TIMEOUT);
+        mgr.evict_idle_clients();
+        assert!(!mgr.clients.contains_key(&4));
+        assert!(mgr.clients.contains_key(&2));
+    }
+}
@@ -0,0 +1,455 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use dashmap::DashMap;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpSocket,
+};
+
+use crate::{
+    common::{
+        config::ExitNodeHealthConfig,
+        global_ctx::{ArcGlobalCtx, GlobalCtxEvent},
+        PeerId,
+    },
+    proto::{
+        peer_rpc::{
+            ExitNodeRpc, ExitNodeRpcClientFactory, ExitNodeRpcServer, ProbeUpstreamRequest,
+            ProbeUpstreamResponse,
+        },
+        rpc_types::{
+            self,
+            controller::{BaseController, Controller},
+        },
+    },
+};
+
+use super::{peer_map::PeerMap, peer_rpc::PeerRpcManager};
+
+// upstream targets of an exit node are probed with this timeout
+const UPSTREAM_PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+// and at most this often, however many peers ask
+const UPSTREAM_PROBE_MIN_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Default)]
+struct ExitNodeHealth {
+    unhealthy: bool,
+    failures: u32,
+    successes: u32,
+}
+
+/// Orders the configured exit nodes by health. Without a health config all
+/// exit nodes are healthy and the configured order is kept.
+#[derive(Debug)]
+pub struct ExitNodeSelector {
+    exit_nodes: Vec<IpAddr>,
+    health_config: Option<ExitNodeHealthConfig>,
+    health: DashMap<IpAddr, ExitNodeHealth>,
+    primary: Mutex<Option<IpAddr>>,
+}
+
+impl ExitNodeSelector {
+    pub fn new(exit_nodes: Vec<IpAddr>, health_config: Option<ExitNodeHealthConfig>) -> Self {
+        Self {
+            exit_nodes,
+            health_config,
+            health: DashMap::new(),
+            primary: Mutex::new(None),
+        }
+    }
+
+    pub fn is_healthy(&self, exit_node: &IpAddr) -> bool {
+        self.health
+            .get(exit_node)
+            .map(|x| !x.unhealthy)
+            .unwrap_or(true)
+    }
+
+    /// Exit nodes of the address family of `dst` in the order they should be
+    /// tried. Healthy ones come first, rotated by destination if load balancing
+    /// is on, unhealthy ones are only a last resort.
+    pub fn candidates(&self, dst: &IpAddr) -> Vec<IpAddr> {
+        let (mut healthy, unhealthy): (Vec<IpAddr>, Vec<IpAddr>) = self
+            .exit_nodes
+            .iter()
+            .filter(|x| x.is_ipv4() == dst.is_ipv4())
+            .partition(|x| self.is_healthy(x));
+
+        let load_balance = self
+            .health_config
+            .as_ref()
+            .map(|x| x.load_balance)
+            .unwrap_or(false);
+        if load_balance && healthy.len() > 1 {
+            // keep a destination on the same exit node, its connections break otherwise
+            let mut hasher = DefaultHasher::new();
+            dst.hash(&mut hasher);
+            let n = (hasher.finish() % healthy.len() as u64) as usize;
+            healthy.rotate_left(n);
+        }
+
+        healthy.extend(unhealthy);
+        healthy
+    }
+
+    // returns the new health if this probe changed it.
+    fn report(&self, exit_node: IpAddr, ok: bool, config: &ExitNodeHealthConfig) -> Option<bool> {
+        let mut health = self.health.entry(exit_node).or_default();
+        if ok {
+            health.failures = 0;
+            health.successes += 1;
+            if health.unhealthy && health.successes >= config.recovery_threshold() {
+                health.unhealthy = false;
+                return Some(true);
+            }
+        } else {
+            health.successes = 0;
+            health.failures += 1;
+            if !health.unhealthy && health.failures >= config.failure_threshold() {
+                health.unhealthy = true;
+                return Some(false);
+            }
+        }
+        None
+    }
+
+    // returns (old, new) if the preferred exit node changed.
+    fn update_primary(&self, reachable: &[IpAddr]) -> Option<(Option<IpAddr>, Option<IpAddr>)> {
+        let new = self
+            .exit_nodes
+            .iter()
+            .find(|x| reachable.contains(x) && self.is_healthy(x))
+            .copied();
+        let mut primary = self.primary.lock().unwrap();
+        if *primary == new {
+            return None;
+        }
+        let old = std::mem::replace(&mut *primary, new);
+        Some((old, new))
+    }
+}
+
+async fn probe_target(target: &str, global_ctx: &ArcGlobalCtx) -> Result<(), anyhow::Error> {
+    let url: url::Url = target.parse().with_context(|| "invalid probe target")?;
+    let host = url.host_str().with_context(|| "probe target has no host")?;
+    let port = url
+        .port_or_known_default()
+        .with_context(|| "probe target has no port")?;
+    if !matches!(url.scheme(), "tcp" | "http") {
+        return Err(anyhow::anyhow!(
+            "unsupported probe scheme: {}",
+            url.scheme()
+        ));
+    }
+
+    let addr: SocketAddr = tokio::net::lookup_host((host, port))
+        .await?
+        .next()
+        .with_context(|| format!("cannot resolve {}", host))?;
+    let socket = {
+        let _g = global_ctx.net_ns.guard();
+        if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        }
+    };
+    let mut stream = socket.connect(addr).await?;
+    if url.scheme() == "tcp" {
+        return Ok(());
+    }
+
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: easytier\r\nConnection: close\r\n\r\n",
+        &url[url::Position::BeforePath..url::Position::AfterQuery],
+        host
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    // only the status line is needed
+    let mut buf = vec![0u8; 64];
+    let mut len = 0;
+    while len < buf.len() && !buf[..len].contains(&b'\n') {
+        let n = stream.read(&mut buf[len..]).await?;
+        if n == 0 {
+            break;
+        }
+        len += n;
+    }
+    let status_line = String::from_utf8_lossy(&buf[..len]);
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|x| x.parse::<u16>().ok())
+        .with_context(|| format!("invalid http response: {:?}", status_line))?;
+    if !(200..400).contains(&status) {
+        return Err(anyhow::anyhow!("http status {}", status));
+    }
+    Ok(())
+}
+
+async fn probe_upstream_targets(targets: &[String], global_ctx: &ArcGlobalCtx) -> bool {
+    if targets.is_empty() {
+        return true;
+    }
+    let probes = targets.iter().map(|target| async move {
+        let ret = tokio::time::timeout(UPSTREAM_PROBE_TIMEOUT, probe_target(target, global_ctx))
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("timeout")));
+        tracing::debug!(?target, ?ret, "upstream probed");
+        ret.is_ok()
+    });
+    futures::future::join_all(probes)
+        .await
+        .into_iter()
+        .any(|x| x)
+}
+
+#[derive(Clone)]
+pub struct ExitNodeRpcService {
+    global_ctx: ArcGlobalCtx,
+    probe_targets: Vec<String>,
+    last_probe: Arc<tokio::sync::Mutex<Option<(Instant, bool)>>>,
+}
+
+impl ExitNodeRpcService {
+    pub fn new(global_ctx: ArcGlobalCtx) -> Self {
+        let probe_targets = global_ctx
+            .config
+            .get_exit_node_egress()
+            .map(|x| x.probe_targets)
+            .unwrap_or_default();
+        Self {
+            global_ctx,
+            probe_targets,
+            last_probe: Arc::new(tokio::sync::Mutex::new(None)),
+        }
+    }
+
+    pub fn register(self, peer_rpc_mgr: &PeerRpcManager) {
+        let network_name = self.global_ctx.get_network_name();
+        peer_rpc_mgr
+            .rpc_server()
+            .registry()
+            .register(ExitNodeRpcServer::new(self), &network_name);
+    }
+
+    async fn upstream_healthy(&self) -> bool {
+        let mut last_probe = self.last_probe.lock().await;
+        if let Some((at, healthy)) = *last_probe {
+            if at.elapsed() < UPSTREAM_PROBE_MIN_INTERVAL {
+                return healthy;
+            }
+        }
+        let healthy = probe_upstream_targets(&self.probe_targets, &self.global_ctx).await;
+        *last_probe = Some((Instant::now(), healthy));
+        healthy
+    }
+}
+
+#[async_trait::async_trait]
+impl ExitNodeRpc for ExitNodeRpcService {
+    type Controller = BaseController;
+
+    async fn probe_upstream(
+        &self,
+        _: BaseController,
+        _: ProbeUpstreamRequest,
+    ) -> rpc_types::error::Result<ProbeUpstreamResponse> {
+        let healthy = self.global_ctx.enable_exit_node() && self.upstream_healthy().await;
+        Ok(ProbeUpstreamResponse { healthy })
+    }
+}
+
+async fn probe_exit_node(
+    peer_rpc_mgr: &PeerRpcManager,
+    my_peer_id: PeerId,
+    dst_peer_id: PeerId,
+    global_ctx: &ArcGlobalCtx,
+    config: &ExitNodeHealthConfig,
+) -> Result<(), String> {
+    let stub = peer_rpc_mgr
+        .rpc_client()
+        .scoped_client::<ExitNodeRpcClientFactory<BaseController>>(
+            my_peer_id,
+            dst_peer_id,
+            global_ctx.get_network_name(),
+        );
+    let mut ctrl = BaseController::default();
+    // the exit node may have to probe its upstream before answering
+    ctrl.set_timeout_ms((config.timeout() + UPSTREAM_PROBE_TIMEOUT).as_millis() as i32);
+    let resp = stub
+        .probe_upstream(ctrl, ProbeUpstreamRequest {})
+        .await
+        .map_err(|e| format!("rpc failed: {}", e))?;
+    if resp.healthy {
+        Ok(())
+    } else {
+        Err("upstream unhealthy".to_string())
+    }
+}
+
+/// Probes all exit nodes periodically and updates `selector`, emits events
+/// when an exit node changes health or the preferred one changes.
+pub async fn run_exit_node_health_check(
+    selector: Arc<ExitNodeSelector>,
+    peers: Weak<PeerMap>,
+    peer_rpc_mgr: Weak<PeerRpcManager>,
+    global_ctx: ArcGlobalCtx,
+) {
+    let Some(config) = selector.health_config.clone() else {
+        return;
+    };
+
+    loop {
+        let (Some(peers), Some(peer_rpc_mgr)) = (peers.upgrade(), peer_rpc_mgr.upgrade()) else {
+            return;
+        };
+
+        let mut reachable = vec![];
+        for exit_node in selector.exit_nodes.iter() {
+            let peer_id = match exit_node {
+                IpAddr::V4(ip) => peers.get_peer_id_by_ipv4(ip).await,
+                IpAddr::V6(ip) => peers.get_peer_id_by_ipv6(ip).await,
+            };
+            let ret = match peer_id {
+                Some(peer_id) => {
+                    reachable.push(*exit_node);
+                    probe_exit_node(
+                        &peer_rpc_mgr,
+                        peers.my_peer_id(),
+                        peer_id,
+                        &global_ctx,
+                        &config,
+                    )
+                    .await
+                }
+                None => Err("not reachable in the network".to_string()),
+            };
+
+            tracing::debug!(?exit_node, ?ret, "exit node probed");
+            let reason = ret.as_ref().err().cloned().unwrap_or_default();
+            if let Some(healthy) = selector.report(*exit_node, ret.is_ok(), &config) {
+                tracing::warn!(?exit_node, healthy, ?reason, "exit node health changed");
+                global_ctx.issue_event(GlobalCtxEvent::ExitNodeHealthChanged(
+                    *exit_node, healthy, reason,
+                ));
+            }
+        }
+
+        if let Some((old, new)) = selector.update_primary(&reachable) {
+            tracing::info!(?old, ?new, "exit node switched");
+            global_ctx.issue_event(GlobalCtxEvent::ExitNodeSwitched(old, new));
+        }
+
+        drop(peers);
+        drop(peer_rpc_mgr);
+        tokio::time::sleep(config.interval()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(load_balance: bool) -> ExitNodeHealthConfig {
+        ExitNodeHealthConfig {
+            failure_threshold: Some(2),
+            recovery_threshold: Some(2),
+            load_balance,
+            ..Default::default()
+        }
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn exit_node_failover() {
+        let config = config(false);
+        let nodes = vec![ip("10.0.0.1"), ip("10.0.0.2"), ip("fd00::1")];
+        let selector = ExitNodeSelector::new(nodes.clone(), Some(config.clone()));
+        let dst = ip("1.1.1.1");
+
+        assert_eq!(vec![nodes[0], nodes[1]], selector.candidates(&dst));
+        assert_eq!(vec![nodes[2]], selector.candidates(&ip("2606::1")));
+        assert_eq!(
+            Some((None, Some(nodes[0]))),
+            selector.update_primary(&nodes)
+        );
+
+        assert_eq!(None, selector.report(nodes[0], false, &config));
+        assert_eq!(Some(false), selector.report(nodes[0], false, &config));
+        assert_eq!(vec![nodes[1], nodes[0]], selector.candidates(&dst));
+        assert_eq!(
+            Some((Some(nodes[0]), Some(nodes[1]))),
+            selector.update_primary(&nodes)
+        );
+        assert_eq!(None, selector.update_primary(&nodes));
+
+        assert_eq!(None, selector.report(nodes[0], true, &config));
+        assert_eq!(Some(true), selector.report(nodes[0], true, &config));
+        assert_eq!(vec![nodes[0], nodes[1]], selector.candidates(&dst));
+    }
+
+    #[test]
+    fn exit_node_load_balance() {
+        let nodes = vec![ip("10.0.0.1"), ip("10.0.0.2"), ip("10.0.0.3")];
+        let selector = ExitNodeSelector::new(nodes.clone(), Some(config(true)));
+
+        let mut used = std::collections::BTreeSet::new();
+        for i in 0..64 {
+            let dst = ip(&format!("1.1.1.{}", i));
+            let candidates = selector.candidates(&dst);
+            assert_eq!(candidates, selector.candidates(&dst));
+            assert_eq!(3, candidates.len());
+            used.insert(candidates[0]);
+        }
+        assert_eq!(3, used.len());
+    }
+
+    #[tokio::test]
+    async fn exit_node_probe_target() {
+        let global_ctx = crate::common::global_ctx::tests::get_mock_global_ctx();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let _ = stream.read(&mut [0u8; 1024]).await;
+                let _ = stream
+                    .write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")
+                    .await;
+            }
+        });
+
+        probe_target(&format!("tcp://127.0.0.1:{}", port), &global_ctx)
+            .await
+            .unwrap();
+        probe_target(
+            &format!("http://127.0.0.1:{}/generate_204", port),
+            &global_ctx,
+        )
+        .await
+        .unwrap();
+        assert!(probe_target("udp://127.0.0.1:53", &global_ctx)
+            .await
+            .is_err());
+
+        assert!(probe_upstream_targets(&[], &global_ctx).await);
+        let targets = vec![
+            "tcp://127.0.0.1:1".to_string(),
+            format!("tcp://127.0.0.1:{}", port),
+        ];
+        assert!(probe_upstream_targets(&targets, &global_ctx).await);
+        assert!(!probe_upstream_targets(&targets[..1], &global_ctx).await);
+    }
+}
@@ -7,7 +7,7 @@ connected to any node in the local network.
 */
 use std::{
     sync::{Arc, Weak},
-    time::SystemTime,
+    time::{Duration, SystemTime},
 };
 
 use dashmap::{DashMap, DashSet};
@@ -42,12 +42,14 @@ use crate::{
 
 use super::{
     create_packet_recv_chan,
+    foreign_network_quota::{ForeignNetworkQuota, NetworkUsage},
     peer_conn::PeerConn,
     peer_map::PeerMap,
     peer_ospf_route::PeerRoute,
     peer_rpc::{PeerRpcManager, PeerRpcManagerTransport},
     peer_rpc_service::DirectConnectorManagerRpcServer,
     recv_packet_from_chan,
+    relay_admission::RelayAdmissionCache,
     route_trait::NextHopPolicy,
     PacketRecvChan, PacketRecvChanReceiver,
 };
@@ -73,6 +75,7 @@ struct ForeignNetworkEntry {
     packet_recv: Mutex<Option<PacketRecvChanReceiver>>,
 
     bps_limiter: Arc<TokenBucket>,
+    usage: Arc<NetworkUsage>,
 
     peer_center: Arc<PeerCenterInstance>,
 
@@ -91,6 +94,7 @@ impl ForeignNetworkEntry {
         global_ctx: ArcGlobalCtx,
         relay_data: bool,
         pm_packet_sender: PacketRecvChan,
+        usage: Arc<NetworkUsage>,
     ) -> Self {
         let stats_mgr = global_ctx.stats_manager().clone();
         let foreign_global_ctx = Self::build_foreign_global_ctx(&network, global_ctx.clone());
@@ -112,7 +116,9 @@ impl ForeignNetworkEntry {
             &network.network_name,
         );
 
-        let relay_bps_limit = global_ctx.config.get_flags().foreign_relay_bps_limit;
+        let relay_bps_limit = usage
+            .bps_limit()
+            .unwrap_or(global_ctx.config.get_flags().foreign_relay_bps_limit);
         let limiter_config = LimiterConfig {
             burst_rate: None,
             bps: Some(relay_bps_limit),
@@ -144,6 +150,7 @@ impl ForeignNetworkEntry {
             packet_recv: Mutex::new(Some(packet_recv)),
 
             bps_limiter,
+            usage,
 
             stats_mgr,
 
@@ -306,6 +313,7 @@ impl ForeignNetworkEntry {
         let pm_sender = self.pm_packet_sender.lock().await.take().unwrap();
         let network_name = self.network.network_name.clone();
         let bps_limiter = self.bps_limiter.clone();
+        let usage = self.usage.clone();
 
         let label_set =
             LabelSet::new().with_label_type(LabelType::NetworkName(network_name.clone()));
@@ -347,7 +355,7 @@ impl ForeignNetworkEntry {
                         || hdr.packet_type == PacketType::KcpSrc as u8
                         || hdr.packet_type == PacketType::KcpDst as u8
                     {
-                        if !relay_data {
+                        if !relay_data || usage.quota_exceeded() {
                             continue;
                         }
                         if !bps_limiter.try_consume(hdr.len.into()) {
@@ -357,6 +365,7 @@ impl ForeignNetworkEntry {
 
                     forward_bytes.add(buf_len as u64);
                     forward_packets.inc();
+                    usage.add(buf_len as u64);
 
                     let gateway_peer_id = peer_map
                         .get_gateway_peer_id(to_peer_id, NextHopPolicy::LeastHop)
@@ -417,6 +426,7 @@ struct ForeignNetworkManagerData {
     peer_network_map: DashMap<PeerId, DashSet<String>>,
     network_peer_last_update: DashMap<String, SystemTime>,
     accessor: Arc<Box<dyn GlobalForeignNetworkAccessor>>,
+    quota: Arc<ForeignNetworkQuota>,
     lock: std::sync::Mutex<()>,
 }
 
@@ -488,6 +498,7 @@ impl ForeignNetworkManagerData {
                     global_ctx.clone(),
                     relay_data,
                     pm_packet_sender.clone(),
+                    self.quota.get_usage(&network_identity.network_name),
                 ))
             })
             .clone();
@@ -518,6 +529,7 @@ pub struct ForeignNetworkManager {
     packet_sender_to_mgr: PacketRecvChan,
 
     data: Arc<ForeignNetworkManagerData>,
+    relay_admission: RelayAdmissionCache,
 
     tasks: Arc<std::sync::Mutex<JoinSet<()>>>,
 }
@@ -534,18 +546,31 @@ impl ForeignNetworkManager {
             peer_network_map: DashMap::new(),
             network_peer_last_update: DashMap::new(),
             accessor: Arc::new(accessor),
+            quota: Arc::new(ForeignNetworkQuota::from_config(global_ctx.config.as_ref())),
             lock: std::sync::Mutex::new(()),
         });
 
         let tasks = Arc::new(std::sync::Mutex::new(JoinSet::new()));
         join_joinset_background(tasks.clone(), "ForeignNetworkManager".to_string());
 
+        let quota = data.quota.clone();
+        tasks.lock().unwrap().spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                quota.roll_month();
+                if let Err(e) = quota.persist() {
+                    tracing::warn!(?e, "persist foreign network usage failed");
+                }
+            }
+        });
+
         Self {
             my_peer_id,
             global_ctx,
             packet_sender_to_mgr,
 
             data,
+            relay_admission: RelayAdmissionCache::default(),
 
             tasks,
         }
@@ -562,10 +587,16 @@ impl ForeignNetworkManager {
         tracing::info!(peer_conn = ?peer_conn.get_conn_info(), network = ?peer_conn.get_network_identity(), "add new peer conn in foreign network manager");
 
         let relay_peer_rpc = self.global_ctx.get_flags().relay_all_peer_rpc;
-        let ret = self
-            .global_ctx
-            .check_network_in_whitelist(&peer_conn.get_network_identity().network_name)
-            .map_err(Into::into);
+        let network = peer_conn.get_network_identity();
+        let ret = self.check_admission(&network, peer_conn.get_relay_admission_token());
+        if let Err(e) = &ret {
+            self.global_ctx
+                .issue_event(GlobalCtxEvent::ForeignNetworkRejected(
+                    network.network_name.clone(),
+                    e.to_string(),
+                ));
+        }
+        let ret = ret.map_err(Into::into);
         if ret.is_err() && !relay_peer_rpc {
             return ret;
         }
@@ -608,6 +639,24 @@ impl ForeignNetworkManager {
             return Err(err.into());
         }
 
+        if let Some(max_peers) = entry.usage.max_peers() {
+            let peer_count = entry.peer_map.list_peers_with_conn().await.len();
+            if !entry.peer_map.has_peer(peer_conn.get_peer_id()) && peer_count >= max_peers as usize
+            {
+                if new_added {
+                    self.data
+                        .remove_network(&entry.network.network_name.clone());
+                }
+                return Err(anyhow::anyhow!(
+                    "too many peers in foreign network {}, cur: {}, max: {}",
+                    entry.network.network_name,
+                    peer_count,
+                    max_peers
+                )
+                .into());
+            }
+        }
+
         if new_added {
             self.start_event_handler(&entry).await;
         } else if let Some(peer) = entry.peer_map.get_peer_by_id(peer_conn.get_peer_id()) {
@@ -627,6 +676,20 @@ impl ForeignNetworkManager {
         Ok(())
     }
 
+    fn check_admission(
+        &self,
+        network: &NetworkIdentity,
+        token: &[u8],
+    ) -> Result<(), anyhow::Error> {
+        self.global_ctx
+            .check_network_in_whitelist(&network.network_name)?;
+        // an invalid config rejects every foreign network instead of relaying all
+        if let Some(config) = self.global_ctx.config.get_relay_admission() {
+            self.relay_admission.get(&config)?.check(network, token)?;
+        }
+        Ok(())
+    }
+
     async fn start_event_handler(&self, entry: &ForeignNetworkEntry) {
         let data = self.data.clone();
         let network_name = entry.network.network_name.clone();
@@ -685,6 +748,7 @@ impl ForeignNetworkManager {
                     .to_vec(),
                 my_peer_id_for_this_network: item.my_peer_id,
                 peers: Default::default(),
+                usage: Some(item.usage.to_pb()),
             };
             for peer in item.peer_map.list_peers().await {
                 let peer_info = PeerInfo {
@@ -743,6 +807,9 @@ impl ForeignNetworkManager {
 
 impl Drop for ForeignNetworkManager {
     fn drop(&mut self) {
+        if let Err(e) = self.data.quota.persist() {
+            tracing::warn!(?e, "persist foreign network usage failed");
+        }
         self.data.peer_network_map.clear();
         self.data.network_peer_maps.clear();
     }
@@ -751,7 +818,10 @@ impl Drop for ForeignNetworkManager {
 #[cfg(test)]
 pub mod tests {
     use crate::{
-        common::global_ctx::tests::get_mock_global_ctx_with_network,
+        common::{
+            config::{RelayAdmissionConfig, RelayAdmissionEntry},
+            global_ctx::tests::get_mock_global_ctx_with_network,
+        },
         connector::udp_hole_punch::tests::{
             create_mock_peer_manager_with_mock_stun, replace_stun_info_collector,
         },
@@ -870,6 +940,47 @@ pub mod tests {
         assert_eq!(2, pmb_net1.list_routes().await.len());
     }
 
+    #[tokio::test]
+    async fn foreign_network_admission() {
+        let pm_center = create_mock_peer_manager_with_mock_stun(NatType::Unknown).await;
+        pm_center
+            .get_global_ctx()
+            .config
+            .set_relay_admission(Some(RelayAdmissionConfig {
+                networks: vec![RelayAdmissionEntry {
+                    network_name: "net*".to_string(),
+                    network_secret: Some("good".to_string()),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }));
+        let mut events = pm_center.get_global_ctx().subscribe();
+
+        let pma_net1 = create_mock_peer_manager_for_foreign_network_ext("net1", "good").await;
+        connect_peer_manager(pma_net1.clone(), pm_center.clone()).await;
+        wait_for_condition(
+            || async { pma_net1.list_routes().await.len() == 1 },
+            Duration::from_secs(5),
+        )
+        .await;
+
+        // a matching name with a guessed secret is rejected
+        let pm_guess = create_mock_peer_manager_for_foreign_network_ext("net2", "guess").await;
+        let (a_ring, b_ring) = crate::tunnel::ring::create_ring_tunnel_pair();
+        let b_mgr_copy = pm_center.clone();
+        let s_ret =
+            tokio::spawn(async move { b_mgr_copy.add_tunnel_as_server(b_ring, true).await });
+        let _ = pm_guess.add_client_tunnel(a_ring, false).await;
+        assert!(s_ret.await.unwrap().is_err());
+
+        let rejected = loop {
+            if let GlobalCtxEvent::ForeignNetworkRejected(name, _) = events.recv().await.unwrap() {
+                break name;
+            }
+        };
+        assert_eq!("net2", rejected);
+    }
+
     #[tokio::test]
     #[should_panic]
     async fn foreign_network_whitelist_fail() {
@@ -0,0 +1,350 @@
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Context;
+use atomic_shim::AtomicU64;
+use chrono::Datelike;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    common::config::{ConfigLoader, ForeignNetworkPolicy},
+    proto::cli::ForeignNetworkUsage,
+};
+
+/// Current month as `yyyymm` in UTC, quotas are reset when it changes.
+pub fn current_month() -> u32 {
+    let now = chrono::Utc::now();
+    now.year() as u32 * 100 + now.month()
+}
+
+/// Relayed bytes of one foreign network in the current month.
+#[derive(Debug)]
+pub struct NetworkUsage {
+    policy: Option<ForeignNetworkPolicy>,
+    month: AtomicU32,
+    bytes: AtomicU64,
+}
+
+impl NetworkUsage {
+    fn new(policy: Option<ForeignNetworkPolicy>, month: u32, bytes: u64) -> Self {
+        Self {
+            policy,
+            month: AtomicU32::new(month),
+            bytes: AtomicU64::new(bytes),
+        }
+    }
+
+    pub fn policy(&self) -> Option<&ForeignNetworkPolicy> {
+        self.policy.as_ref()
+    }
+
+    pub fn bps_limit(&self) -> Option<u64> {
+        self.policy.as_ref().and_then(|p| p.bps_limit)
+    }
+
+    pub fn max_peers(&self) -> Option<u32> {
+        self.policy.as_ref().and_then(|p| p.max_peers)
+    }
+
+    pub fn add(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn month_bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn quota_exceeded(&self) -> bool {
+        self.policy
+            .as_ref()
+            .and_then(|p| p.monthly_quota_bytes)
+            .is_some_and(|quota| self.month_bytes() >= quota)
+    }
+
+    fn record(&self) -> UsageRecord {
+        UsageRecord {
+            month: self.month.load(Ordering::Relaxed),
+            bytes: self.month_bytes(),
+        }
+    }
+
+    fn roll_month(&self, month: u32) -> bool {
+        if self.month.swap(month, Ordering::Relaxed) != month {
+            self.bytes.store(0, Ordering::Relaxed);
+            return true;
+        }
+        false
+    }
+
+    pub fn to_pb(&self) -> ForeignNetworkUsage {
+        ForeignNetworkUsage {
+            month_bytes: self.month_bytes(),
+            monthly_quota_bytes: self.policy.as_ref().and_then(|p| p.monthly_quota_bytes),
+            bps_limit: self.bps_limit(),
+            max_peers: self.max_peers(),
+            policy: self
+                .policy
+                .as_ref()
+                .map(|p| p.network_name.clone())
+                .unwrap_or_default(),
+            quota_exceeded: self.quota_exceeded(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+struct UsageRecord {
+    month: u32,
+    bytes: u64,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct UsageFile {
+    networks: BTreeMap<String, UsageRecord>,
+}
+
+/// Policies and monthly accounting of the foreign networks relayed by this
+/// node. Usage of networks matching a policy is persisted so quotas survive a
+/// restart, other networks are only accounted while they are relayed.
+#[derive(Debug)]
+pub struct ForeignNetworkQuota {
+    policies: Vec<ForeignNetworkPolicy>,
+    path: Option<PathBuf>,
+    // networks matching a policy that are relayed or were since the last persist
+    usages: DashMap<String, Arc<NetworkUsage>>,
+    // records of this month for networks not relayed now
+    saved: DashMap<String, UsageRecord>,
+}
+
+impl ForeignNetworkQuota {
+    pub fn new(policies: Vec<ForeignNetworkPolicy>, path: Option<PathBuf>) -> Self {
+        let ret = Self {
+            policies,
+            path,
+            usages: DashMap::new(),
+            saved: DashMap::new(),
+        };
+        ret.load();
+        ret
+    }
+
+    pub fn from_config(config: &dyn ConfigLoader) -> Self {
+        let policies = config.get_foreign_network_policies();
+        // only keep usage on disk if there is something to enforce or it is asked for
+        let path = config.get_foreign_network_usage_file().or_else(|| {
+            (!policies.is_empty())
+                .then(|| crate::common::default_state_file("foreign_network_usage.json"))
+                .flatten()
+        });
+        Self::new(policies, path)
+    }
+
+    pub fn match_policy(&self, network_name: &str) -> Option<&ForeignNetworkPolicy> {
+        self.policies.iter().find(|p| p.matches(network_name))
+    }
+
+    pub fn get_usage(&self, network_name: &str) -> Arc<NetworkUsage> {
+        let month = current_month();
+        let Some(policy) = self.match_policy(network_name) else {
+            // network names are chosen by the peers, keep no state for them
+            return Arc::new(NetworkUsage::new(None, month, 0));
+        };
+        self.usages
+            .entry(network_name.to_string())
+            .or_insert_with(|| {
+                let bytes = self
+                    .saved
+                    .remove(network_name)
+                    .map(|(_, r)| r)
+                    .filter(|r| r.month == month)
+                    .map(|r| r.bytes)
+                    .unwrap_or(0);
+                Arc::new(NetworkUsage::new(Some(policy.clone()), month, bytes))
+            })
+            .clone()
+    }
+
+    // networks no longer relayed are only held by the map, they are kept as
+    // saved records if they used some bytes this month
+    fn evict_left_networks(&self) {
+        let month = current_month();
+        self.usages.retain(|name, usage| {
+            if Arc::strong_count(usage) > 1 {
+                return true;
+            }
+            let record = usage.record();
+            if record.month == month && record.bytes > 0 {
+                self.saved.insert(name.clone(), record);
+            }
+            false
+        });
+        self.saved.retain(|_, r| r.month == month);
+    }
+
+    fn load(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let Some(file) = std::fs::read_to_string(path)
+            .ok()
+            .and_then(|x| serde_json::from_str::<UsageFile>(&x).ok())
+        else {
+            return;
+        };
+        let month = current_month();
+        for (name, record) in file.networks {
+            if record.month == month {
+                self.saved.insert(name, record);
+            }
+        }
+    }
+
+    /// Reset the counters if a new month started, called periodically.
+    pub fn roll_month(&self) {
+        let month = current_month();
+        for usage in self.usages.iter() {
+            usage.roll_month(month);
+        }
+    }
+
+    pub fn persist(&self) -> anyhow::Result<()> {
+        self.evict_left_networks();
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let mut file = UsageFile::default();
+        for r in self.saved.iter() {
+            file.networks.insert(r.key().clone(), r.value().clone());
+        }
+        for u in self.usages.iter() {
+            file.networks.insert(u.key().clone(), u.record());
+        }
+
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, serde_json::to_string_pretty(&file)?)
+            .with_context(|| format!("failed to write foreign network usage: {:?}", tmp_path))?;
+        std::fs::rename(&tmp_path, path)
+            .with_context(|| format!("failed to write foreign network usage: {:?}", path))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(name: &str) -> ForeignNetworkPolicy {
+        ForeignNetworkPolicy {
+            network_name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn foreign_network_policy_match() {
+        let quota = ForeignNetworkQuota::new(
+            vec![
+                ForeignNetworkPolicy {
+                    max_peers: Some(2),
+                    ..policy("vip")
+                },
+                ForeignNetworkPolicy {
+                    bps_limit: Some(1000),
+                    ..policy("*")
+                },
+            ],
+            None,
+        );
+
+        assert_eq!(Some(2), quota.get_usage("vip").max_peers());
+        assert_eq!(None, quota.get_usage("vip").bps_limit());
+        assert_eq!(Some(1000), quota.get_usage("other").bps_limit());
+        assert!(Arc::ptr_eq(
+            &quota.get_usage("other"),
+            &quota.get_usage("other")
+        ));
+    }
+
+    #[test]
+    fn foreign_network_usage_persist() {
+        let path = std::env::temp_dir().join(format!(
+            "et_foreign_network_usage_{}.json",
+            uuid::Uuid::new_v4()
+        ));
+        let policies = vec![ForeignNetworkPolicy {
+            monthly_quota_bytes: Some(100),
+            ..policy("net*")
+        }];
+
+        let quota = ForeignNetworkQuota::new(policies.clone(), Some(path.clone()));
+        let usage = quota.get_usage("net1");
+        usage.add(60);
+        assert!(!usage.quota_exceeded());
+        usage.add(40);
+        assert!(usage.quota_exceeded());
+        // a network that left keeps its usage
+        quota.get_usage("net2").add(10);
+        quota.persist().unwrap();
+        drop(usage);
+
+        let quota = ForeignNetworkQuota::new(policies, Some(path.clone()));
+        assert_eq!(100, quota.get_usage("net1").month_bytes());
+        assert!(quota.get_usage("net1").quota_exceeded());
+        assert_eq!(10, quota.get_usage("net2").month_bytes());
+
+        // a new month starts from zero
+        let usage = quota.get_usage("net1");
+        assert!(usage.roll_month(current_month() + 1));
+        assert_eq!(0, usage.month_bytes());
+        assert!(!usage.quota_exceeded());
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn foreign_network_usage_bounded() {
+        let path = std::env::temp_dir().join(format!(
+            "et_foreign_network_usage_{}.json",
+            uuid::Uuid::new_v4()
+        ));
+        let month = current_month();
+        let mut file = UsageFile::default();
+        for (name, month) in [("net_old", month - 1), ("net_now", month)] {
+            file.networks
+                .insert(name.to_owned(), UsageRecord { month, bytes: 10 });
+        }
+        std::fs::write(&path, serde_json::to_string(&file).unwrap()).unwrap();
+
+        let quota = ForeignNetworkQuota::new(vec![policy("net*")], Some(path.clone()));
+        assert_eq!(0, quota.get_usage("net_old").month_bytes());
+
+        // networks without a policy are not tracked
+        quota.get_usage("other").add(10);
+        // networks that left without using bytes are dropped
+        let idle = quota.get_usage("net_idle");
+        let busy = quota.get_usage("net_busy");
+        busy.add(10);
+        quota.persist().unwrap();
+        assert!(quota.usages.contains_key("net_idle"));
+        drop((idle, busy));
+        quota.persist().unwrap();
+
+        assert!(quota.usages.is_empty());
+        let file: UsageFile =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(
+            vec!["net_busy", "net_now"],
+            file.networks.keys().map(String::as_str).collect::<Vec<_>>()
+        );
+
+        let _ = std::fs::remove_file(path);
+    }
+}
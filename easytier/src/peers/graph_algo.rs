@@ -4,10 +4,14 @@ use hashbrown::hash_map::{
     HashMap,
 };
 use petgraph::{
-    algo::Measure,
-    visit::{EdgeRef as _, IntoEdges, VisitMap as _, Visitable},
+    algo::{dijkstra, Measure},
+    graph::{Graph, NodeIndex},
+    visit::{EdgeRef as _, IntoEdges, Reversed, VisitMap as _, Visitable},
+};
+use std::{
+    collections::{BinaryHeap, HashSet},
+    hash::Hash,
 };
-use std::{collections::BinaryHeap, hash::Hash};
 
 /// `MinScored<K, T>` holds a score `K` and a scored object `T` in
 /// a pair for use with a `BinaryHeap`.
@@ -124,6 +128,47 @@ where
     (scores, first_hop)
 }
 
+/// First hop from `start` towards `goal` after which the path stays clear of
+/// the nodes in `avoid`. Every node is assumed to forward along one of its own
+/// shortest paths to `goal`, so a node only counts as clear if all of its
+/// shortest paths are. The cheapest clear first hop is returned.
+pub fn first_hop_avoiding<N, E, F>(
+    graph: &Graph<N, E>,
+    start: NodeIndex,
+    goal: NodeIndex,
+    avoid: impl Fn(NodeIndex) -> bool,
+    edge_cost: F,
+) -> Option<NodeIndex>
+where
+    F: Fn(&E) -> usize,
+{
+    // cost from every node to the goal
+    let dist = dijkstra(Reversed(graph), goal, None, |e| edge_cost(e.weight()));
+
+    let mut nodes: Vec<_> = dist.iter().map(|(node, d)| (*d, *node)).collect();
+    nodes.sort();
+    let mut clear = HashSet::new();
+    for (d, node) in nodes {
+        if avoid(node) {
+            continue;
+        }
+        // edges cost at least 1, so the next hops are already decided
+        let next_hops_clear = graph
+            .edges(node)
+            .filter(|e| dist.get(&e.target()).map(|t| t + edge_cost(e.weight())) == Some(d))
+            .all(|e| clear.contains(&e.target()));
+        if node == goal || next_hops_clear {
+            clear.insert(node);
+        }
+    }
+
+    graph
+        .edges(start)
+        .filter(|e| clear.contains(&e.target()))
+        .min_by_key(|e| edge_cost(e.weight()) + dist[&e.target()])
+        .map(|e| e.target())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -175,4 +220,38 @@ mod tests {
         assert_eq!(first_hop[&d], (b, 2)); // d is reached via b
         assert_eq!(first_hop[&e], (b, 3)); // e is reached via d
     }
+
+    #[test]
+    fn test_first_hop_avoiding() {
+        let mut graph = DiGraph::<&str, usize>::new();
+        let a = graph.add_node("a");
+        let b = graph.add_node("b");
+        let c = graph.add_node("c");
+        let d = graph.add_node("d");
+        let y = graph.add_node("y");
+        let z = graph.add_node("z");
+        let e = graph.add_node("e");
+
+        // b forwards to e through y, c through y or z which are equally
+        // short, so only the detour a -> d -> e surely avoids y.
+        graph.extend_with_edges([
+            (a, b, 1),
+            (a, c, 1),
+            (a, d, 1),
+            (b, y, 1),
+            (c, y, 1),
+            (c, z, 1),
+            (y, e, 1),
+            (z, e, 1),
+            (d, e, 5),
+        ]);
+        let cost = |w: &usize| *w;
+
+        assert_ne!(Some(d), first_hop_avoiding(&graph, a, e, |_| false, cost));
+        assert_eq!(Some(d), first_hop_avoiding(&graph, a, e, |n| n == y, cost));
+        assert_eq!(
+            None,
+            first_hop_avoiding(&graph, a, e, |n| n == y || n == d, cost)
+        );
+    }
 }
@@ -0,0 +1,184 @@
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    common::PeerId,
+    proto::{cli::PeerConnInfo, peer_rpc::RouteLinkMetric},
+};
+
+// jitter counts twice, a jittery link delays some packets by about that much
+const JITTER_WEIGHT: u32 = 2;
+// one percent of loss costs as much as this many ms of latency
+const LOSS_PENALTY_MS_PER_PERCENT: f32 = 10.0;
+
+// a new cost is only published if it moved this much from the published one
+const MIN_COST_CHANGE_RATIO: f32 = 0.2;
+const MIN_COST_CHANGE_MS: u32 = 5;
+// and the published one is older than this, unless the link got much worse
+const MIN_PUBLISH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Cost in ms of a hop over a link nobody measured, e.g. to a node that does
+/// not publish link metrics, so it compares with the measured ones.
+pub const UNMEASURED_LINK_COST_MS: u32 = 100;
+
+/// Live measurement of a direct link, from the pinger of its connections.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkSample {
+    pub latency_us: u32,
+    pub jitter_us: u32,
+    pub loss_rate: f32,
+}
+
+impl LinkSample {
+    /// Best connection to a peer, None if none of them is measured yet.
+    pub fn from_conns(conns: &[PeerConnInfo]) -> Option<Self> {
+        conns
+            .iter()
+            .filter(|c| !c.is_closed)
+            .filter_map(|c| {
+                let stats = c.stats.as_ref()?;
+                (stats.latency_us > 0).then(|| LinkSample {
+                    latency_us: stats.latency_us.min(u32::MAX as u64) as u32,
+                    jitter_us: stats.jitter_us.min(u32::MAX as u64) as u32,
+                    loss_rate: c.loss_rate,
+                })
+            })
+            .min_by_key(|s| s.cost())
+    }
+
+    /// Route cost in milliseconds of latency, at least 1 like the hop cost.
+    pub fn cost(&self) -> u32 {
+        let latency_ms =
+            (self.latency_us as u64 + JITTER_WEIGHT as u64 * self.jitter_us as u64) as f32 / 1000.0;
+        let loss_ms = self.loss_rate.clamp(0.0, 1.0) * 100.0 * LOSS_PENALTY_MS_PER_PERCENT;
+        ((latency_ms + loss_ms).round() as u32).max(1)
+    }
+
+    fn to_metric(self, peer_id: PeerId) -> RouteLinkMetric {
+        RouteLinkMetric {
+            peer_id,
+            cost: self.cost(),
+            latency_us: self.latency_us,
+            jitter_us: self.jitter_us,
+            loss_rate: self.loss_rate,
+        }
+    }
+}
+
+fn should_republish(published: u32, new: u32, age: Duration) -> bool {
+    let change = published.abs_diff(new);
+    if change < MIN_COST_CHANGE_MS || (change as f32) < published as f32 * MIN_COST_CHANGE_RATIO {
+        return false;
+    }
+    // a link getting twice as bad is not held back
+    age >= MIN_PUBLISH_INTERVAL || new > published * 2
+}
+
+/// Turns the samples of the direct links into the metrics published in the
+/// route info of this node. A cost is only replaced when it changed by a
+/// noticeable amount and the previous one is old enough, so small jitter does
+/// not make every node recompute and switch routes.
+#[derive(Debug, Default)]
+pub struct LinkMetricTracker {
+    published: Mutex<BTreeMap<PeerId, (RouteLinkMetric, Instant)>>,
+}
+
+impl LinkMetricTracker {
+    /// Feed the current samples, returns the metrics to publish sorted by
+    /// peer id. Peers missing from `samples` are dropped.
+    pub fn update(&self, samples: Vec<(PeerId, LinkSample)>) -> Vec<RouteLinkMetric> {
+        self.update_at(samples, Instant::now())
+    }
+
+    fn update_at(&self, samples: Vec<(PeerId, LinkSample)>, now: Instant) -> Vec<RouteLinkMetric> {
+        let mut published = self.published.lock().unwrap();
+        published.retain(|peer_id, _| samples.iter().any(|(p, _)| p == peer_id));
+        for (peer_id, sample) in samples {
+            match published.entry(peer_id) {
+                Entry::Vacant(e) => {
+                    e.insert((sample.to_metric(peer_id), now));
+                }
+                Entry::Occupied(mut e) => {
+                    let (metric, at) = e.get();
+                    if should_republish(metric.cost, sample.cost(), now - *at) {
+                        e.insert((sample.to_metric(peer_id), now));
+                    }
+                }
+            }
+        }
+        published.values().map(|(m, _)| m.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(latency_ms: u32) -> LinkSample {
+        LinkSample {
+            latency_us: latency_ms * 1000,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn link_sample_cost() {
+        assert_eq!(1, sample(0).cost());
+        assert_eq!(20, sample(20).cost());
+        let jittery = LinkSample {
+            jitter_us: 5000,
+            ..sample(20)
+        };
+        assert_eq!(30, jittery.cost());
+        let lossy = LinkSample {
+            loss_rate: 0.05,
+            ..sample(20)
+        };
+        assert_eq!(70, lossy.cost());
+    }
+
+    #[test]
+    fn link_metric_hysteresis() {
+        let tracker = LinkMetricTracker::default();
+        let t0 = Instant::now();
+        let cost_at = |samples, t| -> Vec<u32> {
+            tracker
+                .update_at(samples, t)
+                .iter()
+                .map(|m| m.cost)
+                .collect()
+        };
+
+        assert_eq!(
+            vec![20, 40],
+            cost_at(vec![(2, sample(40)), (1, sample(20))], t0)
+        );
+        // small changes are ignored
+        assert_eq!(
+            vec![20, 40],
+            cost_at(
+                vec![(1, sample(23)), (2, sample(37))],
+                t0 + Duration::from_secs(60)
+            )
+        );
+        // large changes wait for the hold down
+        let t1 = t0 + Duration::from_secs(60);
+        assert_eq!(vec![30], cost_at(vec![(1, sample(30))], t1));
+        assert_eq!(
+            vec![30],
+            cost_at(vec![(1, sample(20))], t1 + Duration::from_secs(1))
+        );
+        // unless the link got much worse
+        assert_eq!(
+            vec![100],
+            cost_at(vec![(1, sample(100))], t1 + Duration::from_secs(2))
+        );
+        assert_eq!(
+            vec![20],
+            cost_at(vec![(1, sample(20))], t1 + Duration::from_secs(20))
+        );
+    }
+}
@@ -1,7 +1,12 @@
 mod graph_algo;
 
 pub mod acl_filter;
+pub mod data_compress;
+pub mod link_metric;
+pub mod multipath;
+pub mod node_auth;
 pub mod peer;
+pub mod relay_admission;
 // pub mod peer_conn;
 pub mod peer_conn;
 pub mod peer_conn_ping;
@@ -10,13 +15,18 @@ pub mod peer_map;
 pub mod peer_ospf_route;
 pub mod peer_rpc;
 pub mod peer_rpc_service;
+pub mod pmtu;
+pub mod policy_route;
 pub mod route_trait;
 pub mod rpc_service;
 
 pub mod foreign_network_client;
 pub mod foreign_network_manager;
+pub mod foreign_network_quota;
 
 pub mod encrypt;
+pub mod exit_egress;
+pub mod exit_node;
 
 pub mod peer_task;
 
@@ -0,0 +1,257 @@
+use std::{
+    collections::HashMap,
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use arc_swap::ArcSwap;
+use atomic_shim::AtomicU64;
+use crossbeam::atomic::AtomicCell;
+
+use crate::{
+    common::compressor::flow_key, proto::common::MultipathModePb, tunnel::packet_def::ZCPacket,
+};
+
+use super::peer_conn::{PeerConn, PeerConnId};
+
+const WEIGHT_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+// a connection losing more than this is only used if all others are worse
+const MAX_HEALTHY_LOSS_RATE: f32 = 0.3;
+// a connection slower than this many times the fastest one is not used
+const MAX_LATENCY_RATIO: u64 = 4;
+// added to the latency when comparing connections, so sub-millisecond jitter
+// on a lan does not skew the weights
+const LATENCY_SLACK_US: u64 = 5_000;
+const MAX_WEIGHT: u32 = 1000;
+
+/// Multipath counters of one connection, reported in `PeerConnStats`.
+#[derive(Debug, Default)]
+pub struct MultipathConnStats {
+    pub tx_packets: AtomicU64,
+    pub tx_bytes: AtomicU64,
+    pub weight: AtomicU32,
+    pub failovers: AtomicU64,
+}
+
+impl MultipathConnStats {
+    fn record(&self, bytes: usize) {
+        self.tx_packets.fetch_add(1, Ordering::Relaxed);
+        self.tx_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn set_weight(&self, weight: u32) {
+        let prev = self.weight.swap(weight, Ordering::Relaxed);
+        if prev != 0 && weight == 0 {
+            self.failovers.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Scheduling weight from the latency and loss measured by the pinger, higher
+/// is better. Returns 0 for connections that should not be used.
+fn conn_weight(latency_us: u64, loss_rate: f32, min_latency_us: u64) -> u32 {
+    if loss_rate > MAX_HEALTHY_LOSS_RATE {
+        return 0;
+    }
+    // no sample yet, assume it is as good as the best one
+    let latency_us = if latency_us == 0 {
+        min_latency_us
+    } else {
+        latency_us
+    } + LATENCY_SLACK_US;
+    let min_latency_us = min_latency_us + LATENCY_SLACK_US;
+    if latency_us > min_latency_us * MAX_LATENCY_RATIO {
+        return 0;
+    }
+    let by_latency = MAX_WEIGHT as u64 * min_latency_us / latency_us;
+    let by_loss = (1.0 - loss_rate).powi(2);
+    ((by_latency as f32 * by_loss) as u32).clamp(1, MAX_WEIGHT)
+}
+
+#[derive(Default)]
+struct Schedule {
+    conns: Vec<(Arc<PeerConn>, u32)>,
+}
+
+/// Spreads the packets to a peer over all its connections. Weights follow the
+/// latency and loss of each connection and lossy or slow connections are
+/// taken out until they recover, so traffic fails over without waiting for
+/// the connection to close.
+pub struct MultipathScheduler {
+    mode: MultipathModePb,
+    schedule: ArcSwap<Schedule>,
+    last_refresh: AtomicCell<Option<Instant>>,
+    // smooth weighted round robin state, see nginx upstream
+    current_weights: Mutex<HashMap<PeerConnId, i64>>,
+}
+
+impl MultipathScheduler {
+    pub fn new(mode: MultipathModePb) -> Self {
+        Self {
+            mode,
+            schedule: ArcSwap::from_pointee(Schedule::default()),
+            last_refresh: AtomicCell::new(None),
+            current_weights: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.mode != MultipathModePb::Disabled
+    }
+
+    /// Force the weights to be computed again on the next packet, called
+    /// when connections are added, closed or fail to send.
+    pub fn invalidate(&self) {
+        self.last_refresh.store(None);
+    }
+
+    fn refresh(&self, conns: Vec<Arc<PeerConn>>) {
+        let conns = conns
+            .into_iter()
+            .filter(|c| !c.get_close_notifier().is_closed())
+            .map(|c| {
+                let (latency, loss) = (c.get_stats().latency_us, c.get_loss_rate());
+                (c, latency, loss)
+            })
+            .collect::<Vec<_>>();
+
+        let min_latency = conns
+            .iter()
+            .filter(|(_, latency, loss)| *latency > 0 && *loss <= MAX_HEALTHY_LOSS_RATE)
+            .map(|(_, latency, _)| *latency)
+            .min()
+            .unwrap_or(0);
+
+        let mut weighted = conns
+            .iter()
+            .map(|(c, latency, loss)| (c.clone(), conn_weight(*latency, *loss, min_latency)))
+            .collect::<Vec<_>>();
+        // all connections are bad, use them anyway
+        if weighted.iter().all(|(_, w)| *w == 0) {
+            weighted.iter_mut().for_each(|(_, w)| *w = 1);
+        }
+        for (c, w) in weighted.iter() {
+            c.multipath_stats().set_weight(*w);
+        }
+        weighted.retain(|(_, w)| *w > 0);
+        // stable order so flow hashing does not move flows around
+        weighted.sort_by_key(|(c, _)| c.get_conn_id());
+
+        self.current_weights
+            .lock()
+            .unwrap()
+            .retain(|id, _| weighted.iter().any(|(c, _)| c.get_conn_id() == *id));
+        self.schedule.store(Arc::new(Schedule { conns: weighted }));
+        self.last_refresh.store(Some(Instant::now()));
+    }
+
+    fn select_round_robin(&self, schedule: &Schedule) -> Option<Arc<PeerConn>> {
+        let mut current = self.current_weights.lock().unwrap();
+        let total: i64 = schedule.conns.iter().map(|(_, w)| *w as i64).sum();
+        let mut best: Option<(&Arc<PeerConn>, i64)> = None;
+        for (conn, weight) in schedule.conns.iter() {
+            let cur = current.entry(conn.get_conn_id()).or_insert(0);
+            *cur += *weight as i64;
+            if best.map_or(true, |(_, b)| *cur > b) {
+                best = Some((conn, *cur));
+            }
+        }
+        let (conn, _) = best?;
+        *current.get_mut(&conn.get_conn_id()).unwrap() -= total;
+        Some(conn.clone())
+    }
+
+    fn select_by_flow(schedule: &Schedule, flow: u64) -> Option<Arc<PeerConn>> {
+        // weighted rendezvous hashing, only the flows of a removed connection
+        // move to others
+        schedule
+            .conns
+            .iter()
+            .map(|(conn, weight)| {
+                let mut hasher = std::collections::hash_map::DefaultHasher::new();
+                (flow, conn.get_conn_id()).hash(&mut hasher);
+                let u = (hasher.finish() as f64 + 1.0) / (u64::MAX as f64 + 2.0);
+                (conn, *weight as f64 / -u.ln())
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(conn, _)| conn.clone())
+    }
+
+    /// Pick the connection for `msg`, `list_conns` is only called when the
+    /// weights need to be computed again.
+    pub fn select(
+        &self,
+        msg: &ZCPacket,
+        list_conns: impl FnOnce() -> Vec<Arc<PeerConn>>,
+    ) -> Option<Arc<PeerConn>> {
+        if self
+            .last_refresh
+            .load()
+            .map_or(true, |t| t.elapsed() > WEIGHT_REFRESH_INTERVAL)
+        {
+            self.refresh(list_conns());
+        }
+
+        let schedule = self.schedule.load();
+        let conn = match self.mode {
+            MultipathModePb::FlowHash => match flow_hint(msg) {
+                Some(flow) => Self::select_by_flow(&schedule, flow),
+                None => self.select_round_robin(&schedule),
+            },
+            _ => self.select_round_robin(&schedule),
+        }?;
+        conn.multipath_stats().record(msg.buf_len());
+        Some(conn)
+    }
+}
+
+/// Flow of a packet, taken from the peer manager header if the sender filled
+/// it before encryption, otherwise from the plain payload.
+pub fn flow_hint(msg: &ZCPacket) -> Option<u64> {
+    match msg.peer_manager_header().map(|hdr| hdr.flow_hint) {
+        Some(hint) if hint != 0 => Some(hint as u64),
+        _ => flow_key(msg),
+    }
+}
+
+/// Reduce a flow key to the byte carried in the peer manager header, 0 is
+/// left for packets without a known flow.
+pub fn compact_flow_hint(flow: u64) -> u8 {
+    (flow % 255) as u8 + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multipath_conn_weight() {
+        // equal links share equally
+        assert_eq!(conn_weight(20_000, 0.0, 20_000), MAX_WEIGHT);
+        // twice the latency, about half the weight
+        assert_eq!(conn_weight(45_000, 0.0, 20_000), MAX_WEIGHT / 2);
+        // lan jitter does not matter
+        assert!(conn_weight(300, 0.0, 100) > MAX_WEIGHT * 9 / 10);
+        // loss lowers the weight, heavy loss removes the link
+        assert!(conn_weight(20_000, 0.1, 20_000) < MAX_WEIGHT);
+        assert_eq!(conn_weight(20_000, 0.5, 20_000), 0);
+        // far slower than the best link
+        assert_eq!(conn_weight(200_000, 0.0, 20_000), 0);
+        // not measured yet
+        assert_eq!(conn_weight(0, 0.0, 20_000), MAX_WEIGHT);
+    }
+
+    #[test]
+    fn multipath_failover_counter() {
+        let stats = MultipathConnStats::default();
+        stats.set_weight(100);
+        stats.set_weight(0);
+        stats.set_weight(0);
+        stats.set_weight(50);
+        assert_eq!(1, stats.failovers.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,359 @@
+// Node certificate authentication for the peer handshake.
+//
+// Every node owns an ed25519 key pair. A node is accepted by another node of
+// the same network if its public key is in the member list, or if it presents
+// a certificate signed by one of the trusted ca keys. Revoked keys are always
+// rejected. Possession of the private key is proven by signing the handshake
+// transcript, which contains random nonces from both sides.
+
+use std::{
+    collections::HashSet,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use prost::Message;
+use ring::{
+    rand::{SecureRandom, SystemRandom},
+    signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519},
+};
+
+use crate::{
+    common::{
+        config::{ConfigLoader, NodeAuthConfig},
+        error::Error,
+        global_ctx::GlobalCtx,
+        PeerId,
+    },
+    proto::peer_rpc::{NodeAuthInfo, NodeCertificate, SignedNodeCertificate},
+};
+
+const NONCE_LEN: usize = 32;
+const TRANSCRIPT_LABEL: &[u8] = b"easytier-node-auth-v1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeRole {
+    Client,
+    Server,
+}
+
+pub(crate) fn auth_err(msg: impl Into<String>) -> Error {
+    Error::NodeAuthError(msg.into())
+}
+
+pub(crate) fn decode_base64(what: &str, value: &str) -> Result<Vec<u8>, Error> {
+    BASE64_STANDARD
+        .decode(value.trim())
+        .map_err(|e| auth_err(format!("invalid base64 in {}: {:?}", what, e)))
+}
+
+pub(crate) fn load_key_pair(private_key: &str) -> Result<Ed25519KeyPair, Error> {
+    let pkcs8 = decode_base64("private key", private_key)?;
+    Ed25519KeyPair::from_pkcs8_maybe_unchecked(&pkcs8)
+        .map_err(|e| auth_err(format!("invalid ed25519 private key: {}", e)))
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Generate a new ed25519 private key, returned as base64 encoded pkcs8.
+pub fn generate_private_key() -> Result<String, Error> {
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+        .map_err(|_| auth_err("failed to generate ed25519 key"))?;
+    Ok(BASE64_STANDARD.encode(pkcs8.as_ref()))
+}
+
+/// Get the base64 encoded public key of a base64 encoded pkcs8 private key.
+pub fn public_key_of(private_key: &str) -> Result<String, Error> {
+    let key_pair = load_key_pair(private_key)?;
+    Ok(BASE64_STANDARD.encode(key_pair.public_key().as_ref()))
+}
+
+/// Sign a certificate for `node_public_key` with the ca private key. The
+/// returned certificate is base64 encoded and can be put into the config of
+/// the node.
+pub fn issue_certificate(
+    ca_private_key: &str,
+    node_public_key: &str,
+    network_name: &str,
+    node_name: &str,
+    valid_for: Option<Duration>,
+) -> Result<String, Error> {
+    let ca_key_pair = load_key_pair(ca_private_key)?;
+    let public_key = decode_base64("node public key", node_public_key)?;
+    if public_key.len() != 32 {
+        return Err(auth_err("node public key must be 32 bytes"));
+    }
+
+    let not_before = unix_now();
+    let cert = NodeCertificate {
+        public_key,
+        network_name: network_name.to_string(),
+        node_name: node_name.to_string(),
+        not_before,
+        not_after: valid_for.map(|d| not_before + d.as_secs()).unwrap_or(0),
+    };
+    let cert = cert.encode_to_vec();
+    let signed = SignedNodeCertificate {
+        signature: ca_key_pair.sign(&cert).as_ref().to_vec(),
+        ca_public_key: ca_key_pair.public_key().as_ref().to_vec(),
+        certificate: cert,
+    };
+    Ok(BASE64_STANDARD.encode(signed.encode_to_vec()))
+}
+
+/// Build the bytes signed by `role` to prove it owns the key in its auth info.
+/// Both nonces and both peer ids are covered, so a signature cannot be
+/// replayed in another handshake. Callers append the session key exchange
+/// public keys so they cannot be swapped by a man in the middle.
+pub fn handshake_transcript(
+    role: HandshakeRole,
+    network_name: &str,
+    client_peer_id: PeerId,
+    client: &NodeAuthInfo,
+    server_peer_id: PeerId,
+    server: &NodeAuthInfo,
+) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(256);
+    ret.extend_from_slice(TRANSCRIPT_LABEL);
+    ret.push(match role {
+        HandshakeRole::Client => 1,
+        HandshakeRole::Server => 2,
+    });
+    ret.extend_from_slice(&(network_name.len() as u32).to_be_bytes());
+    ret.extend_from_slice(network_name.as_bytes());
+    for (peer_id, info) in [(client_peer_id, client), (server_peer_id, server)] {
+        ret.extend_from_slice(&peer_id.to_be_bytes());
+        ret.extend_from_slice(&info.public_key);
+        ret.extend_from_slice(&info.nonce);
+    }
+    ret
+}
+
+pub struct NodeAuthenticator {
+    network_name: String,
+    key_pair: Ed25519KeyPair,
+    certificate: Option<SignedNodeCertificate>,
+    ca_public_keys: Vec<Vec<u8>>,
+    member_public_keys: HashSet<Vec<u8>>,
+    revoked_public_keys: HashSet<Vec<u8>>,
+}
+
+impl std::fmt::Debug for NodeAuthenticator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NodeAuthenticator")
+            .field("network_name", &self.network_name)
+            .field(
+                "public_key",
+                &BASE64_STANDARD.encode(self.key_pair.public_key().as_ref()),
+            )
+            .field("has_certificate", &self.certificate.is_some())
+            .finish()
+    }
+}
+
+impl NodeAuthenticator {
+    pub fn new(network_name: &str, config: &NodeAuthConfig) -> Result<Self, Error> {
+        let key_pair = load_key_pair(&config.private_key)?;
+
+        let certificate = match &config.certificate {
+            Some(cert) => Some(
+                SignedNodeCertificate::decode(decode_base64("certificate", cert)?.as_slice())
+                    .map_err(|e| auth_err(format!("invalid certificate: {:?}", e)))?,
+            ),
+            None => None,
+        };
+
+        let decode_keys = |what: &str, keys: &[String]| -> Result<Vec<Vec<u8>>, Error> {
+            keys.iter().map(|k| decode_base64(what, k)).collect()
+        };
+
+        Ok(Self {
+            network_name: network_name.to_string(),
+            key_pair,
+            certificate,
+            ca_public_keys: decode_keys("ca public key", &config.ca_public_keys)?,
+            member_public_keys: decode_keys("member public key", &config.member_public_keys)?
+                .into_iter()
+                .collect(),
+            revoked_public_keys: decode_keys("revoked public key", &config.revoked_public_keys)?
+                .into_iter()
+                .collect(),
+        })
+    }
+
+    /// Returns None if node auth is not configured, so the handshake falls
+    /// back to the shared network secret only.
+    pub fn from_global_ctx(global_ctx: &GlobalCtx) -> Result<Option<Self>, Error> {
+        let Some(config) = global_ctx.config.get_node_auth() else {
+            return Ok(None);
+        };
+        let network_name = global_ctx.get_network_identity().network_name;
+        Self::new(&network_name, &config).map(Some)
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        self.key_pair.public_key().as_ref()
+    }
+
+    /// Auth info sent before the transcript is known, carrying a fresh nonce.
+    pub fn hello(&self) -> NodeAuthInfo {
+        let mut nonce = vec![0u8; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .expect("system random should not fail");
+        NodeAuthInfo {
+            public_key: self.public_key().to_vec(),
+            certificate: self.certificate.clone(),
+            nonce,
+            signature: Vec::new(),
+        }
+    }
+
+    pub fn sign(&self, transcript: &[u8]) -> Vec<u8> {
+        self.key_pair.sign(transcript).as_ref().to_vec()
+    }
+
+    /// Check whether the remote node is a member of the network, either by the
+    /// member list or by its certificate.
+    pub fn verify_peer(&self, info: &NodeAuthInfo) -> Result<(), Error> {
+        if self.revoked_public_keys.contains(&info.public_key) {
+            return Err(auth_err("node public key is revoked"));
+        }
+
+        if self.member_public_keys.contains(&info.public_key) {
+            return Ok(());
+        }
+
+        let Some(signed) = &info.certificate else {
+            return Err(auth_err("node is not a member and has no certificate"));
+        };
+
+        if !self.ca_public_keys.contains(&signed.ca_public_key) {
+            return Err(auth_err("certificate is not signed by a trusted ca"));
+        }
+        if self.revoked_public_keys.contains(&signed.ca_public_key) {
+            return Err(auth_err("certificate ca key is revoked"));
+        }
+
+        UnparsedPublicKey::new(&ED25519, &signed.ca_public_key)
+            .verify(&signed.certificate, &signed.signature)
+            .map_err(|_| auth_err("invalid certificate signature"))?;
+
+        let cert = NodeCertificate::decode(signed.certificate.as_slice())
+            .map_err(|e| auth_err(format!("decode certificate error: {:?}", e)))?;
+
+        if cert.public_key != info.public_key {
+            return Err(auth_err("certificate does not match node public key"));
+        }
+        if cert.network_name != self.network_name {
+            return Err(auth_err(format!(
+                "certificate is issued for network {}",
+                cert.network_name
+            )));
+        }
+
+        let now = unix_now();
+        if now < cert.not_before || (cert.not_after != 0 && now > cert.not_after) {
+            return Err(auth_err("certificate is expired or not yet valid"));
+        }
+
+        Ok(())
+    }
+
+    /// Verify the transcript signature made by the remote node.
+    pub fn verify_signature(&self, info: &NodeAuthInfo, transcript: &[u8]) -> Result<(), Error> {
+        UnparsedPublicKey::new(&ED25519, &info.public_key)
+            .verify(transcript, &info.signature)
+            .map_err(|_| auth_err("invalid handshake signature"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member_config(ca_private_key: &str, network_name: &str) -> NodeAuthConfig {
+        let private_key = generate_private_key().unwrap();
+        let certificate = issue_certificate(
+            ca_private_key,
+            &public_key_of(&private_key).unwrap(),
+            network_name,
+            "node",
+            None,
+        )
+        .unwrap();
+        NodeAuthConfig {
+            private_key,
+            certificate: Some(certificate),
+            ca_public_keys: vec![public_key_of(ca_private_key).unwrap()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn verify_certificate() {
+        let ca = generate_private_key().unwrap();
+        let a = NodeAuthenticator::new("net", &member_config(&ca, "net")).unwrap();
+        let b = NodeAuthenticator::new("net", &member_config(&ca, "net")).unwrap();
+        a.verify_peer(&b.hello()).unwrap();
+
+        // certificate from another network
+        let c = NodeAuthenticator::new("net", &member_config(&ca, "other")).unwrap();
+        assert!(a.verify_peer(&c.hello()).is_err());
+
+        // certificate from an untrusted ca
+        let other_ca = generate_private_key().unwrap();
+        let d = NodeAuthenticator::new("net", &member_config(&other_ca, "net")).unwrap();
+        assert!(a.verify_peer(&d.hello()).is_err());
+
+        // certificate of b presented with another key
+        let mut stolen = d.hello();
+        stolen.certificate = b.hello().certificate;
+        assert!(a.verify_peer(&stolen).is_err());
+    }
+
+    #[test]
+    fn verify_member_and_revoked() {
+        let ca = generate_private_key().unwrap();
+        let member_key = generate_private_key().unwrap();
+        let member = NodeAuthenticator::new(
+            "net",
+            &NodeAuthConfig {
+                private_key: member_key.clone(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let revoked = NodeAuthenticator::new("net", &member_config(&ca, "net")).unwrap();
+
+        let mut config = member_config(&ca, "net");
+        config.member_public_keys = vec![public_key_of(&member_key).unwrap()];
+        config.revoked_public_keys = vec![BASE64_STANDARD.encode(revoked.public_key())];
+        let a = NodeAuthenticator::new("net", &config).unwrap();
+
+        a.verify_peer(&member.hello()).unwrap();
+        assert!(a.verify_peer(&revoked.hello()).is_err());
+    }
+
+    #[test]
+    fn verify_transcript_signature() {
+        let ca = generate_private_key().unwrap();
+        let a = NodeAuthenticator::new("net", &member_config(&ca, "net")).unwrap();
+        let b = NodeAuthenticator::new("net", &member_config(&ca, "net")).unwrap();
+        let (a_hello, mut b_hello) = (a.hello(), b.hello());
+
+        let transcript =
+            handshake_transcript(HandshakeRole::Server, "net", 1, &a_hello, 2, &b_hello);
+        b_hello.signature = b.sign(&transcript);
+        a.verify_signature(&b_hello, &transcript).unwrap();
+
+        let client_transcript =
+            handshake_transcript(HandshakeRole::Client, "net", 1, &a_hello, 2, &b_hello);
+        assert!(a.verify_signature(&b_hello, &client_transcript).is_err());
+    }
+}
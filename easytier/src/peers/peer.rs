@@ -1,13 +1,20 @@
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
 
 use crossbeam::atomic::AtomicCell;
 use dashmap::{DashMap, DashSet};
 
-use tokio::{select, sync::mpsc};
+use tokio::{
+    select,
+    sync::{mpsc, Notify},
+};
 
 use tracing::Instrument;
 
 use super::{
+    multipath::MultipathScheduler,
     peer_conn::{PeerConn, PeerConnId},
     PacketRecvChan,
 };
@@ -38,6 +45,13 @@ pub struct Peer {
 
     default_conn_id: Arc<AtomicCell<PeerConnId>>,
     default_conn_id_clear_task: ScopedTask<()>,
+
+    multipath: Arc<MultipathScheduler>,
+
+    // smallest path mtu of the conns, 0 if none is known yet
+    pmtu: Arc<AtomicU32>,
+    pmtu_changed: Arc<Notify>,
+    pmtu_update_task: ScopedTask<()>,
 }
 
 impl Peer {
@@ -49,10 +63,17 @@ impl Peer {
         let conns: ConnMap = Arc::new(DashMap::new());
         let (close_event_sender, mut close_event_receiver) = mpsc::channel(10);
         let shutdown_notifier = Arc::new(tokio::sync::Notify::new());
+        let pmtu_changed = Arc::new(Notify::new());
+
+        let multipath = Arc::new(MultipathScheduler::new(
+            global_ctx.get_flags().multipath_mode(),
+        ));
 
         let conns_copy = conns.clone();
         let shutdown_notifier_copy = shutdown_notifier.clone();
         let global_ctx_copy = global_ctx.clone();
+        let multipath_copy = multipath.clone();
+        let pmtu_changed_copy = pmtu_changed.clone();
         let close_event_listener = tokio::spawn(
             async move {
                 loop {
@@ -69,6 +90,8 @@ impl Peer {
                             );
 
                             if let Some((_, conn)) = conns_copy.remove(&ret) {
+                                multipath_copy.invalidate();
+                                pmtu_changed_copy.notify_one();
                                 global_ctx_copy.issue_event(GlobalCtxEvent::PeerConnRemoved(
                                     conn.get_conn_info(),
                                 ));
@@ -103,6 +126,21 @@ impl Peer {
             }
         }));
 
+        let pmtu = Arc::new(AtomicU32::new(0));
+        let conns_copy = conns.clone();
+        let pmtu_copy = pmtu.clone();
+        let pmtu_changed_copy = pmtu_changed.clone();
+        let pmtu_update_task = ScopedTask::from(tokio::spawn(async move {
+            loop {
+                pmtu_changed_copy.notified().await;
+                let pmtu = conns_copy
+                    .iter()
+                    .filter_map(|conn| conn.value().get_pmtu())
+                    .min();
+                pmtu_copy.store(pmtu.unwrap_or(0), Ordering::Relaxed);
+            }
+        }));
+
         Peer {
             peer_node_id,
             conns: conns.clone(),
@@ -115,6 +153,12 @@ impl Peer {
             shutdown_notifier,
             default_conn_id,
             default_conn_id_clear_task,
+
+            multipath,
+
+            pmtu,
+            pmtu_changed,
+            pmtu_update_task,
         }
     }
 
@@ -124,7 +168,12 @@ impl Peer {
 
         conn.start_recv_loop(self.packet_recv_chan.clone()).await;
         conn.start_pingpong();
+        if !self.global_ctx.get_flags().disable_pmtu_discovery {
+            conn.start_pmtu_discovery(self.pmtu_changed.clone());
+        }
         self.conns.insert(conn.get_conn_id(), Arc::new(conn));
+        self.multipath.invalidate();
+        self.pmtu_changed.notify_one();
 
         let close_event_sender = self.close_event_sender.clone();
         tokio::spawn(async move {
@@ -162,11 +211,27 @@ impl Peer {
             .map(|conn| conn.clone())
     }
 
+    fn select_multipath_conn(&self, msg: &ZCPacket) -> Option<ArcPeerConn> {
+        self.multipath.select(msg, || {
+            self.conns.iter().map(|conn| conn.value().clone()).collect()
+        })
+    }
+
     pub async fn send_msg(&self, msg: ZCPacket) -> Result<(), Error> {
-        let Some(conn) = self.select_conn().await else {
+        let conn = if self.multipath.enabled() {
+            self.select_multipath_conn(&msg)
+        } else {
+            self.select_conn().await
+        };
+        let Some(conn) = conn else {
             return Err(Error::PeerNoConnectionError(self.peer_node_id));
         };
-        conn.send_msg(msg).await?;
+        let ret = conn.send_msg(msg).await;
+        if ret.is_err() && self.multipath.enabled() {
+            // the conn may be closing, do not wait for the next refresh
+            self.multipath.invalidate();
+        }
+        ret?;
 
         Ok(())
     }
@@ -214,6 +279,14 @@ impl Peer {
             .collect()
     }
 
+    /// Smallest path mtu of the conns, packets may be sent on any of them.
+    pub fn get_pmtu(&self) -> Option<u32> {
+        match self.pmtu.load(Ordering::Relaxed) {
+            0 => None,
+            pmtu => Some(pmtu),
+        }
+    }
+
     pub fn get_default_conn_id(&self) -> PeerConnId {
         self.default_conn_id.load()
     }
@@ -235,7 +308,8 @@ mod tests {
     use crate::{
         common::{global_ctx::tests::get_mock_global_ctx, new_peer_id},
         peers::{create_packet_recv_chan, peer_conn::PeerConn},
-        tunnel::ring::create_ring_tunnel_pair,
+        proto::common::MultipathModePb,
+        tunnel::{packet_def::ZCPacket, ring::create_ring_tunnel_pair},
     };
 
     use super::Peer;
@@ -287,4 +361,49 @@ mod tests {
         println!("wait for close handler");
         close_handler.await.unwrap().unwrap();
     }
+
+    #[tokio::test]
+    async fn multipath_round_robin() {
+        let (local_packet_send, _local_packet_recv) = create_packet_recv_chan();
+        let (remote_packet_send, _remote_packet_recv) = create_packet_recv_chan();
+        let global_ctx = get_mock_global_ctx();
+        let mut flags = global_ctx.get_flags();
+        flags.multipath_mode = MultipathModePb::RoundRobin.into();
+        global_ctx.config.set_flags(flags);
+
+        let local_peer = Peer::new(new_peer_id(), local_packet_send, global_ctx.clone());
+        let remote_peer = Peer::new(new_peer_id(), remote_packet_send, global_ctx.clone());
+
+        for _ in 0..2 {
+            let (local_tunnel, remote_tunnel) = create_ring_tunnel_pair();
+            let mut local_peer_conn =
+                PeerConn::new(local_peer.peer_node_id, global_ctx.clone(), local_tunnel);
+            let mut remote_peer_conn =
+                PeerConn::new(remote_peer.peer_node_id, global_ctx.clone(), remote_tunnel);
+            let (a, b) = tokio::join!(
+                local_peer_conn.do_handshake_as_client(),
+                remote_peer_conn.do_handshake_as_server()
+            );
+            a.unwrap();
+            b.unwrap();
+            local_peer.add_peer_conn(local_peer_conn).await;
+            remote_peer.add_peer_conn(remote_peer_conn).await;
+        }
+
+        for _ in 0..10 {
+            let mut msg = ZCPacket::new_with_payload(b"hello");
+            msg.fill_peer_manager_hdr(local_peer.peer_node_id, remote_peer.peer_node_id, 0);
+            local_peer.send_msg(msg).await.unwrap();
+        }
+
+        // both conns have about the same latency and share the packets
+        let conns = local_peer.list_peer_conns().await;
+        assert_eq!(2, conns.len());
+        let sent = conns
+            .iter()
+            .map(|c| c.stats.as_ref().unwrap().multipath_tx_packets)
+            .collect::<Vec<_>>();
+        assert_eq!(10, sent.iter().sum::<u64>());
+        assert!(sent.iter().all(|x| *x >= 4), "{:?}", sent);
+    }
 }
@@ -8,13 +8,14 @@ use std::{
     },
 };
 
+use anyhow::Context;
 use arc_swap::ArcSwapOption;
 use futures::{StreamExt, TryFutureExt};
 
 use prost::Message;
 
 use tokio::{
-    sync::{broadcast, Mutex},
+    sync::{broadcast, Mutex, Notify},
     task::JoinSet,
     time::{timeout, Duration},
 };
@@ -34,18 +35,31 @@ use crate::{
     proto::{
         cli::{PeerConnInfo, PeerConnStats},
         common::TunnelInfo,
-        peer_rpc::HandshakeRequest,
+        peer_rpc::{HandshakeRequest, NodeAuthInfo},
     },
     tunnel::{
         filter::{StatsRecorderTunnelFilter, TunnelFilter, TunnelWithFilter},
         mpsc::{MpscTunnel, MpscTunnelSender},
-        packet_def::{PacketType, ZCPacket},
+        packet_def::{PacketType, ZCPacket, TAIL_RESERVED_SIZE},
         stats::{Throughput, WindowLatency},
         Tunnel, TunnelError, ZCPacketStream,
     },
 };
 
-use super::{peer_conn_ping::PeerConnPinger, PacketRecvChan};
+use super::{
+    encrypt::{
+        session_key::{
+            SessionCipher, SessionKeyExchange, SESSION_ENCRYPTION_RESERVED, SESSION_KEY_FEATURE,
+        },
+        Error as EncryptError,
+    },
+    multipath::MultipathConnStats,
+    node_auth::{handshake_transcript, HandshakeRole, NodeAuthenticator},
+    peer_conn_ping::PeerConnPinger,
+    pmtu::PmtuProber,
+    relay_admission::{decode_relay_token, RELAY_ADMISSION_FEATURE},
+    PacketRecvChan,
+};
 
 pub type PeerConnId = uuid::Uuid;
 
@@ -87,6 +101,12 @@ impl PeerConnCloseNotify {
     }
 }
 
+struct ServerAuthState {
+    authenticator: NodeAuthenticator,
+    client_auth: NodeAuthInfo,
+    server_hello: NodeAuthInfo,
+}
+
 struct PeerConnCounter {
     traffic_tx_bytes: CounterHandle,
     traffic_rx_bytes: CounterHandle,
@@ -110,6 +130,9 @@ pub struct PeerConn {
     info: Option<HandshakeRequest>,
     is_client: Option<bool>,
 
+    local_session_public_key: Vec<u8>,
+    session_cipher: Option<Arc<SessionCipher>>,
+
     // remote or local
     is_hole_punched: bool,
 
@@ -120,6 +143,8 @@ pub struct PeerConn {
     latency_stats: Arc<WindowLatency>,
     throughput: Arc<Throughput>,
     loss_rate_stats: Arc<AtomicU32>,
+    pmtu: Arc<AtomicU32>,
+    multipath_stats: MultipathConnStats,
 
     counters: ArcSwapOption<PeerConnCounter>,
 }
@@ -166,6 +191,9 @@ impl PeerConn {
             info: None,
             is_client: None,
 
+            local_session_public_key: Vec::new(),
+            session_cipher: None,
+
             is_hole_punched: true,
 
             close_event_notifier: Arc::new(PeerConnCloseNotify::new(conn_id)),
@@ -175,6 +203,8 @@ impl PeerConn {
             latency_stats: Arc::new(WindowLatency::new(15)),
             throughput,
             loss_rate_stats: Arc::new(AtomicU32::new(0)),
+            pmtu: Arc::new(AtomicU32::new(0)),
+            multipath_stats: MultipathConnStats::default(),
 
             counters: ArcSwapOption::new(None),
         }
@@ -260,7 +290,12 @@ impl PeerConn {
         .await?
     }
 
-    async fn send_handshake(&mut self, send_secret_digest: bool) -> Result<(), Error> {
+    async fn send_handshake(
+        &mut self,
+        send_secret_digest: bool,
+        node_auth: Option<NodeAuthInfo>,
+        session_kx: Option<&SessionKeyExchange>,
+    ) -> Result<(), Error> {
         let network = self.global_ctx.get_network_identity();
         let mut req = HandshakeRequest {
             magic: MAGIC,
@@ -268,9 +303,22 @@ impl PeerConn {
             version: VERSION,
             features: Vec::new(),
             network_name: network.network_name.clone(),
+            node_auth,
             ..Default::default()
         };
 
+        // clients offer their relay admission token, relays that want it ask
+        // for it in their reply
+        let offer_token = self.is_client.is_none() && self.relay_admission_token().is_some();
+        if offer_token || (self.is_client == Some(false) && self.need_relay_admission_token()) {
+            req.features.push(RELAY_ADMISSION_FEATURE.to_owned());
+        }
+
+        if let Some(session_kx) = session_kx {
+            req.features.push(SESSION_KEY_FEATURE.to_owned());
+            req.session_public_key = session_kx.public_key().to_vec();
+        }
+
         // only send network secret digest if the network is the same
         if send_secret_digest {
             req.network_secret_digrest
@@ -281,6 +329,10 @@ impl PeerConn {
                 .extend_from_slice(&[0u8; std::mem::size_of::<NetworkSecretDigest>()]);
         }
 
+        self.send_handshake_request(req).await
+    }
+
+    async fn send_handshake_request(&mut self, req: HandshakeRequest) -> Result<(), Error> {
         let hs_req = req.encode_to_vec();
         let mut zc_packet = ZCPacket::new_with_payload(hs_req.as_bytes());
         zc_packet.fill_peer_manager_hdr(
@@ -312,42 +364,65 @@ impl PeerConn {
 
         handshake_recved(self, &rsp)?;
 
-        tracing::info!("handshake request: {:?}", rsp);
-        self.info = Some(rsp);
-        self.is_client = Some(false);
-
-        let send_digest = self.get_network_identity() == self.global_ctx.get_network_identity();
-        self.send_handshake(send_digest).await?;
-
-        if self.get_peer_id() == self.my_peer_id {
-            Err(Error::WaitRespError("peer id conflict".to_owned()))
-        } else {
-            Ok(())
-        }
+        self.reply_handshake_as_server(rsp, "peer id conflict")
+            .await
     }
 
     #[tracing::instrument]
     pub async fn do_handshake_as_server(&mut self) -> Result<(), Error> {
         let rsp = self.wait_handshake_loop().await?;
+        self.reply_handshake_as_server(rsp, "peer id conflict, are you connecting to yourself?")
+            .await
+    }
+
+    async fn reply_handshake_as_server(
+        &mut self,
+        rsp: HandshakeRequest,
+        peer_id_conflict_msg: &str,
+    ) -> Result<(), Error> {
         tracing::info!("handshake request: {:?}", rsp);
         self.info = Some(rsp);
         self.is_client = Some(false);
 
         let send_digest = self.get_network_identity() == self.global_ctx.get_network_identity();
-        self.send_handshake(send_digest).await?;
+        // only answer the key exchange if the client asked for it
+        let session_kx = if self.peer_supports_session_key() {
+            self.new_session_key_exchange()?
+        } else {
+            None
+        };
+        let server_auth = self.prepare_server_auth()?;
+        self.send_handshake(
+            send_digest,
+            server_auth.as_ref().map(|a| a.server_hello.clone()),
+            session_kx.as_ref(),
+        )
+        .await?;
 
         if self.get_peer_id() == self.my_peer_id {
-            Err(Error::WaitRespError(
-                "peer id conflict, are you connecting to yourself?".to_owned(),
-            ))
-        } else {
-            Ok(())
+            return Err(Error::WaitRespError(peer_id_conflict_msg.to_owned()));
+        }
+
+        self.establish_session(session_kx)?;
+
+        if let Some(server_auth) = server_auth {
+            self.wait_client_auth_proof(server_auth).await?;
+        }
+
+        if self.need_relay_admission_token() {
+            self.wait_relay_admission_token().await?;
         }
+
+        Ok(())
     }
 
     #[tracing::instrument]
     pub async fn do_handshake_as_client(&mut self) -> Result<(), Error> {
-        self.send_handshake(true).await?;
+        let authenticator = NodeAuthenticator::from_global_ctx(&self.global_ctx)?;
+        let hello = authenticator.as_ref().map(|a| a.hello());
+        let session_kx = self.new_session_key_exchange()?;
+        self.send_handshake(true, hello.clone(), session_kx.as_ref())
+            .await?;
         tracing::info!("waiting for handshake request from server");
         let rsp = self.wait_handshake_loop().await?;
         tracing::info!("handshake response: {:?}", rsp);
@@ -355,11 +430,229 @@ impl PeerConn {
         self.is_client = Some(true);
 
         if self.get_peer_id() == self.my_peer_id {
-            Err(Error::WaitRespError(
+            return Err(Error::WaitRespError(
                 "peer id conflict, are you connecting to yourself?".to_owned(),
-            ))
+            ));
+        }
+
+        self.establish_session(session_kx)?;
+
+        if let (Some(authenticator), Some(hello)) = (authenticator, hello) {
+            self.send_client_auth_proof(&authenticator, hello).await?;
+        }
+
+        if self.peer_has_feature(RELAY_ADMISSION_FEATURE) {
+            self.send_relay_admission_token().await?;
+        }
+
+        Ok(())
+    }
+
+    fn peer_has_feature(&self, feature: &str) -> bool {
+        self.info
+            .as_ref()
+            .unwrap()
+            .features
+            .iter()
+            .any(|f| f == feature)
+    }
+
+    fn relay_admission_token(&self) -> Option<Vec<u8>> {
+        let token = self.global_ctx.config.get_relay_admission_token()?;
+        decode_relay_token(&token)
+            .inspect_err(|e| tracing::warn!(?e, "ignore invalid relay admission token"))
+            .ok()
+    }
+
+    // relays only ask foreign networks offering a token, and only if
+    // admission is configured
+    fn need_relay_admission_token(&self) -> bool {
+        !self.need_node_auth()
+            && self.peer_has_feature(RELAY_ADMISSION_FEATURE)
+            && self.global_ctx.config.get_relay_admission().is_some()
+    }
+
+    async fn send_relay_admission_token(&mut self) -> Result<(), Error> {
+        let req = HandshakeRequest {
+            magic: MAGIC,
+            my_peer_id: self.my_peer_id,
+            version: VERSION,
+            network_name: self.global_ctx.get_network_identity().network_name,
+            network_secret_digrest: vec![0u8; std::mem::size_of::<NetworkSecretDigest>()],
+            relay_admission_token: self.relay_admission_token().unwrap_or_default(),
+            ..Default::default()
+        };
+        self.send_handshake_request(req).await
+    }
+
+    async fn wait_relay_admission_token(&mut self) -> Result<(), Error> {
+        let req = self.wait_handshake_loop().await?;
+        if req.my_peer_id != self.get_peer_id() {
+            return Err(Error::WaitRespError(
+                "relay admission token does not match handshake".to_owned(),
+            ));
+        }
+        self.info.as_mut().unwrap().relay_admission_token = req.relay_admission_token;
+        Ok(())
+    }
+
+    // node auth only applies to peers of our own network, foreign networks
+    // relayed by this node have their own credentials.
+    fn need_node_auth(&self) -> bool {
+        self.info.as_ref().unwrap().network_name
+            == self.global_ctx.get_network_identity().network_name
+    }
+
+    fn auth_transcript(
+        &self,
+        role: HandshakeRole,
+        client: &NodeAuthInfo,
+        server: &NodeAuthInfo,
+    ) -> Vec<u8> {
+        let (client_peer_id, server_peer_id) = self.client_server_peer_ids();
+        let mut transcript = handshake_transcript(
+            role,
+            &self.global_ctx.get_network_identity().network_name,
+            client_peer_id,
+            client,
+            server_peer_id,
+            server,
+        );
+
+        let remote_session_key = &self.info.as_ref().unwrap().session_public_key;
+        let (client_session_key, server_session_key) = if self.is_client.unwrap_or(false) {
+            (&self.local_session_public_key, remote_session_key)
         } else {
-            Ok(())
+            (remote_session_key, &self.local_session_public_key)
+        };
+        transcript.extend_from_slice(client_session_key);
+        transcript.extend_from_slice(server_session_key);
+        transcript
+    }
+
+    /// Verify the certificate of the client and build the signed server hello.
+    /// Returns None if node auth is disabled or not needed for this peer.
+    fn prepare_server_auth(&self) -> Result<Option<ServerAuthState>, Error> {
+        let Some(authenticator) = NodeAuthenticator::from_global_ctx(&self.global_ctx)? else {
+            return Ok(None);
+        };
+        if !self.need_node_auth() {
+            return Ok(None);
+        }
+
+        let Some(client_auth) = self.info.as_ref().unwrap().node_auth.clone() else {
+            return Err(Error::NodeAuthError(
+                "client does not provide node credentials".to_owned(),
+            ));
+        };
+        authenticator.verify_peer(&client_auth)?;
+
+        let mut server_hello = authenticator.hello();
+        server_hello.signature = authenticator.sign(&self.auth_transcript(
+            HandshakeRole::Server,
+            &client_auth,
+            &server_hello,
+        ));
+
+        Ok(Some(ServerAuthState {
+            authenticator,
+            client_auth,
+            server_hello,
+        }))
+    }
+
+    async fn wait_client_auth_proof(
+        &mut self,
+        ServerAuthState {
+            authenticator,
+            client_auth,
+            server_hello,
+        }: ServerAuthState,
+    ) -> Result<(), Error> {
+        let proof = self.wait_handshake_loop().await?;
+        let Some(proof_auth) = proof.node_auth else {
+            return Err(Error::NodeAuthError("missing client auth proof".to_owned()));
+        };
+        if proof.my_peer_id != self.get_peer_id() || proof_auth.public_key != client_auth.public_key
+        {
+            return Err(Error::NodeAuthError(
+                "client auth proof does not match handshake".to_owned(),
+            ));
+        }
+
+        let transcript = self.auth_transcript(HandshakeRole::Client, &client_auth, &server_hello);
+        authenticator.verify_signature(&proof_auth, &transcript)
+    }
+
+    async fn send_client_auth_proof(
+        &mut self,
+        authenticator: &NodeAuthenticator,
+        mut hello: NodeAuthInfo,
+    ) -> Result<(), Error> {
+        if !self.need_node_auth() {
+            return Ok(());
+        }
+
+        let Some(server_auth) = self.info.as_ref().unwrap().node_auth.clone() else {
+            return Err(Error::NodeAuthError(
+                "server does not provide node credentials".to_owned(),
+            ));
+        };
+        authenticator.verify_peer(&server_auth)?;
+        authenticator.verify_signature(
+            &server_auth,
+            &self.auth_transcript(HandshakeRole::Server, &hello, &server_auth),
+        )?;
+
+        hello.signature =
+            authenticator.sign(&self.auth_transcript(HandshakeRole::Client, &hello, &server_auth));
+        self.send_handshake(true, Some(hello), None).await
+    }
+
+    fn peer_supports_session_key(&self) -> bool {
+        let info = self.info.as_ref().unwrap();
+        info.features.iter().any(|f| f == SESSION_KEY_FEATURE)
+            && !info.session_public_key.is_empty()
+    }
+
+    /// Start an ephemeral key exchange if encryption is enabled. The public key
+    /// is remembered so node auth signatures can cover it.
+    fn new_session_key_exchange(&mut self) -> Result<Option<SessionKeyExchange>, Error> {
+        if !self.global_ctx.get_flags().enable_encryption {
+            return Ok(None);
+        }
+        let kx = SessionKeyExchange::new().with_context(|| "create session key exchange")?;
+        self.local_session_public_key = kx.public_key().to_vec();
+        Ok(Some(kx))
+    }
+
+    fn establish_session(&mut self, session_kx: Option<SessionKeyExchange>) -> Result<(), Error> {
+        let Some(session_kx) = session_kx else {
+            return Ok(());
+        };
+        if !self.peer_supports_session_key() {
+            tracing::info!("peer does not support session key, use network key only");
+            return Ok(());
+        }
+
+        let (client_peer_id, server_peer_id) = self.client_server_peer_ids();
+        let cipher = session_kx
+            .derive(
+                &self.info.as_ref().unwrap().session_public_key,
+                self.is_client.unwrap_or(false),
+                client_peer_id,
+                server_peer_id,
+            )
+            .with_context(|| "derive session key")?;
+        self.session_cipher = Some(Arc::new(cipher));
+        Ok(())
+    }
+
+    fn client_server_peer_ids(&self) -> (PeerId, PeerId) {
+        if self.is_client.unwrap_or(false) {
+            (self.my_peer_id, self.get_peer_id())
+        } else {
+            (self.get_peer_id(), self.my_peer_id)
         }
     }
 
@@ -390,6 +683,9 @@ impl PeerConn {
         self.counters.store(Some(Arc::new(counters)));
 
         let counters = self.counters.load_full().unwrap();
+        let session_cipher = self.session_cipher.clone();
+        let replay_dropped_packets =
+            stats_mgr.get_counter(MetricName::TrafficPacketsReplayDropped, label_set.clone());
 
         self.tasks.spawn(
             async move {
@@ -407,7 +703,7 @@ impl PeerConn {
                     counters.traffic_rx_bytes.add(zc_packet.buf_len() as u64);
                     counters.traffic_rx_packets.inc();
 
-                    let Some(peer_mgr_hdr) = zc_packet.mut_peer_manager_header() else {
+                    let Some(peer_mgr_hdr) = zc_packet.peer_manager_header() else {
                         tracing::error!(
                             "unexpected packet: {:?}, cannot decode peer manager hdr",
                             zc_packet
@@ -415,6 +711,36 @@ impl PeerConn {
                         continue;
                     };
 
+                    let packet_type = peer_mgr_hdr.packet_type;
+                    let is_session_encrypted = peer_mgr_hdr.is_session_encrypted();
+                    if let Some(session_cipher) = &session_cipher {
+                        if is_session_encrypted {
+                            match session_cipher.decrypt(&mut zc_packet) {
+                                Ok(()) => {}
+                                Err(EncryptError::ReplayDetected) => {
+                                    tracing::debug!("drop replayed session packet");
+                                    replay_dropped_packets.inc();
+                                    continue;
+                                }
+                                Err(e) => {
+                                    tracing::warn!(?e, "session decrypt failed, drop packet");
+                                    continue;
+                                }
+                            }
+                        } else if packet_type != PacketType::Ping as u8
+                            && packet_type != PacketType::Pong as u8
+                        {
+                            // ping and pong carry no data and are sent in plaintext
+                            tracing::warn!(?packet_type, "drop packet without session encryption");
+                            continue;
+                        }
+                    } else if is_session_encrypted {
+                        tracing::warn!("session encrypted packet on conn without session key");
+                        continue;
+                    }
+
+                    let peer_mgr_hdr = zc_packet.mut_peer_manager_header().unwrap();
+
                     if peer_mgr_hdr.packet_type == PacketType::Ping as u8 {
                         peer_mgr_hdr.packet_type = PacketType::Pong as u8;
                         if let Err(e) = sink.send(zc_packet).await {
@@ -466,7 +792,36 @@ impl PeerConn {
         });
     }
 
-    pub async fn send_msg(&self, msg: ZCPacket) -> Result<(), Error> {
+    /// Probes the path mtu of the conn, `pmtu_changed` is notified whenever
+    /// it changes.
+    pub fn start_pmtu_discovery(&mut self, pmtu_changed: Arc<Notify>) {
+        let mut tail_reserved = TAIL_RESERVED_SIZE;
+        if self.session_cipher.is_some() {
+            tail_reserved += SESSION_ENCRYPTION_RESERVED;
+        }
+        let prober = PmtuProber::new(
+            self.my_peer_id,
+            self.get_peer_id(),
+            self.sink.clone(),
+            self.ctrl_resp_sender.clone(),
+            self.pmtu.clone(),
+            pmtu_changed,
+            self.global_ctx.get_flags().mtu,
+            tail_reserved,
+        );
+        self.tasks.spawn(async move {
+            prober.run().await;
+            Ok(())
+        });
+    }
+
+    pub async fn send_msg(&self, mut msg: ZCPacket) -> Result<(), Error> {
+        if let Some(session_cipher) = &self.session_cipher {
+            session_cipher
+                .encrypt(&mut msg)
+                .with_context(|| "session encrypt failed")?;
+        }
+
         let counters = self.counters.load();
         if let Some(ref counters) = *counters {
             counters.traffic_tx_bytes.add(msg.buf_len() as u64);
@@ -479,6 +834,11 @@ impl PeerConn {
         self.info.as_ref().unwrap().my_peer_id
     }
 
+    /// Relay admission token presented by the peer, empty if none.
+    pub fn get_relay_admission_token(&self) -> &[u8] {
+        &self.info.as_ref().unwrap().relay_admission_token
+    }
+
     pub fn get_network_identity(&self) -> NetworkIdentity {
         let info = self.info.as_ref().unwrap();
         let mut ret = NetworkIdentity {
@@ -500,15 +860,37 @@ impl PeerConn {
     pub fn get_stats(&self) -> PeerConnStats {
         PeerConnStats {
             latency_us: self.latency_stats.get_latency_us(),
+            jitter_us: self.latency_stats.get_jitter_us() as u64,
 
             tx_bytes: self.throughput.tx_bytes(),
             rx_bytes: self.throughput.rx_bytes(),
 
             tx_packets: self.throughput.tx_packets(),
             rx_packets: self.throughput.rx_packets(),
+
+            multipath_tx_packets: self.multipath_stats.tx_packets.load(Ordering::Relaxed),
+            multipath_tx_bytes: self.multipath_stats.tx_bytes.load(Ordering::Relaxed),
+            multipath_weight: self.multipath_stats.weight.load(Ordering::Relaxed),
+            multipath_failovers: self.multipath_stats.failovers.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn get_loss_rate(&self) -> f32 {
+        (f64::from(self.loss_rate_stats.load(Ordering::Relaxed)) / 100.0) as f32
+    }
+
+    /// Path mtu found by probing, None before the first probe finished.
+    pub fn get_pmtu(&self) -> Option<u32> {
+        match self.pmtu.load(Ordering::Relaxed) {
+            0 => None,
+            pmtu => Some(pmtu),
         }
     }
 
+    pub fn multipath_stats(&self) -> &MultipathConnStats {
+        &self.multipath_stats
+    }
+
     pub fn get_conn_info(&self) -> PeerConnInfo {
         let info = self.info.as_ref().unwrap();
         PeerConnInfo {
@@ -518,10 +900,11 @@ impl PeerConn {
             features: info.features.clone(),
             tunnel: self.tunnel_info.clone(),
             stats: Some(self.get_stats()),
-            loss_rate: (f64::from(self.loss_rate_stats.load(Ordering::Relaxed)) / 100.0) as f32,
+            loss_rate: self.get_loss_rate(),
             is_client: self.is_client.unwrap_or_default(),
             network_name: info.network_name.clone(),
             is_closed: self.close_event_notifier.is_closed(),
+            pmtu: self.get_pmtu().unwrap_or_default(),
         }
     }
 
@@ -549,6 +932,7 @@ mod tests {
     use std::sync::Arc;
 
     use super::*;
+    use crate::common::config::ConfigLoader;
     use crate::common::global_ctx::tests::get_mock_global_ctx;
     use crate::common::new_peer_id;
     use crate::common::scoped_task::ScopedTask;
@@ -612,6 +996,120 @@ mod tests {
         assert_eq!(c_peer.get_network_identity(), NetworkIdentity::default());
     }
 
+    #[tokio::test]
+    async fn peer_conn_session_key() {
+        let (c, s) = create_ring_tunnel_pair();
+        let mut c_peer = PeerConn::new(new_peer_id(), get_mock_global_ctx(), Box::new(c));
+        let mut s_peer = PeerConn::new(new_peer_id(), get_mock_global_ctx(), Box::new(s));
+
+        let (c_ret, s_ret) = tokio::join!(
+            c_peer.do_handshake_as_client(),
+            s_peer.do_handshake_as_server()
+        );
+        c_ret.unwrap();
+        s_ret.unwrap();
+        assert!(c_peer.session_cipher.is_some());
+        assert!(s_peer.session_cipher.is_some());
+
+        let (s_chan, mut s_chan_recv) = create_packet_recv_chan();
+        s_peer.start_recv_loop(s_chan).await;
+        c_peer.start_recv_loop(create_packet_recv_chan().0).await;
+
+        let mut packet = ZCPacket::new_with_payload(b"session data");
+        packet.fill_peer_manager_hdr(c_peer.my_peer_id, s_peer.my_peer_id, PacketType::Data as u8);
+        c_peer.send_msg(packet).await.unwrap();
+
+        let packet = crate::peers::recv_packet_from_chan(&mut s_chan_recv)
+            .await
+            .unwrap();
+        assert!(!packet.peer_manager_header().unwrap().is_session_encrypted());
+        assert_eq!(packet.payload(), b"session data");
+    }
+
+    #[tokio::test]
+    async fn peer_conn_session_key_fallback() {
+        let (c, s) = create_ring_tunnel_pair();
+        let s_ctx = get_mock_global_ctx();
+        let mut flags = s_ctx.get_flags();
+        flags.enable_encryption = false;
+        s_ctx.set_flags(flags);
+
+        let mut c_peer = PeerConn::new(new_peer_id(), get_mock_global_ctx(), Box::new(c));
+        let mut s_peer = PeerConn::new(new_peer_id(), s_ctx, Box::new(s));
+
+        let (c_ret, s_ret) = tokio::join!(
+            c_peer.do_handshake_as_client(),
+            s_peer.do_handshake_as_server()
+        );
+        c_ret.unwrap();
+        s_ret.unwrap();
+        assert!(c_peer.session_cipher.is_none());
+        assert!(s_peer.session_cipher.is_none());
+    }
+
+    fn node_auth_config(ca_private_key: &str) -> crate::common::config::NodeAuthConfig {
+        use crate::peers::node_auth::{generate_private_key, issue_certificate, public_key_of};
+        let private_key = generate_private_key().unwrap();
+        let certificate = issue_certificate(
+            ca_private_key,
+            &public_key_of(&private_key).unwrap(),
+            &NetworkIdentity::default().network_name,
+            "node",
+            None,
+        )
+        .unwrap();
+        crate::common::config::NodeAuthConfig {
+            private_key,
+            certificate: Some(certificate),
+            ca_public_keys: vec![public_key_of(ca_private_key).unwrap()],
+            ..Default::default()
+        }
+    }
+
+    async fn node_auth_handshake(
+        c_auth: Option<crate::common::config::NodeAuthConfig>,
+        s_auth: Option<crate::common::config::NodeAuthConfig>,
+    ) -> (Result<(), Error>, Result<(), Error>) {
+        let (c, s) = create_ring_tunnel_pair();
+        let c_ctx = get_mock_global_ctx();
+        c_ctx.config.set_node_auth(c_auth);
+        let s_ctx = get_mock_global_ctx();
+        s_ctx.config.set_node_auth(s_auth);
+
+        let mut c_peer = PeerConn::new(new_peer_id(), c_ctx, Box::new(c));
+        let mut s_peer = PeerConn::new(new_peer_id(), s_ctx, Box::new(s));
+
+        tokio::join!(
+            c_peer.do_handshake_as_client(),
+            s_peer.do_handshake_as_server()
+        )
+    }
+
+    #[tokio::test]
+    async fn peer_conn_handshake_with_node_auth() {
+        let ca = crate::peers::node_auth::generate_private_key().unwrap();
+
+        let (c_ret, s_ret) =
+            node_auth_handshake(Some(node_auth_config(&ca)), Some(node_auth_config(&ca))).await;
+        c_ret.unwrap();
+        s_ret.unwrap();
+
+        // node without credentials cannot join an authenticated network
+        let (_, s_ret) = node_auth_handshake(None, Some(node_auth_config(&ca))).await;
+        assert!(matches!(s_ret, Err(Error::NodeAuthError(_))));
+
+        let (c_ret, _) = node_auth_handshake(Some(node_auth_config(&ca)), None).await;
+        assert!(matches!(c_ret, Err(Error::NodeAuthError(_))));
+
+        // revoked node is rejected even if its certificate is valid
+        let c_auth = node_auth_config(&ca);
+        let mut s_auth = node_auth_config(&ca);
+        s_auth.revoked_public_keys =
+            vec![crate::peers::node_auth::public_key_of(&c_auth.private_key).unwrap()];
+        let (_, s_ret) = node_auth_handshake(Some(c_auth), Some(s_auth)).await;
+        assert!(matches!(s_ret, Err(Error::NodeAuthError(_))));
+    }
+
     async fn peer_conn_pingpong_test_common(
         drop_start: u32,
         drop_end: u32,
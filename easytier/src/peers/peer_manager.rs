@@ -1,4 +1,5 @@
 use std::{
+    collections::BTreeSet,
     fmt::Debug,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     sync::{atomic::AtomicBool, Arc, Weak},
@@ -20,7 +21,7 @@ use tokio::{
 
 use crate::{
     common::{
-        compressor::{Compressor as _, DefaultCompressor},
+        compressor::{flow_key, Compressor as _, DefaultCompressor},
         constants::EASYTIER_VERSION,
         error::Error,
         global_ctx::{ArcGlobalCtx, NetworkIdentity},
@@ -29,6 +30,7 @@ use crate::{
         PeerId,
     },
     peers::{
+        link_metric::LinkSample,
         peer_conn::PeerConn,
         peer_rpc::PeerRpcManagerTransport,
         recv_packet_from_chan,
@@ -38,8 +40,9 @@ use crate::{
     proto::{
         cli::{
             self, list_global_foreign_network_response::OneForeignNetwork,
-            ListGlobalForeignNetworkResponse,
+            ListGlobalForeignNetworkResponse, LookupRouteResponse, PolicyRouteInfo,
         },
+        common::MultipathModePb,
         peer_rpc::{ForeignNetworkRouteInfoEntry, ForeignNetworkRouteInfoKey},
     },
     tunnel::{
@@ -51,13 +54,19 @@ use crate::{
 
 use super::{
     create_packet_recv_chan,
-    encrypt::{Encryptor, NullCipher},
+    data_compress::DataCompressNegotiator,
+    encrypt::{replay_window::PeerReplayFilter, Encryptor, NullCipher},
+    exit_egress::{run_exit_egress_refresh, ExitEgressManager},
+    exit_node::{run_exit_node_health_check, ExitNodeRpcService, ExitNodeSelector},
     foreign_network_client::ForeignNetworkClient,
     foreign_network_manager::{ForeignNetworkManager, GlobalForeignNetworkAccessor},
+    multipath::compact_flow_hint,
     peer_conn::PeerConnId,
     peer_map::PeerMap,
     peer_ospf_route::PeerRoute,
     peer_rpc::PeerRpcManager,
+    pmtu,
+    policy_route::{run_policy_route_refresh, PolicyMatch, PolicyRouter},
     route_trait::{ArcRoute, Route},
     BoxNicPacketFilter, BoxPeerPacketFilter, PacketRecvChan, PacketRecvChanReceiver,
 };
@@ -148,9 +157,11 @@ pub struct PeerManager {
     foreign_network_client: Arc<ForeignNetworkClient>,
 
     encryptor: Arc<dyn Encryptor + 'static>,
-    data_compress_algo: CompressorAlgo,
+    data_compress: Arc<DataCompressNegotiator>,
 
-    exit_nodes: Vec<IpAddr>,
+    exit_node_selector: Arc<ExitNodeSelector>,
+    exit_egress: Arc<ExitEgressManager>,
+    policy_router: Arc<PolicyRouter>,
 
     reserved_my_peer_id_map: DashMap<String, PeerId>,
 
@@ -244,13 +255,14 @@ impl PeerManager {
             my_peer_id,
         ));
 
-        let data_compress_algo = global_ctx
-            .get_flags()
-            .data_compress_algo()
-            .try_into()
-            .expect("invalid data compress algo, maybe some features not enabled");
+        let data_compress = Arc::new(DataCompressNegotiator::new(&global_ctx));
 
-        let exit_nodes = global_ctx.config.get_exit_nodes();
+        let exit_node_selector = Arc::new(ExitNodeSelector::new(
+            global_ctx.config.get_exit_nodes(),
+            global_ctx.config.get_exit_node_health(),
+        ));
+        let exit_egress = Arc::new(ExitEgressManager::new(global_ctx.clone()));
+        let policy_router = Arc::new(PolicyRouter::new(global_ctx.config.get_policy_routes()));
 
         let stats_manager = global_ctx.stats_manager();
         let self_tx_counters = SelfTxCounters {
@@ -300,9 +312,11 @@ impl PeerManager {
             foreign_network_client,
 
             encryptor,
-            data_compress_algo,
+            data_compress,
 
-            exit_nodes,
+            exit_node_selector,
+            exit_egress,
+            policy_router,
 
             reserved_my_peer_id_map: DashMap::new(),
 
@@ -317,6 +331,11 @@ impl PeerManager {
             .store(allow_loopback_tunnel, std::sync::atomic::Ordering::Relaxed);
     }
 
+    pub fn allow_loopback_tunnel(&self) -> bool {
+        self.allow_loopback_tunnel
+            .load(std::sync::atomic::Ordering::Relaxed)
+    }
+
     fn build_foreign_network_manager_accessor(
         peer_map: &Arc<PeerMap>,
     ) -> Box<dyn GlobalForeignNetworkAccessor> {
@@ -628,8 +647,10 @@ impl PeerManager {
         let foreign_client = self.foreign_network_client.clone();
         let foreign_mgr = self.foreign_network_manager.clone();
         let encryptor = self.encryptor.clone();
-        let compress_algo = self.data_compress_algo;
+        let data_compress = self.data_compress.clone();
+        let fill_flow_hint = self.need_flow_hint();
         let acl_filter = self.global_ctx.get_acl_filter().clone();
+        let exit_egress = self.exit_egress.clone();
         let global_ctx = self.global_ctx.clone();
         let stats_mgr = self.global_ctx.stats_manager().clone();
 
@@ -653,6 +674,9 @@ impl PeerManager {
             stats_mgr.get_counter(MetricName::CompressionBytesRxBefore, label_set.clone());
         let compress_rx_bytes_after =
             stats_mgr.get_counter(MetricName::CompressionBytesRxAfter, label_set.clone());
+        let replay_dropped_packets =
+            stats_mgr.get_counter(MetricName::TrafficPacketsReplayDropped, label_set.clone());
+        let replay_filter = PeerReplayFilter::new();
 
         self.tasks.lock().await.spawn(async move {
             tracing::trace!("start_peer_recv");
@@ -693,9 +717,15 @@ impl PeerManager {
                             || hdr.packet_type == PacketType::KcpSrc as u8
                             || hdr.packet_type == PacketType::KcpDst as u8
                         {
-                            let _ =
-                                Self::try_compress_and_encrypt(compress_algo, &encryptor, &mut ret)
-                                    .await;
+                            let compress_algo = data_compress.negotiate(&peers, to_peer_id).await;
+                            let _ = Self::try_compress_and_encrypt(
+                                &data_compress,
+                                compress_algo,
+                                &encryptor,
+                                &mut ret,
+                                fill_flow_hint,
+                            )
+                            .await;
                         }
 
                         compress_tx_bytes_after.add(ret.buf_len() as u64);
@@ -713,16 +743,24 @@ impl PeerManager {
                         tracing::error!(?ret, ?to_peer_id, ?from_peer_id, "forward packet error");
                     }
                 } else {
+                    let nonce = encryptor.nonce(&ret);
                     if let Err(e) = encryptor.decrypt(&mut ret) {
                         tracing::error!(?e, "decrypt failed");
                         continue;
                     }
+                    if let Some(nonce) = nonce {
+                        if !replay_filter.check(&nonce, from_peer_id) {
+                            tracing::debug!(?from_peer_id, "drop replayed packet");
+                            replay_dropped_packets.inc();
+                            continue;
+                        }
+                    }
 
                     self_rx_bytes.add(buf_len as u64);
                     self_rx_packets.inc();
                     compress_rx_bytes_before.add(buf_len as u64);
 
-                    let compressor = DefaultCompressor {};
+                    let compressor = DefaultCompressor::new();
                     if let Err(e) = compressor.decompress(&mut ret).await {
                         tracing::error!(?e, "decompress failed");
                         continue;
@@ -730,6 +768,11 @@ impl PeerManager {
 
                     compress_rx_bytes_after.add(ret.buf_len() as u64);
 
+                    if acl_filter.need_peer_identity(from_peer_id) {
+                        let info = peers.get_route_peer_info(from_peer_id).await;
+                        acl_filter.update_peer_identity(from_peer_id, info.as_ref());
+                    }
+
                     if !acl_filter.process_packet_with_acl(
                         &ret,
                         true,
@@ -739,6 +782,16 @@ impl PeerManager {
                         continue;
                     }
 
+                    if global_ctx.enable_exit_node() {
+                        let hdr = ret.peer_manager_header().unwrap();
+                        if hdr.packet_type == PacketType::Data as u8
+                            && !exit_egress.check_tx(&ret, from_peer_id, &peers).await
+                        {
+                            tracing::trace!(?from_peer_id, "exit traffic dropped");
+                            continue;
+                        }
+                    }
+
                     let mut processed = false;
                     let mut zc_packet = Some(ret);
                     for (idx, pipeline) in pipe_line.read().await.iter().rev().enumerate() {
@@ -892,6 +945,23 @@ impl PeerManager {
                 }
                 ret
             }
+
+            async fn list_link_samples(&self) -> Vec<(PeerId, LinkSample)> {
+                let Some(peer_map) = self.peers.upgrade() else {
+                    return vec![];
+                };
+
+                let mut ret = vec![];
+                for peer_id in peer_map.list_peers_with_conn().await {
+                    let Some(conns) = peer_map.list_peer_conns(peer_id).await else {
+                        continue;
+                    };
+                    if let Some(sample) = LinkSample::from_conns(&conns) {
+                        ret.push((peer_id, sample));
+                    }
+                }
+                ret
+            }
         }
 
         let my_peer_id = self.my_peer_id;
@@ -986,6 +1056,9 @@ impl PeerManager {
     }
 
     pub async fn send_msg(&self, msg: ZCPacket, dst_peer_id: PeerId) -> Result<(), Error> {
+        if !self.check_exit_client_rx(&msg, dst_peer_id) {
+            return Ok(());
+        }
         Self::send_msg_internal(&self.peers, &self.foreign_network_client, msg, dst_peer_id).await
     }
 
@@ -999,18 +1072,8 @@ impl PeerManager {
             Self::get_next_hop_policy(msg.peer_manager_header().unwrap().is_latency_first());
 
         if let Some(gateway) = peers.get_gateway_peer_id(dst_peer_id, policy.clone()).await {
-            if peers.has_peer(gateway) {
-                peers.send_msg_directly(msg, gateway).await
-            } else if foreign_network_client.has_next_hop(gateway) {
-                foreign_network_client.send_msg(msg, gateway).await
-            } else {
-                tracing::warn!(
-                    ?gateway,
-                    ?dst_peer_id,
-                    "cannot send msg to peer through gateway"
-                );
-                Err(Error::RouteError(None))
-            }
+            Self::send_msg_to_gateway(peers, foreign_network_client, msg, gateway, dst_peer_id)
+                .await
         } else if foreign_network_client.has_next_hop(dst_peer_id) {
             // check foreign network again. so in happy path we can avoid extra check
             foreign_network_client.send_msg(msg, dst_peer_id).await
@@ -1020,7 +1083,83 @@ impl PeerManager {
         }
     }
 
-    pub async fn get_msg_dst_peer(&self, ipv4_addr: &Ipv4Addr) -> (Vec<PeerId>, bool) {
+    async fn send_msg_to_gateway(
+        peers: &Arc<PeerMap>,
+        foreign_network_client: &Arc<ForeignNetworkClient>,
+        msg: ZCPacket,
+        gateway: PeerId,
+        dst_peer_id: PeerId,
+    ) -> Result<(), Error> {
+        if peers.has_peer(gateway) {
+            peers.send_msg_directly(msg, gateway).await
+        } else if foreign_network_client.has_next_hop(gateway) {
+            foreign_network_client.send_msg(msg, gateway).await
+        } else {
+            tracing::warn!(
+                ?gateway,
+                ?dst_peer_id,
+                "cannot send msg to peer through gateway"
+            );
+            Err(Error::RouteError(None))
+        }
+    }
+
+    // replies sent back to a peer using this node as exit node, false if they
+    // exceed its limit
+    fn check_exit_client_rx(&self, msg: &ZCPacket, dst_peer_id: PeerId) -> bool {
+        if !self.global_ctx.enable_exit_node() {
+            return true;
+        }
+        let hdr = msg.peer_manager_header().unwrap();
+        hdr.packet_type != PacketType::Data as u8 || self.exit_egress.check_rx(msg, dst_peer_id)
+    }
+
+    // clamps the mss of tcp syns to the path mtu of the next hop and answers
+    // packets too large for it with an icmp error, true if the packet is dropped
+    async fn check_path_mtu(
+        &self,
+        msg: &mut ZCPacket,
+        dst_peer_id: PeerId,
+        is_latency_first: bool,
+    ) -> bool {
+        let policy = Self::get_next_hop_policy(is_latency_first);
+        let Some(pmtu) = self.peers.get_next_hop_pmtu(dst_peer_id, policy).await else {
+            return false;
+        };
+        pmtu::clamp_tcp_mss(msg.mut_payload(), pmtu);
+        if msg.payload().len() <= pmtu as usize {
+            return false;
+        }
+        let Some(reply) = pmtu::build_packet_too_big(msg.payload(), pmtu) else {
+            return false;
+        };
+        if !self.global_ctx.no_tun() {
+            let mut reply = ZCPacket::new_with_payload(&reply);
+            reply.fill_peer_manager_hdr(self.my_peer_id, self.my_peer_id, PacketType::Data as u8);
+            let _ = self.nic_channel.send(reply).await;
+        }
+        true
+    }
+
+    // first reachable exit node for `dst` that is not avoided, healthy ones first.
+    async fn get_exit_node_peer(&self, dst: &IpAddr, avoid: &BTreeSet<PeerId>) -> Option<PeerId> {
+        for exit_node in self.exit_node_selector.candidates(dst) {
+            let peer_id = match exit_node {
+                IpAddr::V4(exit_node) => self.peers.get_peer_id_by_ipv4(&exit_node).await,
+                IpAddr::V6(exit_node) => self.peers.get_peer_id_by_ipv6(&exit_node).await,
+            };
+            if let Some(peer_id) = peer_id.filter(|x| !avoid.contains(x)) {
+                return Some(peer_id);
+            }
+        }
+        None
+    }
+
+    pub async fn get_msg_dst_peer(
+        &self,
+        ipv4_addr: &Ipv4Addr,
+        avoid: &BTreeSet<PeerId>,
+    ) -> (Vec<PeerId>, bool) {
         let mut is_exit_node = false;
         let mut dst_peers = vec![];
         let network_length = self
@@ -1036,17 +1175,9 @@ impl PeerManager {
             dst_peers.extend(self.peers.list_routes().await.iter().map(|x| *x.key()));
         } else if let Some(peer_id) = self.peers.get_peer_id_by_ipv4(ipv4_addr).await {
             dst_peers.push(peer_id);
-        } else {
-            for exit_node in &self.exit_nodes {
-                let IpAddr::V4(exit_node) = exit_node else {
-                    continue;
-                };
-                if let Some(peer_id) = self.peers.get_peer_id_by_ipv4(exit_node).await {
-                    dst_peers.push(peer_id);
-                    is_exit_node = true;
-                    break;
-                }
-            }
+        } else if let Some(peer_id) = self.get_exit_node_peer(&(*ipv4_addr).into(), avoid).await {
+            dst_peers.push(peer_id);
+            is_exit_node = true;
         }
         #[cfg(target_env = "ohos")]
         {
@@ -1059,7 +1190,11 @@ impl PeerManager {
         (dst_peers, is_exit_node)
     }
 
-    pub async fn get_msg_dst_peer_ipv6(&self, ipv6_addr: &Ipv6Addr) -> (Vec<PeerId>, bool) {
+    pub async fn get_msg_dst_peer_ipv6(
+        &self,
+        ipv6_addr: &Ipv6Addr,
+        avoid: &BTreeSet<PeerId>,
+    ) -> (Vec<PeerId>, bool) {
         let mut is_exit_node = false;
         let mut dst_peers = vec![];
         let network_length = self
@@ -1074,28 +1209,152 @@ impl PeerManager {
             dst_peers.push(peer_id);
         } else if !ipv6_addr.is_unicast_link_local() {
             // NOTE: never route link local address to exit node.
-            for exit_node in &self.exit_nodes {
-                let IpAddr::V6(exit_node) = exit_node else {
-                    continue;
-                };
-                if let Some(peer_id) = self.peers.get_peer_id_by_ipv6(exit_node).await {
-                    dst_peers.push(peer_id);
-                    is_exit_node = true;
-                    break;
-                }
+            if let Some(peer_id) = self.get_exit_node_peer(&(*ipv6_addr).into(), avoid).await {
+                dst_peers.push(peer_id);
+                is_exit_node = true;
             }
         }
 
         (dst_peers, is_exit_node)
     }
 
+    /// Destination peers of a packet to `ip_addr` and whether they are exit
+    /// nodes. The matched policy route decides first, then the default routing
+    /// without the peers avoided by the policy.
+    pub async fn get_msg_dst_peer_with_policy(
+        &self,
+        ip_addr: &IpAddr,
+    ) -> (Vec<PeerId>, bool, Option<PolicyMatch>) {
+        let unicast = match ip_addr {
+            IpAddr::V4(ip) => !ip.is_broadcast() && !ip.is_multicast(),
+            IpAddr::V6(ip) => !ip.is_multicast() && !ip.is_unicast_link_local(),
+        };
+        let policy = if unicast && !self.policy_router.is_empty() {
+            self.policy_router.lookup(ip_addr)
+        } else {
+            None
+        };
+
+        let avoid = match &policy {
+            Some(m) if m.blackhole => return (vec![], false, policy),
+            Some(m) => {
+                if let Some(via) = m.via {
+                    let owner = match ip_addr {
+                        IpAddr::V4(ip) => self.peers.get_peer_id_by_ipv4(ip).await,
+                        IpAddr::V6(ip) => self.peers.get_peer_id_by_ipv6(ip).await,
+                    };
+                    return (vec![via], owner != Some(via), policy);
+                }
+                m.avoid.clone()
+            }
+            None => Default::default(),
+        };
+
+        let (mut dst_peers, is_exit_node) = match ip_addr {
+            IpAddr::V4(ip) => self.get_msg_dst_peer(ip, &avoid).await,
+            IpAddr::V6(ip) => self.get_msg_dst_peer_ipv6(ip, &avoid).await,
+        };
+        dst_peers.retain(|x| !avoid.contains(x));
+        (dst_peers, is_exit_node, policy)
+    }
+
+    // next hop to `dst_peer_id` on a path clear of the avoided peers and the
+    // latency first flag it was found with, the configured one is preferred.
+    // Ok(None) for peers without route, e.g. in a foreign network, they are
+    // left to send_msg_internal.
+    async fn get_next_hop_avoiding(
+        &self,
+        dst_peer_id: PeerId,
+        latency_first: bool,
+        avoid: &BTreeSet<PeerId>,
+    ) -> Result<Option<(PeerId, bool)>, Error> {
+        for latency_first in [latency_first, !latency_first] {
+            let policy = Self::get_next_hop_policy(latency_first);
+            if let Some(next_hop) = self
+                .peers
+                .get_gateway_peer_id_avoiding(dst_peer_id, policy, avoid)
+                .await
+            {
+                return Ok(Some((next_hop, latency_first)));
+            }
+        }
+        // a direct conn passes nobody, even if the routes do not know it yet
+        if self.peers.has_peer(dst_peer_id) {
+            return Ok(Some((dst_peer_id, latency_first)));
+        }
+        let policy = Self::get_next_hop_policy(latency_first);
+        if self
+            .peers
+            .get_gateway_peer_id(dst_peer_id, policy)
+            .await
+            .is_none()
+        {
+            return Ok(None);
+        }
+        Err(Error::RouteError(Some(format!(
+            "all paths to {} pass a peer avoided by policy route",
+            dst_peer_id
+        ))))
+    }
+
+    pub async fn list_policy_routes(&self) -> Vec<PolicyRouteInfo> {
+        self.policy_router.list()
+    }
+
+    pub async fn lookup_route(&self, ip_addr: &IpAddr) -> LookupRouteResponse {
+        let (dst_peer_ids, is_exit_node, policy) = self.get_msg_dst_peer_with_policy(ip_addr).await;
+        let latency_first = self.global_ctx.get_flags().latency_first;
+        let mut next_hop_peer_ids = vec![];
+        for peer_id in dst_peer_ids.iter() {
+            if let Some(m) = policy.as_ref().filter(|m| !m.avoid.is_empty()) {
+                match self
+                    .get_next_hop_avoiding(*peer_id, latency_first, &m.avoid)
+                    .await
+                {
+                    Ok(Some((next_hop, _))) => {
+                        next_hop_peer_ids.push(next_hop);
+                        continue;
+                    }
+                    Ok(None) => {}
+                    Err(_) => {
+                        next_hop_peer_ids.push(0);
+                        continue;
+                    }
+                }
+            }
+            let next_hop = self
+                .peers
+                .get_gateway_peer_id(*peer_id, Self::get_next_hop_policy(latency_first))
+                .await;
+            next_hop_peer_ids.push(next_hop.unwrap_or(0));
+        }
+
+        LookupRouteResponse {
+            policy_route: policy.map(|m| self.policy_router.get_info(m.index, Some(&m))),
+            dst_peer_ids,
+            is_exit_node,
+            next_hop_peer_ids,
+        }
+    }
+
+    fn need_flow_hint(&self) -> bool {
+        self.global_ctx.get_flags().multipath_mode() == MultipathModePb::FlowHash
+    }
+
     pub async fn try_compress_and_encrypt(
+        data_compress: &DataCompressNegotiator,
         compress_algo: CompressorAlgo,
         encryptor: &Arc<dyn Encryptor + 'static>,
         msg: &mut ZCPacket,
+        fill_flow_hint: bool,
     ) -> Result<(), Error> {
-        let compressor = DefaultCompressor {};
-        compressor
+        // the flow cannot be parsed from the payload once it is encrypted
+        if fill_flow_hint {
+            if let Some(flow) = flow_key(msg) {
+                msg.mut_peer_manager_header().unwrap().flow_hint = compact_flow_hint(flow);
+            }
+        }
+        data_compress
             .compress(msg, compress_algo)
             .await
             .with_context(|| "compress failed")?;
@@ -1127,27 +1386,55 @@ impl PeerManager {
             .await;
         }
 
-        let (dst_peers, is_exit_node) = match ip_addr {
-            IpAddr::V4(ipv4_addr) => self.get_msg_dst_peer(&ipv4_addr).await,
-            IpAddr::V6(ipv6_addr) => self.get_msg_dst_peer_ipv6(&ipv6_addr).await,
-        };
+        let (dst_peers, is_exit_node, policy) = self.get_msg_dst_peer_with_policy(&ip_addr).await;
+        if let Some(m) = &policy {
+            self.policy_router.record_hit(m.index);
+        }
 
         if dst_peers.is_empty() {
             tracing::info!("no peer id for ip: {}", ip_addr);
             return Ok(());
         }
 
+        if !is_exit_node && dst_peers.len() == 1 && !self.check_exit_client_rx(&msg, dst_peers[0]) {
+            tracing::trace!(dst_peer_id = dst_peers[0], "exit reply dropped");
+            return Ok(());
+        }
+
+        let is_latency_first = self.global_ctx.get_flags().latency_first;
+        let payload = msg.payload();
+        let may_exceed_pmtu = payload.len() > pmtu::MIN_PMTU as usize || pmtu::is_tcp_syn(payload);
+        if dst_peers.len() == 1
+            && may_exceed_pmtu
+            && self
+                .check_path_mtu(&mut msg, dst_peers[0], is_latency_first)
+                .await
+        {
+            tracing::trace!(dst_peer_id = dst_peers[0], "packet exceeds path mtu, drop");
+            return Ok(());
+        }
+
         self.self_tx_counters
             .compress_tx_bytes_before
             .add(msg.buf_len() as u64);
 
-        Self::try_compress_and_encrypt(self.data_compress_algo, &self.encryptor, &mut msg).await?;
+        let compress_algo = self
+            .data_compress
+            .negotiate_many(&self.peers, &dst_peers)
+            .await;
+        Self::try_compress_and_encrypt(
+            &self.data_compress,
+            compress_algo,
+            &self.encryptor,
+            &mut msg,
+            self.need_flow_hint(),
+        )
+        .await?;
 
         self.self_tx_counters
             .compress_tx_bytes_after
             .add(msg.buf_len() as u64);
 
-        let is_latency_first = self.global_ctx.get_flags().latency_first;
         msg.mut_peer_manager_header()
             .unwrap()
             .set_latency_first(is_latency_first)
@@ -1168,15 +1455,53 @@ impl PeerManager {
                 .to_peer_id
                 .set(*peer_id);
 
+            let mut next_hop = None;
+            if let Some(m) = policy.as_ref().filter(|m| !m.avoid.is_empty()) {
+                match self
+                    .get_next_hop_avoiding(*peer_id, is_latency_first, &m.avoid)
+                    .await
+                {
+                    Ok(Some((gateway, latency_first))) => {
+                        msg.mut_peer_manager_header()
+                            .unwrap()
+                            .set_latency_first(latency_first);
+                        next_hop = Some(gateway);
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        errs.push(e);
+                        continue;
+                    }
+                }
+            }
+
             self.self_tx_counters
                 .self_tx_bytes
                 .add(msg.buf_len() as u64);
             self.self_tx_counters.self_tx_packets.inc();
 
-            if let Err(e) =
-                Self::send_msg_internal(&self.peers, &self.foreign_network_client, msg, *peer_id)
+            let ret = match next_hop {
+                Some(gateway) => {
+                    Self::send_msg_to_gateway(
+                        &self.peers,
+                        &self.foreign_network_client,
+                        msg,
+                        gateway,
+                        *peer_id,
+                    )
                     .await
-            {
+                }
+                None => {
+                    Self::send_msg_internal(
+                        &self.peers,
+                        &self.foreign_network_client,
+                        msg,
+                        *peer_id,
+                    )
+                    .await
+                }
+            };
+            if let Err(e) = ret {
                 errs.push(e);
             }
         }
@@ -1193,9 +1518,12 @@ impl PeerManager {
 
     async fn run_clean_peer_without_conn_routine(&self) {
         let peer_map = self.peers.clone();
+        let acl_filter = self.global_ctx.get_acl_filter().clone();
         self.tasks.lock().await.spawn(async move {
             loop {
                 peer_map.clean_peer_without_conn().await;
+                let routes = peer_map.list_routes().await;
+                acl_filter.retain_peer_identities(|peer_id| routes.contains_key(&peer_id));
                 tokio::time::sleep(std::time::Duration::from_secs(3)).await;
             }
         });
@@ -1224,10 +1552,31 @@ impl PeerManager {
         self.run_clean_peer_without_conn_routine().await;
 
         self.run_foriegn_network().await;
+        self.run_exit_node_health_check().await;
+
+        self.tasks.lock().await.spawn(run_policy_route_refresh(
+            self.policy_router.clone(),
+            Arc::downgrade(&self.peers),
+        ));
+        self.tasks.lock().await.spawn(run_exit_egress_refresh(
+            self.exit_egress.clone(),
+            Arc::downgrade(&self.peers),
+        ));
 
         Ok(())
     }
 
+    async fn run_exit_node_health_check(&self) {
+        ExitNodeRpcService::new(self.global_ctx.clone()).register(&self.peer_rpc_mgr);
+
+        self.tasks.lock().await.spawn(run_exit_node_health_check(
+            self.exit_node_selector.clone(),
+            Arc::downgrade(&self.peers),
+            Arc::downgrade(&self.peer_rpc_mgr),
+            self.global_ctx.clone(),
+        ));
+    }
+
     pub fn get_peer_map(&self) -> Arc<PeerMap> {
         self.peers.clone()
     }
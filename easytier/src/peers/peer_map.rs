@@ -1,6 +1,8 @@
 use std::{
+    collections::BTreeSet,
     net::{Ipv4Addr, Ipv6Addr},
     sync::Arc,
+    time::Instant,
 };
 
 use anyhow::Context;
@@ -167,6 +169,40 @@ impl PeerMap {
         None
     }
 
+    /// Like get_gateway_peer_id, but the path must not pass any peer in
+    /// `avoid`. None if there is no route or every path passes one.
+    pub async fn get_gateway_peer_id_avoiding(
+        &self,
+        dst_peer_id: PeerId,
+        policy: NextHopPolicy,
+        avoid: &BTreeSet<PeerId>,
+    ) -> Option<PeerId> {
+        if dst_peer_id == self.my_peer_id {
+            return Some(dst_peer_id);
+        }
+
+        for route in self.routes.read().await.iter() {
+            if let Some(gateway_peer_id) = route
+                .get_next_hop_avoiding(dst_peer_id, policy.clone(), avoid)
+                .await
+            {
+                return Some(gateway_peer_id);
+            }
+        }
+
+        None
+    }
+
+    /// Path mtu of the conns to the next hop towards `dst_peer_id`.
+    pub async fn get_next_hop_pmtu(
+        &self,
+        dst_peer_id: PeerId,
+        policy: NextHopPolicy,
+    ) -> Option<u32> {
+        let gateway = self.get_gateway_peer_id(dst_peer_id, policy).await?;
+        self.get_peer_by_id(gateway)?.get_pmtu()
+    }
+
     pub async fn list_peers_own_foreign_network(
         &self,
         network_identity: &NetworkIdentity,
@@ -343,6 +379,13 @@ impl PeerMap {
         vec![]
     }
 
+    pub async fn get_route_peer_info_last_update_time(&self) -> Option<Instant> {
+        if let Some(route) = self.routes.read().await.iter().next() {
+            return Some(route.get_peer_info_last_update_time().await);
+        }
+        None
+    }
+
     pub async fn need_relay_by_foreign_network(&self, dst_peer_id: PeerId) -> Result<bool, Error> {
         // if gateway_peer_id is not connected to me, means need relay by foreign network
         let gateway_id = self
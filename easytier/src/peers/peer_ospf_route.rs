@@ -37,8 +37,8 @@ use crate::{
         peer_rpc::{
             route_foreign_network_infos, ForeignNetworkRouteInfoEntry, ForeignNetworkRouteInfoKey,
             OspfRouteRpc, OspfRouteRpcClientFactory, OspfRouteRpcServer, PeerIdVersion,
-            RouteForeignNetworkInfos, RoutePeerInfo, RoutePeerInfos, SyncRouteInfoError,
-            SyncRouteInfoRequest, SyncRouteInfoResponse,
+            RouteForeignNetworkInfos, RouteLinkMetric, RoutePeerInfo, RoutePeerInfos,
+            SyncRouteInfoError, SyncRouteInfoRequest, SyncRouteInfoResponse,
         },
         rpc_types::{
             self,
@@ -49,7 +49,9 @@ use crate::{
 };
 
 use super::{
-    graph_algo::dijkstra_with_first_hop,
+    data_compress::{local_zstd_dict_id, supported_compress_algos},
+    graph_algo::{dijkstra_with_first_hop, first_hop_avoiding},
+    link_metric::{LinkMetricTracker, UNMEASURED_LINK_COST_MS},
     peer_rpc::PeerRpcManager,
     route_trait::{
         DefaultRouteCostCalculator, ForeignNetworkRouteInfoMap, NextHopPolicy, RouteCostCalculator,
@@ -126,6 +128,10 @@ impl RoutePeerInfo {
             network_length: 24,
             quic_port: None,
             ipv6_addr: None,
+            tags: Vec::new(),
+            supported_compress_algos: Vec::new(),
+            compress_dict_id: 0,
+            link_metrics: Vec::new(),
         }
     }
 
@@ -134,7 +140,9 @@ impl RoutePeerInfo {
         my_peer_id: PeerId,
         peer_route_id: u64,
         global_ctx: &ArcGlobalCtx,
+        link_metrics: Vec<RouteLinkMetric>,
     ) -> Self {
+        let compress_dict_id = local_zstd_dict_id(global_ctx);
         let mut new = Self {
             peer_id: my_peer_id,
             inst_id: Some(global_ctx.get_id().into()),
@@ -145,7 +153,7 @@ impl RoutePeerInfo {
                 .get_proxy_cidrs()
                 .iter()
                 .map(|x| x.mapped_cidr.unwrap_or(x.cidr))
-                .chain(global_ctx.get_vpn_portal_cidr())
+                .chain(global_ctx.get_vpn_portal_cidr().map(Into::into))
                 .map(|x| x.to_string())
                 .collect(),
             hostname: Some(global_ctx.get_hostname()),
@@ -167,6 +175,10 @@ impl RoutePeerInfo {
 
             quic_port: global_ctx.get_quic_proxy_port().map(|x| x as u32),
             ipv6_addr: global_ctx.get_ipv6().map(|x| x.into()),
+            tags: global_ctx.config.get_tags(),
+            supported_compress_algos: supported_compress_algos(compress_dict_id),
+            compress_dict_id,
+            link_metrics,
         };
 
         let need_update_periodically = if let Ok(Ok(d)) =
@@ -221,6 +233,7 @@ impl From<RoutePeerInfo> for crate::proto::cli::Route {
             path_latency_latency_first: None,
 
             ipv6_addr: val.ipv6_addr,
+            next_hop_link_metric: None, // filled for direct neighbors in list_routes.
         }
     }
 }
@@ -507,9 +520,10 @@ impl SyncedRouteInfo {
         my_peer_id: PeerId,
         my_peer_route_id: u64,
         global_ctx: &ArcGlobalCtx,
+        link_metrics: Vec<RouteLinkMetric>,
     ) -> bool {
         let mut old = self.peer_infos.entry(my_peer_id).or_default();
-        let new = old.update_self(my_peer_id, my_peer_route_id, global_ctx);
+        let new = old.update_self(my_peer_id, my_peer_route_id, global_ctx, link_metrics);
         let new_version = new.version;
         let old_version = old.version;
         *old = new;
@@ -615,6 +629,16 @@ impl SyncedRouteInfo {
 }
 
 type PeerGraph = Graph<PeerId, usize, Directed>;
+
+// cost of an edge when counting hops, relays to avoid still cost more
+fn hop_cost(cost: usize) -> usize {
+    if cost >= AVOID_RELAY_COST {
+        AVOID_RELAY_COST + 1
+    } else {
+        1
+    }
+}
+
 type PeerIdToNodexIdxMap = DashMap<PeerId, NodeIndex>;
 #[derive(Debug, Clone, Copy)]
 struct NextHopInfo {
@@ -664,6 +688,20 @@ impl RouteTable {
         self.get_next_hop(peer_id).is_some()
     }
 
+    // cost of the link src -> dst as measured by src, or by dst for the
+    // opposite direction if src did not publish one.
+    fn get_link_metric_cost(&self, src: PeerId, dst: PeerId) -> Option<u32> {
+        let find = |from: PeerId, to: PeerId| {
+            self.peer_infos.get(&from).and_then(|info| {
+                info.link_metrics
+                    .iter()
+                    .find(|m| m.peer_id == to)
+                    .map(|m| m.cost)
+            })
+        };
+        find(src, dst).or_else(|| find(dst, src))
+    }
+
     fn get_nat_type(&self, peer_id: PeerId) -> Option<NatType> {
         self.peer_infos
             .get(&peer_id)
@@ -675,6 +713,7 @@ impl RouteTable {
         my_peer_id: PeerId,
         synced_info: &SyncedRouteInfo,
         cost_calc: &T,
+        use_link_metrics: bool,
     ) -> (PeerGraph, NodeIndex) {
         let mut graph: PeerGraph = PeerGraph::new();
 
@@ -716,6 +755,15 @@ impl RouteTable {
                 };
 
                 let mut cost = cost_calc.calculate_cost(*src_peer_id, *dst_peer_id) as usize;
+                if use_link_metrics {
+                    // links nobody measured have to be in ms as well, a hop
+                    // count would make them look faster than any measured one.
+                    cost = match synced_info.get_link_metric_cost(*src_peer_id, *dst_peer_id) {
+                        Some(c) => c as usize,
+                        None if cost_calc.cost_in_ms() => cost,
+                        None => cost * UNMEASURED_LINK_COST_MS as usize,
+                    };
+                }
                 if peer_avoid_relay_data {
                     cost += AVOID_RELAY_COST;
                 }
@@ -757,13 +805,7 @@ impl RouteTable {
         start_node: &NodeIndex,
         version: Version,
     ) {
-        let normalize_edge_cost = |e: petgraph::graph::EdgeReference<usize>| {
-            if *e.weight() >= AVOID_RELAY_COST {
-                AVOID_RELAY_COST + 1
-            } else {
-                1
-            }
-        };
+        let normalize_edge_cost = |e: petgraph::graph::EdgeReference<usize>| hop_cost(*e.weight());
         // Step 1: 第一次 Dijkstra - 计算最短跳数
         let path_len_map = dijkstra(&graph, *start_node, None, normalize_edge_cost);
 
@@ -829,12 +871,17 @@ impl RouteTable {
         synced_info: &SyncedRouteInfo,
         policy: NextHopPolicy,
         cost_calc: &T,
+        use_link_metrics: bool,
     ) {
         let version = synced_info.version.get();
 
         // build next hop map
-        let (graph, start_node) =
-            Self::build_peer_graph_from_synced_info(my_peer_id, synced_info, cost_calc);
+        let (graph, start_node) = Self::build_peer_graph_from_synced_info(
+            my_peer_id,
+            synced_info,
+            cost_calc,
+            use_link_metrics,
+        );
 
         if graph.node_count() == 0 {
             tracing::warn!("no peer in graph, cannot build next hop map");
@@ -904,11 +951,10 @@ impl RouteTable {
         }
     }
 
-    fn get_peer_id_for_proxy(&self, ipv4: &Ipv4Addr) -> Option<PeerId> {
-        let ipv4 = std::net::IpAddr::V4(*ipv4);
+    fn get_peer_id_for_proxy(&self, ip: &std::net::IpAddr) -> Option<PeerId> {
         for item in self.cidr_peer_id_map.iter() {
             let (k, v) = item.pair();
-            if k.contains(&ipv4) {
+            if k.contains(ip) {
                 return Some(*v);
             }
         }
@@ -1106,6 +1152,13 @@ struct PeerRouteServiceImpl {
     last_update_my_foreign_network: AtomicCell<Option<std::time::Instant>>,
 
     peer_info_last_update: AtomicCell<std::time::Instant>,
+
+    link_metric_tracker: LinkMetricTracker,
+    my_link_metrics: std::sync::Mutex<Vec<RouteLinkMetric>>,
+
+    // next hops keeping clear of some peers for policy routes, computed on
+    // demand and dropped when the route table is rebuilt
+    avoiding_next_hops: DashMap<(PeerId, bool, BTreeSet<PeerId>), (Version, Option<PeerId>)>,
 }
 
 impl Debug for PeerRouteServiceImpl {
@@ -1161,6 +1214,11 @@ impl PeerRouteServiceImpl {
             last_update_my_foreign_network: AtomicCell::new(None),
 
             peer_info_last_update: AtomicCell::new(std::time::Instant::now()),
+
+            link_metric_tracker: LinkMetricTracker::default(),
+            my_link_metrics: std::sync::Mutex::new(Vec::new()),
+
+            avoiding_next_hops: DashMap::new(),
         }
     }
 
@@ -1196,11 +1254,28 @@ impl PeerRouteServiceImpl {
             .collect()
     }
 
+    async fn update_my_link_metrics(&self) {
+        // nodes not routing by link metrics do not publish them either
+        if !self.global_ctx.get_flags().enable_link_metric_cost {
+            return;
+        }
+        let samples = self
+            .interface
+            .lock()
+            .await
+            .as_ref()
+            .unwrap()
+            .list_link_samples()
+            .await;
+        *self.my_link_metrics.lock().unwrap() = self.link_metric_tracker.update(samples);
+    }
+
     fn update_my_peer_info(&self) -> bool {
         if self.synced_route_info.update_my_peer_info(
             self.my_peer_id,
             self.my_peer_route_id,
             &self.global_ctx,
+            self.my_link_metrics.lock().unwrap().clone(),
         ) {
             self.update_route_table_and_cached_local_conn_bitmap();
             return true;
@@ -1257,12 +1332,14 @@ impl PeerRouteServiceImpl {
             .begin_update();
 
         let calc_locked = self.cost_calculator.read().unwrap();
+        let use_link_metrics = self.global_ctx.get_flags().enable_link_metric_cost;
 
         self.route_table.build_from_synced_info(
             self.my_peer_id,
             &self.synced_route_info,
             NextHopPolicy::LeastHop,
             calc_locked.as_ref().unwrap(),
+            use_link_metrics,
         );
 
         self.route_table_with_cost.build_from_synced_info(
@@ -1270,10 +1347,13 @@ impl PeerRouteServiceImpl {
             &self.synced_route_info,
             NextHopPolicy::LeastCost,
             calc_locked.as_ref().unwrap(),
+            use_link_metrics,
         );
 
         drop(calc_locked);
 
+        self.avoiding_next_hops.clear();
+
         self.cost_calculator
             .write()
             .unwrap()
@@ -1282,6 +1362,49 @@ impl PeerRouteServiceImpl {
             .end_update();
     }
 
+    fn get_next_hop_avoiding(
+        &self,
+        dst_peer_id: PeerId,
+        policy: NextHopPolicy,
+        avoid: &BTreeSet<PeerId>,
+    ) -> Option<PeerId> {
+        let least_cost = matches!(policy, NextHopPolicy::LeastCost);
+        let version = self.synced_route_info.version.get();
+        let key = (dst_peer_id, least_cost, avoid.clone());
+        if let Some(cached) = self.avoiding_next_hops.get(&key) {
+            if cached.0 == version {
+                return cached.1;
+            }
+        }
+
+        let calc_locked = self.cost_calculator.read().unwrap();
+        let (graph, start_node) = RouteTable::build_peer_graph_from_synced_info(
+            self.my_peer_id,
+            &self.synced_route_info,
+            calc_locked.as_ref().unwrap(),
+            self.global_ctx.get_flags().enable_link_metric_cost,
+        );
+        drop(calc_locked);
+
+        // every node on the way forwards by its own route table, so the
+        // whole path has to be clear, not only the first hop.
+        let next_hop = graph
+            .node_indices()
+            .find(|x| graph[*x] == dst_peer_id)
+            .and_then(|dst_node| {
+                first_hop_avoiding(
+                    &graph,
+                    start_node,
+                    dst_node,
+                    |x| avoid.contains(&graph[x]),
+                    |cost| if least_cost { *cost } else { hop_cost(*cost) },
+                )
+            })
+            .map(|x| graph[x]);
+        self.avoiding_next_hops.insert(key, (version, next_hop));
+        next_hop
+    }
+
     fn update_foreign_network_owner_map(&self) {
         self.foreign_network_my_peer_id_map.clear();
         self.foreign_network_owner_map.clear();
@@ -1454,6 +1577,7 @@ impl PeerRouteServiceImpl {
     }
 
     async fn update_my_infos(&self) -> bool {
+        self.update_my_link_metrics().await;
         let my_peer_info_updated = self.update_my_peer_info();
         let my_conn_info_updated = self.update_my_conn_info().await;
         let my_foreign_network_updated = self.update_my_foreign_network().await;
@@ -2231,9 +2355,19 @@ impl Route for PeerRoute {
             .map(|x| x.next_hop_peer_id)
     }
 
+    async fn get_next_hop_avoiding(
+        &self,
+        dst_peer_id: PeerId,
+        policy: NextHopPolicy,
+        avoid: &BTreeSet<PeerId>,
+    ) -> Option<PeerId> {
+        self.service_impl.get_next_hop_avoiding(dst_peer_id, policy, avoid)
+    }
+
     async fn list_routes(&self) -> Vec<crate::proto::cli::Route> {
         let route_table = &self.service_impl.route_table;
         let route_table_with_cost = &self.service_impl.route_table_with_cost;
+        let my_link_metrics = self.service_impl.my_link_metrics.lock().unwrap().clone();
         let mut routes = Vec::new();
         for item in route_table.peer_infos.iter() {
             if *item.key() == self.my_peer_id {
@@ -2254,6 +2388,10 @@ impl Route for PeerRoute {
             route.path_latency_latency_first = next_hop_peer_latency_first.map(|x| x.path_latency);
 
             route.feature_flag = item.feature_flag;
+            route.next_hop_link_metric = my_link_metrics
+                .iter()
+                .find(|m| m.peer_id == route.next_hop_peer_id)
+                .cloned();
 
             routes.push(route);
         }
@@ -2266,7 +2404,7 @@ impl Route for PeerRoute {
             return Some(*peer_id);
         }
 
-        if let Some(peer_id) = route_table.get_peer_id_for_proxy(ipv4_addr) {
+        if let Some(peer_id) = route_table.get_peer_id_for_proxy(&(*ipv4_addr).into()) {
             return Some(peer_id);
         }
 
@@ -2280,10 +2418,9 @@ impl Route for PeerRoute {
             return Some(*peer_id);
         }
 
-        // TODO: Add proxy support for IPv6 similar to IPv4
-        // if let Some(peer_id) = route_table.get_peer_id_for_proxy_ipv6(ipv6_addr) {
-        //     return Some(peer_id);
-        // }
+        if let Some(peer_id) = route_table.get_peer_id_for_proxy(&(*ipv6_addr).into()) {
+            return Some(peer_id);
+        }
 
         tracing::debug!(?ipv6_addr, "no peer id for ipv6");
         None
@@ -2375,18 +2512,20 @@ mod tests {
             create_packet_recv_chan,
             peer_manager::{PeerManager, RouteAlgoType},
             peer_ospf_route::PeerRouteServiceImpl,
-            route_trait::{NextHopPolicy, Route, RouteCostCalculatorInterface},
+            route_trait::{
+                DefaultRouteCostCalculator, NextHopPolicy, Route, RouteCostCalculatorInterface,
+            },
             tests::connect_peer_manager,
         },
         proto::{
             common::NatType,
-            peer_rpc::{RoutePeerInfo, RoutePeerInfos, SyncRouteInfoRequest},
+            peer_rpc::{RouteLinkMetric, RoutePeerInfo, RoutePeerInfos, SyncRouteInfoRequest},
         },
         tunnel::common::tests::wait_for_condition,
     };
     use prost::Message;
 
-    use super::PeerRoute;
+    use super::{AtomicVersion, PeerRoute, RouteTable};
 
     async fn create_mock_route(peer_mgr: Arc<PeerManager>) -> Arc<PeerRoute> {
         let peer_route = PeerRoute::new(
@@ -2836,4 +2975,96 @@ mod tests {
 
         assert_eq!(req, req2);
     }
+
+    #[tokio::test]
+    async fn test_ipv6_proxy_cidr_route() {
+        let p_a = create_mock_pmgr().await;
+        let p_b = create_mock_pmgr().await;
+        p_b.get_global_ctx()
+            .config
+            .add_proxy_cidr("fd00:1234::/64".parse().unwrap(), None)
+            .unwrap();
+        connect_peer_manager(p_a.clone(), p_b.clone()).await;
+
+        let r_a = create_mock_route(p_a.clone()).await;
+        let _r_b = create_mock_route(p_b.clone()).await;
+
+        let dst: std::net::Ipv6Addr = "fd00:1234::1".parse().unwrap();
+        wait_for_condition(
+            || async { r_a.get_peer_id_by_ipv6(&dst).await == Some(p_b.my_peer_id()) },
+            Duration::from_secs(5),
+        )
+        .await;
+
+        let other: std::net::Ipv6Addr = "fd00:5678::1".parse().unwrap();
+        assert_eq!(None, r_a.get_peer_id_by_ipv6(&other).await);
+    }
+
+    #[tokio::test]
+    async fn test_link_metric_cost() {
+        let service = PeerRouteServiceImpl::new(1, get_mock_global_ctx());
+        let info = &service.synced_route_info;
+        let metric = |peer_id, cost| RouteLinkMetric {
+            peer_id,
+            cost,
+            ..Default::default()
+        };
+        // 1 reaches 4 through 2 or 3, the link to 2 is slow.
+        for (peer_id, conns, link_metrics) in [
+            (1, vec![2, 3], vec![metric(2, 50), metric(3, 10)]),
+            (2, vec![1, 4], vec![metric(4, 10)]),
+            (3, vec![1, 4], vec![]),
+            (4, vec![2, 3], vec![metric(3, 10)]),
+        ] {
+            info.peer_infos.insert(
+                peer_id,
+                RoutePeerInfo {
+                    peer_id,
+                    version: 1,
+                    link_metrics,
+                    ..RoutePeerInfo::new()
+                },
+            );
+            info.conn_map
+                .insert(peer_id, (conns.into_iter().collect(), AtomicVersion::new()));
+        }
+
+        // the 3 -> 4 link is only measured by 4
+        assert_eq!(Some(10), info.get_link_metric_cost(3, 4));
+        assert_eq!(None, info.get_link_metric_cost(2, 3));
+
+        let table = RouteTable::new();
+        table.build_from_synced_info(
+            1,
+            info,
+            NextHopPolicy::LeastCost,
+            &DefaultRouteCostCalculator,
+            true,
+        );
+        assert_eq!(3, table.get_next_hop(4).unwrap().next_hop_peer_id);
+
+        info.peer_infos.get_mut(&1).unwrap().link_metrics = vec![metric(2, 10), metric(3, 50)];
+        let table = RouteTable::new();
+        table.build_from_synced_info(
+            1,
+            info,
+            NextHopPolicy::LeastCost,
+            &DefaultRouteCostCalculator,
+            true,
+        );
+        assert_eq!(2, table.get_next_hop(4).unwrap().next_hop_peer_id);
+
+        // an unmeasured link costs more than a fast measured one
+        info.peer_infos.get_mut(&1).unwrap().link_metrics = vec![metric(2, 20)];
+        info.peer_infos.get_mut(&4).unwrap().link_metrics = vec![];
+        let table = RouteTable::new();
+        table.build_from_synced_info(
+            1,
+            info,
+            NextHopPolicy::LeastCost,
+            &DefaultRouteCostCalculator,
+            true,
+        );
+        assert_eq!(2, table.get_next_hop(4).unwrap().next_hop_peer_id);
+    }
 }
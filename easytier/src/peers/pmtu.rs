@@ -0,0 +1,429 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use pnet::packet::{
+    icmp::{self, IcmpPacket},
+    icmpv6::{self, Icmpv6Packet},
+    ipv4::{self, Ipv4Packet},
+    tcp::{self, TcpPacket},
+};
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        Notify,
+    },
+    time::timeout,
+};
+
+use crate::{
+    common::PeerId,
+    tunnel::{
+        mpsc::MpscTunnelSender,
+        packet_def::{PacketType, ZCPacket},
+    },
+};
+
+/// Smallest path mtu probed, the minimum every ipv4 host must accept.
+pub const MIN_PMTU: u32 = 576;
+const IPV6_MIN_MTU: u32 = 1280;
+// the search stops once the bounds are this close
+const PMTU_PRECISION: u32 = 16;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+const PROBE_ATTEMPTS: u32 = 2;
+const REPROBE_INTERVAL: Duration = Duration::from_secs(600);
+// probes are pings with seqs the pinger never reaches, so it ignores their pongs
+const PROBE_SEQ_BASE: u32 = 0x8000_0000;
+
+/// Finds the largest ip packet a peer conn carries by sending padded pings,
+/// the peer echoes them back as pongs of the same size.
+pub struct PmtuProber {
+    my_peer_id: PeerId,
+    peer_id: PeerId,
+    sink: MpscTunnelSender,
+    ctrl_sender: broadcast::Sender<ZCPacket>,
+    pmtu: Arc<AtomicU32>,
+    pmtu_changed: Arc<Notify>,
+    max_pmtu: u32,
+    tail_reserved: usize,
+    seq: u32,
+}
+
+impl PmtuProber {
+    pub fn new(
+        my_peer_id: PeerId,
+        peer_id: PeerId,
+        sink: MpscTunnelSender,
+        ctrl_sender: broadcast::Sender<ZCPacket>,
+        pmtu: Arc<AtomicU32>,
+        pmtu_changed: Arc<Notify>,
+        max_pmtu: u32,
+        tail_reserved: usize,
+    ) -> Self {
+        Self {
+            my_peer_id,
+            peer_id,
+            sink,
+            ctrl_sender,
+            pmtu,
+            pmtu_changed,
+            max_pmtu: max_pmtu.max(MIN_PMTU),
+            tail_reserved,
+            seq: 0,
+        }
+    }
+
+    fn new_probe_packet(&self, seq: u32, size: u32) -> ZCPacket {
+        // probes are sent in plaintext, they carry the tails the network and
+        // session ciphers add to data packets of `size` as padding
+        let mut payload = vec![0u8; size as usize + self.tail_reserved];
+        payload[..4].copy_from_slice(&seq.to_le_bytes());
+        let mut packet = ZCPacket::new_with_payload(&payload);
+        packet.fill_peer_manager_hdr(self.my_peer_id, self.peer_id, PacketType::Ping as u8);
+        packet
+    }
+
+    async fn probe_once(&mut self, size: u32) -> bool {
+        let seq = PROBE_SEQ_BASE | (self.seq & !PROBE_SEQ_BASE);
+        self.seq = self.seq.wrapping_add(1);
+
+        let mut receiver = self.ctrl_sender.subscribe();
+        if self
+            .sink
+            .send(self.new_probe_packet(seq, size))
+            .await
+            .is_err()
+        {
+            return false;
+        }
+        timeout(PROBE_TIMEOUT, async {
+            loop {
+                match receiver.recv().await {
+                    Ok(p) if p.payload().get(..4) == Some(&seq.to_le_bytes()[..]) => return true,
+                    Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return false,
+                }
+            }
+        })
+        .await
+        .unwrap_or(false)
+    }
+
+    async fn probe(&mut self, size: u32) -> bool {
+        for _ in 0..PROBE_ATTEMPTS {
+            if self.probe_once(size).await {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Largest ip packet size passing the conn, None if even the smallest
+    /// probe is lost.
+    pub async fn discover(&mut self) -> Option<u32> {
+        if self.probe(self.max_pmtu).await {
+            return Some(self.max_pmtu);
+        }
+        if !self.probe(MIN_PMTU).await {
+            return None;
+        }
+        let (mut lo, mut hi) = (MIN_PMTU, self.max_pmtu);
+        while hi - lo > PMTU_PRECISION {
+            let mid = (lo + hi) / 2;
+            if self.probe(mid).await {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        Some(lo)
+    }
+
+    pub async fn run(mut self) {
+        loop {
+            if let Some(pmtu) = self.discover().await {
+                let old = self.pmtu.swap(pmtu, Ordering::Relaxed);
+                if old != pmtu {
+                    tracing::info!(peer_id = self.peer_id, old, pmtu, "path mtu changed");
+                    self.pmtu_changed.notify_one();
+                }
+            }
+            tokio::time::sleep(REPROBE_INTERVAL).await;
+        }
+    }
+}
+
+// icmp errors are never answered with another one
+fn is_icmp_error(protocol: u8, icmp_type: Option<&u8>) -> bool {
+    match protocol {
+        // echo reply, echo request
+        1 => !matches!(icmp_type, Some(0) | Some(8)),
+        // informational messages start at 128
+        58 => icmp_type.is_none_or(|x| *x < 128),
+        _ => false,
+    }
+}
+
+fn build_frag_needed(packet: &[u8], mtu: u32) -> Option<Vec<u8>> {
+    let ihl = ((*packet.first()? & 0x0f) as usize) * 4;
+    let flags_offset = u16::from_be_bytes([*packet.get(6)?, *packet.get(7)?]);
+    // only the first fragment of packets with the don't fragment bit
+    if flags_offset & 0x4000 == 0 || flags_offset & 0x1fff != 0 {
+        return None;
+    }
+    if is_icmp_error(*packet.get(9)?, packet.get(ihl)) {
+        return None;
+    }
+    let src: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+    let dst: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+
+    let quoted = &packet[..packet.len().min(ihl + 8)];
+    let mut buf = vec![0u8; 28 + quoted.len()];
+    buf[0] = 0x45;
+    buf[2..4].copy_from_slice(&(buf.len() as u16).to_be_bytes());
+    buf[8] = 64;
+    buf[9] = 1;
+    // from the destination, some hosts drop icmp with one of their own addresses as source
+    buf[12..16].copy_from_slice(&dst);
+    buf[16..20].copy_from_slice(&src);
+    // destination unreachable, fragmentation needed
+    buf[20] = 3;
+    buf[21] = 4;
+    buf[26..28].copy_from_slice(&(mtu.min(u16::MAX as u32) as u16).to_be_bytes());
+    buf[28..].copy_from_slice(quoted);
+
+    let checksum = icmp::checksum(&IcmpPacket::new(&buf[20..])?);
+    buf[22..24].copy_from_slice(&checksum.to_be_bytes());
+    let checksum = ipv4::checksum(&Ipv4Packet::new(&buf)?);
+    buf[10..12].copy_from_slice(&checksum.to_be_bytes());
+    Some(buf)
+}
+
+fn build_ipv6_packet_too_big(packet: &[u8], mtu: u32) -> Option<Vec<u8>> {
+    if is_icmp_error(*packet.get(6)?, packet.get(40)) {
+        return None;
+    }
+    let src = Ipv6Addr::from(<[u8; 16]>::try_from(packet.get(8..24)?).ok()?);
+    let dst = Ipv6Addr::from(<[u8; 16]>::try_from(packet.get(24..40)?).ok()?);
+    if src.is_unspecified() || src.is_multicast() {
+        return None;
+    }
+
+    // the whole error must fit the minimum ipv6 mtu
+    let quoted = &packet[..packet.len().min(IPV6_MIN_MTU as usize - 48)];
+    let mut buf = vec![0u8; 48 + quoted.len()];
+    buf[0] = 0x60;
+    buf[4..6].copy_from_slice(&((buf.len() - 40) as u16).to_be_bytes());
+    buf[6] = 58;
+    buf[7] = 64;
+    buf[8..24].copy_from_slice(&dst.octets());
+    buf[24..40].copy_from_slice(&src.octets());
+    // packet too big
+    buf[40] = 2;
+    buf[44..48].copy_from_slice(&mtu.max(IPV6_MIN_MTU).to_be_bytes());
+    buf[48..].copy_from_slice(quoted);
+
+    let checksum = icmpv6::checksum(&Icmpv6Packet::new(&buf[40..])?, &dst, &src);
+    buf[42..44].copy_from_slice(&checksum.to_be_bytes());
+    Some(buf)
+}
+
+/// ICMP error for an ip packet larger than the path mtu to its next hop,
+/// written back to the sender as if the destination sent it. None if the
+/// packet may be fragmented instead or is not answered.
+pub fn build_packet_too_big(packet: &[u8], mtu: u32) -> Option<Vec<u8>> {
+    match packet.first()? >> 4 {
+        4 => build_frag_needed(packet, mtu),
+        6 => build_ipv6_packet_too_big(packet, mtu),
+        _ => None,
+    }
+}
+
+// ip version and header length of a tcp syn
+fn parse_tcp_syn(packet: &[u8]) -> Option<(u8, usize)> {
+    let version = packet.first()? >> 4;
+    let (ip_hdr_len, protocol) = match version {
+        4 => {
+            // fragments other than the first carry no tcp header
+            if packet.len() < 20 || u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff != 0 {
+                return None;
+            }
+            (((packet[0] & 0x0f) as usize) * 4, packet[9])
+        }
+        6 if packet.len() >= 40 => (40, packet[6]),
+        _ => return None,
+    };
+    if protocol != 6 || packet.len() < ip_hdr_len + 20 {
+        return None;
+    }
+    // syn flag
+    (packet[ip_hdr_len + 13] & 0x02 != 0).then_some((version, ip_hdr_len))
+}
+
+pub fn is_tcp_syn(packet: &[u8]) -> bool {
+    parse_tcp_syn(packet).is_some()
+}
+
+/// Lowers the mss option of a tcp syn so its segments fit in `mtu`, returns
+/// whether the packet changed.
+pub fn clamp_tcp_mss(packet: &mut [u8], mtu: u32) -> bool {
+    let Some((version, ip_hdr_len)) = parse_tcp_syn(packet) else {
+        return false;
+    };
+    let tcp_hdr = &packet[ip_hdr_len..];
+    let tcp_hdr_len = ((tcp_hdr[12] >> 4) as usize * 4).min(tcp_hdr.len());
+    let max_mss = mtu
+        .saturating_sub((ip_hdr_len + 20) as u32)
+        .min(u16::MAX as u32) as u16;
+
+    let mut offset = 20;
+    let mut mss_offset = None;
+    while offset < tcp_hdr_len {
+        match tcp_hdr[offset] {
+            0 => break,
+            1 => offset += 1,
+            kind => {
+                let Some(len) = tcp_hdr.get(offset + 1).map(|x| *x as usize) else {
+                    break;
+                };
+                if len < 2 {
+                    break;
+                }
+                if kind == 2 && len == 4 && offset + 4 <= tcp_hdr_len {
+                    mss_offset = Some(ip_hdr_len + offset + 2);
+                    break;
+                }
+                offset += len;
+            }
+        }
+    }
+    let Some(mss_offset) = mss_offset else {
+        return false;
+    };
+    let mss = u16::from_be_bytes([packet[mss_offset], packet[mss_offset + 1]]);
+    if mss <= max_mss {
+        return false;
+    }
+    packet[mss_offset..mss_offset + 2].copy_from_slice(&max_mss.to_be_bytes());
+
+    let checksum = if version == 4 {
+        let src = Ipv4Addr::from(<[u8; 4]>::try_from(&packet[12..16]).unwrap());
+        let dst = Ipv4Addr::from(<[u8; 4]>::try_from(&packet[16..20]).unwrap());
+        let tcp_len = (u16::from_be_bytes([packet[2], packet[3]]) as usize)
+            .clamp(ip_hdr_len, packet.len())
+            - ip_hdr_len;
+        let Some(tcp_packet) = TcpPacket::new(&packet[ip_hdr_len..ip_hdr_len + tcp_len]) else {
+            return false;
+        };
+        tcp::ipv4_checksum(&tcp_packet, &src, &dst)
+    } else {
+        let src = Ipv6Addr::from(<[u8; 16]>::try_from(&packet[8..24]).unwrap());
+        let dst = Ipv6Addr::from(<[u8; 16]>::try_from(&packet[24..40]).unwrap());
+        let tcp_len = (u16::from_be_bytes([packet[4], packet[5]]) as usize).min(packet.len() - 40);
+        let Some(tcp_packet) = TcpPacket::new(&packet[40..40 + tcp_len]) else {
+            return false;
+        };
+        tcp::ipv6_checksum(&tcp_packet, &src, &dst)
+    };
+    packet[ip_hdr_len + 16..ip_hdr_len + 18].copy_from_slice(&checksum.to_be_bytes());
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ipv4_header(len: usize, protocol: u8, dont_fragment: bool) -> Vec<u8> {
+        let mut packet = vec![0u8; len];
+        packet[0] = 0x45;
+        packet[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+        if dont_fragment {
+            packet[6] = 0x40;
+        }
+        packet[8] = 64;
+        packet[9] = protocol;
+        packet[12..16].copy_from_slice(&[10, 144, 144, 1]);
+        packet[16..20].copy_from_slice(&[10, 144, 144, 2]);
+        packet
+    }
+
+    fn tcp_syn(mss: u16) -> Vec<u8> {
+        let mut packet = ipv4_header(44, 6, true);
+        let tcp = &mut packet[20..];
+        tcp[12] = 6 << 4;
+        tcp[13] = 0x02;
+        tcp[20..24].copy_from_slice(&[2, 4, (mss >> 8) as u8, mss as u8]);
+        packet
+    }
+
+    #[test]
+    fn pmtu_frag_needed() {
+        let packet = ipv4_header(1400, 17, true);
+        let reply = build_packet_too_big(&packet, 1200).unwrap();
+        let ip = Ipv4Packet::new(&reply).unwrap();
+        assert_eq!(Ipv4Addr::new(10, 144, 144, 2), ip.get_source());
+        assert_eq!(Ipv4Addr::new(10, 144, 144, 1), ip.get_destination());
+        assert_eq!(ipv4::checksum(&ip), ip.get_checksum());
+        assert_eq!(56, reply.len());
+        assert_eq!([3, 4], reply[20..22]);
+        assert_eq!(1200, u16::from_be_bytes([reply[26], reply[27]]));
+        let icmp_packet = IcmpPacket::new(&reply[20..]).unwrap();
+        assert_eq!(icmp::checksum(&icmp_packet), icmp_packet.get_checksum());
+
+        // may be fragmented
+        assert!(build_packet_too_big(&ipv4_header(1400, 17, false), 1200).is_none());
+        // never answer an icmp error
+        assert!(build_packet_too_big(&reply, 500).is_none());
+    }
+
+    #[test]
+    fn pmtu_ipv6_packet_too_big() {
+        let mut packet = vec![0u8; 1400];
+        packet[0] = 0x60;
+        packet[6] = 17;
+        packet[8..24].copy_from_slice(&"fd00::1".parse::<Ipv6Addr>().unwrap().octets());
+        packet[24..40].copy_from_slice(&"fd00::2".parse::<Ipv6Addr>().unwrap().octets());
+        let reply = build_packet_too_big(&packet, 1300).unwrap();
+        assert_eq!(IPV6_MIN_MTU as usize, reply.len());
+        assert_eq!(2, reply[40]);
+        assert_eq!(1300, u32::from_be_bytes(reply[44..48].try_into().unwrap()));
+        let icmp_packet = Icmpv6Packet::new(&reply[40..]).unwrap();
+        assert_eq!(
+            icmpv6::checksum(
+                &icmp_packet,
+                &"fd00::2".parse().unwrap(),
+                &"fd00::1".parse().unwrap()
+            ),
+            icmp_packet.get_checksum()
+        );
+        assert!(build_packet_too_big(&reply, 1280).is_none());
+    }
+
+    #[test]
+    fn pmtu_clamp_tcp_mss() {
+        let mut packet = tcp_syn(1460);
+        assert!(clamp_tcp_mss(&mut packet, 1200));
+        assert_eq!(1160, u16::from_be_bytes([packet[42], packet[43]]));
+        let tcp_packet = TcpPacket::new(&packet[20..]).unwrap();
+        assert_eq!(
+            tcp::ipv4_checksum(
+                &tcp_packet,
+                &Ipv4Addr::new(10, 144, 144, 1),
+                &Ipv4Addr::new(10, 144, 144, 2)
+            ),
+            tcp_packet.get_checksum()
+        );
+        // already small enough
+        assert!(!clamp_tcp_mss(&mut packet, 1300));
+
+        // not a syn
+        let mut packet = tcp_syn(1460);
+        packet[33] = 0x10;
+        assert!(!clamp_tcp_mss(&mut packet, 1200));
+    }
+}
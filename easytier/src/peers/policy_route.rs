@@ -0,0 +1,322 @@
+use std::{
+    collections::BTreeSet,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
+
+use arc_swap::ArcSwap;
+use atomic_shim::AtomicU64;
+
+use crate::{
+    common::{config::PolicyRouteConfig, PeerId},
+    proto::cli::{PolicyRouteInfo, Route},
+};
+
+use super::peer_map::PeerMap;
+
+// how often the routes are checked for changes to re-resolve the peers
+const ROUTE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// domains are re-resolved this often, roughly a common dns ttl
+const DOMAIN_RESOLVE_INTERVAL: Duration = Duration::from_secs(60);
+const DOMAIN_RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Policy route matched by a destination, with its peers resolved.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PolicyMatch {
+    pub index: usize,
+    pub via: Option<PeerId>,
+    pub avoid: Arc<BTreeSet<PeerId>>,
+    /// `via` is configured but cannot be reached and the rule is strict
+    pub blackhole: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+struct ResolvedRule {
+    via: Option<PeerId>,
+    avoid: Arc<BTreeSet<PeerId>>,
+}
+
+/// Policy routing table consulted by the peer manager before the default
+/// routing. Matching is done on the destination address only, domains match
+/// the addresses this node resolves them to.
+///
+/// Peers and domains are resolved in the background by
+/// [`run_policy_route_refresh`], a lookup only loads the last result.
+pub struct PolicyRouter {
+    rules: Vec<PolicyRouteConfig>,
+    hits: Vec<AtomicU64>,
+    resolved: ArcSwap<Vec<ResolvedRule>>,
+    domain_addrs: ArcSwap<Vec<BTreeSet<IpAddr>>>,
+}
+
+impl std::fmt::Debug for PolicyRouter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PolicyRouter")
+            .field("rules", &self.rules)
+            .finish()
+    }
+}
+
+pub(super) fn resolve_peer(name: &str, routes: &[Route]) -> Option<PeerId> {
+    let name = name.trim();
+    let found = match name.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => routes
+            .iter()
+            .find(|r| r.ipv4_addr.and_then(|x| x.address).map(Ipv4Addr::from) == Some(ip)),
+        Ok(IpAddr::V6(ip)) => routes
+            .iter()
+            .find(|r| r.ipv6_addr.and_then(|x| x.address).map(Ipv6Addr::from) == Some(ip)),
+        Err(_) => routes.iter().find(|r| r.hostname == name).or_else(|| {
+            let peer_id = name.parse::<PeerId>().ok()?;
+            routes.iter().find(|r| r.peer_id == peer_id)
+        }),
+    };
+    found.map(|r| r.peer_id)
+}
+
+async fn resolve_domain(domain: &str) -> anyhow::Result<Vec<IpAddr>> {
+    let addrs = tokio::time::timeout(DOMAIN_RESOLVE_TIMEOUT, tokio::net::lookup_host((domain, 0)))
+        .await??;
+    Ok(addrs.map(|x| x.ip()).collect())
+}
+
+impl PolicyRouter {
+    pub fn new(rules: Vec<PolicyRouteConfig>) -> Self {
+        let hits = rules.iter().map(|_| AtomicU64::new(0)).collect();
+        let resolved = vec![ResolvedRule::default(); rules.len()];
+        let domain_addrs = vec![BTreeSet::new(); rules.len()];
+        PolicyRouter {
+            rules,
+            hits,
+            resolved: ArcSwap::from_pointee(resolved),
+            domain_addrs: ArcSwap::from_pointee(domain_addrs),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    fn has_domains(&self) -> bool {
+        self.rules.iter().any(|r| !r.domains.is_empty())
+    }
+
+    fn match_index(&self, dst: &IpAddr, domain_addrs: &[BTreeSet<IpAddr>]) -> Option<usize> {
+        self.rules.iter().enumerate().position(|(index, r)| {
+            r.destinations.iter().any(|c| c.contains(dst)) || domain_addrs[index].contains(dst)
+        })
+    }
+
+    fn resolve(&self, routes: &[Route]) -> Vec<ResolvedRule> {
+        self.rules
+            .iter()
+            .map(|r| ResolvedRule {
+                via: r.via.as_ref().and_then(|x| resolve_peer(x, routes)),
+                avoid: Arc::new(
+                    r.avoid
+                        .iter()
+                        .filter_map(|x| resolve_peer(x, routes))
+                        .collect(),
+                ),
+            })
+            .collect()
+    }
+
+    /// Re-resolves the peers of the rules, called when the routes changed.
+    pub fn update_peers(&self, routes: &[Route]) {
+        self.resolved.store(Arc::new(self.resolve(routes)));
+    }
+
+    /// Re-resolves the domains of the rules. A rule keeps its previous
+    /// addresses of a domain that fails to resolve.
+    pub async fn update_domains(&self) {
+        let prev = self.domain_addrs.load_full();
+        let mut domain_addrs = Vec::with_capacity(self.rules.len());
+        for (index, rule) in self.rules.iter().enumerate() {
+            let mut addrs = BTreeSet::new();
+            for domain in rule.domains.iter() {
+                match resolve_domain(domain).await {
+                    Ok(x) => addrs.extend(x),
+                    Err(e) => {
+                        tracing::warn!(?e, ?domain, "resolve domain of policy route failed");
+                        addrs.extend(prev[index].iter().copied());
+                    }
+                }
+            }
+            domain_addrs.push(addrs);
+        }
+        self.domain_addrs.store(Arc::new(domain_addrs));
+    }
+
+    fn make_match(&self, index: usize, resolved: &ResolvedRule) -> PolicyMatch {
+        let rule = &self.rules[index];
+        PolicyMatch {
+            index,
+            via: resolved.via,
+            avoid: resolved.avoid.clone(),
+            blackhole: rule.strict && rule.via.is_some() && resolved.via.is_none(),
+        }
+    }
+
+    /// First rule containing `dst`, None if the default routing applies.
+    pub fn lookup(&self, dst: &IpAddr) -> Option<PolicyMatch> {
+        let index = self.match_index(dst, &self.domain_addrs.load())?;
+        Some(self.make_match(index, &self.resolved.load()[index]))
+    }
+
+    pub fn record_hit(&self, index: usize) {
+        if let Some(hits) = self.hits.get(index) {
+            hits.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    pub fn get_info(&self, index: usize, resolved: Option<&PolicyMatch>) -> PolicyRouteInfo {
+        let rule = &self.rules[index];
+        PolicyRouteInfo {
+            index: index as u32,
+            name: rule.name.clone().unwrap_or_default(),
+            destinations: rule.destinations.iter().map(|x| x.to_string()).collect(),
+            domains: rule.domains.clone(),
+            domain_addrs: self.domain_addrs.load()[index]
+                .iter()
+                .map(|x| x.to_string())
+                .collect(),
+            via: rule.via.clone().unwrap_or_default(),
+            via_peer_id: resolved.and_then(|x| x.via),
+            avoid: rule.avoid.clone(),
+            avoid_peer_ids: resolved
+                .map(|x| x.avoid.iter().copied().collect())
+                .unwrap_or_default(),
+            strict: rule.strict,
+            hits: self.hits[index].load(std::sync::atomic::Ordering::Relaxed),
+        }
+    }
+
+    pub fn list(&self) -> Vec<PolicyRouteInfo> {
+        self.resolved
+            .load()
+            .iter()
+            .enumerate()
+            .map(|(index, r)| self.get_info(index, Some(&self.make_match(index, r))))
+            .collect()
+    }
+}
+
+/// Keeps the peers of the policy routes resolved against the current routes
+/// and their domains against the system resolver.
+pub async fn run_policy_route_refresh(router: Arc<PolicyRouter>, peers: Weak<PeerMap>) {
+    if router.is_empty() {
+        return;
+    }
+
+    let mut routes_updated_at: Option<Option<Instant>> = None;
+    let mut domains_resolved_at: Option<Instant> = None;
+    loop {
+        let Some(peers) = peers.upgrade() else {
+            return;
+        };
+
+        let updated_at = peers.get_route_peer_info_last_update_time().await;
+        if routes_updated_at != Some(updated_at) {
+            router.update_peers(&peers.list_route_infos().await);
+            routes_updated_at = Some(updated_at);
+        }
+        drop(peers);
+
+        if router.has_domains()
+            && !matches!(domains_resolved_at, Some(at) if at.elapsed() < DOMAIN_RESOLVE_INTERVAL)
+        {
+            router.update_domains().await;
+            domains_resolved_at = Some(Instant::now());
+        }
+
+        tokio::time::sleep(ROUTE_CHECK_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::common::{Ipv4Inet, Ipv6Inet};
+
+    fn route(peer_id: PeerId, hostname: &str, ipv4: &str) -> Route {
+        Route {
+            peer_id,
+            hostname: hostname.to_string(),
+            ipv4_addr: Some(Ipv4Inet {
+                address: Some(ipv4.parse::<Ipv4Addr>().unwrap().into()),
+                network_length: 24,
+            }),
+            ipv6_addr: Some(Ipv6Inet {
+                address: Some(
+                    format!("fd00::{}", peer_id)
+                        .parse::<Ipv6Addr>()
+                        .unwrap()
+                        .into(),
+                ),
+                network_length: 64,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn policy_route_resolve_and_match() {
+        let routes = vec![
+            route(11, "gw-shanghai", "10.144.144.1"),
+            route(12, "exit-x", "10.144.144.2"),
+            route(13, "peer-y", "10.144.144.3"),
+        ];
+        assert_eq!(Some(11), resolve_peer("gw-shanghai", &routes));
+        assert_eq!(Some(12), resolve_peer("10.144.144.2", &routes));
+        assert_eq!(Some(13), resolve_peer("fd00::13", &routes));
+        assert_eq!(Some(13), resolve_peer("13", &routes));
+        assert_eq!(None, resolve_peer("14", &routes));
+
+        let router = PolicyRouter::new(vec![
+            PolicyRouteConfig {
+                destinations: vec!["10.20.0.0/16".parse().unwrap()],
+                via: Some("gw-shanghai".to_string()),
+                ..Default::default()
+            },
+            PolicyRouteConfig {
+                destinations: vec!["10.30.0.0/16".parse().unwrap()],
+                via: Some("gw-beijing".to_string()),
+                strict: true,
+                ..Default::default()
+            },
+            PolicyRouteConfig {
+                destinations: vec!["0.0.0.0/0".parse().unwrap()],
+                avoid: vec!["peer-y".to_string(), "unknown".to_string()],
+                ..Default::default()
+            },
+        ]);
+        router.update_peers(&routes);
+        let lookup = |ip: &str| router.lookup(&ip.parse().unwrap());
+
+        let m = lookup("10.20.1.1").unwrap();
+        assert_eq!((0, Some(11), false), (m.index, m.via, m.blackhole));
+        let m = lookup("10.30.1.1").unwrap();
+        assert_eq!((1, None, true), (m.index, m.via, m.blackhole));
+        let m = lookup("8.8.8.8").unwrap();
+        assert_eq!(2, m.index);
+        assert_eq!(BTreeSet::from([13]), *m.avoid);
+        assert!(lookup("fd00::1").is_none());
+    }
+
+    #[tokio::test]
+    async fn policy_route_domain() {
+        let router = PolicyRouter::new(vec![PolicyRouteConfig {
+            domains: vec!["localhost".to_string()],
+            via: Some("exit-x".to_string()),
+            ..Default::default()
+        }]);
+        assert!(router.lookup(&"127.0.0.1".parse().unwrap()).is_none());
+
+        router.update_domains().await;
+        let m = router.lookup(&"127.0.0.1".parse().unwrap()).unwrap();
+        assert_eq!(0, m.index);
+        assert!(router.lookup(&"10.0.0.1".parse().unwrap()).is_none());
+    }
+}
@@ -0,0 +1,327 @@
+// Admission of foreign networks on a relay node.
+//
+// The network name whitelist can be passed by anyone who knows or guesses a
+// name. With admission configured, a foreign network is only relayed if its
+// (name, secret digest) pair is listed, or if it presents a token signed by
+// one of the keys trusted by the relay operator.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use prost::Message;
+use ring::signature::{KeyPair, UnparsedPublicKey, ED25519};
+
+use crate::{
+    common::{
+        config::{NetworkIdentity, RelayAdmissionConfig},
+        error::Error,
+    },
+    proto::peer_rpc::{RelayAdmissionToken, SignedRelayAdmissionToken},
+};
+
+use super::node_auth::{auth_err, decode_base64, load_key_pair, unix_now};
+
+const TOKEN_SIGN_LABEL: &[u8] = b"easytier-relay-admission-v1";
+
+/// Advertised in `HandshakeRequest.features` by clients holding a relay
+/// admission token, and echoed by relays that want the token for the client's
+/// network. The token itself is only sent after the relay asked for it.
+pub const RELAY_ADMISSION_FEATURE: &str = "relay-admission-v1";
+
+fn token_sign_data(token: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(TOKEN_SIGN_LABEL.len() + token.len());
+    ret.extend_from_slice(TOKEN_SIGN_LABEL);
+    ret.extend_from_slice(token);
+    ret
+}
+
+/// Sign a relay admission token for `network_name` (a glob) with the private
+/// key of the relay operator. If `network_identity` is given the token is
+/// bound to its secret. The returned token is base64 encoded.
+pub fn issue_relay_token(
+    signer_private_key: &str,
+    network_name: &str,
+    network_identity: Option<&NetworkIdentity>,
+    valid_for: Option<Duration>,
+) -> Result<String, Error> {
+    if network_identity
+        .is_some_and(|n| n.network_name != network_name || network_name.contains(['*', '?']))
+    {
+        return Err(auth_err(
+            "a token bound to a network secret needs the exact network name",
+        ));
+    }
+
+    let key_pair = load_key_pair(signer_private_key)?;
+    let not_before = unix_now();
+    let token = RelayAdmissionToken {
+        network_name: network_name.to_string(),
+        network_secret_digest: network_identity
+            .and_then(|n| n.network_secret_digest)
+            .map(|d| d.to_vec())
+            .unwrap_or_default(),
+        not_before,
+        not_after: valid_for.map(|d| not_before + d.as_secs()).unwrap_or(0),
+    }
+    .encode_to_vec();
+    let signed = SignedRelayAdmissionToken {
+        signature: key_pair.sign(&token_sign_data(&token)).as_ref().to_vec(),
+        signer_public_key: key_pair.public_key().as_ref().to_vec(),
+        token,
+    };
+    Ok(BASE64_STANDARD.encode(signed.encode_to_vec()))
+}
+
+/// Decode a base64 token from the config into the bytes sent in the handshake.
+pub fn decode_relay_token(token: &str) -> Result<Vec<u8>, Error> {
+    let raw = decode_base64("relay admission token", token)?;
+    SignedRelayAdmissionToken::decode(raw.as_slice())
+        .map_err(|e| auth_err(format!("invalid relay admission token: {:?}", e)))?;
+    Ok(raw)
+}
+
+// the digest covers the network name, so with a plain secret it is computed
+// for each network matching the name glob
+#[derive(Debug)]
+enum AdmittedSecret {
+    Secret(String),
+    Digest([u8; 32]),
+}
+
+impl AdmittedSecret {
+    fn matches(&self, network: &NetworkIdentity) -> bool {
+        let digest = match self {
+            AdmittedSecret::Secret(secret) => {
+                NetworkIdentity::new(network.network_name.clone(), secret.clone())
+                    .network_secret_digest
+            }
+            AdmittedSecret::Digest(digest) => Some(*digest),
+        };
+        digest.is_some() && digest == network.network_secret_digest
+    }
+}
+
+#[derive(Debug)]
+pub struct RelayAdmission {
+    networks: Vec<(wildmatch::WildMatch, AdmittedSecret)>,
+    token_public_keys: Vec<Vec<u8>>,
+}
+
+impl RelayAdmission {
+    pub fn new(config: &RelayAdmissionConfig) -> Result<Self, Error> {
+        let mut networks = Vec::with_capacity(config.networks.len());
+        for entry in config.networks.iter() {
+            let secret = match (&entry.network_secret, &entry.network_secret_digest) {
+                (Some(secret), _) => AdmittedSecret::Secret(secret.clone()),
+                (None, Some(digest)) => AdmittedSecret::Digest(
+                    decode_base64("network secret digest", digest)?
+                        .try_into()
+                        .map_err(|_| auth_err("network secret digest must be 32 bytes"))?,
+                ),
+                (None, None) => {
+                    return Err(auth_err(format!(
+                        "relay admission of {} needs network_secret or network_secret_digest",
+                        entry.network_name
+                    )))
+                }
+            };
+            networks.push((wildmatch::WildMatch::new(&entry.network_name), secret));
+        }
+
+        let token_public_keys = config
+            .token_public_keys
+            .iter()
+            .map(|k| decode_base64("token public key", k))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            networks,
+            token_public_keys,
+        })
+    }
+
+    /// Check a foreign network and the token it presented in the handshake,
+    /// the error is the reason of the rejection.
+    pub fn check(&self, network: &NetworkIdentity, token: &[u8]) -> Result<(), anyhow::Error> {
+        if self
+            .networks
+            .iter()
+            .any(|(name, secret)| name.matches(&network.network_name) && secret.matches(network))
+        {
+            return Ok(());
+        }
+
+        if token.is_empty() {
+            return Err(anyhow::anyhow!(
+                "network {} is not admitted and presented no token",
+                network.network_name
+            ));
+        }
+        self.verify_token(network, token)
+    }
+
+    fn verify_token(&self, network: &NetworkIdentity, token: &[u8]) -> Result<(), anyhow::Error> {
+        let signed = SignedRelayAdmissionToken::decode(token)
+            .map_err(|e| anyhow::anyhow!("invalid relay admission token: {:?}", e))?;
+        if !self.token_public_keys.contains(&signed.signer_public_key) {
+            return Err(anyhow::anyhow!(
+                "relay admission token signer is not trusted"
+            ));
+        }
+        UnparsedPublicKey::new(&ED25519, &signed.signer_public_key)
+            .verify(&token_sign_data(&signed.token), &signed.signature)
+            .map_err(|_| anyhow::anyhow!("invalid relay admission token signature"))?;
+
+        let token = RelayAdmissionToken::decode(signed.token.as_slice())
+            .map_err(|e| anyhow::anyhow!("decode relay admission token error: {:?}", e))?;
+        if !wildmatch::WildMatch::new(&token.network_name).matches(&network.network_name) {
+            return Err(anyhow::anyhow!(
+                "relay admission token is for network {}, not {}",
+                token.network_name,
+                network.network_name
+            ));
+        }
+        if !token.network_secret_digest.is_empty()
+            && Some(token.network_secret_digest.as_slice())
+                != network.network_secret_digest.as_ref().map(|d| d.as_slice())
+        {
+            return Err(anyhow::anyhow!(
+                "relay admission token is bound to another network secret"
+            ));
+        }
+
+        let now = unix_now();
+        if now < token.not_before || (token.not_after != 0 && now > token.not_after) {
+            return Err(anyhow::anyhow!("relay admission token expired"));
+        }
+        Ok(())
+    }
+}
+
+type CachedRelayAdmission = (RelayAdmissionConfig, Result<Arc<RelayAdmission>, String>);
+
+/// Admission of the last seen config, rebuilt only when the config changes.
+#[derive(Default)]
+pub struct RelayAdmissionCache {
+    cached: Mutex<Option<CachedRelayAdmission>>,
+}
+
+impl RelayAdmissionCache {
+    pub fn get(&self, config: &RelayAdmissionConfig) -> Result<Arc<RelayAdmission>, anyhow::Error> {
+        let mut cached = self.cached.lock().unwrap();
+        if cached.as_ref().map(|(c, _)| c != config).unwrap_or(true) {
+            let admission = RelayAdmission::new(config)
+                .map(Arc::new)
+                .map_err(|e| format!("{:?}", e));
+            *cached = Some((config.clone(), admission));
+        }
+        cached
+            .as_ref()
+            .unwrap()
+            .1
+            .clone()
+            .map_err(|e| anyhow::anyhow!("invalid relay admission config: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        common::config::RelayAdmissionEntry,
+        peers::node_auth::{generate_private_key, public_key_of},
+    };
+
+    use super::*;
+
+    #[test]
+    fn relay_admission_cache() {
+        let mut config = RelayAdmissionConfig {
+            networks: vec![RelayAdmissionEntry {
+                network_name: "team-*".to_string(),
+                network_secret: Some("secret".to_string()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let cache = RelayAdmissionCache::default();
+        let admission = cache.get(&config).unwrap();
+        assert!(Arc::ptr_eq(&admission, &cache.get(&config).unwrap()));
+
+        config.networks[0].network_secret = None;
+        assert!(cache.get(&config).is_err());
+        config.networks[0].network_secret = Some("other".to_string());
+        assert!(!Arc::ptr_eq(&admission, &cache.get(&config).unwrap()));
+    }
+
+    #[test]
+    fn relay_admission_by_secret() {
+        let admission = RelayAdmission::new(&RelayAdmissionConfig {
+            networks: vec![RelayAdmissionEntry {
+                network_name: "team-*".to_string(),
+                network_secret: Some("secret".to_string()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        })
+        .unwrap();
+
+        let net = NetworkIdentity::new("team-a".to_string(), "secret".to_string());
+        admission.check(&net, &[]).unwrap();
+
+        let net = NetworkIdentity::new("team-b".to_string(), "secret".to_string());
+        admission.check(&net, &[]).unwrap();
+
+        let net = NetworkIdentity::new("team-a".to_string(), "guess".to_string());
+        assert!(admission.check(&net, &[]).is_err());
+        let net = NetworkIdentity::new("other".to_string(), "secret".to_string());
+        assert!(admission.check(&net, &[]).is_err());
+    }
+
+    #[test]
+    fn relay_admission_by_token() {
+        let operator = generate_private_key().unwrap();
+        let admission = RelayAdmission::new(&RelayAdmissionConfig {
+            token_public_keys: vec![public_key_of(&operator).unwrap()],
+            ..Default::default()
+        })
+        .unwrap();
+
+        let net = NetworkIdentity::new("net1".to_string(), "secret".to_string());
+        let token = issue_relay_token(&operator, "net*", None, None).unwrap();
+        let token = decode_relay_token(&token).unwrap();
+        admission.check(&net, &token).unwrap();
+        assert!(admission.check(&net, &[]).is_err());
+
+        let other = NetworkIdentity::new("other".to_string(), "secret".to_string());
+        assert!(admission.check(&other, &token).is_err());
+
+        // bound to the secret
+        let token = issue_relay_token(&operator, "net1", Some(&net), None).unwrap();
+        let token = decode_relay_token(&token).unwrap();
+        admission.check(&net, &token).unwrap();
+        let guessed = NetworkIdentity::new("net1".to_string(), "guess".to_string());
+        assert!(admission.check(&guessed, &token).is_err());
+
+        // signed by an untrusted key
+        let token = issue_relay_token(&generate_private_key().unwrap(), "*", None, None).unwrap();
+        let token = decode_relay_token(&token).unwrap();
+        assert!(admission.check(&net, &token).is_err());
+
+        // tampered token
+        let mut signed = SignedRelayAdmissionToken::decode(
+            decode_relay_token(&issue_relay_token(&operator, "net1", None, None).unwrap())
+                .unwrap()
+                .as_slice(),
+        )
+        .unwrap();
+        signed.token = RelayAdmissionToken {
+            network_name: "*".to_string(),
+            ..Default::default()
+        }
+        .encode_to_vec();
+        assert!(admission.check(&net, &signed.encode_to_vec()).is_err());
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    collections::BTreeSet,
     net::{Ipv4Addr, Ipv6Addr},
     sync::Arc,
 };
@@ -7,6 +8,7 @@ use dashmap::DashMap;
 
 use crate::{
     common::{global_ctx::NetworkIdentity, PeerId},
+    peers::link_metric::LinkSample,
     proto::peer_rpc::{
         ForeignNetworkRouteInfoEntry, ForeignNetworkRouteInfoKey, RouteForeignNetworkInfos,
         RoutePeerInfo,
@@ -30,6 +32,10 @@ pub trait RouteInterface {
     async fn list_foreign_networks(&self) -> ForeignNetworkRouteInfoMap {
         DashMap::new()
     }
+    /// Measured quality of the direct links to each neighbor.
+    async fn list_link_samples(&self) -> Vec<(PeerId, LinkSample)> {
+        vec![]
+    }
 }
 
 pub type RouteInterfaceBox = Box<dyn RouteInterface + Send + Sync>;
@@ -50,6 +56,12 @@ pub trait RouteCostCalculatorInterface: Send + Sync {
     fn dump(&self) -> String {
         "All routes have cost 1".to_string()
     }
+
+    /// True if calculate_cost returns a latency in ms, the unit of the link
+    /// metrics, instead of a hop count.
+    fn cost_in_ms(&self) -> bool {
+        false
+    }
 }
 
 #[derive(Clone, Debug, Default)]
@@ -74,6 +86,19 @@ pub trait Route {
         self.get_next_hop(peer_id).await
     }
 
+    /// Next hop towards `peer_id` after which the packet does not pass any
+    /// peer in `avoid`, None if every path passes one.
+    async fn get_next_hop_avoiding(
+        &self,
+        peer_id: PeerId,
+        policy: NextHopPolicy,
+        avoid: &BTreeSet<PeerId>,
+    ) -> Option<PeerId> {
+        self.get_next_hop_with_policy(peer_id, policy)
+            .await
+            .filter(|x| !avoid.contains(x))
+    }
+
     async fn list_routes(&self) -> Vec<crate::proto::cli::Route>;
 
     async fn get_peer_id_by_ipv4(&self, _ipv4: &Ipv4Addr) -> Option<PeerId> {
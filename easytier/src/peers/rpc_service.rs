@@ -1,5 +1,7 @@
 use std::sync::Arc;
 
+use anyhow::Context as _;
+
 use crate::{
     common::acl_processor::AclRuleBuilder,
     proto::{
@@ -7,9 +9,10 @@ use crate::{
             AclManageRpc, DumpRouteRequest, DumpRouteResponse, GetAclStatsRequest,
             GetAclStatsResponse, GetWhitelistRequest, GetWhitelistResponse,
             ListForeignNetworkRequest, ListForeignNetworkResponse, ListGlobalForeignNetworkRequest,
-            ListGlobalForeignNetworkResponse, ListPeerRequest, ListPeerResponse, ListRouteRequest,
-            ListRouteResponse, PeerInfo, PeerManageRpc, SetWhitelistRequest, SetWhitelistResponse,
-            ShowNodeInfoRequest, ShowNodeInfoResponse,
+            ListGlobalForeignNetworkResponse, ListPeerRequest, ListPeerResponse,
+            ListPolicyRouteRequest, ListPolicyRouteResponse, ListRouteRequest, ListRouteResponse,
+            LookupRouteRequest, LookupRouteResponse, PeerInfo, PeerManageRpc, SetWhitelistRequest,
+            SetWhitelistResponse, ShowNodeInfoRequest, ShowNodeInfoResponse,
         },
         rpc_types::{self, controller::BaseController},
     },
@@ -142,6 +145,28 @@ impl PeerManageRpc for PeerManagerRpcService {
             node_info: Some(self.peer_manager.get_my_info().await),
         })
     }
+
+    async fn list_policy_route(
+        &self,
+        _: BaseController,
+        _request: ListPolicyRouteRequest,
+    ) -> Result<ListPolicyRouteResponse, rpc_types::error::Error> {
+        Ok(ListPolicyRouteResponse {
+            policy_routes: self.peer_manager.list_policy_routes().await,
+        })
+    }
+
+    async fn lookup_route(
+        &self,
+        _: BaseController,
+        request: LookupRouteRequest,
+    ) -> Result<LookupRouteResponse, rpc_types::error::Error> {
+        let dst = request
+            .destination
+            .parse::<std::net::IpAddr>()
+            .with_context(|| format!("invalid destination ip: {}", request.destination))?;
+        Ok(self.peer_manager.lookup_route(&dst).await)
+    }
 }
 
 #[async_trait::async_trait]
@@ -35,11 +35,94 @@ impl Display for ConnTrackEntry {
     }
 }
 
+const MINUTES_PER_DAY: i64 = 24 * 60;
+
+impl TimeWindow {
+    fn start_minute(&self) -> i64 {
+        (self.start_time as i64).min(MINUTES_PER_DAY)
+    }
+
+    fn end_minute(&self) -> i64 {
+        (self.end_time as i64).min(MINUTES_PER_DAY)
+    }
+
+    // returns (day of week, minute of day) in the window's timezone
+    fn local_day_and_minute(&self, unix_secs: u64) -> (u32, i64) {
+        let local_minutes = (unix_secs / 60) as i64 + self.timezone_offset as i64;
+        let days = local_minutes.div_euclid(MINUTES_PER_DAY);
+        // 1970-01-01 is a Thursday
+        let day_of_week = (days + 4).rem_euclid(7) as u32;
+        (day_of_week, local_minutes.rem_euclid(MINUTES_PER_DAY))
+    }
+
+    fn contains_day(&self, day_of_week: u32) -> bool {
+        self.days_of_week.is_empty() || self.days_of_week.contains(&day_of_week)
+    }
+
+    /// Whether the window is active at the given unix timestamp.
+    /// A window whose end is before its start spans midnight, and the
+    /// part after midnight belongs to the day the window started.
+    pub fn is_active_at(&self, unix_secs: u64) -> bool {
+        let (day, minute) = self.local_day_and_minute(unix_secs);
+        let (start, end) = (self.start_minute(), self.end_minute());
+        if start == end {
+            return self.contains_day(day);
+        }
+        if start < end {
+            return self.contains_day(day) && minute >= start && minute < end;
+        }
+        let prev_day = (day + 6) % 7;
+        (self.contains_day(day) && minute >= start) || (self.contains_day(prev_day) && minute < end)
+    }
+
+    /// Next unix timestamp (in seconds, minute aligned) after `unix_secs` at
+    /// which the window may change its state.
+    pub fn next_boundary_after(&self, unix_secs: u64) -> u64 {
+        let (_, minute) = self.local_day_and_minute(unix_secs);
+        let delta = [self.start_minute(), self.end_minute(), 0]
+            .iter()
+            .map(|m| {
+                let d = (m - minute).rem_euclid(MINUTES_PER_DAY);
+                if d == 0 {
+                    MINUTES_PER_DAY
+                } else {
+                    d
+                }
+            })
+            .min()
+            .unwrap();
+        (unix_secs / 60 + delta as u64) * 60
+    }
+}
+
+impl Display for TimeWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let offset = self.timezone_offset;
+        write!(
+            f,
+            "days: {:?}, {:02}:{:02}-{:02}:{:02} UTC{}{:02}:{:02}",
+            self.days_of_week,
+            self.start_time / 60,
+            self.start_time % 60,
+            self.end_time / 60,
+            self.end_time % 60,
+            if offset < 0 { '-' } else { '+' },
+            offset.abs() / 60,
+            offset.abs() % 60
+        )
+    }
+}
+
 impl Display for Rule {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let time_windows = self
+            .time_windows
+            .iter()
+            .map(|w| format!("({})", w))
+            .collect::<Vec<_>>();
         write!(
             f,
-            "[name: '{}', prio: {}, action: {:?}, enabled: {}, proto: {:?}, ports: {:?}, src_ports: {:?}, src_ips: {:?}, dst_ips: {:?}, stateful: {}, rate: {}, burst: {}]",
+            "[name: '{}', prio: {}, action: {:?}, enabled: {}, proto: {:?}, ports: {:?}, src_ports: {:?}, src_ips: {:?}, dst_ips: {:?}, stateful: {}, rate: {}, burst: {}, time_windows: {:?}, src_peer_ids: {:?}, src_inst_ids: {:?}, src_hostnames: {:?}, src_tags: {:?}]",
             self.name,
             self.priority,
             Action::try_from(self.action).unwrap_or(Action::Noop),
@@ -51,7 +134,12 @@ impl Display for Rule {
             self.destination_ips,
             self.stateful,
             self.rate_limit,
-            self.burst_limit
+            self.burst_limit,
+            time_windows,
+            self.source_peer_ids,
+            self.source_instance_ids,
+            self.source_hostnames,
+            self.source_tags
         )
     }
 }
@@ -84,6 +172,9 @@ impl Display for AclStats {
             } else {
                 write!(f, "    <default/none> ")?;
             }
+            if rule_stat.inactive_by_schedule {
+                write!(f, "(inactive due to schedule) ")?;
+            }
             if let Some(stat) = &rule_stat.stat {
                 writeln!(f, "{}", stat)?;
             } else {
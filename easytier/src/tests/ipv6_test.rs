@@ -39,7 +39,7 @@ async fn test_route_peer_info_ipv6() {
 
     // Create RoutePeerInfo with IPv6 support
     let peer_info = RoutePeerInfo::new();
-    let updated_info = peer_info.update_self(123, 456, &global_ctx);
+    let updated_info = peer_info.update_self(123, 456, &global_ctx, vec![]);
 
     // Verify IPv6 address is included
     assert!(updated_info.ipv6_addr.is_some());
@@ -59,7 +59,9 @@ async fn test_peer_manager_ipv6() {
 
     // Test IPv6 address lookup for unknown address
     let ipv6_addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
-    let (peers, _is_self) = peer_mgr.get_msg_dst_peer_ipv6(&ipv6_addr).await;
+    let (peers, _is_self) = peer_mgr
+        .get_msg_dst_peer_ipv6(&ipv6_addr, &Default::default())
+        .await;
 
     // Should return empty peers list for unknown IPv6
     assert!(peers.is_empty());
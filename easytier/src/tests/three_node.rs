@@ -635,6 +635,48 @@ pub async fn data_compress(
     drop_insts(_insts).await;
 }
 
+#[rstest::rstest]
+#[tokio::test]
+#[serial_test::serial]
+pub async fn data_compress_negotiation(
+    #[values(
+        CompressionAlgoPb::Lz4,
+        CompressionAlgoPb::Zstd,
+        CompressionAlgoPb::None
+    )]
+    inst3_algo: CompressionAlgoPb,
+) {
+    let _insts = init_three_node_ex(
+        "udp",
+        |cfg| {
+            if cfg.get_inst_name() == "inst1" {
+                let mut flags = cfg.get_flags();
+                flags.data_compress_algo = CompressionAlgoPb::Lz4.into();
+                flags.data_compress_level = 1;
+                cfg.set_flags(flags);
+            }
+
+            if cfg.get_inst_name() == "inst3" {
+                let mut flags = cfg.get_flags();
+                flags.data_compress_algo = inst3_algo.into();
+                cfg.set_flags(flags);
+            }
+
+            cfg
+        },
+        false,
+    )
+    .await;
+
+    wait_for_condition(
+        || async { ping_test("net_a", "10.144.144.3", Some(5 * 1024)).await },
+        Duration::from_secs(5),
+    )
+    .await;
+
+    drop_insts(_insts).await;
+}
+
 #[cfg(feature = "wireguard")]
 #[rstest::rstest]
 #[tokio::test]
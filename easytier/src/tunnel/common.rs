@@ -435,6 +435,22 @@ pub(crate) fn setup_sokcet2(
     )
 }
 
+/// Tcp socket bound to `port` on all ipv4 addresses with reuse port, so the
+/// listener and the outgoing connections of a tcp hole punch can share it.
+pub(crate) fn bind_reuse_port_tcp_socket(port: u16) -> std::io::Result<tokio::net::TcpSocket> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::IPV4,
+        socket2::Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )?;
+    socket.set_nonblocking(true)?;
+    socket.set_reuse_address(true)?;
+    #[cfg(all(unix, not(target_os = "solaris"), not(target_os = "illumos")))]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((std::net::Ipv4Addr::UNSPECIFIED, port)).into())?;
+    Ok(tokio::net::TcpSocket::from_std_stream(socket.into()))
+}
+
 pub fn reserve_buf(buf: &mut BytesMut, min_size: usize, max_size: usize) {
     if buf.capacity() < min_size {
         buf.reserve(max_size);
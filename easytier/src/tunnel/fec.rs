@@ -0,0 +1,611 @@
+// Forward error correction for lossy udp links.
+//
+// Data packets are sent in groups and each group is followed by parity shards
+// computed with Reed-Solomon over GF(2^8), so the receiver can rebuild up to
+// that many lost packets of a group without waiting for a retransmission.
+// With a single parity shard this is the same as xor parity.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    time::{Duration, Instant},
+};
+
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+use crate::common::stats_manager::{CounterHandle, LabelSet, MetricName, StatsManager};
+
+use super::TunnelError;
+
+pub const MAX_FEC_GROUP_SIZE: u8 = 64;
+pub const DEFAULT_FEC_GROUP_SIZE: u8 = 8;
+pub const DEFAULT_FEC_REDUNDANCY: f32 = 0.25;
+// a partial group is closed after this, so parity is not held back on an idle link
+pub const FEC_GROUP_TIMEOUT: Duration = Duration::from_millis(10);
+// groups this far behind the newest one are given up
+const FEC_DECODE_WINDOW: i32 = 8;
+
+/// group id (u32 le) and index in the group, appended to a data payload
+pub const FEC_DATA_TRAILER_SIZE: usize = 5;
+/// group id (u32 le), parity index, data count and parity count of the group
+pub const FEC_PARITY_TRAILER_SIZE: usize = 7;
+// every shard starts with the payload length so the padding can be removed
+const SHARD_LEN_PREFIX: usize = 2;
+
+/// Size of a fec group and the parity shards sent for a full group. It is
+/// set on the connector url and sent to the listener in the syn packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FecConfig {
+    pub group_size: u8,
+    pub parity_shards: u8,
+}
+
+impl FecConfig {
+    fn checked(group_size: u8, parity_shards: u8) -> Result<Self, String> {
+        if group_size == 0 || group_size > MAX_FEC_GROUP_SIZE {
+            return Err(format!(
+                "fec group size must be in 1..={}, got {}",
+                MAX_FEC_GROUP_SIZE, group_size
+            ));
+        }
+        if parity_shards == 0 || parity_shards > group_size {
+            return Err(format!(
+                "fec parity shards must be in 1..={}, got {}",
+                group_size, parity_shards
+            ));
+        }
+        Ok(Self {
+            group_size,
+            parity_shards,
+        })
+    }
+
+    /// Read `fec_group` and `fec_redundancy` from the query of a connector
+    /// url, e.g. `udp://1.2.3.4:11010?fec_group=10&fec_redundancy=0.2` sends 2
+    /// parity packets for every 10 data packets. None if fec is not asked for.
+    pub fn from_url(url: &url::Url) -> Result<Option<Self>, TunnelError> {
+        let mut group_size = None;
+        let mut redundancy = None;
+        for (k, v) in url.query_pairs() {
+            match k.as_ref() {
+                "fec_group" => group_size = Some(v.parse::<u8>().map_err(|_| invalid(&k, &v))?),
+                "fec_redundancy" => {
+                    redundancy = Some(v.parse::<f32>().map_err(|_| invalid(&k, &v))?)
+                }
+                _ => {}
+            }
+        }
+        if group_size.is_none() && redundancy.is_none() {
+            return Ok(None);
+        }
+
+        let group_size = group_size.unwrap_or(DEFAULT_FEC_GROUP_SIZE);
+        let redundancy = redundancy.unwrap_or(DEFAULT_FEC_REDUNDANCY);
+        if !(redundancy > 0.0 && redundancy <= 1.0) {
+            return Err(invalid("fec_redundancy", &redundancy.to_string()));
+        }
+        let parity_shards = (group_size as f32 * redundancy).ceil().max(1.0) as u8;
+        Self::checked(group_size, parity_shards)
+            .map(Some)
+            .map_err(TunnelError::InvalidAddr)
+    }
+
+    pub fn to_bytes(&self) -> [u8; 2] {
+        [self.group_size, self.parity_shards]
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, TunnelError> {
+        let [group_size, parity_shards] = buf
+            .try_into()
+            .map_err(|_| TunnelError::InvalidPacket("invalid fec config len".to_owned()))?;
+        Self::checked(group_size, parity_shards).map_err(TunnelError::InvalidPacket)
+    }
+
+    // a partial group gets about the same ratio of parity as a full one
+    fn parity_for(&self, data_count: usize) -> usize {
+        (self.parity_shards as usize * data_count)
+            .div_ceil(self.group_size as usize)
+            .max(1)
+    }
+}
+
+/// Recovered and unrecoverable packet counters of the fec decoders.
+#[derive(Clone, Default)]
+pub struct FecStats {
+    recovered: Option<CounterHandle>,
+    unrecoverable: Option<CounterHandle>,
+}
+
+impl FecStats {
+    pub fn new(stats_manager: &StatsManager, labels: LabelSet) -> Self {
+        Self {
+            recovered: Some(
+                stats_manager.get_counter(MetricName::FecPacketsRecovered, labels.clone()),
+            ),
+            unrecoverable: Some(
+                stats_manager.get_counter(MetricName::FecPacketsUnrecoverable, labels),
+            ),
+        }
+    }
+
+    fn add_recovered(&self, count: usize) {
+        if let Some(c) = &self.recovered {
+            c.add(count as u64);
+        }
+    }
+
+    fn add_unrecoverable(&self, count: usize) {
+        if let Some(c) = &self.unrecoverable {
+            c.add(count as u64);
+        }
+    }
+}
+
+impl std::fmt::Debug for FecStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FecStats")
+            .field("recovered", &self.recovered.as_ref().map(|c| c.get()))
+            .field(
+                "unrecoverable",
+                &self.unrecoverable.as_ref().map(|c| c.get()),
+            )
+            .finish()
+    }
+}
+
+// building a codec inverts a matrix, so they are kept per group shape
+#[derive(Default)]
+struct CodecCache(HashMap<(usize, usize), ReedSolomon>);
+
+impl CodecCache {
+    fn get(&mut self, data_shards: usize, parity_shards: usize) -> Option<&ReedSolomon> {
+        match self.0.entry((data_shards, parity_shards)) {
+            Entry::Occupied(e) => Some(&*e.into_mut()),
+            Entry::Vacant(e) => {
+                Some(&*e.insert(ReedSolomon::new(data_shards, parity_shards).ok()?))
+            }
+        }
+    }
+}
+
+fn invalid(key: &str, value: &str) -> TunnelError {
+    TunnelError::InvalidAddr(format!("invalid {}: {}", key, value))
+}
+
+fn to_shard(payload: &[u8]) -> Vec<u8> {
+    let mut shard = Vec::with_capacity(SHARD_LEN_PREFIX + payload.len());
+    shard.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+    shard.extend_from_slice(payload);
+    shard
+}
+
+fn from_shard(mut shard: Vec<u8>) -> Option<Vec<u8>> {
+    let len = u16::from_le_bytes(shard.get(..SHARD_LEN_PREFIX)?.try_into().unwrap()) as usize;
+    if SHARD_LEN_PREFIX + len > shard.len() {
+        return None;
+    }
+    shard.truncate(SHARD_LEN_PREFIX + len);
+    shard.drain(..SHARD_LEN_PREFIX);
+    Some(shard)
+}
+
+/// Groups the outgoing data packets of a connection and computes their parity.
+pub struct FecEncoder {
+    config: FecConfig,
+    group: u32,
+    shards: Vec<Vec<u8>>,
+    group_started: Option<Instant>,
+    codecs: CodecCache,
+}
+
+impl FecEncoder {
+    pub fn new(config: FecConfig) -> Self {
+        Self {
+            config,
+            group: 0,
+            shards: Vec::with_capacity(config.group_size as usize),
+            group_started: None,
+            codecs: CodecCache::default(),
+        }
+    }
+
+    /// Add a data payload to the current group, returns the trailer to append
+    /// to the packet.
+    pub fn add_data(&mut self, payload: &[u8]) -> [u8; FEC_DATA_TRAILER_SIZE] {
+        let index = self.shards.len() as u8;
+        self.shards.push(to_shard(payload));
+        self.group_started.get_or_insert_with(Instant::now);
+
+        let mut trailer = [0u8; FEC_DATA_TRAILER_SIZE];
+        trailer[..4].copy_from_slice(&self.group.to_le_bytes());
+        trailer[4] = index;
+        trailer
+    }
+
+    pub fn is_group_full(&self) -> bool {
+        self.shards.len() >= self.config.group_size as usize
+    }
+
+    /// When the current partial group should be closed.
+    pub fn flush_deadline(&self) -> Option<Instant> {
+        self.group_started.map(|t| t + FEC_GROUP_TIMEOUT)
+    }
+
+    /// Close the current group, returns the parity payloads with their
+    /// trailers.
+    pub fn finish_group(&mut self) -> Vec<Vec<u8>> {
+        let mut shards = std::mem::take(&mut self.shards);
+        let group = self.group;
+        self.group = self.group.wrapping_add(1);
+        self.group_started = None;
+        if shards.is_empty() {
+            return vec![];
+        }
+
+        let data_count = shards.len();
+        let parity_count = self.config.parity_for(data_count);
+        let shard_len = shards.iter().map(|s| s.len()).max().unwrap();
+        shards.iter_mut().for_each(|s| s.resize(shard_len, 0));
+        shards.resize(data_count + parity_count, vec![0u8; shard_len]);
+
+        let Some(codec) = self.codecs.get(data_count, parity_count) else {
+            return vec![];
+        };
+        if let Err(e) = codec.encode(&mut shards) {
+            tracing::warn!(?e, "fec encode error");
+            return vec![];
+        }
+
+        shards
+            .into_iter()
+            .skip(data_count)
+            .enumerate()
+            .map(|(index, mut shard)| {
+                shard.extend_from_slice(&group.to_le_bytes());
+                shard.extend_from_slice(&[index as u8, data_count as u8, parity_count as u8]);
+                shard
+            })
+            .collect()
+    }
+}
+
+struct FecGroup {
+    data: Vec<Option<Vec<u8>>>,
+    parity: Vec<Option<Vec<u8>>>,
+    // known once a parity shard arrived
+    data_count: Option<usize>,
+    parity_count: usize,
+    // bit i is set once data packet i was delivered, received or rebuilt
+    delivered: u64,
+    done: bool,
+}
+
+impl FecGroup {
+    fn new(config: &FecConfig) -> Self {
+        Self {
+            data: vec![None; config.group_size as usize],
+            parity: vec![None; config.parity_shards as usize],
+            data_count: None,
+            parity_count: 0,
+            delivered: 0,
+            done: false,
+        }
+    }
+
+    fn is_delivered(&self, index: usize) -> bool {
+        self.delivered & (1 << index) != 0
+    }
+
+    fn set_delivered(&mut self, index: usize) {
+        self.delivered |= 1 << index;
+    }
+
+    fn missing_data(&self) -> usize {
+        // without parity, assume the group ends at the last packet seen
+        let data_count = self.data_count.unwrap_or_else(|| {
+            self.data
+                .iter()
+                .rposition(|d| d.is_some())
+                .map_or(0, |i| i + 1)
+        });
+        self.data[..data_count]
+            .iter()
+            .filter(|d| d.is_none())
+            .count()
+    }
+}
+
+/// Rebuilds the lost data packets of a connection from the parity shards.
+pub struct FecDecoder {
+    config: FecConfig,
+    groups: HashMap<u32, FecGroup>,
+    newest: Option<u32>,
+    codecs: CodecCache,
+    stats: FecStats,
+}
+
+impl FecDecoder {
+    pub fn new(config: FecConfig, stats: FecStats) -> Self {
+        Self {
+            config,
+            groups: HashMap::new(),
+            newest: None,
+            codecs: CodecCache::default(),
+            stats,
+        }
+    }
+
+    fn group_mut(&mut self, group: u32) -> Option<&mut FecGroup> {
+        match self.newest {
+            Some(newest) if (newest.wrapping_sub(group) as i32) >= FEC_DECODE_WINDOW => {
+                return None;
+            }
+            Some(newest) if (group.wrapping_sub(newest) as i32) <= 0 => {}
+            _ => {
+                self.newest = Some(group);
+                let stats = &self.stats;
+                self.groups.retain(|id, g| {
+                    if (group.wrapping_sub(*id) as i32) < FEC_DECODE_WINDOW {
+                        return true;
+                    }
+                    if !g.done {
+                        stats.add_unrecoverable(g.missing_data());
+                    }
+                    false
+                });
+            }
+        }
+        let config = self.config;
+        Some(
+            self.groups
+                .entry(group)
+                .or_insert_with(|| FecGroup::new(&config)),
+        )
+    }
+
+    /// Handle a data payload with its trailer. Returns the length of the
+    /// payload without the trailer, or None if the packet was already
+    /// delivered (e.g. rebuilt from parity before it arrived), and adds the
+    /// packets it allowed to rebuild to `recovered`.
+    pub fn on_data(
+        &mut self,
+        payload: &[u8],
+        recovered: &mut Vec<Vec<u8>>,
+    ) -> Result<Option<usize>, TunnelError> {
+        let len = payload
+            .len()
+            .checked_sub(FEC_DATA_TRAILER_SIZE)
+            .ok_or_else(|| TunnelError::InvalidPacket("fec data trailer too short".to_owned()))?;
+        let trailer = &payload[len..];
+        let group = u32::from_le_bytes(trailer[..4].try_into().unwrap());
+        let index = trailer[4] as usize;
+        if index >= self.config.group_size as usize {
+            return Err(TunnelError::InvalidPacket(format!(
+                "invalid fec data index: {}",
+                index
+            )));
+        }
+
+        // groups behind the decode window are no longer tracked
+        let Some(g) = self.group_mut(group) else {
+            return Ok(Some(len));
+        };
+        if g.is_delivered(index) {
+            return Ok(None);
+        }
+        g.set_delivered(index);
+        if !g.done {
+            g.data[index] = Some(to_shard(&payload[..len]));
+            self.try_recover(group, recovered);
+        }
+        Ok(Some(len))
+    }
+
+    /// Handle a parity payload with its trailer, adds the packets it allowed
+    /// to rebuild to `recovered`.
+    pub fn on_parity(&mut self, payload: &[u8], recovered: &mut Vec<Vec<u8>>) {
+        let Some(len) = payload.len().checked_sub(FEC_PARITY_TRAILER_SIZE) else {
+            return;
+        };
+        let trailer = &payload[len..];
+        let group = u32::from_le_bytes(trailer[..4].try_into().unwrap());
+        let (index, data_count, parity_count) = (
+            trailer[4] as usize,
+            trailer[5] as usize,
+            trailer[6] as usize,
+        );
+        if data_count == 0
+            || data_count > self.config.group_size as usize
+            || parity_count > self.config.parity_shards as usize
+            || index >= parity_count
+        {
+            return;
+        }
+
+        let Some(g) = self.group_mut(group).filter(|g| !g.done) else {
+            return;
+        };
+        // all parity shards of a group have the same length
+        if g.parity.iter().flatten().any(|p| p.len() != len) {
+            return;
+        }
+        g.data_count = Some(data_count);
+        g.parity_count = parity_count;
+        if g.parity[index].is_none() {
+            g.parity[index] = Some(payload[..len].to_vec());
+            self.try_recover(group, recovered);
+        }
+    }
+
+    fn try_recover(&mut self, group: u32, recovered: &mut Vec<Vec<u8>>) {
+        let Some(g) = self.groups.get_mut(&group) else {
+            return;
+        };
+        let Some(data_count) = g.data_count else {
+            return;
+        };
+        let missing = g.missing_data();
+        if missing == 0 {
+            g.done = true;
+            return;
+        }
+        let parity = &g.parity[..g.parity_count];
+        if data_count - missing + parity.iter().flatten().count() < data_count {
+            return;
+        }
+
+        let shard_len = parity.iter().flatten().next().unwrap().len();
+        let mut shards = Vec::with_capacity(data_count + parity.len());
+        for d in g.data[..data_count].iter() {
+            match d {
+                Some(d) if d.len() <= shard_len => {
+                    let mut d = d.clone();
+                    d.resize(shard_len, 0);
+                    shards.push(Some(d));
+                }
+                Some(_) => {
+                    tracing::debug!(?group, "fec data shard longer than parity");
+                    g.done = true;
+                    self.stats.add_unrecoverable(missing);
+                    return;
+                }
+                None => shards.push(None),
+            }
+        }
+        shards.extend(parity.iter().cloned());
+
+        g.done = true;
+        let Some(codec) = self.codecs.get(data_count, g.parity_count) else {
+            self.stats.add_unrecoverable(missing);
+            return;
+        };
+        if let Err(e) = codec.reconstruct_data(&mut shards) {
+            tracing::debug!(?e, ?group, "fec reconstruct error");
+            self.stats.add_unrecoverable(missing);
+            return;
+        }
+
+        let mut count = 0;
+        for (i, shard) in shards.into_iter().take(data_count).enumerate() {
+            if g.data[i].is_some() {
+                continue;
+            }
+            if let Some(payload) = shard.and_then(from_shard) {
+                recovered.push(payload);
+                g.set_delivered(i);
+                count += 1;
+            }
+        }
+        self.stats.add_recovered(count);
+        self.stats.add_unrecoverable(missing - count);
+        // nothing else is needed from a finished group
+        g.data = vec![];
+        g.parity = vec![];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_group(encoder: &mut FecEncoder, count: usize) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+        let data = (0..count)
+            .map(|i| {
+                let mut payload = vec![i as u8; 100 + i * 7];
+                let trailer = encoder.add_data(&payload);
+                payload.extend_from_slice(&trailer);
+                payload
+            })
+            .collect::<Vec<_>>();
+        (data, encoder.finish_group())
+    }
+
+    #[test]
+    fn fec_config_from_url() {
+        let url = "udp://127.0.0.1:11010".parse().unwrap();
+        assert_eq!(None, FecConfig::from_url(&url).unwrap());
+
+        let url = "udp://127.0.0.1:11010?fec_group=10&fec_redundancy=0.2"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            Some(FecConfig::checked(10, 2).unwrap()),
+            FecConfig::from_url(&url).unwrap()
+        );
+
+        let url = "udp://127.0.0.1:11010?fec_group=3".parse().unwrap();
+        assert_eq!(1, FecConfig::from_url(&url).unwrap().unwrap().parity_shards);
+
+        let url = "udp://127.0.0.1:11010?fec_redundancy=2".parse().unwrap();
+        assert!(FecConfig::from_url(&url).is_err());
+        let url = "udp://127.0.0.1:11010?fec_group=200".parse().unwrap();
+        assert!(FecConfig::from_url(&url).is_err());
+
+        let config = FecConfig::checked(10, 2).unwrap();
+        assert_eq!(config, FecConfig::from_bytes(&config.to_bytes()).unwrap());
+        assert!(FecConfig::from_bytes(&[4, 5]).is_err());
+    }
+
+    #[tokio::test]
+    async fn fec_recover_lost_packets() {
+        let stats_manager = StatsManager::new();
+        let config = FecConfig::checked(8, 2).unwrap();
+        let mut encoder = FecEncoder::new(config);
+        let mut decoder = FecDecoder::new(config, FecStats::new(&stats_manager, LabelSet::new()));
+        let recovered_counter =
+            stats_manager.get_counter(MetricName::FecPacketsRecovered, LabelSet::new());
+        let unrecoverable_counter =
+            stats_manager.get_counter(MetricName::FecPacketsUnrecoverable, LabelSet::new());
+
+        // two lost packets are rebuilt from the parity
+        let (data, parity) = encode_group(&mut encoder, 8);
+        assert_eq!(2, parity.len());
+        let mut recovered = vec![];
+        for (i, d) in data.iter().enumerate() {
+            if i == 2 || i == 5 {
+                continue;
+            }
+            let len = decoder.on_data(d, &mut recovered).unwrap().unwrap();
+            assert_eq!(d.len() - FEC_DATA_TRAILER_SIZE, len);
+        }
+        for p in parity.iter() {
+            decoder.on_parity(p, &mut recovered);
+        }
+        assert_eq!(
+            vec![
+                data[2][..data[2].len() - FEC_DATA_TRAILER_SIZE].to_vec(),
+                data[5][..data[5].len() - FEC_DATA_TRAILER_SIZE].to_vec()
+            ],
+            recovered
+        );
+        assert_eq!(2, recovered_counter.get());
+        // late originals of rebuilt packets are not delivered twice
+        assert_eq!(None, decoder.on_data(&data[2], &mut recovered).unwrap());
+        assert_eq!(None, decoder.on_data(&data[0], &mut recovered).unwrap());
+        assert_eq!(2, recovered.len());
+
+        // a partial group closed by the timeout, parity arrives first
+        let (data, parity) = encode_group(&mut encoder, 3);
+        assert_eq!(1, parity.len());
+        let mut recovered = vec![];
+        decoder.on_parity(&parity[0], &mut recovered);
+        decoder.on_data(&data[0], &mut recovered).unwrap();
+        decoder.on_data(&data[2], &mut recovered).unwrap();
+        assert_eq!(1, recovered.len());
+        assert_eq!(3, recovered_counter.get());
+
+        // too many losses, counted once the group leaves the window
+        let (data, parity) = encode_group(&mut encoder, 8);
+        let mut recovered = vec![];
+        for d in data.iter().skip(3) {
+            decoder.on_data(d, &mut recovered).unwrap();
+        }
+        decoder.on_parity(&parity[0], &mut recovered);
+        assert!(recovered.is_empty());
+        for _ in 0..FEC_DECODE_WINDOW {
+            let (data, _) = encode_group(&mut encoder, 1);
+            decoder.on_data(&data[0], &mut recovered).unwrap();
+        }
+        assert_eq!(3, unrecoverable_counter.get());
+        assert_eq!(3, recovered_counter.get());
+    }
+}
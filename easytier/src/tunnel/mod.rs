@@ -15,9 +15,11 @@ use self::packet_def::ZCPacket;
 
 pub mod buf;
 pub mod common;
+pub mod fec;
 pub mod filter;
 pub mod mpsc;
 pub mod packet_def;
+pub mod proxy;
 pub mod ring;
 pub mod stats;
 pub mod tcp;
@@ -38,6 +40,9 @@ pub mod websocket;
 #[cfg(any(feature = "quic", feature = "websocket"))]
 pub mod insecure_tls;
 
+#[cfg(any(feature = "quic", feature = "websocket"))]
+pub mod tls;
+
 #[derive(thiserror::Error, Debug)]
 pub enum TunnelError {
     #[error("io error")]
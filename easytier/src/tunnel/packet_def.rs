@@ -28,8 +28,13 @@ pub enum UdpPacketType {
     Data = 3,
     Fin = 4,
     HolePunch = 5,
-    V6HolePunch = 6, // when receiving v6 hole punch packet, the packet contains a socket addr of other peer, we
-                     // will send a hole punch packet to that peer. we only accept this packet from lookback interface.
+    // when receiving v6 hole punch packet, the packet contains a socket addr of other peer, we
+    // will send a hole punch packet to that peer. we only accept this packet from lookback interface.
+    V6HolePunch = 6,
+    // data packet with a fec trailer, only sent if fec is negotiated in syn
+    FecData = 7,
+    // parity shard of a fec group
+    FecParity = 8,
 }
 
 #[repr(C, packed)]
@@ -82,6 +87,7 @@ bitflags::bitflags! {
         const NO_PROXY = 0b0000_1000;
         const COMPRESSED = 0b0001_0000;
         const KCP_SRC_MODIFIED = 0b0010_0000;
+        const SESSION_ENCRYPTED = 0b0100_0000;
 
         const _ = !0;
     }
@@ -95,7 +101,9 @@ pub struct PeerManagerHeader {
     pub packet_type: u8,
     pub flags: u8,
     pub forward_counter: u8,
-    reserved: u8,
+    // hash of the inner flow, set before the payload is encrypted so multipath
+    // can keep a flow on one connection. 0 if unknown.
+    pub flow_hint: u8,
     pub len: U32<DefaultEndian>,
 }
 pub const PEER_MANAGER_HEADER_SIZE: usize = std::mem::size_of::<PeerManagerHeader>();
@@ -141,6 +149,22 @@ impl PeerManagerHeader {
             .contains(PeerManagerHeaderFlags::COMPRESSED)
     }
 
+    pub fn is_session_encrypted(&self) -> bool {
+        PeerManagerHeaderFlags::from_bits(self.flags)
+            .unwrap()
+            .contains(PeerManagerHeaderFlags::SESSION_ENCRYPTED)
+    }
+
+    pub fn set_session_encrypted(&mut self, encrypted: bool) {
+        let mut flags = PeerManagerHeaderFlags::from_bits(self.flags).unwrap();
+        if encrypted {
+            flags.insert(PeerManagerHeaderFlags::SESSION_ENCRYPTED);
+        } else {
+            flags.remove(PeerManagerHeaderFlags::SESSION_ENCRYPTED);
+        }
+        self.flags = flags.bits();
+    }
+
     pub fn set_latency_first(&mut self, latency_first: bool) -> &mut Self {
         let mut flags = PeerManagerHeaderFlags::from_bits(self.flags).unwrap();
         if latency_first {
@@ -257,6 +281,9 @@ pub const AES_GCM_ENCRYPTION_RESERVED: usize = std::mem::size_of::<AesGcmTail>()
 pub enum CompressorAlgo {
     None = 0,
     ZstdDefault = 1,
+    Lz4 = 2,
+    // zstd with a trained dictionary, the dictionary id is in the zstd frame
+    ZstdDict = 3,
 }
 
 #[repr(C, packed)]
@@ -270,6 +297,8 @@ impl CompressorTail {
     pub fn get_algo(&self) -> Option<CompressorAlgo> {
         match self.algo {
             1 => Some(CompressorAlgo::ZstdDefault),
+            2 => Some(CompressorAlgo::Lz4),
+            3 => Some(CompressorAlgo::ZstdDict),
             _ => None,
         }
     }
@@ -578,6 +607,7 @@ impl ZCPacket {
         hdr.packet_type = packet_type;
         hdr.flags = 0;
         hdr.forward_counter = 1;
+        hdr.flow_hint = 0;
         hdr.len.set(payload_len as u32);
     }
 
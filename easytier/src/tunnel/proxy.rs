@@ -0,0 +1,608 @@
+//! Dial tcp based tunnels through an upstream http CONNECT or socks5 proxy.
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{lookup_host, TcpStream},
+};
+
+use super::{default_port, TunnelError};
+
+const MAX_HTTP_RESPONSE_HEADER_LEN: usize = 8192;
+
+const SOCKS5_VERSION: u8 = 5;
+const SOCKS5_AUTH_NONE: u8 = 0;
+const SOCKS5_AUTH_PASSWORD: u8 = 2;
+const SOCKS5_CMD_CONNECT: u8 = 1;
+const SOCKS5_ATYP_IPV4: u8 = 1;
+const SOCKS5_ATYP_DOMAIN: u8 = 3;
+const SOCKS5_ATYP_IPV6: u8 = 4;
+
+/// Checks that `proxy` is an url this module can dial through.
+pub fn check_proxy_url(proxy: &url::Url) -> Result<(), TunnelError> {
+    if !matches!(proxy.scheme(), "http" | "socks5" | "socks5h") {
+        return Err(TunnelError::InvalidProtocol(format!(
+            "unsupported proxy scheme: {}, expect http, socks5 or socks5h",
+            proxy.scheme()
+        )));
+    }
+    if proxy.host_str().is_none() {
+        return Err(TunnelError::InvalidAddr(format!(
+            "proxy host is missing: {}",
+            proxy
+        )));
+    }
+    Ok(())
+}
+
+/// Proxy for `target` given by the `HTTPS_PROXY` / `ALL_PROXY` environment
+/// variables, unless the target host is listed in `NO_PROXY`.
+pub fn proxy_from_env(target: &url::Url) -> Option<url::Url> {
+    proxy_from_vars(target, |name| {
+        std::env::var(name)
+            .or_else(|_| std::env::var(name.to_lowercase()))
+            .ok()
+            .filter(|v| !v.is_empty())
+    })
+}
+
+fn proxy_from_vars(
+    target: &url::Url,
+    get_var: impl Fn(&str) -> Option<String>,
+) -> Option<url::Url> {
+    let host = target
+        .host_str()?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    if let Some(no_proxy) = get_var("NO_PROXY") {
+        if no_proxy.split(',').map(str::trim).any(|pattern| {
+            pattern == "*"
+                || (!pattern.is_empty() && {
+                    let pattern = pattern.trim_start_matches('*').trim_start_matches('.');
+                    host == pattern || host.ends_with(&format!(".{}", pattern))
+                })
+        }) {
+            return None;
+        }
+    }
+
+    let proxy = get_var("HTTPS_PROXY").or_else(|| get_var("ALL_PROXY"))?;
+    // curl accepts proxies without a scheme and treats them as http
+    let proxy = if proxy.contains("://") {
+        proxy
+    } else {
+        format!("http://{}", proxy)
+    };
+    match url::Url::parse(&proxy) {
+        Ok(proxy) if check_proxy_url(&proxy).is_ok() => Some(proxy),
+        _ => {
+            tracing::warn!(?proxy, "ignore invalid proxy in environment");
+            None
+        }
+    }
+}
+
+fn decoded(s: &str) -> String {
+    percent_encoding::percent_decode_str(s)
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+fn credentials(proxy: &url::Url) -> Option<(String, String)> {
+    if proxy.username().is_empty() {
+        return None;
+    }
+    Some((
+        decoded(proxy.username()),
+        decoded(proxy.password().unwrap_or_default()),
+    ))
+}
+
+fn target_host_port(target: &url::Url) -> Result<(url::Host<&str>, u16), TunnelError> {
+    let host = target.host().ok_or(TunnelError::InvalidAddr(format!(
+        "host is missing: {}",
+        target
+    )))?;
+    let port = target
+        .port()
+        .or(default_port(target.scheme()))
+        .ok_or(TunnelError::InvalidAddr(format!(
+            "port is missing: {}",
+            target
+        )))?;
+    Ok((host, port))
+}
+
+/// Opens a tcp stream to the host and port of `target` through `proxy`. The
+/// target host name is resolved by http and socks5h proxies, and locally for
+/// socks5 proxies.
+pub async fn connect_through_proxy(
+    proxy: &url::Url,
+    target: &url::Url,
+) -> Result<TcpStream, TunnelError> {
+    check_proxy_url(proxy)?;
+    let (host, port) = target_host_port(target)?;
+    let proxy_port = match proxy.scheme() {
+        "http" => proxy.port_or_known_default().unwrap_or(80),
+        _ => proxy.port().unwrap_or(1080),
+    };
+
+    let mut stream = match proxy.host() {
+        Some(url::Host::Ipv4(ip)) => TcpStream::connect((ip, proxy_port)).await?,
+        Some(url::Host::Ipv6(ip)) => TcpStream::connect((ip, proxy_port)).await?,
+        Some(url::Host::Domain(domain)) => TcpStream::connect((domain, proxy_port)).await?,
+        None => unreachable!("checked by check_proxy_url"),
+    };
+    tracing::debug!(%proxy, %target, "connected to proxy");
+    if proxy.scheme() == "http" {
+        http_connect(&mut stream, proxy, host, port).await?;
+    } else {
+        let host = socks5_target_host(proxy, host, port).await?;
+        socks5_connect(&mut stream, proxy, host, port).await?;
+    }
+    tracing::info!(%proxy, %target, "proxy tunnel established");
+    Ok(stream)
+}
+
+async fn http_connect(
+    stream: &mut TcpStream,
+    proxy: &url::Url,
+    host: url::Host<&str>,
+    port: u16,
+) -> Result<(), TunnelError> {
+    // url::Host displays ipv6 addresses in brackets, as an authority wants
+    let authority = format!("{}:{}", host, port);
+    let mut req = format!(
+        "CONNECT {0} HTTP/1.1\r\nHost: {0}\r\nProxy-Connection: Keep-Alive\r\n",
+        authority
+    );
+    if let Some((user, password)) = credentials(proxy) {
+        let token = BASE64_STANDARD.encode(format!("{}:{}", user, password));
+        req.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+    }
+    req.push_str("\r\n");
+    stream.write_all(req.as_bytes()).await?;
+
+    // read byte by byte so nothing after the header is taken from the tunnel
+    let mut resp = Vec::with_capacity(256);
+    while !resp.ends_with(b"\r\n\r\n") {
+        if resp.len() >= MAX_HTTP_RESPONSE_HEADER_LEN {
+            return Err(TunnelError::InvalidPacket(
+                "http proxy response header too long".to_owned(),
+            ));
+        }
+        resp.push(stream.read_u8().await?);
+    }
+
+    let resp = String::from_utf8_lossy(&resp);
+    let status_line = resp.lines().next().unwrap_or_default();
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse::<u16>().ok());
+    match status {
+        Some(200..=299) => Ok(()),
+        _ => Err(TunnelError::InternalError(format!(
+            "http proxy refused CONNECT to {}: {}",
+            authority, status_line
+        ))),
+    }
+}
+
+// socks5 proxies get an address, socks5h proxies resolve the host themselves
+async fn socks5_target_host<'a>(
+    proxy: &url::Url,
+    host: url::Host<&'a str>,
+    port: u16,
+) -> Result<url::Host<&'a str>, TunnelError> {
+    let url::Host::Domain(domain) = host else {
+        return Ok(host);
+    };
+    if proxy.scheme() == "socks5h" {
+        return Ok(host);
+    }
+    let addr = lookup_host((domain, port))
+        .await?
+        .next()
+        .ok_or(TunnelError::InvalidAddr(format!(
+            "can not resolve {}",
+            domain
+        )))?;
+    Ok(match addr.ip() {
+        std::net::IpAddr::V4(ip) => url::Host::Ipv4(ip),
+        std::net::IpAddr::V6(ip) => url::Host::Ipv6(ip),
+    })
+}
+
+async fn socks5_connect(
+    stream: &mut TcpStream,
+    proxy: &url::Url,
+    host: url::Host<&str>,
+    port: u16,
+) -> Result<(), TunnelError> {
+    let credentials = credentials(proxy);
+    if credentials.is_some() {
+        stream
+            .write_all(&[SOCKS5_VERSION, 2, SOCKS5_AUTH_NONE, SOCKS5_AUTH_PASSWORD])
+            .await?;
+    } else {
+        stream
+            .write_all(&[SOCKS5_VERSION, 1, SOCKS5_AUTH_NONE])
+            .await?;
+    }
+
+    let mut buf = [0u8; 2];
+    stream.read_exact(&mut buf).await?;
+    if buf[0] != SOCKS5_VERSION {
+        return Err(TunnelError::InvalidProtocol(format!(
+            "socks5 proxy answered with version {}",
+            buf[0]
+        )));
+    }
+    match (buf[1], credentials) {
+        (SOCKS5_AUTH_NONE, _) => {}
+        (SOCKS5_AUTH_PASSWORD, Some((user, password))) => {
+            if user.len() > 255 || password.len() > 255 {
+                return Err(TunnelError::InvalidAddr(
+                    "socks5 user name or password too long".to_owned(),
+                ));
+            }
+            // rfc 1929 sub negotiation
+            let mut req = vec![1, user.len() as u8];
+            req.extend_from_slice(user.as_bytes());
+            req.push(password.len() as u8);
+            req.extend_from_slice(password.as_bytes());
+            stream.write_all(&req).await?;
+
+            stream.read_exact(&mut buf).await?;
+            if buf[1] != 0 {
+                return Err(TunnelError::InternalError(
+                    "socks5 proxy rejected the credentials".to_owned(),
+                ));
+            }
+        }
+        _ => {
+            return Err(TunnelError::InternalError(
+                "socks5 proxy accepts none of the offered auth methods".to_owned(),
+            ));
+        }
+    }
+
+    let mut req = vec![SOCKS5_VERSION, SOCKS5_CMD_CONNECT, 0];
+    match host {
+        url::Host::Ipv4(ip) => {
+            req.push(SOCKS5_ATYP_IPV4);
+            req.extend_from_slice(&ip.octets());
+        }
+        url::Host::Ipv6(ip) => {
+            req.push(SOCKS5_ATYP_IPV6);
+            req.extend_from_slice(&ip.octets());
+        }
+        url::Host::Domain(domain) => {
+            if domain.len() > 255 {
+                return Err(TunnelError::InvalidAddr(format!(
+                    "domain too long for socks5: {}",
+                    domain
+                )));
+            }
+            req.push(SOCKS5_ATYP_DOMAIN);
+            req.push(domain.len() as u8);
+            req.extend_from_slice(domain.as_bytes());
+        }
+    }
+    req.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&req).await?;
+
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    if head[1] != 0 {
+        return Err(TunnelError::InternalError(format!(
+            "socks5 proxy connect failed, reply code: {}",
+            head[1]
+        )));
+    }
+    // skip the bound address
+    let addr_len = match head[3] {
+        SOCKS5_ATYP_IPV4 => 4,
+        SOCKS5_ATYP_IPV6 => 16,
+        SOCKS5_ATYP_DOMAIN => stream.read_u8().await? as usize,
+        atyp => {
+            return Err(TunnelError::InvalidPacket(format!(
+                "socks5 reply with unknown address type {}",
+                atyp
+            )))
+        }
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::{collections::HashMap, net::Ipv4Addr};
+
+    use tokio::{
+        io::{AsyncBufReadExt, BufReader},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    const SOCKS5_AUTH_NO_ACCEPTABLE: u8 = 0xff;
+
+    async fn relay(mut client: TcpStream, target: String) {
+        let mut server = TcpStream::connect(target).await.unwrap();
+        let _ = tokio::io::copy_bidirectional(&mut client, &mut server).await;
+    }
+
+    /// Http proxy answering CONNECT, requiring `auth` as basic credentials if
+    /// given. Returns the proxy url.
+    pub async fn start_http_proxy(auth: Option<&str>) -> url::Url {
+        start_http_proxy_on("127.0.0.1:0", auth).await
+    }
+
+    async fn start_http_proxy_on(bind: &str, auth: Option<&str>) -> url::Url {
+        let listener = TcpListener::bind(bind).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let expected = auth.map(|a| format!("Basic {}", BASE64_STANDARD.encode(a)));
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let expected = expected.clone();
+                tokio::spawn(async move {
+                    let mut reader = BufReader::new(stream);
+                    let mut headers = HashMap::new();
+                    let mut line = String::new();
+                    reader.read_line(&mut line).await.unwrap();
+                    let target = line.split_whitespace().nth(1).unwrap().to_owned();
+                    loop {
+                        let mut header = String::new();
+                        reader.read_line(&mut header).await.unwrap();
+                        let Some((k, v)) = header.trim_end().split_once(": ") else {
+                            break;
+                        };
+                        headers.insert(k.to_owned(), v.to_owned());
+                    }
+                    let mut stream = reader.into_inner();
+                    if expected.is_some() && headers.get("Proxy-Authorization") != expected.as_ref()
+                    {
+                        let _ = stream
+                            .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                            .await;
+                        return;
+                    }
+                    stream
+                        .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                        .await
+                        .unwrap();
+                    relay(stream, target).await;
+                });
+            }
+        });
+        format!("http://{}", addr).parse().unwrap()
+    }
+
+    /// Socks5 proxy supporting the no auth and user/password methods.
+    pub async fn start_socks5_proxy(auth: Option<(&'static str, &'static str)>) -> url::Url {
+        start_socks5_proxy_on("127.0.0.1:0", auth).await
+    }
+
+    async fn start_socks5_proxy_on(
+        bind: &str,
+        auth: Option<(&'static str, &'static str)>,
+    ) -> url::Url {
+        let listener = TcpListener::bind(bind).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut head = [0u8; 2];
+                    stream.read_exact(&mut head).await.unwrap();
+                    let mut methods = vec![0u8; head[1] as usize];
+                    stream.read_exact(&mut methods).await.unwrap();
+                    if let Some((user, password)) = auth {
+                        if !methods.contains(&SOCKS5_AUTH_PASSWORD) {
+                            let _ = stream
+                                .write_all(&[SOCKS5_VERSION, SOCKS5_AUTH_NO_ACCEPTABLE])
+                                .await;
+                            return;
+                        }
+                        stream
+                            .write_all(&[SOCKS5_VERSION, SOCKS5_AUTH_PASSWORD])
+                            .await
+                            .unwrap();
+                        let mut ver_len = [0u8; 2];
+                        stream.read_exact(&mut ver_len).await.unwrap();
+                        let mut u = vec![0u8; ver_len[1] as usize];
+                        stream.read_exact(&mut u).await.unwrap();
+                        let mut p = vec![0u8; stream.read_u8().await.unwrap() as usize];
+                        stream.read_exact(&mut p).await.unwrap();
+                        let ok = u == user.as_bytes() && p == password.as_bytes();
+                        stream.write_all(&[1, u8::from(!ok)]).await.unwrap();
+                        if !ok {
+                            return;
+                        }
+                    } else {
+                        stream
+                            .write_all(&[SOCKS5_VERSION, SOCKS5_AUTH_NONE])
+                            .await
+                            .unwrap();
+                    }
+
+                    let mut req = [0u8; 4];
+                    stream.read_exact(&mut req).await.unwrap();
+                    let host = match req[3] {
+                        SOCKS5_ATYP_IPV4 => {
+                            let mut ip = [0u8; 4];
+                            stream.read_exact(&mut ip).await.unwrap();
+                            Ipv4Addr::from(ip).to_string()
+                        }
+                        SOCKS5_ATYP_DOMAIN => {
+                            let mut domain = vec![0u8; stream.read_u8().await.unwrap() as usize];
+                            stream.read_exact(&mut domain).await.unwrap();
+                            String::from_utf8(domain).unwrap()
+                        }
+                        _ => unreachable!(),
+                    };
+                    let port = stream.read_u16().await.unwrap();
+                    stream
+                        .write_all(&[SOCKS5_VERSION, 0, 0, SOCKS5_ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+                        .await
+                        .unwrap();
+                    relay(stream, format!("{}:{}", host, port)).await;
+                });
+            }
+        });
+        format!("socks5h://{}", addr).parse().unwrap()
+    }
+
+    async fn echo_server() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut r, mut w) = stream.split();
+                    let _ = tokio::io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+        port
+    }
+
+    async fn assert_echo(mut stream: TcpStream) {
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"hello", &buf);
+    }
+
+    #[tokio::test]
+    async fn http_connect_proxy() {
+        let target: url::Url = format!("tcp://127.0.0.1:{}", echo_server().await)
+            .parse()
+            .unwrap();
+
+        let proxy = start_http_proxy(None).await;
+        assert_echo(connect_through_proxy(&proxy, &target).await.unwrap()).await;
+
+        let mut proxy = start_http_proxy(Some("user:p@ss")).await;
+        assert!(connect_through_proxy(&proxy, &target).await.is_err());
+        proxy.set_username("user").unwrap();
+        proxy.set_password(Some("p@ss")).unwrap();
+        assert_echo(connect_through_proxy(&proxy, &target).await.unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn socks5_proxy() {
+        let port = echo_server().await;
+        let proxy = start_socks5_proxy(None).await;
+        for target in [
+            format!("tcp://127.0.0.1:{}", port),
+            format!("tcp://localhost:{}", port),
+        ] {
+            let target = target.parse().unwrap();
+            assert_echo(connect_through_proxy(&proxy, &target).await.unwrap()).await;
+        }
+
+        let target = format!("tcp://127.0.0.1:{}", port).parse().unwrap();
+        let mut proxy = start_socks5_proxy(Some(("user", "secret"))).await;
+        assert!(connect_through_proxy(&proxy, &target).await.is_err());
+        proxy.set_username("user").unwrap();
+        proxy.set_password(Some("wrong")).unwrap();
+        assert!(connect_through_proxy(&proxy, &target).await.is_err());
+        proxy.set_password(Some("secret")).unwrap();
+        assert_echo(connect_through_proxy(&proxy, &target).await.unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn ipv6_proxy() {
+        let target: url::Url = format!("tcp://127.0.0.1:{}", echo_server().await)
+            .parse()
+            .unwrap();
+        for proxy in [
+            start_http_proxy_on("[::1]:0", None).await,
+            start_socks5_proxy_on("[::1]:0", None).await,
+        ] {
+            assert_eq!(Some("[::1]"), proxy.host_str());
+            assert_echo(connect_through_proxy(&proxy, &target).await.unwrap()).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn socks5_resolves_locally() {
+        let socks5: url::Url = "socks5://127.0.0.1:1080".parse().unwrap();
+        let socks5h: url::Url = "socks5h://127.0.0.1:1080".parse().unwrap();
+        let localhost = url::Host::Domain("localhost");
+
+        let host = socks5_target_host(&socks5, localhost.clone(), 80)
+            .await
+            .unwrap();
+        assert!(
+            matches!(host, url::Host::Ipv4(ip) if ip.is_loopback())
+                || matches!(host, url::Host::Ipv6(ip) if ip.is_loopback()),
+            "{:?}",
+            host
+        );
+        assert_eq!(
+            localhost,
+            socks5_target_host(&socks5h, localhost.clone(), 80)
+                .await
+                .unwrap()
+        );
+        let ip = url::Host::Ipv4(Ipv4Addr::LOCALHOST);
+        assert_eq!(
+            ip,
+            socks5_target_host(&socks5, ip.clone(), 80).await.unwrap()
+        );
+    }
+
+    fn vars(vars: Vec<(&'static str, &'static str)>) -> impl Fn(&str) -> Option<String> {
+        move |name| {
+            vars.iter()
+                .find(|(k, _)| *k == name)
+                .map(|(_, v)| v.to_string())
+        }
+    }
+
+    #[test]
+    fn proxy_from_environment() {
+        let target: url::Url = "wss://public.example.com:11012".parse().unwrap();
+
+        assert_eq!(None, proxy_from_vars(&target, vars(vec![])));
+        assert_eq!(
+            Some("http://proxy:3128".parse().unwrap()),
+            proxy_from_vars(
+                &target,
+                vars(vec![
+                    ("HTTPS_PROXY", "proxy:3128"),
+                    ("ALL_PROXY", "socks5://other:1080")
+                ])
+            )
+        );
+        assert_eq!(
+            Some("socks5://other:1080".parse().unwrap()),
+            proxy_from_vars(&target, vars(vec![("ALL_PROXY", "socks5://other:1080")]))
+        );
+        assert_eq!(
+            None,
+            proxy_from_vars(&target, vars(vec![("HTTPS_PROXY", "ftp://proxy:21")]))
+        );
+
+        for no_proxy in ["example.com", ".example.com", "*.example.com", "a, *"] {
+            let v = vars(vec![
+                ("HTTPS_PROXY", "http://proxy:3128"),
+                ("NO_PROXY", no_proxy),
+            ]);
+            assert_eq!(None, proxy_from_vars(&target, v), "{}", no_proxy);
+        }
+        let v = vars(vec![
+            ("HTTPS_PROXY", "http://proxy:3128"),
+            ("NO_PROXY", "ample.com,localhost"),
+        ]);
+        assert!(proxy_from_vars(&target, v).is_some());
+    }
+}
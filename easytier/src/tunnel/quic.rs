@@ -3,7 +3,14 @@
 //! Checkout the `README.md` for guidance.
 
 use std::{
-    error::Error, io::IoSliceMut, net::SocketAddr, pin::Pin, sync::Arc, task::Poll, time::Duration,
+    error::Error,
+    io::IoSliceMut,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::Poll,
+    time::Duration,
 };
 
 use crate::tunnel::{
@@ -13,18 +20,23 @@ use crate::tunnel::{
 use anyhow::Context;
 
 use quinn::{
-    congestion::BbrConfig, crypto::rustls::QuicClientConfig, udp::RecvMeta, AsyncUdpSocket,
-    ClientConfig, Connection, Endpoint, EndpointConfig, ServerConfig, TransportConfig, UdpPoller,
+    congestion::BbrConfig,
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+    udp::RecvMeta,
+    AsyncUdpSocket, ClientConfig, Connection, Endpoint, EndpointConfig, ServerConfig,
+    TransportConfig, UdpPoller,
 };
 
 use super::{
     check_scheme_and_get_socket_addr,
-    insecure_tls::{get_insecure_tls_cert, get_insecure_tls_client_config},
+    tls::{get_client_config, get_server_config, ServerVerification},
     IpVersion, Tunnel, TunnelConnector, TunnelError, TunnelListener,
 };
 
-pub fn configure_client() -> ClientConfig {
-    let client_crypto = QuicClientConfig::try_from(get_insecure_tls_client_config()).unwrap();
+/// Client configuration verifying the server as given by `verification`.
+pub fn configure_client(verification: &ServerVerification) -> Result<ClientConfig, TunnelError> {
+    let client_crypto = QuicClientConfig::try_from(get_client_config(verification, None, None)?)
+        .with_context(|| "failed to create quic client config")?;
     let mut client_config = ClientConfig::new(Arc::new(client_crypto));
 
     // // Create a new TransportConfig and set BBR
@@ -34,7 +46,7 @@ pub fn configure_client() -> ClientConfig {
     // Replace the default TransportConfig with the transport_config() method
     client_config.transport_config(Arc::new(transport_config));
 
-    client_config
+    Ok(client_config)
 }
 
 #[derive(Clone, Debug)]
@@ -79,16 +91,16 @@ impl AsyncUdpSocket for NoGroAsyncUdpSocket {
     }
 }
 
-/// Constructs a QUIC endpoint configured to listen for incoming connections on a certain address
-/// and port.
-///
-/// ## Returns
-///
-/// - a stream of incoming QUIC connections
-/// - server certificate serialized into DER format
-#[allow(unused)]
-pub fn make_server_endpoint(bind_addr: SocketAddr) -> Result<(Endpoint, Vec<u8>), Box<dyn Error>> {
-    let (server_config, server_cert) = configure_server()?;
+/// Constructs a QUIC endpoint with a self signed certificate configured to listen for incoming
+/// connections on a certain address and port.
+pub fn make_server_endpoint(bind_addr: SocketAddr) -> Result<Endpoint, Box<dyn Error>> {
+    make_server_endpoint_with_config(bind_addr, configure_server(None)?)
+}
+
+fn make_server_endpoint_with_config(
+    bind_addr: SocketAddr,
+    server_config: ServerConfig,
+) -> Result<Endpoint, Box<dyn Error>> {
     let socket = std::net::UdpSocket::bind(bind_addr)?;
     let runtime =
         quinn::default_runtime().ok_or_else(|| std::io::Error::other("no async runtime found"))?;
@@ -103,21 +115,24 @@ pub fn make_server_endpoint(bind_addr: SocketAddr) -> Result<(Endpoint, Vec<u8>)
         Arc::new(socket),
         runtime,
     )?;
-    Ok((endpoint, server_cert))
+    Ok(endpoint)
 }
 
-/// Returns default server configuration along with its certificate.
-pub fn configure_server() -> Result<(ServerConfig, Vec<u8>), Box<dyn Error>> {
-    let (certs, key) = get_insecure_tls_cert();
-
-    let mut server_config = ServerConfig::with_single_cert(certs.clone(), key)?;
+/// Server configuration presenting the certificate chain and key in the pem
+/// files of `cert_files`, reloaded when they are renewed, or a self signed
+/// certificate if not given.
+pub fn configure_server(
+    cert_files: Option<(&Path, &Path)>,
+) -> Result<ServerConfig, Box<dyn Error>> {
+    let tls_config = get_server_config(cert_files, None)?;
+    let mut server_config =
+        ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls_config)?));
     let transport_config = Arc::get_mut(&mut server_config.transport).unwrap();
     transport_config.max_concurrent_uni_streams(10_u8.into());
     transport_config.max_concurrent_bidi_streams(10_u8.into());
     // Setting BBR congestion control
     transport_config.congestion_controller_factory(Arc::new(BbrConfig::default()));
-
-    Ok((server_config, certs[0].to_vec()))
+    Ok(server_config)
 }
 
 #[allow(unused)]
@@ -136,7 +151,7 @@ impl Drop for ConnWrapper {
 pub struct QUICTunnelListener {
     addr: url::Url,
     endpoint: Option<Endpoint>,
-    server_cert: Option<Vec<u8>>,
+    cert_files: Option<(PathBuf, PathBuf)>,
 }
 
 impl QUICTunnelListener {
@@ -144,9 +159,15 @@ impl QUICTunnelListener {
         QUICTunnelListener {
             addr,
             endpoint: None,
-            server_cert: None,
+            cert_files: None,
         }
     }
+
+    /// Serve the certificate chain and key in these pem files instead of a
+    /// self signed certificate.
+    pub fn set_cert_files(&mut self, cert_file: PathBuf, key_file: PathBuf) {
+        self.cert_files = Some((cert_file, key_file));
+    }
 }
 
 #[async_trait::async_trait]
@@ -155,9 +176,16 @@ impl TunnelListener for QUICTunnelListener {
         let addr =
             check_scheme_and_get_socket_addr::<SocketAddr>(&self.addr, "quic", IpVersion::Both)
                 .await?;
-        let (endpoint, server_cert) = make_server_endpoint(addr).unwrap();
+        let server_config = configure_server(
+            self.cert_files
+                .as_ref()
+                .map(|(cert, key)| (cert.as_path(), key.as_path())),
+        )
+        .map_err(|e| TunnelError::InternalError(format!("invalid quic server config: {}", e)))?;
+        let endpoint = make_server_endpoint_with_config(addr, server_config).map_err(|e| {
+            TunnelError::InternalError(format!("failed to create quic endpoint: {}", e))
+        })?;
         self.endpoint = Some(endpoint);
-        self.server_cert = Some(server_cert);
 
         self.addr
             .set_port(Some(self.endpoint.as_ref().unwrap().local_addr()?.port()))
@@ -216,6 +244,7 @@ pub struct QUICTunnelConnector {
     addr: url::Url,
     endpoint: Option<Endpoint>,
     ip_version: IpVersion,
+    server_verification: ServerVerification,
 }
 
 impl QUICTunnelConnector {
@@ -224,6 +253,23 @@ impl QUICTunnelConnector {
             addr,
             endpoint: None,
             ip_version: IpVersion::Both,
+            server_verification: ServerVerification::Insecure,
+        }
+    }
+
+    pub fn set_server_verification(&mut self, verification: ServerVerification) {
+        self.server_verification = verification;
+    }
+
+    fn server_name(&self) -> String {
+        if self.server_verification.is_insecure() {
+            return "localhost".to_owned();
+        }
+        match self.addr.host() {
+            Some(url::Host::Domain(domain)) => domain.to_owned(),
+            Some(url::Host::Ipv4(ip)) => ip.to_string(),
+            Some(url::Host::Ipv6(ip)) => ip.to_string(),
+            None => "localhost".to_owned(),
         }
     }
 }
@@ -241,12 +287,12 @@ impl TunnelConnector for QUICTunnelConnector {
         };
 
         let mut endpoint = Endpoint::client(local_addr.parse().unwrap())?;
-        endpoint.set_default_client_config(configure_client());
+        endpoint.set_default_client_config(configure_client(&self.server_verification)?);
 
         // connect to server
         let connection = endpoint
-            .connect(addr, "localhost")
-            .unwrap()
+            .connect(addr, &self.server_name())
+            .with_context(|| "invalid quic server name")?
             .await
             .with_context(|| "connect failed")?;
         tracing::info!("[client] connected: addr={}", connection.remote_address());
@@ -289,6 +335,7 @@ impl TunnelConnector for QUICTunnelConnector {
 mod tests {
     use crate::tunnel::{
         common::tests::{_tunnel_bench, _tunnel_pingpong},
+        tls::tests::TestPki,
         IpVersion,
     };
 
@@ -301,6 +348,28 @@ mod tests {
         _tunnel_pingpong(listener, connector).await
     }
 
+    #[tokio::test]
+    async fn quic_pingpong_with_cert_files() {
+        let pki = TestPki::generate();
+        let (cert, key) = pki.issue("server", &["127.0.0.1"]);
+        let mut listener = QUICTunnelListener::new("quic://0.0.0.0:21013".parse().unwrap());
+        listener.set_cert_files(cert.into(), key.into());
+        let mut connector = QUICTunnelConnector::new("quic://127.0.0.1:21013".parse().unwrap());
+        connector.set_server_verification(ServerVerification::Ca(pki.path("ca.pem").into()));
+        _tunnel_pingpong(listener, connector).await;
+
+        // the self signed certificate fails verification
+        let mut listener = QUICTunnelListener::new("quic://0.0.0.0:21014".parse().unwrap());
+        listener.listen().await.unwrap();
+        let mut connector = QUICTunnelConnector::new("quic://127.0.0.1:21014".parse().unwrap());
+        connector.set_server_verification(ServerVerification::Ca(pki.path("ca.pem").into()));
+        let j = tokio::spawn(async move {
+            let _ = listener.accept().await;
+        });
+        connector.connect().await.unwrap_err();
+        j.abort();
+    }
+
     #[tokio::test]
     async fn quic_bench() {
         let listener = QUICTunnelListener::new("quic://0.0.0.0:21012".parse().unwrap());
@@ -59,6 +59,20 @@ impl WindowLatency {
             (T::from(sum)) / T::from(count)
         }
     }
+
+    /// Mean absolute deviation of the samples in the window.
+    pub fn get_jitter_us(&self) -> u32 {
+        let count = self.count.load(Relaxed);
+        if count == 0 {
+            return 0;
+        }
+        let mean: u32 = self.get_latency_us();
+        let deviation: u64 = self.latency_us_window[..count as usize]
+            .iter()
+            .map(|x| x.load(Relaxed).abs_diff(mean) as u64)
+            .sum();
+        (deviation / count as u64) as u32
+    }
 }
 
 #[derive(Debug)]
@@ -10,6 +10,7 @@ use crate::tunnel::common::setup_sokcet2;
 use super::{
     check_scheme_and_get_socket_addr,
     common::{wait_for_connect_futures, FramedReader, FramedWriter, TunnelWrapper},
+    proxy::connect_through_proxy,
     IpVersion, Tunnel, TunnelError, TunnelListener,
 };
 
@@ -107,7 +108,7 @@ impl TunnelListener for TcpTunnelListener {
     }
 }
 
-fn get_tunnel_with_tcp_stream(
+pub(crate) fn get_tunnel_with_tcp_stream(
     stream: TcpStream,
     remote_url: url::Url,
 ) -> Result<Box<dyn Tunnel>, super::TunnelError> {
@@ -137,6 +138,7 @@ pub struct TcpTunnelConnector {
 
     bind_addrs: Vec<SocketAddr>,
     ip_version: IpVersion,
+    proxy: Option<url::Url>,
 }
 
 impl TcpTunnelConnector {
@@ -145,9 +147,16 @@ impl TcpTunnelConnector {
             addr,
             bind_addrs: vec![],
             ip_version: IpVersion::Both,
+            proxy: None,
         }
     }
 
+    /// Dial through an http CONNECT or socks5 proxy instead of directly, the
+    /// bind addrs and ip version are ignored then.
+    pub fn set_proxy(&mut self, proxy: Option<url::Url>) {
+        self.proxy = proxy;
+    }
+
     async fn connect_with_default_bind(
         &mut self,
         addr: SocketAddr,
@@ -190,6 +199,14 @@ impl TcpTunnelConnector {
 #[async_trait]
 impl super::TunnelConnector for TcpTunnelConnector {
     async fn connect(&mut self) -> Result<Box<dyn Tunnel>, super::TunnelError> {
+        if let Some(proxy) = &self.proxy {
+            if self.addr.scheme() != "tcp" {
+                return Err(TunnelError::InvalidProtocol(self.addr.scheme().to_string()));
+            }
+            let stream = connect_through_proxy(proxy, &self.addr).await?;
+            return get_tunnel_with_tcp_stream(stream, self.addr.clone());
+        }
+
         let addr =
             check_scheme_and_get_socket_addr::<SocketAddr>(&self.addr, "tcp", self.ip_version)
                 .await?;
@@ -253,6 +270,21 @@ mod tests {
         _tunnel_pingpong(listener, connector).await
     }
 
+    #[tokio::test]
+    async fn tcp_pingpong_through_proxy() {
+        let proxy = crate::tunnel::proxy::tests::start_http_proxy(None).await;
+        let listener = TcpTunnelListener::new("tcp://0.0.0.0:31016".parse().unwrap());
+        let mut connector = TcpTunnelConnector::new("tcp://127.0.0.1:31016".parse().unwrap());
+        connector.set_proxy(Some(proxy));
+        _tunnel_pingpong(listener, connector).await;
+
+        let proxy = crate::tunnel::proxy::tests::start_socks5_proxy(None).await;
+        let listener = TcpTunnelListener::new("tcp://0.0.0.0:31017".parse().unwrap());
+        let mut connector = TcpTunnelConnector::new("tcp://localhost:31017".parse().unwrap());
+        connector.set_proxy(Some(proxy));
+        _tunnel_pingpong(listener, connector).await
+    }
+
     #[tokio::test]
     async fn bind_same_port() {
         let mut listener = TcpTunnelListener::new("tcp://[::]:31014".parse().unwrap());
@@ -0,0 +1,393 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock, Weak},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+
+use super::{
+    insecure_tls::{get_insecure_tls_cert, init_crypto_provider, SkipServerVerification},
+    TunnelError,
+};
+
+// how often the certificate files of a listener are checked for renewal
+const CERT_RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+pub fn load_certs(path: impl AsRef<Path>) -> Result<Vec<CertificateDer<'static>>, TunnelError> {
+    let path = path.as_ref();
+    let pem = std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let certs = CertificateDer::pem_slice_iter(&pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            anyhow::anyhow!(
+                "failed to parse certificates in {}: {:?}",
+                path.display(),
+                e
+            )
+        })?;
+    if certs.is_empty() {
+        return Err(TunnelError::InternalError(format!(
+            "no certificate found in {}",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+pub fn load_private_key(path: impl AsRef<Path>) -> Result<PrivateKeyDer<'static>, TunnelError> {
+    let path = path.as_ref();
+    let pem = std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let key = PrivateKeyDer::from_pem_slice(&pem).map_err(|e| {
+        anyhow::anyhow!("failed to parse private key in {}: {:?}", path.display(), e)
+    })?;
+    Ok(key)
+}
+
+pub fn load_root_store(path: impl AsRef<Path>) -> Result<rustls::RootCertStore, TunnelError> {
+    let path = path.as_ref();
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(cert)
+            .with_context(|| format!("invalid ca certificate in {}", path.display()))?;
+    }
+    Ok(roots)
+}
+
+fn system_root_store() -> Result<Arc<rustls::RootCertStore>, TunnelError> {
+    static ROOTS: OnceLock<Arc<rustls::RootCertStore>> = OnceLock::new();
+    if let Some(roots) = ROOTS.get() {
+        return Ok(roots.clone());
+    }
+
+    let native = rustls_native_certs::load_native_certs();
+    for e in native.errors.iter() {
+        tracing::warn!(?e, "failed to load system root certificates");
+    }
+    let mut roots = rustls::RootCertStore::empty();
+    let (added, ignored) = roots.add_parsable_certificates(native.certs);
+    tracing::debug!(added, ignored, "system root certificates loaded");
+    if roots.is_empty() {
+        return Err(TunnelError::InternalError(
+            "no system root certificate found".to_owned(),
+        ));
+    }
+    Ok(ROOTS.get_or_init(|| Arc::new(roots)).clone())
+}
+
+/// How a tls client verifies the certificate of the server.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum ServerVerification {
+    /// accept any certificate, the peers authenticate each other anyway
+    #[default]
+    Insecure,
+    /// verify against the root certificates of the system
+    SystemRoots,
+    /// verify against the ca certificates in this pem file
+    Ca(PathBuf),
+}
+
+impl ServerVerification {
+    pub fn is_insecure(&self) -> bool {
+        matches!(self, ServerVerification::Insecure)
+    }
+}
+
+/// Client config verifying the server as given by `verification`. `cert` and
+/// `key` are the pem files of a client certificate to present.
+pub fn get_client_config(
+    verification: &ServerVerification,
+    cert: Option<&Path>,
+    key: Option<&Path>,
+) -> Result<rustls::ClientConfig, TunnelError> {
+    init_crypto_provider();
+    let builder = rustls::ClientConfig::builder();
+    let builder = match verification {
+        ServerVerification::Insecure => {
+            let provider = rustls::crypto::CryptoProvider::get_default().unwrap();
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(SkipServerVerification::new(provider.clone()))
+        }
+        ServerVerification::SystemRoots => builder.with_root_certificates(system_root_store()?),
+        ServerVerification::Ca(ca) => builder.with_root_certificates(load_root_store(ca)?),
+    };
+    let mut config = match (cert, key) {
+        (Some(cert), Some(key)) => builder
+            .with_client_auth_cert(load_certs(cert)?, load_private_key(key)?)
+            .with_context(|| "invalid client certificate")?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => {
+            return Err(TunnelError::InvalidAddr(
+                "client certificate and key must be given together".to_owned(),
+            ))
+        }
+    };
+    config.enable_sni = true;
+    config.enable_early_data = false;
+    Ok(config)
+}
+
+/// Serves the certificate chain and key of pem files, reloading them when
+/// they change so renewed certificates are picked up without a restart. A
+/// renewal failing to load keeps the previous certificate. The files are
+/// checked by a background task, handshakes only load the current key.
+pub struct ReloadingCertResolver {
+    cert_file: PathBuf,
+    key_file: PathBuf,
+    key: ArcSwap<CertifiedKey>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl std::fmt::Debug for ReloadingCertResolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReloadingCertResolver")
+            .field("cert_file", &self.cert_file)
+            .field("key_file", &self.key_file)
+            .finish()
+    }
+}
+
+impl ReloadingCertResolver {
+    pub fn new(cert_file: PathBuf, key_file: PathBuf) -> Result<Arc<Self>, TunnelError> {
+        let resolver = Self::new_without_reload(cert_file, key_file)?;
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn(Self::reload_routine(
+                    Arc::downgrade(&resolver),
+                    CERT_RELOAD_CHECK_INTERVAL,
+                ));
+            }
+            Err(_) => {
+                tracing::warn!(cert_file = ?resolver.cert_file, "no runtime, tls certificate will not be reloaded");
+            }
+        }
+        Ok(resolver)
+    }
+
+    fn new_without_reload(cert_file: PathBuf, key_file: PathBuf) -> Result<Arc<Self>, TunnelError> {
+        let modified = Self::modified(&cert_file, &key_file);
+        let key = Self::load(&cert_file, &key_file)?;
+        Ok(Arc::new(ReloadingCertResolver {
+            cert_file,
+            key_file,
+            key: ArcSwap::new(key),
+            modified: Mutex::new(modified),
+        }))
+    }
+
+    // runs until the resolver is dropped together with its server config
+    async fn reload_routine(resolver: Weak<Self>, check_interval: Duration) {
+        let mut interval = tokio::time::interval(check_interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let Some(resolver) = resolver.upgrade() else {
+                break;
+            };
+            let _ = tokio::task::spawn_blocking(move || resolver.reload()).await;
+        }
+    }
+
+    fn load(cert_file: &Path, key_file: &Path) -> Result<Arc<CertifiedKey>, TunnelError> {
+        init_crypto_provider();
+        let provider = rustls::crypto::CryptoProvider::get_default().unwrap();
+        let certs = load_certs(cert_file)?;
+        let key = provider
+            .key_provider
+            .load_private_key(load_private_key(key_file)?)
+            .with_context(|| format!("unsupported private key in {}", key_file.display()))?;
+        Ok(Arc::new(CertifiedKey::new(certs, key)))
+    }
+
+    // the later modification time of both files
+    fn modified(cert_file: &Path, key_file: &Path) -> Option<SystemTime> {
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        modified(cert_file).max(modified(key_file))
+    }
+
+    // blocking, reads the files if they changed since the last load
+    fn reload(&self) {
+        let mut loaded_modified = self.modified.lock().unwrap();
+        let modified = Self::modified(&self.cert_file, &self.key_file);
+        if modified == *loaded_modified {
+            return;
+        }
+        match Self::load(&self.cert_file, &self.key_file) {
+            Ok(key) => {
+                tracing::info!(cert_file = ?self.cert_file, "tls certificate reloaded");
+                self.key.store(key);
+                *loaded_modified = modified;
+            }
+            Err(e) => {
+                tracing::warn!(?e, cert_file = ?self.cert_file, "failed to reload tls certificate");
+            }
+        }
+    }
+
+    fn current(&self) -> Arc<CertifiedKey> {
+        self.key.load_full()
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current())
+    }
+}
+
+/// Server config presenting the certificate chain and key in `cert_files`, or
+/// a self signed certificate if not given. Clients must present a certificate
+/// signed by the ca certificates in `client_ca` if given.
+pub fn get_server_config(
+    cert_files: Option<(&Path, &Path)>,
+    client_ca: Option<&Path>,
+) -> Result<rustls::ServerConfig, TunnelError> {
+    init_crypto_provider();
+    let builder = rustls::ServerConfig::builder();
+    let builder = match client_ca {
+        Some(ca) => {
+            let verifier =
+                rustls::server::WebPkiClientVerifier::builder(Arc::new(load_root_store(ca)?))
+                    .build()
+                    .with_context(|| "failed to create client certificate verifier")?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    match cert_files {
+        Some((cert_file, key_file)) => Ok(builder.with_cert_resolver(ReloadingCertResolver::new(
+            cert_file.to_path_buf(),
+            key_file.to_path_buf(),
+        )?)),
+        None => {
+            let (certs, key) = get_insecure_tls_cert();
+            Ok(builder
+                .with_single_cert(certs, key)
+                .with_context(|| "failed to create server config")?)
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa, SanType};
+
+    use super::*;
+
+    /// A ca writing its certificates into a temporary directory.
+    pub struct TestPki {
+        dir: std::path::PathBuf,
+        ca: Certificate,
+    }
+
+    impl TestPki {
+        pub fn generate() -> Self {
+            let mut params = CertificateParams::new(vec![]);
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca = Certificate::from_params(params).unwrap();
+            let dir = std::env::temp_dir().join(format!("et_tls_{}", uuid::Uuid::new_v4()));
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+            Self { dir, ca }
+        }
+
+        pub fn path(&self, name: &str) -> String {
+            self.dir.join(name).to_str().unwrap().to_owned()
+        }
+
+        /// Writes `<name>.pem` and `<name>.key` signed by the ca, valid for
+        /// `names`, which may be dns names or ip addresses.
+        pub fn issue(&self, name: &str, names: &[&str]) -> (String, String) {
+            let mut params = CertificateParams::new(vec![]);
+            params.subject_alt_names = names
+                .iter()
+                .map(|n| match n.parse() {
+                    Ok(ip) => SanType::IpAddress(ip),
+                    Err(_) => SanType::DnsName(n.to_string()),
+                })
+                .collect();
+            let cert = Certificate::from_params(params).unwrap();
+            let (cert_path, key_path) = (
+                self.path(&format!("{}.pem", name)),
+                self.path(&format!("{}.key", name)),
+            );
+            std::fs::write(
+                &cert_path,
+                cert.serialize_pem_with_signer(&self.ca).unwrap(),
+            )
+            .unwrap();
+            std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+            (cert_path, key_path)
+        }
+    }
+
+    impl Drop for TestPki {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn reload_renewed_certificate() {
+        let pki = TestPki::generate();
+        let (cert, key) = pki.issue("server", &["a.example.com"]);
+        let resolver =
+            ReloadingCertResolver::new_without_reload(cert.clone().into(), key.into()).unwrap();
+        let first = resolver.current();
+        resolver.reload();
+        assert!(Arc::ptr_eq(&first, &resolver.current()));
+
+        // file timestamps are coarse, make sure each write changes them
+        std::thread::sleep(Duration::from_millis(50));
+        std::fs::write(&cert, "renewing").unwrap();
+        resolver.reload();
+        assert!(Arc::ptr_eq(&first, &resolver.current()));
+
+        std::thread::sleep(Duration::from_millis(50));
+        pki.issue("server", &["b.example.com"]);
+        // the background task picks up the renewal, not the handshake
+        assert!(Arc::ptr_eq(&first, &resolver.current()));
+        resolver.reload();
+        let renewed = resolver.current();
+        assert_ne!(first.cert, renewed.cert);
+        resolver.reload();
+        assert!(Arc::ptr_eq(&renewed, &resolver.current()));
+    }
+
+    #[tokio::test]
+    async fn reload_routine_stops_with_resolver() {
+        let pki = TestPki::generate();
+        let (cert, key) = pki.issue("server", &["a.example.com"]);
+        let resolver = ReloadingCertResolver::new_without_reload(cert.into(), key.into()).unwrap();
+        let first = resolver.current();
+        let task = tokio::spawn(ReloadingCertResolver::reload_routine(
+            Arc::downgrade(&resolver),
+            Duration::from_millis(10),
+        ));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        pki.issue("server", &["b.example.com"]);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while Arc::ptr_eq(&first, &resolver.current()) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        drop(resolver);
+        tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
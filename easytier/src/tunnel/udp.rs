@@ -2,6 +2,7 @@ use std::{
     fmt::Debug,
     net::{Ipv6Addr, SocketAddrV6},
     sync::{Arc, Weak},
+    time::Duration,
 };
 
 use anyhow::Context;
@@ -27,6 +28,7 @@ use crate::{
     tunnel::{
         build_url_from_socket_addr,
         common::{reserve_buf, TunnelWrapper},
+        fec::{FecConfig, FecDecoder, FecEncoder, FecStats},
         packet_def::{UdpPacketType, ZCPacket, ZCPacketType},
         ring::RingTunnel,
     },
@@ -41,6 +43,10 @@ use super::{
 
 pub const UDP_DATA_MTU: usize = 2000;
 
+const SYN_TIMEOUT: Duration = Duration::from_secs(3);
+// how long a syn carrying a fec config waits before retrying without fec
+const FEC_SYN_TIMEOUT: Duration = Duration::from_secs(1);
+
 type UdpCloseEventSender = UnboundedSender<(SocketAddr, Option<TunnelError>)>;
 type UdpCloseEventReceiver = UnboundedReceiver<(SocketAddr, Option<TunnelError>)>;
 
@@ -61,25 +67,64 @@ where
     ret
 }
 
-fn new_syn_packet(conn_id: u32, magic: u64) -> ZCPacket {
+// the fec config follows the magic if the connector asks for fec, the
+// listener echoes the config it accepted in the sack
+fn handshake_body(magic: u64, fec: Option<FecConfig>) -> Vec<u8> {
+    let mut body = magic.to_le_bytes().to_vec();
+    if let Some(fec) = fec {
+        body.extend_from_slice(&fec.to_bytes());
+    }
+    body
+}
+
+fn parse_handshake_body(body: &[u8]) -> Result<(u64, Option<FecConfig>), TunnelError> {
+    if body.len() != 8 && body.len() != 10 {
+        return Err(TunnelError::InvalidPacket(format!(
+            "udp handshake payload len not match: {}",
+            body.len()
+        )));
+    }
+    let magic = u64::from_le_bytes(body[..8].try_into().unwrap());
+    let fec = if body.len() > 8 {
+        Some(FecConfig::from_bytes(&body[8..])?)
+    } else {
+        None
+    };
+    Ok((magic, fec))
+}
+
+fn new_syn_packet(conn_id: u32, magic: u64, fec: Option<FecConfig>) -> ZCPacket {
+    let body = handshake_body(magic, fec);
     new_udp_packet(
         |header| {
             header.msg_type = UdpPacketType::Syn as u8;
             header.conn_id.set(conn_id);
-            header.len.set(8);
+            header.len.set(body.len() as u16);
         },
-        Some(&magic.to_le_bytes()),
+        Some(&body),
     )
 }
 
-fn new_sack_packet(conn_id: u32, magic: u64) -> ZCPacket {
+fn new_sack_packet(conn_id: u32, magic: u64, fec: Option<FecConfig>) -> ZCPacket {
+    let body = handshake_body(magic, fec);
     new_udp_packet(
         |header| {
             header.msg_type = UdpPacketType::Sack as u8;
             header.conn_id.set(conn_id);
-            header.len.set(8);
+            header.len.set(body.len() as u16);
+        },
+        Some(&body),
+    )
+}
+
+fn new_fec_parity_packet(conn_id: u32, parity: &[u8]) -> ZCPacket {
+    new_udp_packet(
+        |header| {
+            header.msg_type = UdpPacketType::FecParity as u8;
+            header.conn_id.set(conn_id);
+            header.len.set(parity.len() as u16);
         },
-        Some(&magic.to_le_bytes()),
+        Some(parity),
     )
 }
 
@@ -230,16 +275,44 @@ fn get_zcpacket_from_buf(buf: BytesMut, allow_stun: bool) -> Result<ZCPacket, Tu
     Ok(zc_packet)
 }
 
+async fn send_fec_parity(
+    encoder: &mut FecEncoder,
+    socket: &Arc<UdpSocket>,
+    addr: &SocketAddr,
+    conn_id: u32,
+) -> Result<(), TunnelError> {
+    for parity in encoder.finish_group() {
+        let buf = new_fec_parity_packet(conn_id, &parity).into_bytes();
+        socket.send_to(&buf, &addr).await?;
+    }
+    Ok(())
+}
+
 #[instrument]
 async fn forward_from_ring_to_udp(
     mut ring_recv: RingStream,
     socket: &Arc<UdpSocket>,
     addr: &SocketAddr,
     conn_id: u32,
+    fec: Option<FecConfig>,
 ) -> Option<TunnelError> {
     tracing::debug!("udp forward from ring to udp");
+    let mut fec_encoder = fec.map(FecEncoder::new);
     loop {
-        let buf = ring_recv.next().await?;
+        let flush_deadline = fec_encoder.as_ref().and_then(|e| e.flush_deadline());
+        let buf = tokio::select! {
+            buf = ring_recv.next() => buf?,
+            _ = tokio::time::sleep_until(
+                flush_deadline.unwrap_or_else(std::time::Instant::now).into()
+            ), if flush_deadline.is_some() => {
+                // close a partial group so its packets are protected too
+                let encoder = fec_encoder.as_mut().unwrap();
+                if let Err(e) = send_fec_parity(encoder, socket, addr, conn_id).await {
+                    return Some(e);
+                }
+                continue;
+            }
+        };
         let packet = match buf {
             Ok(v) => v,
             Err(e) => {
@@ -248,11 +321,18 @@ async fn forward_from_ring_to_udp(
         };
 
         let mut packet = packet.convert_type(ZCPacketType::UDP);
+        let msg_type = if let Some(encoder) = fec_encoder.as_mut() {
+            let trailer = encoder.add_data(packet.udp_payload());
+            packet.mut_inner().extend_from_slice(&trailer);
+            UdpPacketType::FecData
+        } else {
+            UdpPacketType::Data
+        };
         let udp_payload_len = packet.udp_payload().len();
         let header = packet.mut_udp_tunnel_header().unwrap();
         header.conn_id.set(conn_id);
         header.len.set(udp_payload_len as u16);
-        header.msg_type = UdpPacketType::Data as u8;
+        header.msg_type = msg_type as u8;
 
         let buf = packet.into_bytes();
         tracing::trace!(?udp_payload_len, ?buf, "udp forward from ring to udp");
@@ -262,6 +342,12 @@ async fn forward_from_ring_to_udp(
         } else if ret.unwrap() == 0 {
             return None;
         }
+
+        if let Some(encoder) = fec_encoder.as_mut().filter(|e| e.is_group_full()) {
+            if let Err(e) = send_fec_parity(encoder, socket, addr, conn_id).await {
+                return Some(e);
+            }
+        }
     }
 }
 
@@ -305,6 +391,7 @@ struct UdpConnection {
 
     ring_sender: RingSink,
     forward_task: ScopedTask<()>,
+    fec_decoder: Option<FecDecoder>,
 }
 
 impl UdpConnection {
@@ -315,11 +402,13 @@ impl UdpConnection {
         ring_sender: RingSink,
         ring_recv: RingStream,
         close_event_sender: UdpCloseEventSender,
+        fec: Option<(FecConfig, FecStats)>,
     ) -> Self {
+        let fec_config = fec.as_ref().map(|(config, _)| *config);
         let s = socket.clone();
         let forward_task = tokio::spawn(async move {
             let close_event_sender = close_event_sender;
-            let err = forward_from_ring_to_udp(ring_recv, &s, &dst_addr, conn_id).await;
+            let err = forward_from_ring_to_udp(ring_recv, &s, &dst_addr, conn_id, fec_config).await;
             if let Err(e) = close_event_sender.send((dst_addr, err)) {
                 tracing::error!(?e, "udp send close event error");
             }
@@ -332,14 +421,18 @@ impl UdpConnection {
             dst_addr,
             ring_sender,
             forward_task,
+            fec_decoder: fec.map(|(config, stats)| FecDecoder::new(config, stats)),
         }
     }
 
     pub fn handle_packet_from_remote(&mut self, zc_packet: ZCPacket) -> Result<(), TunnelError> {
         let header = zc_packet.udp_tunnel_header().unwrap();
         let conn_id = header.conn_id.get();
+        let msg_type = header.msg_type;
 
-        if header.msg_type != UdpPacketType::Data as u8 {
+        let is_fec =
+            msg_type == UdpPacketType::FecData as u8 || msg_type == UdpPacketType::FecParity as u8;
+        if msg_type != UdpPacketType::Data as u8 && !is_fec {
             return Err(TunnelError::InvalidPacket("not data packet".to_owned()));
         }
 
@@ -347,6 +440,53 @@ impl UdpConnection {
             return Err(TunnelError::ConnIdNotMatch(self.conn_id, conn_id));
         }
 
+        if !is_fec {
+            self.push_to_ring(zc_packet);
+            return Ok(());
+        }
+        let Some(decoder) = self.fec_decoder.as_mut() else {
+            return Err(TunnelError::InvalidPacket(
+                "fec packet on a connection without fec".to_owned(),
+            ));
+        };
+
+        let mut recovered = vec![];
+        if msg_type == UdpPacketType::FecParity as u8 {
+            decoder.on_parity(zc_packet.udp_payload(), &mut recovered);
+        } else {
+            let Some(len) = decoder.on_data(zc_packet.udp_payload(), &mut recovered)? else {
+                // a late original of a packet already rebuilt from parity
+                return Ok(());
+            };
+            let mut zc_packet = zc_packet;
+            let buf_len = zc_packet.buf_len();
+            let payload_len = zc_packet.udp_payload().len();
+            zc_packet
+                .mut_inner()
+                .truncate(buf_len - (payload_len - len));
+            zc_packet
+                .mut_udp_tunnel_header()
+                .unwrap()
+                .len
+                .set(len as u16);
+            self.push_to_ring(zc_packet);
+        }
+
+        for payload in recovered {
+            self.push_to_ring(new_udp_packet(
+                |header| {
+                    header.msg_type = UdpPacketType::Data as u8;
+                    header.conn_id.set(conn_id);
+                    header.len.set(payload.len() as u16);
+                },
+                Some(&payload),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn push_to_ring(&mut self, zc_packet: ZCPacket) {
         if zc_packet.is_lossy() {
             if let Err(e) = self.ring_sender.try_send(zc_packet) {
                 tracing::trace!(?e, "ring sender full, drop lossy packet");
@@ -354,8 +494,6 @@ impl UdpConnection {
         } else if let Err(e) = self.ring_sender.force_send(zc_packet) {
             tracing::trace!(?e, "ring sender full, drop non-lossy packet");
         }
-
-        Ok(())
     }
 }
 
@@ -366,6 +504,7 @@ struct UdpTunnelListenerData {
     sock_map: Arc<DashMap<SocketAddr, UdpConnection>>,
     conn_send: Sender<Box<dyn Tunnel>>,
     close_event_sender: UdpCloseEventSender,
+    fec_stats: FecStats,
 }
 
 impl UdpTunnelListenerData {
@@ -380,26 +519,24 @@ impl UdpTunnelListenerData {
             sock_map: Arc::new(DashMap::new()),
             conn_send,
             close_event_sender,
+            fec_stats: FecStats::default(),
         }
     }
 
     async fn handle_new_connect(self, remote_addr: SocketAddr, zc_packet: ZCPacket) {
-        let udp_payload = zc_packet.udp_payload();
-        if udp_payload.len() != 8 {
-            tracing::warn!(
-                "udp syn packet payload len not match: {:?}, packet: {:?}",
-                udp_payload.len(),
-                zc_packet,
-            );
-            return;
-        }
-        let magic = u64::from_le_bytes(udp_payload[..8].try_into().unwrap());
+        let (magic, fec) = match parse_handshake_body(zc_packet.udp_payload()) {
+            Ok(v) => v,
+            Err(e) => {
+                tracing::warn!(?e, packet = ?zc_packet, "udp syn packet invalid");
+                return;
+            }
+        };
         let conn_id = zc_packet.udp_tunnel_header().unwrap().conn_id.get();
 
         tracing::info!(?conn_id, ?remote_addr, "udp connection accept handling",);
         let socket = self.socket.as_ref().unwrap().clone();
 
-        let sack_buf = new_sack_packet(conn_id, magic).into_bytes();
+        let sack_buf = new_sack_packet(conn_id, magic, fec).into_bytes();
         if let Err(e) = socket.send_to(&sack_buf, remote_addr).await {
             tracing::error!(?e, "udp send sack packet error");
             return;
@@ -420,6 +557,7 @@ impl UdpTunnelListenerData {
             RingSink::new(ring_for_recv_udp.clone()),
             RingStream::new(ring_for_send_udp.clone()),
             self.close_event_sender.clone(),
+            fec.map(|fec| (fec, self.fec_stats.clone())),
         );
         self.sock_map.insert(remote_addr, internal_conn);
 
@@ -525,6 +663,12 @@ impl UdpTunnelListener {
     pub fn get_socket(&self) -> Option<Arc<UdpSocket>> {
         self.socket.clone()
     }
+
+    /// Counters for the fec decoders of accepted connections, set before
+    /// `listen`.
+    pub fn set_fec_stats(&mut self, stats: FecStats) {
+        self.data.fec_stats = stats;
+    }
 }
 
 #[async_trait]
@@ -622,6 +766,7 @@ pub struct UdpTunnelConnector {
     addr: url::Url,
     bind_addrs: Vec<SocketAddr>,
     ip_version: IpVersion,
+    fec_stats: FecStats,
 }
 
 impl UdpTunnelConnector {
@@ -630,15 +775,20 @@ impl UdpTunnelConnector {
             addr,
             bind_addrs: vec![],
             ip_version: IpVersion::Both,
+            fec_stats: FecStats::default(),
         }
     }
 
+    pub fn set_fec_stats(&mut self, stats: FecStats) {
+        self.fec_stats = stats;
+    }
+
     async fn wait_sack(
         socket: &UdpSocket,
         addr: SocketAddr,
         conn_id: u32,
         magic: u64,
-    ) -> Result<SocketAddr, TunnelError> {
+    ) -> Result<(SocketAddr, Option<FecConfig>), TunnelError> {
         let mut buf = BytesMut::new();
         buf.reserve(UDP_DATA_MTU);
 
@@ -665,21 +815,14 @@ impl UdpTunnelConnector {
             return Err(TunnelError::InvalidPacket("not sack packet".to_owned()));
         }
 
-        let payload = zc_packet.udp_payload();
-        if payload.len() != 8 {
-            return Err(TunnelError::InvalidPacket(
-                "udp sack packet payload len not match".to_owned(),
-            ));
-        }
-
-        let sack_magic = u64::from_le_bytes(payload[..8].try_into().unwrap());
+        let (sack_magic, fec) = parse_handshake_body(zc_packet.udp_payload())?;
         if sack_magic != magic {
             return Err(TunnelError::InvalidPacket(
                 "udp sack magic not match".to_owned(),
             ));
         }
 
-        Ok(recv_addr)
+        Ok((recv_addr, fec))
     }
 
     async fn wait_sack_loop(
@@ -687,7 +830,7 @@ impl UdpTunnelConnector {
         addr: SocketAddr,
         conn_id: u32,
         magic: u64,
-    ) -> Result<SocketAddr, super::TunnelError> {
+    ) -> Result<(SocketAddr, Option<FecConfig>), super::TunnelError> {
         loop {
             let ret = Self::wait_sack(socket, addr, conn_id, magic).await;
             if ret.is_err() {
@@ -704,6 +847,7 @@ impl UdpTunnelConnector {
         socket: Arc<UdpSocket>,
         dst_addr: SocketAddr,
         conn_id: u32,
+        fec: Option<FecConfig>,
     ) -> Result<Box<dyn super::Tunnel>, super::TunnelError> {
         let ring_for_send_udp = Arc::new(RingTunnel::new(128));
         let ring_for_recv_udp = Arc::new(RingTunnel::new(128));
@@ -724,6 +868,7 @@ impl UdpTunnelConnector {
             ring_sender,
             ring_recv,
             close_event_sender,
+            fec.map(|fec| (fec, self.fec_stats.clone())),
         );
 
         let socket_clone = socket.clone();
@@ -763,6 +908,26 @@ impl UdpTunnelConnector {
         )))
     }
 
+    async fn handshake(
+        socket: &UdpSocket,
+        addr: SocketAddr,
+        fec: Option<FecConfig>,
+        wait: Duration,
+    ) -> Result<(u32, SocketAddr, Option<FecConfig>), TunnelError> {
+        // send syn
+        let conn_id = rand::random();
+        let magic = rand::random();
+        let udp_packet = new_syn_packet(conn_id, magic, fec).into_bytes();
+        let ret = socket.send_to(&udp_packet, &addr).await?;
+        tracing::warn!(?udp_packet, ?ret, "udp send syn");
+
+        // wait sack
+        let (recv_addr, sack_fec) =
+            tokio::time::timeout(wait, Self::wait_sack_loop(socket, addr, conn_id, magic))
+                .await??;
+        Ok((conn_id, recv_addr, sack_fec))
+    }
+
     pub async fn try_connect_with_socket(
         &self,
         socket: Arc<UdpSocket>,
@@ -773,25 +938,33 @@ impl UdpTunnelConnector {
         #[cfg(target_os = "windows")]
         crate::arch::windows::disable_connection_reset(socket.as_ref())?;
 
-        // send syn
-        let conn_id = rand::random();
-        let magic = rand::random();
-        let udp_packet = new_syn_packet(conn_id, magic).into_bytes();
-        let ret = socket.send_to(&udp_packet, &addr).await?;
-        tracing::warn!(?udp_packet, ?ret, "udp send syn");
-
-        // wait sack
-        let recv_addr = tokio::time::timeout(
-            tokio::time::Duration::from_secs(3),
-            Self::wait_sack_loop(&socket, addr, conn_id, magic),
-        )
-        .await??;
+        // listeners without fec support drop a syn carrying a fec config, so
+        // fall back to a plain syn if it is not answered in time
+        let fec = FecConfig::from_url(&self.addr)?;
+        let (conn_id, recv_addr, sack_fec) = match fec {
+            Some(_) => match Self::handshake(&socket, addr, fec, FEC_SYN_TIMEOUT).await {
+                Err(TunnelError::Timeout(_)) => {
+                    tracing::warn!(?addr, "no answer to fec syn, retry without fec");
+                    Self::handshake(&socket, addr, None, SYN_TIMEOUT - FEC_SYN_TIMEOUT).await?
+                }
+                ret => ret?,
+            },
+            None => Self::handshake(&socket, addr, None, SYN_TIMEOUT).await?,
+        };
 
         if recv_addr != addr {
             tracing::debug!(?recv_addr, ?addr, "udp connect addr not match");
         }
 
-        self.build_tunnel(socket, addr, conn_id).await
+        if sack_fec != fec {
+            tracing::warn!(
+                ?fec,
+                ?sack_fec,
+                "udp listener did not accept the fec config"
+            );
+        }
+
+        self.build_tunnel(socket, addr, conn_id, sack_fec).await
     }
 
     async fn connect_with_default_bind(
@@ -886,6 +1059,57 @@ mod tests {
         _tunnel_pingpong(listener, connector).await;
     }
 
+    #[tokio::test]
+    async fn udp_fec_pingpong() {
+        let listener = UdpTunnelListener::new("udp://0.0.0.0:5557".parse().unwrap());
+        let connector = UdpTunnelConnector::new(
+            "udp://127.0.0.1:5557?fec_group=4&fec_redundancy=0.5"
+                .parse()
+                .unwrap(),
+        );
+        _tunnel_pingpong(listener, connector).await;
+    }
+
+    #[test]
+    fn udp_handshake_body() {
+        let fec = FecConfig::from_url(&"udp://127.0.0.1:1?fec_group=4".parse().unwrap()).unwrap();
+        assert_eq!(
+            (7, fec),
+            parse_handshake_body(&handshake_body(7, fec)).unwrap()
+        );
+        assert_eq!(
+            (7, None),
+            parse_handshake_body(&handshake_body(7, None)).unwrap()
+        );
+        assert!(parse_handshake_body(&[0u8; 9]).is_err());
+    }
+
+    #[tokio::test]
+    async fn udp_fec_fallback_to_listener_without_fec() {
+        // listeners without fec support drop syn packets longer than 8 bytes
+        let old_listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = old_listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 256];
+            loop {
+                let (len, from) = old_listener.recv_from(&mut buf).await.unwrap();
+                let syn = ZCPacket::new_from_buf(BytesMut::from(&buf[..len]), ZCPacketType::UDP);
+                let payload = syn.udp_payload();
+                if payload.len() != 8 {
+                    continue;
+                }
+                let conn_id = syn.udp_tunnel_header().unwrap().conn_id.get();
+                let magic = u64::from_le_bytes(payload.try_into().unwrap());
+                let sack = new_sack_packet(conn_id, magic, None).into_bytes();
+                old_listener.send_to(&sack, from).await.unwrap();
+            }
+        });
+
+        let mut connector =
+            UdpTunnelConnector::new(format!("udp://{}?fec_group=4", addr).parse().unwrap());
+        connector.connect().await.unwrap();
+    }
+
     #[tokio::test]
     async fn udp_bench() {
         let listener = UdpTunnelListener::new("udp://0.0.0.0:5555".parse().unwrap());
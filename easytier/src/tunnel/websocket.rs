@@ -1,9 +1,16 @@
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
 
-use anyhow::Context;
-use bytes::BytesMut;
+use bytes::{Buf, BytesMut};
 use futures::{stream::FuturesUnordered, SinkExt, StreamExt};
 use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
     net::{TcpListener, TcpSocket, TcpStream},
     time::timeout,
 };
@@ -12,15 +19,17 @@ use tokio_websockets::{ClientBuilder, Limits, MaybeTlsStream, Message};
 use zerocopy::AsBytes;
 
 use super::TunnelInfo;
-use crate::tunnel::insecure_tls::get_insecure_tls_client_config;
 
 use super::{
     common::{setup_sokcet2, wait_for_connect_futures, TunnelWrapper},
-    insecure_tls::{get_insecure_tls_cert, init_crypto_provider},
     packet_def::{ZCPacket, ZCPacketType},
+    proxy::connect_through_proxy,
+    tls::{get_client_config, get_server_config, ServerVerification},
     FromUrl, IpVersion, Tunnel, TunnelConnector, TunnelError, TunnelListener,
 };
 
+const MAX_REQUEST_HEAD_LEN: usize = 8192;
+
 fn is_wss(addr: &url::Url) -> Result<bool, TunnelError> {
     match addr.scheme() {
         "ws" => Ok(false),
@@ -59,10 +68,180 @@ async fn map_from_ws_message(
     )))
 }
 
+/// A stream replaying the bytes already read from it before reading on.
+struct PrefixedStream<S> {
+    prefix: BytesMut,
+    inner: S,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PrefixedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.prefix.has_remaining() {
+            let n = self.prefix.len().min(buf.remaining());
+            buf.put_slice(&self.prefix[..n]);
+            self.prefix.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PrefixedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Request checks of a ws/wss listener. A path other than `/` in the listener
+/// url must match the request path, a token must be presented as the `token`
+/// query parameter or a bearer authorization of the request, and a client ca
+/// makes wss require client certificates signed by the ca certificates in that
+/// pem file. The token and client ca are not part of the url, listener urls
+/// are published to other peers.
+#[derive(Debug, Default)]
+struct WSListenerOptions {
+    path: Option<String>,
+    token: Option<String>,
+    client_ca: Option<PathBuf>,
+}
+
+impl WSListenerOptions {
+    fn parse_path(&mut self, addr: &url::Url) -> Result<(), TunnelError> {
+        if addr
+            .query_pairs()
+            .any(|(k, _)| k == "token" || k == "client_ca")
+        {
+            return Err(TunnelError::InvalidAddr(format!(
+                "token and client_ca are configured outside of the listener url: {}",
+                addr
+            )));
+        }
+        self.path = Some(addr.path())
+            .filter(|p| !p.is_empty() && *p != "/")
+            .map(str::to_owned);
+        Ok(())
+    }
+
+    fn token_matches(&self, token: &str) -> bool {
+        self.token
+            .as_ref()
+            .is_some_and(|expected| constant_time_eq(expected.as_bytes(), token.as_bytes()))
+    }
+
+    // returns the http status to reject the request with
+    fn check_request_head(&self, head: &str) -> Result<(), &'static str> {
+        let mut lines = head.split("\r\n");
+        let target = lines
+            .next()
+            .and_then(|l| l.split_whitespace().nth(1))
+            .ok_or("400 Bad Request")?;
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        if self.path.as_ref().is_some_and(|expected| expected != path) {
+            return Err("404 Not Found");
+        }
+
+        if self.token.is_some() {
+            let in_query = url::form_urlencoded::parse(query.as_bytes())
+                .any(|(k, v)| k == "token" && self.token_matches(&v));
+            let in_header = lines.filter_map(|l| l.split_once(':')).any(|(k, v)| {
+                k.trim().eq_ignore_ascii_case("authorization")
+                    && v.trim()
+                        .strip_prefix("Bearer ")
+                        .is_some_and(|v| self.token_matches(v))
+            });
+            if !in_query && !in_header {
+                return Err("401 Unauthorized");
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the http request head from `stream` and rejects requests failing
+    /// the checks, the returned stream replays the head to the handshake.
+    async fn check_request<S>(&self, mut stream: S) -> Result<PrefixedStream<S>, TunnelError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut head = BytesMut::with_capacity(1024);
+        if self.path.is_none() && self.token.is_none() {
+            return Ok(PrefixedStream {
+                prefix: head,
+                inner: stream,
+            });
+        }
+
+        let end = loop {
+            if let Some(end) = head.windows(4).position(|w| w == b"\r\n\r\n") {
+                break end;
+            }
+            if head.len() >= MAX_REQUEST_HEAD_LEN {
+                return Err(TunnelError::InvalidPacket(
+                    "websocket request head too long".to_owned(),
+                ));
+            }
+            if stream.read_buf(&mut head).await? == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+        };
+
+        if let Err(status) = self.check_request_head(&String::from_utf8_lossy(&head[..end])) {
+            let resp = format!(
+                "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            );
+            let _ = stream.write_all(resp.as_bytes()).await;
+            return Err(TunnelError::InvalidPacket(format!(
+                "websocket request rejected: {}",
+                status
+            )));
+        }
+        Ok(PrefixedStream {
+            prefix: head,
+            inner: stream,
+        })
+    }
+}
+
+async fn accept_websocket<S>(stream: S, info: TunnelInfo) -> Result<Box<dyn Tunnel>, TunnelError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let server_bulder = tokio_websockets::ServerBuilder::new().limits(Limits::unlimited());
+    let (write, read) = server_bulder.accept(stream).await?.split();
+    Ok(Box::new(TunnelWrapper::new(
+        read.filter_map(map_from_ws_message),
+        write.with(sink_from_zc_packet),
+        Some(info),
+    )))
+}
+
 #[derive(Debug)]
 pub struct WSTunnelListener {
     addr: url::Url,
     listener: Option<TcpListener>,
+    options: WSListenerOptions,
+    cert_files: Option<(PathBuf, PathBuf)>,
+    tls_config: Option<Arc<rustls::ServerConfig>>,
 }
 
 impl WSTunnelListener {
@@ -70,9 +249,29 @@ impl WSTunnelListener {
         WSTunnelListener {
             addr,
             listener: None,
+            options: WSListenerOptions::default(),
+            cert_files: None,
+            tls_config: None,
         }
     }
 
+    /// Serve the certificate chain and key in these pem files on wss instead
+    /// of a self signed certificate.
+    pub fn set_cert_files(&mut self, cert_file: PathBuf, key_file: PathBuf) {
+        self.cert_files = Some((cert_file, key_file));
+    }
+
+    /// Only accept requests presenting this token.
+    pub fn set_token(&mut self, token: String) {
+        self.options.token = Some(token);
+    }
+
+    /// Require wss clients to present a certificate signed by the ca
+    /// certificates in this pem file.
+    pub fn set_client_ca_file(&mut self, client_ca_file: PathBuf) {
+        self.options.client_ca = Some(client_ca_file);
+    }
+
     async fn try_accept(&mut self, stream: TcpStream) -> Result<Box<dyn Tunnel>, TunnelError> {
         let info = TunnelInfo {
             tunnel_type: self.addr.scheme().to_owned(),
@@ -86,41 +285,33 @@ impl WSTunnelListener {
             ),
         };
 
-        let server_bulder = tokio_websockets::ServerBuilder::new().limits(Limits::unlimited());
-
-        let ret: Box<dyn Tunnel> = if is_wss(&self.addr)? {
-            init_crypto_provider();
-            let (certs, key) = get_insecure_tls_cert();
-            let config = rustls::ServerConfig::builder()
-                .with_no_client_auth()
-                .with_single_cert(certs, key)
-                .with_context(|| "Failed to create server config")?;
-            let acceptor = TlsAcceptor::from(Arc::new(config));
-
-            let stream = acceptor.accept(stream).await?;
-            let (write, read) = server_bulder.accept(stream).await?.split();
-
-            Box::new(TunnelWrapper::new(
-                read.filter_map(map_from_ws_message),
-                write.with(sink_from_zc_packet),
-                Some(info),
-            ))
+        if let Some(tls_config) = &self.tls_config {
+            let stream = TlsAcceptor::from(tls_config.clone()).accept(stream).await?;
+            accept_websocket(self.options.check_request(stream).await?, info).await
         } else {
-            let (write, read) = server_bulder.accept(stream).await?.split();
-            Box::new(TunnelWrapper::new(
-                read.filter_map(map_from_ws_message),
-                write.with(sink_from_zc_packet),
-                Some(info),
-            ))
-        };
-
-        Ok(ret)
+            accept_websocket(self.options.check_request(stream).await?, info).await
+        }
     }
 }
 
 #[async_trait::async_trait]
 impl TunnelListener for WSTunnelListener {
     async fn listen(&mut self) -> Result<(), TunnelError> {
+        self.options.parse_path(&self.addr)?;
+        if is_wss(&self.addr)? {
+            let config = get_server_config(
+                self.cert_files
+                    .as_ref()
+                    .map(|(cert, key)| (cert.as_path(), key.as_path())),
+                self.options.client_ca.as_deref(),
+            )?;
+            self.tls_config = Some(Arc::new(config));
+        } else if self.options.client_ca.is_some() {
+            return Err(TunnelError::InvalidProtocol(
+                "a client ca requires a wss listener".to_owned(),
+            ));
+        }
+
         let addr = SocketAddr::from_url(self.addr.clone(), IpVersion::Both).await?;
         let socket2_socket = socket2::Socket::new(
             socket2::Domain::for_address(addr),
@@ -159,11 +350,105 @@ impl TunnelListener for WSTunnelListener {
     }
 }
 
+/// Handshake options of a ws/wss connector, given as query parameters of its
+/// url and stripped from the request sent to the server. `sni` overrides the
+/// tls server name and `host` the host of the request, every
+/// `header=Name: Value` adds a request header, `ca` verifies the server
+/// against the ca certificates in that pem file instead of the verification
+/// of the connector, `cert` and `key` are the pem files of a client
+/// certificate. They are only applied to urls configured on this node, for
+/// urls advertised by other peers they are stripped and ignored.
+#[derive(Debug, Default)]
+struct WSConnectorOptions {
+    sni: Option<String>,
+    host: Option<String>,
+    headers: Vec<(http::HeaderName, http::HeaderValue)>,
+    verification: ServerVerification,
+    cert: Option<String>,
+    key: Option<String>,
+}
+
+impl WSConnectorOptions {
+    // returns the options and the uri to request
+    fn parse(
+        addr: &url::Url,
+        verification: &ServerVerification,
+        url_options: bool,
+    ) -> Result<(Self, http::Uri), TunnelError> {
+        let mut options = WSConnectorOptions {
+            verification: verification.clone(),
+            ..Default::default()
+        };
+        let mut query = vec![];
+        for (k, v) in addr.query_pairs() {
+            match k.as_ref() {
+                "sni" | "host" | "header" | "ca" | "cert" | "key" if !url_options => {
+                    tracing::warn!(?addr, option = ?k, "ignore ws option of a remote url");
+                }
+                "sni" => options.sni = Some(v.into_owned()),
+                "host" => options.host = Some(v.into_owned()),
+                "header" => {
+                    let invalid = || TunnelError::InvalidAddr(format!("invalid header: {}", v));
+                    let (name, value) = v.split_once(':').ok_or_else(invalid)?;
+                    options.headers.push((
+                        http::HeaderName::from_bytes(name.trim().as_bytes())
+                            .map_err(|_| invalid())?,
+                        http::HeaderValue::from_str(value.trim()).map_err(|_| invalid())?,
+                    ));
+                }
+                "ca" => options.verification = ServerVerification::Ca(v.as_ref().into()),
+                "cert" => options.cert = Some(v.into_owned()),
+                "key" => options.key = Some(v.into_owned()),
+                _ => query.push((k.into_owned(), v.into_owned())),
+            }
+        }
+
+        let mut request_url = addr.clone();
+        request_url.set_query(None);
+        if !query.is_empty() {
+            request_url.query_pairs_mut().extend_pairs(query);
+        }
+        if let Some(host) = &options.host {
+            request_url
+                .set_host(Some(host))
+                .map_err(|e| TunnelError::InvalidAddr(format!("invalid host {}: {}", host, e)))?;
+        }
+        let uri = http::Uri::try_from(request_url.as_str())
+            .map_err(|e| TunnelError::InvalidAddr(format!("{}: {}", request_url, e)))?;
+        Ok((options, uri))
+    }
+
+    fn tls_client_config(&self) -> Result<rustls::ClientConfig, TunnelError> {
+        get_client_config(
+            &self.verification,
+            self.cert.as_deref().map(Path::new),
+            self.key.as_deref().map(Path::new),
+        )
+    }
+
+    fn server_name(&self, addr: &url::Url) -> String {
+        if let Some(sni) = &self.sni {
+            return sni.clone();
+        }
+        match addr.host() {
+            Some(url::Host::Domain(domain)) => domain.to_owned(),
+            // a verified server certificate must be issued for the ip
+            Some(url::Host::Ipv4(ip)) if !self.verification.is_insecure() => ip.to_string(),
+            Some(url::Host::Ipv6(ip)) if !self.verification.is_insecure() => ip.to_string(),
+            // use "localhost" as SNI for url without domain to avoid IP blocking.
+            _ => "localhost".to_owned(),
+        }
+    }
+}
+
 pub struct WSTunnelConnector {
     addr: url::Url,
     ip_version: IpVersion,
 
     bind_addrs: Vec<SocketAddr>,
+    proxy: Option<url::Url>,
+    server_verification: ServerVerification,
+    url_options: bool,
 }
 
 impl WSTunnelConnector {
@@ -173,17 +458,49 @@ impl WSTunnelConnector {
             ip_version: IpVersion::Both,
 
             bind_addrs: vec![],
+            proxy: None,
+            server_verification: ServerVerification::Insecure,
+            url_options: false,
         }
     }
 
+    /// Apply the handshake options in the query of the url. Only for urls
+    /// configured on this node, the options pick local files and request
+    /// headers.
+    pub fn set_url_options(&mut self, url_options: bool) {
+        self.url_options = url_options;
+    }
+
+    /// How wss servers are verified, unless the url pins a `ca`.
+    pub fn set_server_verification(&mut self, verification: ServerVerification) {
+        self.server_verification = verification;
+    }
+
+    /// Dial through an http CONNECT or socks5 proxy instead of directly, the
+    /// bind addrs and ip version are ignored then.
+    pub fn set_proxy(&mut self, proxy: Option<url::Url>) {
+        self.proxy = proxy;
+    }
+
     async fn connect_with(
         addr: url::Url,
         ip_version: IpVersion,
+        verification: ServerVerification,
+        url_options: bool,
         tcp_socket: TcpSocket,
     ) -> Result<Box<dyn Tunnel>, TunnelError> {
-        let is_wss = is_wss(&addr)?;
         let socket_addr = SocketAddr::from_url(addr.clone(), ip_version).await?;
         let stream = tcp_socket.connect(socket_addr).await?;
+        Self::connect_with_stream(addr, verification, url_options, stream).await
+    }
+
+    async fn connect_with_stream(
+        addr: url::Url,
+        verification: ServerVerification,
+        url_options: bool,
+        stream: TcpStream,
+    ) -> Result<Box<dyn Tunnel>, TunnelError> {
+        let is_wss = is_wss(&addr)?;
 
         let info = TunnelInfo {
             tunnel_type: addr.scheme().to_owned(),
@@ -197,18 +514,16 @@ impl WSTunnelConnector {
             remote_addr: Some(addr.clone().into()),
         };
 
-        let c = ClientBuilder::from_uri(http::Uri::try_from(addr.to_string()).unwrap());
+        let (options, uri) = WSConnectorOptions::parse(&addr, &verification, url_options)?;
+        let mut c = ClientBuilder::from_uri(uri);
+        for (name, value) in options.headers.iter() {
+            c = c.add_header(name.clone(), value.clone());
+        }
         let stream: MaybeTlsStream<TcpStream> = if is_wss {
-            init_crypto_provider();
-            let tls_conn =
-                tokio_rustls::TlsConnector::from(Arc::new(get_insecure_tls_client_config()));
-            // Modify SNI logic: use "localhost" as SNI for url without domain to avoid IP blocking.
-            let sni = match addr.domain() {
-                None => "localhost".to_string(),
-                Some(domain) => domain.to_string(),
-            };
-            let server_name = rustls::pki_types::ServerName::try_from(sni)
-                .map_err(|_| TunnelError::InvalidProtocol("Invalid SNI".to_string()))?;
+            let tls_conn = tokio_rustls::TlsConnector::from(Arc::new(options.tls_client_config()?));
+            let server_name =
+                rustls::pki_types::ServerName::try_from(options.server_name(&addr))
+                    .map_err(|_| TunnelError::InvalidProtocol("Invalid SNI".to_string()))?;
             let stream = tls_conn.connect(server_name, stream).await?;
             MaybeTlsStream::Rustls(stream)
         } else {
@@ -231,7 +546,14 @@ impl WSTunnelConnector {
         } else {
             TcpSocket::new_v6()?
         };
-        Self::connect_with(self.addr.clone(), self.ip_version, socket).await
+        Self::connect_with(
+            self.addr.clone(),
+            self.ip_version,
+            self.server_verification.clone(),
+            self.url_options,
+            socket,
+        )
+        .await
     }
 
     async fn connect_with_custom_bind(
@@ -258,6 +580,8 @@ impl WSTunnelConnector {
             futures.push(Self::connect_with(
                 self.addr.clone(),
                 self.ip_version,
+                self.server_verification.clone(),
+                self.url_options,
                 socket,
             ))
         }
@@ -269,6 +593,18 @@ impl WSTunnelConnector {
 #[async_trait::async_trait]
 impl TunnelConnector for WSTunnelConnector {
     async fn connect(&mut self) -> Result<Box<dyn Tunnel>, super::TunnelError> {
+        if let Some(proxy) = &self.proxy {
+            is_wss(&self.addr)?;
+            let stream = connect_through_proxy(proxy, &self.addr).await?;
+            return Self::connect_with_stream(
+                self.addr.clone(),
+                self.server_verification.clone(),
+                self.url_options,
+                stream,
+            )
+            .await;
+        }
+
         let addr = SocketAddr::from_url(self.addr.clone(), self.ip_version).await?;
         if self.bind_addrs.is_empty() || addr.is_ipv6() {
             self.connect_with_default_bind(addr).await
@@ -293,7 +629,8 @@ impl TunnelConnector for WSTunnelConnector {
 #[cfg(test)]
 pub mod tests {
     use crate::tunnel::common::tests::_tunnel_pingpong;
-    use crate::tunnel::websocket::{WSTunnelConnector, WSTunnelListener};
+    use crate::tunnel::tls::{tests::TestPki, ServerVerification};
+    use crate::tunnel::websocket::{WSConnectorOptions, WSTunnelConnector, WSTunnelListener};
     use crate::tunnel::{TunnelConnector, TunnelListener};
 
     #[rstest::rstest]
@@ -317,6 +654,20 @@ pub mod tests {
         _tunnel_pingpong(listener, connector).await
     }
 
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn ws_pingpong_through_proxy(#[values("ws", "wss")] proto: &str) {
+        let listener = WSTunnelListener::new(format!("{}://0.0.0.0:25559", proto).parse().unwrap());
+        let mut connector =
+            WSTunnelConnector::new(format!("{}://127.0.0.1:25559", proto).parse().unwrap());
+        let mut proxy = crate::tunnel::proxy::tests::start_http_proxy(Some("user:pass")).await;
+        proxy.set_username("user").unwrap();
+        proxy.set_password(Some("pass")).unwrap();
+        connector.set_proxy(Some(proxy));
+        _tunnel_pingpong(listener, connector).await
+    }
+
     // TODO: tokio-websockets cannot correctly handle close, benchmark case is disabled
     // #[rstest::rstest]
     // #[tokio::test]
@@ -329,6 +680,136 @@ pub mod tests {
     //     _tunnel_bench(listener, connector).await
     // }
 
+    #[test]
+    fn ws_connector_options() {
+        let addr = "wss://1.2.3.4/ws?token=abc&sni=cdn.example.com&host=origin.example.com&header=X-Auth%3A%20secret&ca=%2Fetc%2Fca.pem"
+            .parse()
+            .unwrap();
+        let (options, uri) = WSConnectorOptions::parse(&addr, &Default::default(), true).unwrap();
+        assert_eq!("wss://origin.example.com/ws?token=abc", uri.to_string());
+        assert_eq!("cdn.example.com", options.server_name(&addr));
+        assert_eq!(
+            ServerVerification::Ca("/etc/ca.pem".into()),
+            options.verification
+        );
+        assert_eq!(1, options.headers.len());
+        assert_eq!("x-auth", options.headers[0].0.as_str());
+        assert_eq!("secret", options.headers[0].1.to_str().unwrap());
+
+        // urls advertised by other peers pick no local files or headers
+        let addr = "wss://1.2.3.4/ws?token=abc&header=X-Auth%3A%20secret&ca=%2Fetc%2Fca.pem&cert=%2Fetc%2Fcert.pem&key=%2Fetc%2Fkey.pem"
+            .parse()
+            .unwrap();
+        let (options, uri) = WSConnectorOptions::parse(&addr, &Default::default(), false).unwrap();
+        assert_eq!("wss://1.2.3.4/ws?token=abc", uri.to_string());
+        assert_eq!(ServerVerification::Insecure, options.verification);
+        assert!(options.headers.is_empty());
+        assert_eq!(None, options.cert);
+        assert_eq!(None, options.key);
+
+        let addr = "wss://1.2.3.4:11012".parse().unwrap();
+        let (options, uri) = WSConnectorOptions::parse(&addr, &Default::default(), true).unwrap();
+        assert_eq!("wss://1.2.3.4:11012/", uri.to_string());
+        assert_eq!("localhost", options.server_name(&addr));
+        let (options, _) =
+            WSConnectorOptions::parse(&addr, &ServerVerification::SystemRoots, true).unwrap();
+        assert_eq!("1.2.3.4", options.server_name(&addr));
+
+        let addr = "ws://1.2.3.4/?header=invalid".parse().unwrap();
+        assert!(WSConnectorOptions::parse(&addr, &Default::default(), true).is_err());
+    }
+
+    async fn spawn_accept_loop(mut listener: WSTunnelListener) -> tokio::task::JoinHandle<()> {
+        listener.listen().await.unwrap();
+        tokio::spawn(async move {
+            let mut tunnels = vec![];
+            while let Ok(tunnel) = listener.accept().await {
+                tunnels.push(tunnel);
+            }
+        })
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn ws_listener_path_and_token() {
+        let mut listener = WSTunnelListener::new("ws://0.0.0.0:25560/tunnel".parse().unwrap());
+        listener.set_token("abc".to_owned());
+        let j = spawn_accept_loop(listener).await;
+
+        for url in [
+            "ws://127.0.0.1:25560/tunnel?token=abc",
+            "ws://127.0.0.1:25560/tunnel?header=Authorization%3A%20Bearer%20abc",
+        ] {
+            let mut connector = WSTunnelConnector::new(url.parse().unwrap());
+            connector.set_url_options(true);
+            connector.connect().await.unwrap();
+        }
+        for url in [
+            "ws://127.0.0.1:25560/tunnel",
+            "ws://127.0.0.1:25560/tunnel?token=abd",
+            "ws://127.0.0.1:25560/tunnel?token=abcd",
+            "ws://127.0.0.1:25560/?token=abc",
+        ] {
+            let mut connector = WSTunnelConnector::new(url.parse().unwrap());
+            connector.connect().await.unwrap_err();
+        }
+
+        j.abort();
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn wss_pinned_ca_and_client_cert() {
+        let pki = TestPki::generate();
+        let (cert, key) = pki.issue("client", &["client.example.com"]);
+        let mut listener = WSTunnelListener::new("wss://0.0.0.0:25561".parse().unwrap());
+        listener.set_client_ca_file(pki.path("ca.pem").into());
+        let j = spawn_accept_loop(listener).await;
+
+        let mut connector = WSTunnelConnector::new("wss://127.0.0.1:25561".parse().unwrap());
+        connector.connect().await.unwrap_err();
+
+        let mut url: url::Url = "wss://127.0.0.1:25561".parse().unwrap();
+        url.query_pairs_mut()
+            .append_pair("cert", &cert)
+            .append_pair("key", &key);
+        let mut connector = WSTunnelConnector::new(url.clone());
+        connector.set_url_options(true);
+        connector.connect().await.unwrap();
+
+        // a url advertised by another peer picks no client certificate
+        let mut connector = WSTunnelConnector::new(url.clone());
+        connector.connect().await.unwrap_err();
+
+        // the listener certificate is not signed by the pinned ca
+        url.query_pairs_mut().append_pair("ca", &pki.path("ca.pem"));
+        let mut connector = WSTunnelConnector::new(url);
+        connector.set_url_options(true);
+        connector.connect().await.unwrap_err();
+
+        j.abort();
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn wss_listener_cert_files() {
+        let pki = TestPki::generate();
+        let (cert, key) = pki.issue("server", &["127.0.0.1", "relay.example.com"]);
+        let mut listener = WSTunnelListener::new("wss://0.0.0.0:25562".parse().unwrap());
+        listener.set_cert_files(cert.into(), key.into());
+        let mut connector = WSTunnelConnector::new("wss://127.0.0.1:25562".parse().unwrap());
+        connector.set_server_verification(ServerVerification::Ca(pki.path("ca.pem").into()));
+        _tunnel_pingpong(listener, connector).await;
+
+        let listener = WSTunnelListener::new("wss://0.0.0.0:25563".parse().unwrap());
+        let j = spawn_accept_loop(listener).await;
+        // the self signed certificate fails verification
+        let mut connector = WSTunnelConnector::new("wss://127.0.0.1:25563".parse().unwrap());
+        connector.set_server_verification(ServerVerification::Ca(pki.path("ca.pem").into()));
+        connector.connect().await.unwrap_err();
+        j.abort();
+    }
+
     #[tokio::test]
     async fn ws_accept_wss() {
         let mut listener = WSTunnelListener::new("wss://0.0.0.0:25558".parse().unwrap());